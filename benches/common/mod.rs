@@ -0,0 +1,11 @@
+//! Helpers shared by the benchmarks
+
+/// The murmur3 finalizer, spreads consecutive integers over the whole `u32` range
+pub fn fmix32(mut h: u32) -> u32 {
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85ebca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2ae35);
+    h ^= h >> 16;
+    h
+}
@@ -0,0 +1,57 @@
+//! Cost of finding a key inside a node, measured through point reads and inserts on an in-memory
+//! database at several key sizes. Run with `cargo bench --bench node_lookup`.
+
+mod common;
+
+use std::{hint::black_box, time::Instant};
+
+use database_from_scratch::kv_store::KV;
+
+use common::fmix32;
+
+const KEYS: u32 = 20_000;
+const KEY_SIZES: [usize; 4] = [8, 32, 128, 512];
+
+/// A key of `size` bytes that sorts in a random position, padded out after the random part
+fn make_key(i: u32, size: usize) -> Vec<u8> {
+    let mut key = format!("{:08x}", fmix32(i)).into_bytes();
+    key.resize(size, b'.');
+    key
+}
+
+fn nanos_per_op(start: Instant) -> f64 {
+    start.elapsed().as_nanos() as f64 / KEYS as f64
+}
+
+fn run(size: usize) {
+    let keys: Vec<Vec<u8>> = (0..KEYS).map(|i| make_key(i, size)).collect();
+    let mut kv = KV::open_in_memory().unwrap();
+
+    let start = Instant::now();
+    for key in &keys {
+        kv.set(key, b"value").unwrap();
+    }
+    let insert = nanos_per_op(start);
+
+    // read back in a different order from the one the keys went in
+    let start = Instant::now();
+    for i in 0..KEYS {
+        let key = &keys[(fmix32(i + KEYS) % KEYS) as usize];
+        black_box(kv.get(key).unwrap());
+    }
+    let get = nanos_per_op(start);
+
+    println!(
+        "key {:>4} B  get {:>7.0} ns  insert {:>7.0} ns  depth {}",
+        size,
+        get,
+        insert,
+        kv.stats().depth
+    );
+}
+
+fn main() {
+    for size in KEY_SIZES {
+        run(size);
+    }
+}
@@ -0,0 +1,103 @@
+//! Fan-out and file size of workloads whose keys share long prefixes. Run with
+//! `cargo bench --bench prefix_compression`.
+
+mod common;
+
+use std::{env, fs, time::Instant};
+
+use database_from_scratch::{
+    kv_store::KV,
+    relational_db::{records::Record, tables::TableDef, value::Value, DB},
+};
+
+use common::fmix32;
+
+const ROWS: u32 = 50_000;
+
+fn temp_path(name: &str) -> String {
+    let path = env::temp_dir().join(format!("prefix_compression_{}.db", name));
+    let _ = fs::remove_file(&path);
+    path.to_string_lossy().to_string()
+}
+
+fn report(name: &str, kv: &KV, seconds: f64) {
+    let stats = kv.stats();
+    println!(
+        "{:<24} keys/leaf {:>6.1}  leaves {:>6}  depth {}  fill {:>4.1}%  file {:>6} KiB  {:.2}s",
+        name,
+        stats.keys as f64 / stats.leaf_nodes as f64,
+        stats.leaf_nodes,
+        stats.depth,
+        stats.avg_fill * 100.0,
+        stats.file_size / 1024,
+        seconds
+    );
+}
+
+/// Rows keyed by a composite key whose leading column repeats, with a short value
+fn relational() {
+    let path = temp_path("relational");
+    let mut db = DB::open(path.clone()).unwrap();
+    db.table_new(TableDef {
+        name: "events".to_string(),
+        types: vec![Value::BYTES_TYPE, Value::INT64_TYPE, Value::INT64_TYPE],
+        columns: vec![
+            "device".to_string(),
+            "time".to_string(),
+            "reading".to_string(),
+        ],
+        primary_keys: 2,
+        prefix: 0,
+    })
+    .unwrap();
+
+    let start = Instant::now();
+    for i in 0..ROWS {
+        let mut record = Record::new();
+        record
+            .add_bytes(
+                "device".to_string(),
+                format!("sensor-{:03}", fmix32(i) % 20).into_bytes(),
+            )
+            .add_int64("time".to_string(), i as i64)
+            .add_int64("reading".to_string(), fmix32(i) as i64);
+        db.insert("events", record).unwrap();
+    }
+    report("relational", db.kv(), start.elapsed().as_secs_f64());
+    db.close();
+    fs::remove_file(path).unwrap();
+}
+
+/// Random inserts of keys that all start with the same long path
+fn long_prefix() {
+    let path = temp_path("long_prefix");
+    let mut kv = KV::open(path.clone()).unwrap();
+    let start = Instant::now();
+    for i in 0..ROWS {
+        let key = format!("/users/accounts/settings/{:010}", fmix32(i));
+        kv.set(key.as_bytes(), b"value").unwrap();
+    }
+    report("long prefix", &kv, start.elapsed().as_secs_f64());
+    kv.close();
+    fs::remove_file(path).unwrap();
+}
+
+/// Random keys with nothing in common, where compression should change nothing
+fn random_keys() {
+    let path = temp_path("random");
+    let mut kv = KV::open(path.clone()).unwrap();
+    let start = Instant::now();
+    for i in 0..ROWS {
+        let key = format!("{:08x}{:08x}", fmix32(i), fmix32(i + ROWS));
+        kv.set(key.as_bytes(), b"value").unwrap();
+    }
+    report("random keys", &kv, start.elapsed().as_secs_f64());
+    kv.close();
+    fs::remove_file(path).unwrap();
+}
+
+fn main() {
+    relational();
+    long_prefix();
+    random_keys();
+}
@@ -0,0 +1,230 @@
+//! Throughput and latency of common workloads against a file backed database. Run with
+//! `cargo bench --bench workloads`, or `cargo bench --bench workloads -- <name>...` to run only the
+//! workloads whose name contains one of the given words.
+//!
+//! Every workload starts from a fresh file. Setup, such as loading the keys a read workload looks
+//! up, is not timed. Each line reports the operations done, their rate, the median and 99th
+//! percentile latency of a single operation, the syncs issued by the timed part and the size of
+//! the file at the end.
+
+mod common;
+
+use std::{
+    env, fs,
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use database_from_scratch::{
+    kv_store::{CmpOption, BULK_LOAD_FILL, KV},
+    relational_db::{records::Record, tables::TableDef, value::Value, DB},
+};
+
+use common::fmix32;
+
+const OPS: u32 = 10_000;
+/// Keys read by each range scan
+const SCAN_LENGTH: usize = 100;
+const VALUE: [u8; 100] = [b'v'; 100];
+
+fn temp_path(name: &str) -> String {
+    let path = env::temp_dir().join(format!("workloads_{}.db", name));
+    let _ = fs::remove_file(&path);
+    path.to_string_lossy().to_string()
+}
+
+fn sequential_key(i: u32) -> Vec<u8> {
+    format!("key{:010}", i).into_bytes()
+}
+
+fn random_key(i: u32) -> Vec<u8> {
+    format!("key{:010}", fmix32(i)).into_bytes()
+}
+
+/// A fresh database holding `OPS` random keys, loaded in one commit
+fn loaded_kv(path: &str) -> KV {
+    let mut keys: Vec<Vec<u8>> = (0..OPS).map(random_key).collect();
+    keys.sort();
+    let mut kv = KV::open(path.to_string()).unwrap();
+    kv.bulk_load(
+        keys.into_iter().map(|key| (key, VALUE.to_vec())),
+        BULK_LOAD_FILL,
+    )
+    .unwrap();
+    kv
+}
+
+/// Latency of each operation of a workload
+struct Timings {
+    latencies: Vec<Duration>,
+    total: Duration,
+}
+
+impl Timings {
+    fn run(ops: u32, mut op: impl FnMut(u32)) -> Timings {
+        let mut latencies = Vec::with_capacity(ops as usize);
+        let start = Instant::now();
+        for i in 0..ops {
+            let op_start = Instant::now();
+            op(i);
+            latencies.push(op_start.elapsed());
+        }
+        let total = start.elapsed();
+        latencies.sort();
+        Timings { latencies, total }
+    }
+
+    fn percentile(&self, percent: usize) -> Duration {
+        let idx = (self.latencies.len() * percent / 100).min(self.latencies.len() - 1);
+        self.latencies[idx]
+    }
+
+    fn report(&self, name: &str, syncs: u64, file_size: u64) {
+        let ops = self.latencies.len();
+        println!(
+            "{:<18} {:>6} ops {:>9.0} ops/s  p50 {:>8.1} us  p99 {:>8.1} us  syncs {:>6}  file {:>6} KiB",
+            name,
+            ops,
+            ops as f64 / self.total.as_secs_f64(),
+            self.percentile(50).as_secs_f64() * 1e6,
+            self.percentile(99).as_secs_f64() * 1e6,
+            syncs,
+            file_size / 1024
+        );
+    }
+}
+
+/// Runs `ops` operations on `kv` and reports them
+fn run_kv(name: &str, kv: &mut KV, ops: u32, mut op: impl FnMut(&mut KV, u32)) {
+    let syncs = kv.stats().syncs;
+    let timings = Timings::run(ops, |i| op(kv, i));
+    let stats = kv.stats();
+    timings.report(name, stats.syncs - syncs, stats.file_size);
+}
+
+fn sequential_insert(path: &str) {
+    let mut kv = KV::open(path.to_string()).unwrap();
+    run_kv("sequential insert", &mut kv, OPS, |kv, i| {
+        kv.set(&sequential_key(i), &VALUE).unwrap()
+    });
+    kv.close();
+}
+
+fn random_insert(path: &str) {
+    let mut kv = KV::open(path.to_string()).unwrap();
+    run_kv("random insert", &mut kv, OPS, |kv, i| {
+        kv.set(&random_key(i), &VALUE).unwrap()
+    });
+    kv.close();
+}
+
+fn point_get(path: &str) {
+    let mut kv = loaded_kv(path);
+    run_kv("point get", &mut kv, OPS, |kv, i| {
+        black_box(kv.get(&random_key(fmix32(i) % OPS)).unwrap());
+    });
+    kv.close();
+}
+
+fn range_scan(path: &str) {
+    let mut kv = loaded_kv(path);
+    let scans = OPS / SCAN_LENGTH as u32;
+    run_kv("range scan", &mut kv, scans, |kv, i| {
+        let mut iter = kv.seek(&random_key(i), CmpOption::GE);
+        for _ in 0..SCAN_LENGTH {
+            if !iter.valid() {
+                break;
+            }
+            black_box(iter.deref());
+            if !iter.next() {
+                break;
+            }
+        }
+    });
+    kv.close();
+}
+
+/// Deletes every key in runs of 10 neighbouring keys, reading each one first
+fn delete_churn(path: &str) {
+    let mut kv = loaded_kv(path);
+    let mut keys: Vec<Vec<u8>> = (0..OPS).map(random_key).collect();
+    keys.sort();
+    let mut run_start = 0;
+    run_kv("delete churn", &mut kv, OPS, |kv, i| {
+        if i % 10 == 0 {
+            run_start = fmix32(i) as usize % keys.len();
+        }
+        let key = keys.remove(run_start.min(keys.len() - 1));
+        black_box(kv.get(&key).unwrap());
+        assert!(kv.del(&key).unwrap());
+    });
+    kv.close();
+}
+
+fn events_table() -> TableDef {
+    TableDef {
+        name: "events".to_string(),
+        types: vec![Value::INT64_TYPE, Value::BYTES_TYPE],
+        columns: vec!["id".to_string(), "payload".to_string()],
+        primary_keys: 1,
+        prefix: 0,
+    }
+}
+
+fn event(id: u32) -> Record {
+    let mut record = Record::new();
+    record.add_int64("id".to_string(), fmix32(id) as i64);
+    record
+}
+
+fn relational(path: &str) {
+    let mut db = DB::open(path.to_string()).unwrap();
+    db.table_new(events_table()).unwrap();
+
+    let syncs = db.kv().stats().syncs;
+    let timings = Timings::run(OPS, |i| {
+        let mut record = event(i);
+        record.add_bytes("payload".to_string(), VALUE.to_vec());
+        assert!(db.insert("events", record).unwrap());
+    });
+    let stats = db.kv().stats();
+    timings.report("relational insert", stats.syncs - syncs, stats.file_size);
+
+    let syncs = stats.syncs;
+    let timings = Timings::run(OPS, |i| {
+        let mut record = event(fmix32(i) % OPS);
+        assert!(db.get("events", &mut record).unwrap());
+        black_box(record);
+    });
+    let stats = db.kv().stats();
+    timings.report("relational get", stats.syncs - syncs, stats.file_size);
+    db.close();
+}
+
+/// A workload and the name it is picked by on the command line
+type Workload = (&'static str, fn(&str));
+
+fn main() {
+    let workloads: [Workload; 6] = [
+        ("sequential_insert", sequential_insert),
+        ("random_insert", random_insert),
+        ("point_get", point_get),
+        ("range_scan", range_scan),
+        ("delete_churn", delete_churn),
+        ("relational", relational),
+    ];
+    // `cargo bench` passes its own flags, such as `--bench`
+    let filters: Vec<String> = env::args()
+        .skip(1)
+        .filter(|arg| !arg.starts_with("--"))
+        .collect();
+
+    for (name, workload) in workloads {
+        if !filters.is_empty() && !filters.iter().any(|filter| name.contains(filter.as_str())) {
+            continue;
+        }
+        let path = temp_path(name);
+        workload(&path);
+        fs::remove_file(path).unwrap();
+    }
+}
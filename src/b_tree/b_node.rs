@@ -1,6 +1,6 @@
 extern crate byteorder;
 use byteorder::{ByteOrder, LittleEndian};
-use std::vec::Vec;
+use std::{borrow::Cow, cmp::Ordering, vec::Vec};
 
 // node format:
 // | type | num_keys |  pointers  |   offsets  | key-values
@@ -10,6 +10,11 @@ use std::vec::Vec;
 // | klen | vlen | key | val |
 // |  2B  |  2B  | ... | ... |
 
+// leaves can store the prefix shared by all their keys once, the type then has `PREFIXED` set
+// and the keys in the key-values leave the prefix out:
+// | type | num_keys | prefix_len | prefix |  pointers  | ...
+// |  2B  |    2B    |     2B     |  ...   | num_keys * 8B | ...
+
 pub const HEADER: u16 = 4;
 
 pub const BTREE_PAGE_SIZE: usize = 4096;
@@ -19,6 +24,11 @@ pub const BTREE_MAX_VAL_SIZE: usize = 3000;
 pub const U16_SIZE: usize = 2;
 pub const U64_SIZE: usize = 8;
 
+/// Set in the type of a leaf that stores a key prefix
+const PREFIXED: u16 = 0x100;
+/// Longest key prefix stored. Bounds how much a leaf grows when a new key shortens its prefix
+pub const MAX_PREFIX_SIZE: usize = 128;
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum NodeType {
     Node = 1,
@@ -42,37 +52,74 @@ impl Node for BNode {
     }
 }
 
+/** Size of the header of a node storing a key prefix of `prefix_len` bytes */
+pub fn header_size_for(prefix_len: usize) -> usize {
+    if prefix_len == 0 {
+        HEADER as usize
+    } else {
+        HEADER as usize + U16_SIZE + prefix_len
+    }
+}
+
+/** Length of the prefix a leaf holding `num_keys` keys from `first` to `last` stores. It is 0
+ * when storing the prefix would not save space */
+pub fn leaf_prefix_len(first: &[u8], last: &[u8], num_keys: u16) -> usize {
+    let len = first
+        .iter()
+        .zip(last)
+        .take_while(|(a, b)| a == b)
+        .count()
+        .min(MAX_PREFIX_SIZE);
+    // every key saves the prefix, which costs its length and the length field once
+    if (num_keys as usize).saturating_sub(1) * len > U16_SIZE {
+        len
+    } else {
+        0
+    }
+}
+
 pub struct BNode {
-    data: [u8; 2 * BTREE_PAGE_SIZE],
-    actual_size: usize,
+    data: Vec<u8>,
 }
 
 impl BNode {
     pub fn new(b_type: NodeType, num_keys: u16) -> Self {
-        let mut new_node = BNode {
-            data: [0; 2 * BTREE_PAGE_SIZE],
-            actual_size: BTREE_PAGE_SIZE,
-        };
-        new_node.set_header(b_type, num_keys);
-        new_node
+        BNode::new_with_size(b_type, num_keys, BTREE_PAGE_SIZE)
     }
     pub fn new_with_size(b_type: NodeType, num_keys: u16, size: usize) -> BNode {
         let mut new_node = BNode {
-            data: [0; 2 * BTREE_PAGE_SIZE],
-            actual_size: size,
+            data: vec![0; size],
         };
         new_node.set_header(b_type, num_keys);
         new_node
     }
 
+    /** Creates a leaf for `num_keys` keys running from `first` to `last`. The prefix they share
+     * is stored once when that saves space */
+    pub fn new_leaf(num_keys: u16, size: usize, first: &[u8], last: &[u8]) -> BNode {
+        let mut new_node = BNode::new_with_size(NodeType::Leaf, num_keys, size);
+        let prefix_len = leaf_prefix_len(first, last, num_keys);
+        if prefix_len > 0 {
+            LittleEndian::write_u16(&mut new_node.data[..2], NodeType::Leaf.value() | PREFIXED);
+            LittleEndian::write_u16(&mut new_node.data[4..6], prefix_len as u16);
+            new_node.data[6..6 + prefix_len].copy_from_slice(&first[..prefix_len]);
+        }
+        new_node
+    }
+
+    /** Creates an empty node of the same type for keys running from `first` to `last` */
+    fn new_like(&self, num_keys: u16, size: usize, first: &[u8], last: &[u8]) -> BNode {
+        match self.b_type() {
+            NodeType::Leaf => BNode::new_leaf(num_keys, size, first, last),
+            NodeType::Node => BNode::new_with_size(NodeType::Node, num_keys, size),
+        }
+    }
+
     /** Creates a BNode from a slice. Slice must be of length BTREE_PAGE_SLICE */
     pub fn from(data_in: &[u8]) -> BNode {
         assert!(data_in.len() == BTREE_PAGE_SIZE);
-        let mut data = [0; 2 * BTREE_PAGE_SIZE];
-        data[..BTREE_PAGE_SIZE].copy_from_slice(data_in);
         let new_node = BNode {
-            data,
-            actual_size: BTREE_PAGE_SIZE,
+            data: data_in.to_vec(),
         };
         // Makes sure not is of valid type
         new_node.b_type();
@@ -80,8 +127,8 @@ impl BNode {
     }
 
     pub fn get_data(self) -> [u8; BTREE_PAGE_SIZE] {
-        assert!(self.actual_size == BTREE_PAGE_SIZE);
-        self.data[..BTREE_PAGE_SIZE].try_into().unwrap()
+        assert!(self.data.len() == BTREE_PAGE_SIZE);
+        self.data.try_into().unwrap()
     }
 
     // Header
@@ -91,7 +138,7 @@ impl BNode {
     }
 
     pub fn b_type(&self) -> NodeType {
-        match LittleEndian::read_u16(&self.data[..2]) {
+        match LittleEndian::read_u16(&self.data[..2]) & !PREFIXED {
             1 => NodeType::Node,
             2 => NodeType::Leaf,
             n => panic!("Invalid BNode type {}", n),
@@ -102,23 +149,36 @@ impl BNode {
         LittleEndian::read_u16(&self.data[2..4])
     }
 
+    /** The prefix shared by every key, which the key-values leave out */
+    pub fn prefix(&self) -> &[u8] {
+        if LittleEndian::read_u16(&self.data[..2]) & PREFIXED == 0 {
+            return &[];
+        }
+        let prefix_len = LittleEndian::read_u16(&self.data[4..6]) as usize;
+        &self.data[6..6 + prefix_len]
+    }
+
+    fn header_size(&self) -> u16 {
+        header_size_for(self.prefix().len()) as u16
+    }
+
     // Page Pointers
     pub fn get_ptr(&self, idx: u16) -> u64 {
         assert!(idx < self.num_keys());
-        let pos: usize = HEADER as usize + 8 * idx as usize;
+        let pos: usize = self.header_size() as usize + 8 * idx as usize;
         LittleEndian::read_u64(&self.data[pos..pos + U64_SIZE])
     }
 
     fn set_ptr(&mut self, idx: u16, val: u64) {
         assert!(idx < self.num_keys());
-        let pos: usize = HEADER as usize + 8 * idx as usize;
+        let pos: usize = self.header_size() as usize + 8 * idx as usize;
         LittleEndian::write_u64(&mut self.data[pos..pos + U64_SIZE], val)
     }
 
     // Offset List
     pub fn offset_pos(&self, idx: u16) -> u16 {
         assert!(1 <= idx && idx <= self.num_keys());
-        HEADER + 8 * self.num_keys() + 2 * (idx - 1)
+        self.header_size() + 8 * self.num_keys() + 2 * (idx - 1)
     }
 
     pub fn get_offset(&self, idx: u16) -> u16 {
@@ -139,11 +199,23 @@ impl BNode {
     pub fn kv_pos(&self, idx: u16) -> u16 {
         let num_keys: u16 = self.num_keys();
         assert!(idx <= num_keys);
-        HEADER + 10 * num_keys + self.get_offset(idx)
+        self.header_size() + 10 * num_keys + self.get_offset(idx)
+    }
+
+    /** `get_key` returns the key at idx. Leaves that store a key prefix have to put the key back
+     * together, `cmp_key` compares without copying */
+    pub fn get_key(&self, idx: u16) -> Cow<'_, [u8]> {
+        let prefix = self.prefix();
+        let suffix = self.get_suffix(idx);
+        if prefix.is_empty() {
+            Cow::Borrowed(suffix)
+        } else {
+            Cow::Owned([prefix, suffix].concat())
+        }
     }
 
-    /** `get_key` returns the key at idx. It returns a reference to the key instead of the actual key for ease of comparison*/
-    pub fn get_key(&self, idx: u16) -> &[u8] {
+    /** The key at idx as stored, without the prefix */
+    fn get_suffix(&self, idx: u16) -> &[u8] {
         assert!(idx < self.num_keys());
 
         // Position of the key
@@ -156,6 +228,16 @@ impl BNode {
         self.data[key_pos..key_pos + key_length as usize].as_ref()
     }
 
+    /** Compares the key at idx with `key` */
+    pub fn cmp_key(&self, idx: u16, key: &[u8]) -> Ordering {
+        let prefix = self.prefix();
+        let shared = prefix.len().min(key.len());
+        match prefix.cmp(&key[..shared]) {
+            Ordering::Equal => self.get_suffix(idx).cmp(&key[shared..]),
+            other => other,
+        }
+    }
+
     pub fn get_val(&self, idx: u16) -> &[u8] {
         assert!(idx < self.num_keys());
 
@@ -177,40 +259,111 @@ impl BNode {
     pub fn num_bytes(&self) -> u16 {
         let num_keys = self.num_keys();
         let num_bytes = self.kv_pos(num_keys);
-        assert!(num_bytes <= self.actual_size as u16);
+        assert!(num_bytes as usize <= self.data.len());
         num_bytes
     }
 
-    // returns the first kid node whose range intersects the key. (kid[i] <= key)
-    // TODO: bisect
+    /** Size of the key-values of keys `[start, end)` with their prefix put back */
+    fn expanded_kv_bytes(&self, start: u16, end: u16) -> usize {
+        let stored = (self.get_offset(end) - self.get_offset(start)) as usize;
+        stored + (end - start) as usize * self.prefix().len()
+    }
+
+    /** Size of a node holding keys `[start, end)` of this node, with its own key prefix */
+    fn range_size(&self, start: u16, end: u16) -> usize {
+        let num_keys = end - start;
+        let prefix_len = match self.b_type() {
+            NodeType::Leaf if num_keys > 0 => {
+                leaf_prefix_len(&self.get_key(start), &self.get_key(end - 1), num_keys)
+            }
+            _ => 0,
+        };
+        header_size_for(prefix_len) + 10 * num_keys as usize + self.expanded_kv_bytes(start, end)
+            - num_keys as usize * prefix_len
+    }
+
+    /** Size of the node `node_merge` would make out of this node and `right` */
+    pub fn merge_size(&self, right: &BNode) -> usize {
+        let num_keys = self.num_keys() + right.num_keys();
+        let kv_bytes = self.expanded_kv_bytes(0, self.num_keys())
+            + right.expanded_kv_bytes(0, right.num_keys());
+        let prefix_len = match self.b_type() {
+            NodeType::Leaf if num_keys > 0 => {
+                let (first, last) = BNode::merged_bounds(self, right);
+                leaf_prefix_len(&first, &last, num_keys)
+            }
+            _ => 0,
+        };
+        header_size_for(prefix_len) + 10 * num_keys as usize + kv_bytes
+            - num_keys as usize * prefix_len
+    }
+
+    /** First and last key of two neighbouring nodes together, at least one must have keys */
+    fn merged_bounds<'n>(left: &'n BNode, right: &'n BNode) -> (Cow<'n, [u8]>, Cow<'n, [u8]>) {
+        let first = if left.num_keys() > 0 { left } else { right };
+        let last = if right.num_keys() > 0 { right } else { left };
+        (first.get_key(0), last.get_key(last.num_keys() - 1))
+    }
+
+    /** Upper bound on the size of this node with one more key, whatever its prefix becomes */
+    fn grown_size(&self, key: &[u8], val: &[u8]) -> usize {
+        let num_keys = self.num_keys();
+        let size = header_size_for(MAX_PREFIX_SIZE)
+            + 10 * (num_keys as usize + 1)
+            + self.expanded_kv_bytes(0, num_keys)
+            + 4
+            + key.len()
+            + val.len();
+        size.max(2 * BTREE_PAGE_SIZE)
+    }
+
+    /** Index of the last key `<= key`, the kid whose range holds `key`. The first key counts as
+     * a match whatever it is, so the lookup always lands somewhere. A leaf's shared prefix is
+     * compared once, then the stored suffixes are bisected straight out of the offset array */
     pub fn node_lookup_le(&self, key: &[u8]) -> u16 {
+        let num_keys = self.num_keys();
+        let prefix = self.prefix();
+        let shared = prefix.len().min(key.len());
+        match prefix.cmp(&key[..shared]) {
+            // every key is bigger, only the first one matches
+            Ordering::Greater => return 0,
+            // every key is smaller
+            Ordering::Less => return num_keys - 1,
+            Ordering::Equal => {}
+        }
+
+        // keys 1..low are <= key, keys high.. are bigger
+        let suffix = &key[shared..];
         let mut low: u16 = 1;
-        let mut high: u16 = self.num_keys() - 1;
-        let mut found: u16 = 0;
-
-        while low <= high {
-            let mid = (low + high) / 2;
-            let cmp = self.get_key(mid).cmp(key);
-
-            match cmp {
-                std::cmp::Ordering::Less | std::cmp::Ordering::Equal => {
-                    found = mid;
-                    low = mid + 1;
-                }
-                std::cmp::Ordering::Greater => {
-                    high = mid.saturating_sub(1);
-                }
+        let mut high: u16 = num_keys;
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if self.get_suffix(mid) <= suffix {
+                low = mid + 1;
+            } else {
+                high = mid;
             }
         }
-        found
+        low - 1
     }
 
-    /** Add a new key to a leaf node. Returns a double sized node which needs to be dealt with */
+    /** Add a new key to a leaf node. Returns a node that may be bigger than a page and needs to
+     * be dealt with */
     pub fn leaf_insert(self, idx: u16, key: &[u8], val: &[u8]) -> BNode {
         let old_num_keys = self.num_keys();
 
+        let first = if idx == 0 {
+            Cow::Borrowed(key)
+        } else {
+            self.get_key(0)
+        };
+        let last = if idx == old_num_keys {
+            Cow::Borrowed(key)
+        } else {
+            self.get_key(old_num_keys - 1)
+        };
         let mut new_node =
-            BNode::new_with_size(NodeType::Leaf, old_num_keys + 1, 2 * BTREE_PAGE_SIZE);
+            self.new_like(old_num_keys + 1, self.grown_size(key, val), &first, &last);
         new_node.node_append_range(&self, 0, 0, idx);
         new_node.node_append_kv(idx, 0, key, val);
         new_node.node_append_range(&self, idx + 1, idx, old_num_keys - idx);
@@ -218,11 +371,14 @@ impl BNode {
         new_node
     }
 
-    /** Update a key in a leaf node. Returns a double sized node which needs to be dealt with */
+    /** Update a key in a leaf node. Returns a node that may be bigger than a page and needs to be
+     * dealt with */
     pub fn leaf_update(self, idx: u16, key: &[u8], val: &[u8]) -> BNode {
         let old_num_keys = self.num_keys();
 
-        let mut new_node = BNode::new_with_size(NodeType::Leaf, old_num_keys, 2 * BTREE_PAGE_SIZE);
+        let first = self.get_key(0);
+        let last = self.get_key(old_num_keys - 1);
+        let mut new_node = self.new_like(old_num_keys, self.grown_size(key, val), &first, &last);
         new_node.node_append_range(&self, 0, 0, idx);
         new_node.node_append_kv(idx, 0, key, val);
         new_node.node_append_range(&self, idx + 1, idx + 1, old_num_keys - idx - 1);
@@ -233,7 +389,17 @@ impl BNode {
     pub fn leaf_delete(self, idx: u16) -> BNode {
         let old_num_keys = self.num_keys();
 
-        let mut new_node = BNode::new(NodeType::Leaf, old_num_keys - 1);
+        let mut new_node = if old_num_keys == 1 {
+            BNode::new(NodeType::Leaf, 0)
+        } else {
+            let first = self.get_key(if idx == 0 { 1 } else { 0 });
+            let last = self.get_key(if idx == old_num_keys - 1 {
+                old_num_keys - 2
+            } else {
+                old_num_keys - 1
+            });
+            BNode::new_leaf(old_num_keys - 1, BTREE_PAGE_SIZE, &first, &last)
+        };
         new_node.node_append_range(&self, 0, 0, idx);
         new_node.node_append_range(&self, idx, idx + 1, old_num_keys - idx - 1);
 
@@ -245,7 +411,12 @@ impl BNode {
         let right_num_keys = right.num_keys();
         let new_num_keys = left_num_keys + right_num_keys;
 
-        let mut new_node = BNode::new(self.b_type(), new_num_keys);
+        let mut new_node = if new_num_keys == 0 {
+            BNode::new(self.b_type(), 0)
+        } else {
+            let (first, last) = BNode::merged_bounds(&self, &right);
+            self.new_like(new_num_keys, BTREE_PAGE_SIZE, &first, &last)
+        };
         new_node.node_append_range(&self, 0, 0, left_num_keys);
         new_node.node_append_range(&right, left_num_keys, 0, right_num_keys);
 
@@ -269,6 +440,13 @@ impl BNode {
         if n == 0 {
             return;
         }
+        if old.prefix() != self.prefix() {
+            // the keys have to be written again without our prefix
+            for i in 0..n {
+                self.append_kv_from(old, dst_new + i, src_old + i);
+            }
+            return;
+        }
 
         // pointers
         for i in 0..n {
@@ -295,91 +473,117 @@ impl BNode {
         self.data[kv_pos..kv_pos + buf_len as usize].copy_from_slice(buf);
     }
 
+    /** Copies the KV at `src` of a node with a different prefix into position `idx` */
+    fn append_kv_from(&mut self, old: &BNode, idx: u16, src: u16) {
+        let old_prefix = old.prefix();
+        let suffix = old.get_suffix(src);
+        let prefix_len = self.prefix().len();
+        assert!(old_prefix
+            .iter()
+            .chain(suffix)
+            .take(prefix_len)
+            .eq(self.prefix().iter()));
+
+        // the key is the old prefix followed by the suffix, minus our own prefix
+        let (head, tail) = if prefix_len <= old_prefix.len() {
+            (&old_prefix[prefix_len..], suffix)
+        } else {
+            (&[][..], &suffix[prefix_len - old_prefix.len()..])
+        };
+        self.write_kv(idx, old.get_ptr(src), head, tail, old.get_val(src));
+    }
+
     // copy a KV into the position
     pub fn node_append_kv(&mut self, idx: u16, ptr: u64, key: &[u8], val: &[u8]) {
+        let prefix_len = self.prefix().len();
+        assert!(key.starts_with(self.prefix()));
+        self.write_kv(idx, ptr, &[], &key[prefix_len..], val);
+    }
+
+    /** Writes a KV whose stored key is `head` followed by `tail` */
+    fn write_kv(&mut self, idx: u16, ptr: u64, head: &[u8], tail: &[u8], val: &[u8]) {
         // ptrs
         self.set_ptr(idx, ptr);
 
         // KVs
+        let key_len = head.len() + tail.len();
         let pos: usize = self.kv_pos(idx) as usize;
-        LittleEndian::write_u16(&mut self.data[pos..pos + U16_SIZE], key.len() as u16);
+        LittleEndian::write_u16(&mut self.data[pos..pos + U16_SIZE], key_len as u16);
         let vlen_pos = pos + U16_SIZE;
         LittleEndian::write_u16(
             &mut self.data[vlen_pos..vlen_pos + U16_SIZE],
             val.len() as u16,
         );
         let key_pos = pos + 2 * U16_SIZE;
-        self.data[key_pos..key_pos + key.len()].copy_from_slice(key);
-        let val_pos = key_pos + key.len();
+        self.data[key_pos..key_pos + head.len()].copy_from_slice(head);
+        self.data[key_pos + head.len()..key_pos + key_len].copy_from_slice(tail);
+        let val_pos = key_pos + key_len;
         self.data[val_pos..val_pos + val.len()].copy_from_slice(val);
 
         // the offset of the next key
         let idx_offset = self.get_offset(idx);
-        self.set_offset(idx + 1, idx_offset + 4 + (key.len() + val.len()) as u16);
+        self.set_offset(idx + 1, idx_offset + 4 + (key_len + val.len()) as u16);
     }
 
-    // split a bigger-than-allowed node into two.
-    // the second node always fits on a page.
-    pub fn split2(self, left_size: usize) -> (BNode, BNode) {
-        assert!(self.num_keys() >= 2);
+    /** Finds where to split keys `[start, end)` in two, starting from the middle. The right part
+     * always fits on a page, the left part may not */
+    fn split_point(&self, start: u16, end: u16) -> u16 {
+        assert!(end - start >= 2);
 
         // initial guess for the split point
-        let mut n_left = self.num_keys() / 2;
-
-        fn calc_num_left_bytes(old_node: &BNode, n_left: u16) -> usize {
-            HEADER as usize + 10 * n_left as usize + old_node.get_offset(n_left) as usize
-        }
+        let mut n_left = start + (end - start) / 2;
 
-        while calc_num_left_bytes(&self, n_left) > BTREE_PAGE_SIZE {
+        while self.range_size(start, n_left) > BTREE_PAGE_SIZE {
             n_left -= 1;
         }
-        assert!(n_left >= 1);
+        assert!(n_left > start);
 
         // try to fit the right half
-        fn calc_num_right_bytes(old_node: &BNode, n_left: u16) -> usize {
-            let num_left_bytes = calc_num_left_bytes(old_node, n_left);
-            old_node.num_bytes() as usize - num_left_bytes + HEADER as usize
-        }
-
-        while calc_num_right_bytes(&self, n_left) > BTREE_PAGE_SIZE {
+        while self.range_size(n_left, end) > BTREE_PAGE_SIZE {
             n_left += 1;
         }
-        assert!(n_left < self.num_keys());
-        let n_right = self.num_keys() - n_left;
-
-        // Create new nodes
-        let mut left_node = BNode::new_with_size(self.b_type(), n_left, left_size); // Might be split later
-        left_node.node_append_range(&self, 0, 0, n_left);
-
-        let mut right_node = BNode::new_with_size(self.b_type(), n_right, BTREE_PAGE_SIZE);
-        right_node.node_append_range(&self, 0, n_left, n_right);
-
-        // Make sure right side is not too big. Left may still be too big
-        assert!(right_node.num_bytes() <= BTREE_PAGE_SIZE as u16);
-        (left_node, right_node)
+        assert!(n_left < end);
+        n_left
+    }
+
+    /** Copies keys `[start, end)` into a new node of a page, with its own key prefix */
+    fn copy_range(&self, start: u16, end: u16) -> BNode {
+        assert!(self.range_size(start, end) <= BTREE_PAGE_SIZE);
+        let mut new_node = self.new_like(
+            end - start,
+            BTREE_PAGE_SIZE,
+            &self.get_key(start),
+            &self.get_key(end - 1),
+        );
+        new_node.node_append_range(self, 0, start, end - start);
+        new_node
     }
 
-    /** split a node if it's too big. the results are 1~3 correctly sized nodes */
+    /** split a node if it's too big. the results are 1~3 correctly sized nodes. Every part
+     * recomputes its key prefix, so a leaf whose prefix got shorter by an insert never needs more
+     * than 3: the keys before the insert, the new key and the keys after it each fit */
     pub fn split3(mut self) -> (u16, Vec<BNode>) {
         if self.num_bytes() <= BTREE_PAGE_SIZE as u16 {
             self.resize_buffer(BTREE_PAGE_SIZE);
             return (1, vec![self]);
         };
 
-        let (mut left_node, right_node) = self.split2(2 * BTREE_PAGE_SIZE);
-        if left_node.num_bytes() <= BTREE_PAGE_SIZE as u16 {
-            left_node.resize_buffer(BTREE_PAGE_SIZE);
-            return (2, vec![left_node, right_node]);
+        let num_keys = self.num_keys();
+        let n_left = self.split_point(0, num_keys);
+        let right_node = self.copy_range(n_left, num_keys);
+        if self.range_size(0, n_left) <= BTREE_PAGE_SIZE {
+            return (2, vec![self.copy_range(0, n_left), right_node]);
         };
 
-        let (left_left_node, middle_node) = left_node.split2(BTREE_PAGE_SIZE);
-        assert!(left_left_node.num_bytes() <= BTREE_PAGE_SIZE as u16);
+        let n_left_left = self.split_point(0, n_left);
+        let left_left_node = self.copy_range(0, n_left_left);
+        let middle_node = self.copy_range(n_left_left, n_left);
         (3, vec![left_left_node, middle_node, right_node])
     }
 
     pub fn resize_buffer(&mut self, new_size: usize) {
-        // Create a new buffer with the desired size and initialize with zeros
-        self.actual_size = new_size;
+        assert!(self.num_bytes() as usize <= new_size);
+        self.data.resize(new_size, 0);
     }
 }
 
@@ -389,11 +593,8 @@ mod tests {
 
     impl Clone for BNode {
         fn clone(&self) -> Self {
-            let mut data = [0; 2 * BTREE_PAGE_SIZE];
-            data[..BTREE_PAGE_SIZE].copy_from_slice(&self.data[..BTREE_PAGE_SIZE]);
             BNode {
-                data,
-                actual_size: BTREE_PAGE_SIZE,
+                data: self.data[..BTREE_PAGE_SIZE].to_vec(),
             }
         }
     }
@@ -534,6 +735,52 @@ mod tests {
         assert_eq!(bnode.node_lookup_le(&[6]), 4);
     }
 
+    #[test]
+    fn test_bnode_node_lookup_le_edges() {
+        // a single key always matches, whatever is looked up
+        let mut single = BNode::new(NodeType::Node, 1);
+        single.node_append_kv(0, 7, &[5], &[]);
+        assert_eq!(single.node_lookup_le(&[]), 0);
+        assert_eq!(single.node_lookup_le(&[9]), 0);
+
+        // the first key is a sentinel, even a smaller key lands on it
+        let mut two = BNode::new(NodeType::Node, 2);
+        two.node_append_kv(0, 0, &[3], &[]);
+        two.node_append_kv(1, 0, &[3, 0], &[]);
+        assert_eq!(two.node_lookup_le(&[1]), 0);
+        assert_eq!(two.node_lookup_le(&[3]), 0);
+        assert_eq!(two.node_lookup_le(&[3, 0]), 1);
+        assert_eq!(two.node_lookup_le(&[3, 0, 0]), 1);
+    }
+
+    #[test]
+    fn test_bnode_node_lookup_le_matches_linear_scan() {
+        let bnode = prefixed_leaf(b"table:", 150, 2);
+        assert_eq!(bnode.prefix(), b"table:0");
+        let linear = |key: &[u8]| {
+            (1..bnode.num_keys())
+                .take_while(|&i| bnode.cmp_key(i, key) != Ordering::Greater)
+                .last()
+                .unwrap_or(0)
+        };
+        let mut probes: Vec<Vec<u8>> = vec![
+            b"".to_vec(),
+            b"t".to_vec(),
+            b"table:".to_vec(),
+            b"table:0".to_vec(),
+            b"table:1".to_vec(),
+            b"tablf".to_vec(),
+        ];
+        for i in 0..160 {
+            probes.push(format!("table:{:04}", i).into_bytes());
+            probes.push(format!("table:{:04}\x00", i).into_bytes());
+            probes.push(format!("table:{:03}", i).into_bytes());
+        }
+        for probe in probes {
+            assert_eq!(bnode.node_lookup_le(&probe), linear(&probe), "{:?}", probe);
+        }
+    }
+
     #[test]
     fn test_bnode_leaf_insert() {
         // Assuming the keys and values are 4 bytes each
@@ -555,4 +802,96 @@ mod tests {
         assert_eq!(bnode.get_key(3), vec![2u8; 4]);
         assert_eq!(bnode.get_val(1), vec![4u8; 4]);
     }
+
+    fn prefixed_leaf(prefix: &[u8], num_keys: u16, val_len: usize) -> BNode {
+        let keys: Vec<Vec<u8>> = (0..num_keys)
+            .map(|i| [prefix, format!("{:04}", i).as_bytes()].concat())
+            .collect();
+        let mut bnode = BNode::new_leaf(
+            num_keys,
+            BTREE_PAGE_SIZE,
+            &keys[0],
+            &keys[num_keys as usize - 1],
+        );
+        for (i, key) in keys.iter().enumerate() {
+            bnode.node_append_kv(i as u16, 0, key, &vec![b'v'; val_len]);
+        }
+        bnode
+    }
+
+    #[test]
+    fn test_bnode_prefixed_leaf() {
+        let bnode = prefixed_leaf(b"table:", 10, 2);
+        assert_eq!(bnode.b_type(), NodeType::Leaf);
+        assert_eq!(bnode.prefix(), b"table:000");
+        assert_eq!(bnode.get_key(3), b"table:0003".to_vec());
+        assert_eq!(bnode.get_val(3), b"vv");
+        // the prefix is stored once instead of 10 times
+        let plain = HEADER + 10 * 10 + 10 * (4 + 10 + 2);
+        assert_eq!(bnode.num_bytes(), plain - 10 * 9 + 2 + 9);
+
+        assert_eq!(bnode.cmp_key(3, b"table:0003"), Ordering::Equal);
+        assert_eq!(bnode.cmp_key(3, b"table:0004"), Ordering::Less);
+        assert_eq!(bnode.cmp_key(3, b"table:"), Ordering::Greater);
+        assert_eq!(bnode.cmp_key(3, b"tablf"), Ordering::Less);
+        assert_eq!(bnode.cmp_key(3, b"a"), Ordering::Greater);
+        assert_eq!(bnode.node_lookup_le(b"table:0005x"), 5);
+        assert_eq!(bnode.node_lookup_le(b"zzz"), 9);
+
+        // a page round trip keeps the format
+        let bnode = BNode::from(&bnode.get_data());
+        assert_eq!(bnode.get_key(9), b"table:0009".to_vec());
+    }
+
+    #[test]
+    fn test_bnode_prefix_only_when_smaller() {
+        assert_eq!(leaf_prefix_len(b"ab", b"ab", 1), 0);
+        assert_eq!(leaf_prefix_len(b"ab1", b"ab2", 2), 0);
+        assert_eq!(leaf_prefix_len(b"abc1", b"abc2", 2), 3);
+        assert_eq!(leaf_prefix_len(b"a1", b"a2", 4), 1);
+        assert_eq!(leaf_prefix_len(&[7; 500], &[7; 500], 10), MAX_PREFIX_SIZE);
+    }
+
+    #[test]
+    fn test_bnode_leaf_insert_shortens_prefix() {
+        // a full leaf whose keys only fit because of the long prefix
+        let prefix = vec![b'p'; MAX_PREFIX_SIZE];
+        let bnode = prefixed_leaf(&prefix, 150, 2);
+        assert!(bnode.num_bytes() as usize <= BTREE_PAGE_SIZE);
+
+        let bnode = bnode.leaf_insert(0, b"a", b"new");
+        assert_eq!(bnode.prefix(), b"");
+        assert!(bnode.num_bytes() as usize > 5 * BTREE_PAGE_SIZE);
+
+        let (num_nodes, nodes) = bnode.split3();
+        assert_eq!(num_nodes as usize, nodes.len());
+        assert!(num_nodes <= 3);
+        let mut keys = Vec::new();
+        for node in nodes {
+            assert!(node.num_bytes() as usize <= BTREE_PAGE_SIZE);
+            keys.extend((0..node.num_keys()).map(|i| node.get_key(i).to_vec()));
+            node.get_data();
+        }
+        assert_eq!(keys.len(), 151);
+        assert_eq!(keys[0], b"a");
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn test_bnode_merge_and_delete_prefixed() {
+        let left = prefixed_leaf(b"left:", 20, 5);
+        let right = prefixed_leaf(b"right:", 20, 5);
+        let merge_size = left.merge_size(&right);
+        let merged = left.node_merge(right);
+        assert_eq!(merged.num_bytes() as usize, merge_size);
+        assert_eq!(merged.get_key(0), b"left:0000".to_vec());
+        assert_eq!(merged.get_key(39), b"right:0019".to_vec());
+
+        // dropping the only key that differs makes the prefix longer again
+        let bnode = prefixed_leaf(b"k", 10, 1).leaf_insert(10, b"x", b"");
+        assert_eq!(bnode.prefix(), b"");
+        let bnode = bnode.leaf_delete(10);
+        assert_eq!(bnode.prefix(), b"k000");
+        assert_eq!(bnode.num_keys(), 10);
+    }
 }
@@ -1,3 +1,5 @@
+use std::borrow::Cow;
+
 use crate::b_tree::b_node::NodeType;
 
 use super::{b_node::BNode, BTree, BTreePageManager};
@@ -19,6 +21,24 @@ impl<'a, B: BTreePageManager> BTreeIterator<'a, B> {
         }
     }
 
+    /** Whether the iterator points at a key. The sentinel empty key at the start of the tree is not
+     * a valid position but moving forward from it reaches the first key */
+    pub fn valid(&self) -> bool {
+        if self.path.is_empty() {
+            return false;
+        }
+        let node = &self.path[self.positions.len() - 1];
+        !node
+            .get_key(self.positions[self.positions.len() - 1])
+            .is_empty()
+    }
+
+    /** Moves the iterator past the end of the tree */
+    pub fn invalidate(&mut self) {
+        self.path.clear();
+        self.positions.clear();
+    }
+
     /** Gets the current key value pair */
     pub fn deref(&self) -> Item {
         let node = &self.path[self.positions.len() - 1];
@@ -27,20 +47,30 @@ impl<'a, B: BTreePageManager> BTreeIterator<'a, B> {
         (key.to_vec(), value.to_vec())
     }
 
+    /** Gets the current key without copying the value */
+    pub fn key(&self) -> Cow<'_, [u8]> {
+        let node = &self.path[self.positions.len() - 1];
+        node.get_key(self.positions[self.positions.len() - 1])
+    }
+
     /** Moves forward along the iterator */
+    #[allow(clippy::should_implement_trait)] // a cursor move, not `Iterator::next`
     pub fn next(&mut self) -> bool {
-        self.nextIter(self.positions.len() - 1)
+        if self.path.is_empty() {
+            return false;
+        }
+        self.next_iter(self.positions.len() - 1)
     }
 
     /** Moves forward along the iterator, returns wether the move was a success or not */
-    fn nextIter(&mut self, level: usize) -> bool {
+    fn next_iter(&mut self, level: usize) -> bool {
         let node = &self.path[level];
         if self.positions[level] < node.num_keys() - 1 {
             // move within this node
             self.positions[level] += 1;
         } else if level > 0 {
             // move to a slibing node
-            if !self.nextIter(level - 1) {
+            if !self.next_iter(level - 1) {
                 //
                 return false;
             }
@@ -67,17 +97,20 @@ impl<'a, B: BTreePageManager> BTreeIterator<'a, B> {
 
     /** Moves backward along the iterator */
     pub fn prev(&mut self) -> bool {
-        self.prevIter(self.positions.len() - 1)
+        if self.path.is_empty() {
+            return false;
+        }
+        self.prev_iter(self.positions.len() - 1)
     }
 
     /** Moves forward along the iterator, returns wether the move was a success or not */
-    fn prevIter(&mut self, level: usize) -> bool {
+    fn prev_iter(&mut self, level: usize) -> bool {
         if self.positions[level] > 0 {
             // move within this node
             self.positions[level] -= 1;
         } else if level > 0 {
             // move to a slibing node
-            if !self.prevIter(level - 1) {
+            if !self.prev_iter(level - 1) {
                 //
                 return false;
             }
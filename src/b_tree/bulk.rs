@@ -0,0 +1,402 @@
+use std::{cmp::Ordering, collections::HashSet, iter::Peekable};
+
+use crate::prelude::*;
+
+use super::{
+    b_node::{
+        header_size_for, leaf_prefix_len, BNode, NodeType, BTREE_MAX_KEY_SIZE, BTREE_MAX_VAL_SIZE,
+        BTREE_PAGE_SIZE, HEADER, U16_SIZE, U64_SIZE,
+    },
+    BTree, BTreePageManager,
+};
+
+/// Fraction of each page filled by default, leaving room for later inserts
+pub const BULK_LOAD_FILL: f64 = 0.9;
+
+/// Bytes taken by one key and value in a node, including its pointer and offset
+fn entry_size(key: &[u8], val: &[u8]) -> usize {
+    U64_SIZE + U16_SIZE + 2 * U16_SIZE + key.len() + val.len()
+}
+
+/// The node currently being filled on one level of the tree
+struct Level {
+    entries: Vec<(u64, Vec<u8>, Vec<u8>)>,
+    /// Sum of `entry_size` over the entries
+    bytes: usize,
+}
+
+impl Level {
+    /** Size of the node once another entry is added. Leaves leave out the prefix their keys
+     * share */
+    fn size_with(&self, leaf: bool, key: &[u8], val: &[u8]) -> usize {
+        let num_keys = self.entries.len() + 1;
+        let prefix_len = match self.entries.first() {
+            Some((_, first, _)) if leaf => leaf_prefix_len(first, key, num_keys as u16),
+            _ => 0,
+        };
+        header_size_for(prefix_len) + self.bytes + entry_size(key, val) - num_keys * prefix_len
+    }
+}
+
+/// Builds a tree from left to right, one level at a time. A node is written as soon as the
+/// next entry would take it past the target size
+struct Builder<'t, B: BTreePageManager> {
+    page_manager: &'t mut B,
+    levels: Vec<Level>,
+    target: usize,
+    /// Pages written so far, freed again if the load fails
+    written: Vec<u64>,
+}
+
+impl<B: BTreePageManager> Builder<'_, B> {
+    fn push(&mut self, depth: usize, ptr: u64, key: Vec<u8>, val: Vec<u8>) {
+        while depth >= self.levels.len() {
+            self.levels.push(Level {
+                entries: Vec::new(),
+                bytes: 0,
+            });
+        }
+        let level = &self.levels[depth];
+        if !level.entries.is_empty() && level.size_with(depth == 0, &key, &val) > self.target {
+            self.flush(depth);
+        }
+        let level = &mut self.levels[depth];
+        level.bytes += entry_size(&key, &val);
+        level.entries.push((ptr, key, val));
+    }
+
+    /** Writes out the node being filled on `depth` and adds it to the level above */
+    fn flush(&mut self, depth: usize) {
+        let (ptr, key) = self.write(depth);
+        self.push(depth + 1, ptr, key, vec![]);
+    }
+
+    fn write(&mut self, depth: usize) -> (u64, Vec<u8>) {
+        let level = &mut self.levels[depth];
+        let entries = std::mem::take(&mut level.entries);
+        level.bytes = 0;
+
+        let num_keys = entries.len() as u16;
+        let mut node = if depth == 0 {
+            let (first, last) = (&entries[0].1, &entries[entries.len() - 1].1);
+            BNode::new_leaf(num_keys, BTREE_PAGE_SIZE, first, last)
+        } else {
+            BNode::new(NodeType::Node, num_keys)
+        };
+        for (i, (ptr, key, val)) in entries.iter().enumerate() {
+            node.node_append_kv(i as u16, *ptr, key, val);
+        }
+        let ptr = self.page_manager.page_new(node);
+        self.written.push(ptr);
+        (ptr, entries.into_iter().next().unwrap().1)
+    }
+
+    /** Writes out the nodes still being filled, bottom up. Returns the new root */
+    fn finish(mut self) -> u64 {
+        let mut depth = 0;
+        loop {
+            if self.levels[depth].entries.is_empty() {
+                depth += 1;
+                continue;
+            }
+            if depth + 1 == self.levels.len() {
+                // the top level, a single child is the root itself
+                let level = &self.levels[depth];
+                if depth > 0 && level.entries.len() == 1 {
+                    return level.entries[0].0;
+                }
+                return self.write(depth).0;
+            }
+            self.flush(depth);
+            depth += 1;
+        }
+    }
+}
+
+/// Checks the input as it is consumed
+struct Input<I: Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>> {
+    items: Peekable<I>,
+    last: Option<Vec<u8>>,
+    count: usize,
+}
+
+impl<I: Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>> Input<I> {
+    /** The next key, without consuming it */
+    fn peek(&mut self) -> Result<Option<&[u8]>> {
+        if matches!(self.items.peek(), Some(Err(_))) {
+            return Err(self.items.next().unwrap().unwrap_err());
+        }
+        Ok(self
+            .items
+            .peek()
+            .map(|item| item.as_ref().unwrap().0.as_slice()))
+    }
+
+    fn next(&mut self) -> Result<(Vec<u8>, Vec<u8>)> {
+        let (key, val) = self.items.next().expect("peek first")?;
+        if key.is_empty() {
+            return Err(Error::Static("bulk load keys must not be empty"));
+        }
+        if key.len() > BTREE_MAX_KEY_SIZE || val.len() > BTREE_MAX_VAL_SIZE {
+            return Err(Error::Static("bulk load key or value is too large"));
+        }
+        if self.last.as_ref().is_some_and(|last| *last >= key) {
+            return Err(Error::Generic(format!(
+                "bulk load keys are not in ascending order at {}",
+                key.escape_ascii()
+            )));
+        }
+        self.last = Some(key.clone());
+        self.count += 1;
+        Ok((key, val))
+    }
+}
+
+impl<B: BTreePageManager> BTree<B> {
+    /** Adds keys in ascending order by building the tree bottom up. Each node is filled to
+     * `fill` of a page and the new root is installed at the end, so nothing changes if the input
+     * is rejected. Existing leaves that no new key falls into are kept as they are, the rest are
+     * merged with the input. Keys must not exist yet. Returns the number of keys added */
+    pub fn bulk_load<I>(&mut self, items: I, fill: f64) -> Result<usize>
+    where
+        I: IntoIterator<Item = Result<(Vec<u8>, Vec<u8>)>>,
+    {
+        if !(fill > 0.0 && fill <= 1.0) {
+            return Err(Error::Static("fill must be above 0 and at most 1"));
+        }
+        let mut input = Input {
+            items: items.into_iter().peekable(),
+            last: None,
+            count: 0,
+        };
+        if input.peek()?.is_none() {
+            return Ok(0);
+        }
+
+        let mut old_nodes = Vec::new();
+        let mut old_leaves = Vec::new();
+        self.walk(&mut |ptr, node, _| {
+            old_nodes.push(ptr);
+            if node.b_type() == NodeType::Leaf {
+                old_leaves.push(ptr);
+            }
+        });
+
+        let mut builder = Builder {
+            page_manager: &mut self.page_manager,
+            levels: Vec::new(),
+            target: ((fill * BTREE_PAGE_SIZE as f64) as usize).max(HEADER as usize + 1),
+            written: Vec::new(),
+        };
+        let result = Self::bulk_merge(&mut builder, &old_leaves, &mut input);
+        let kept = match result {
+            Ok(kept) => kept,
+            Err(err) => {
+                for ptr in builder.written {
+                    builder.page_manager.page_del(ptr);
+                }
+                return Err(err);
+            }
+        };
+
+        let root = builder.finish();
+        for ptr in old_nodes {
+            if !kept.contains(&ptr) {
+                self.page_manager.page_del(ptr);
+            }
+        }
+        self.root = root;
+        Ok(input.count)
+    }
+
+    /** Feeds the builder with the old leaves merged with the input. Returns the leaves that were
+     * reused as they are */
+    fn bulk_merge<I>(
+        builder: &mut Builder<'_, B>,
+        old_leaves: &[u64],
+        input: &mut Input<I>,
+    ) -> Result<HashSet<u64>>
+    where
+        I: Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>,
+    {
+        let mut kept = HashSet::new();
+        if old_leaves.is_empty() {
+            // the sentinel key of a new tree
+            builder.push(0, 0, vec![], vec![]);
+        }
+
+        let mut next_leaf = old_leaves
+            .first()
+            .map(|&ptr| builder.page_manager.page_get(ptr));
+        for i in 0..old_leaves.len() {
+            let leaf = next_leaf.take().unwrap();
+            next_leaf = old_leaves
+                .get(i + 1)
+                .map(|&ptr| builder.page_manager.page_get(ptr));
+            // the leaf covers every key below the first key of the next one
+            let upper = next_leaf.as_ref().map(|next| next.get_key(0).to_vec());
+            let below_upper = |key: &[u8]| upper.as_ref().is_none_or(|upper| key < &upper[..]);
+
+            if !input.peek()?.is_some_and(below_upper) {
+                if builder
+                    .levels
+                    .first()
+                    .is_some_and(|level| !level.entries.is_empty())
+                {
+                    builder.flush(0);
+                }
+                builder.push(1, old_leaves[i], leaf.get_key(0).to_vec(), vec![]);
+                kept.insert(old_leaves[i]);
+                continue;
+            }
+
+            for j in 0..leaf.num_keys() {
+                let key = leaf.get_key(j);
+                while let Some(new_key) = input.peek()? {
+                    match new_key.cmp(&key) {
+                        Ordering::Less => {
+                            let (key, val) = input.next()?;
+                            builder.push(0, 0, key, val);
+                        }
+                        Ordering::Equal => {
+                            return Err(Error::Generic(format!(
+                                "bulk load key already exists: {}",
+                                key.escape_ascii()
+                            )))
+                        }
+                        Ordering::Greater => break,
+                    }
+                }
+                builder.push(0, 0, key.to_vec(), leaf.get_val(j).to_vec());
+            }
+            while input.peek()?.is_some_and(below_upper) {
+                let (key, val) = input.next()?;
+                builder.push(0, 0, key, val);
+            }
+        }
+
+        while input.peek()?.is_some() {
+            let (key, val) = input.next()?;
+            builder.push(0, 0, key, val);
+        }
+        Ok(kept)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv_store::{CmpOption, KV};
+
+    fn items(range: std::ops::Range<u32>, step: u32) -> Vec<(Vec<u8>, Vec<u8>)> {
+        range
+            .step_by(step as usize)
+            .map(|i| {
+                (
+                    format!("key{:06}", i).into_bytes(),
+                    format!("val{}", i).into_bytes(),
+                )
+            })
+            .collect()
+    }
+
+    fn all(kv: &KV) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut out = Vec::new();
+        let mut iter = kv.seek(&[], CmpOption::GT);
+        while iter.valid() {
+            out.push(iter.deref());
+            if !iter.next() {
+                break;
+            }
+        }
+        out
+    }
+
+    /// Every internal key must be the first key of its child
+    fn check_structure(kv: &KV) {
+        let nodes = kv.inspect();
+        for node in &nodes {
+            for (ptr, key) in &node.children {
+                let child = nodes.iter().find(|child| child.ptr == *ptr).unwrap();
+                assert_eq!(&child.first_key, key);
+                assert_eq!(child.depth, node.depth + 1);
+            }
+        }
+        let depths: HashSet<usize> = nodes
+            .iter()
+            .filter(|node| node.node_type == NodeType::Leaf)
+            .map(|node| node.depth)
+            .collect();
+        assert_eq!(depths.len(), 1);
+    }
+
+    #[test]
+    fn test_bulk_load_empty_tree() {
+        let mut kv = KV::open_in_memory().unwrap();
+        let data = items(0..20000, 1);
+        assert_eq!(kv.bulk_load(data.clone(), BULK_LOAD_FILL).unwrap(), 20000);
+        check_structure(&kv);
+        assert_eq!(all(&kv), data);
+        assert_eq!(kv.get(b"key012345").unwrap(), b"val12345");
+
+        let stats = kv.stats();
+        assert!(stats.avg_fill > 0.85 && stats.avg_fill <= BULK_LOAD_FILL);
+        assert_eq!(stats.live_pages() + stats.free_pages, stats.total_pages);
+
+        // the tree is still usable afterwards
+        kv.set(b"key012345a", b"new").unwrap();
+        kv.del(b"key000000").unwrap();
+        check_structure(&kv);
+    }
+
+    #[test]
+    fn test_bulk_load_into_existing_tree() {
+        let mut kv = KV::open_in_memory().unwrap();
+        let mut expected = items(0..10000, 2);
+        for (key, val) in &expected {
+            kv.set(key, val).unwrap();
+        }
+        let before: HashSet<u64> = kv.inspect().iter().map(|node| node.ptr).collect();
+
+        // fills the gaps in the lower half and adds keys past the end
+        let new = items(1..5000, 2).into_iter().chain(items(20000..21000, 1));
+        let new: Vec<_> = new.collect();
+        assert_eq!(kv.bulk_load(new.clone(), 1.0).unwrap(), new.len());
+        check_structure(&kv);
+
+        expected.extend(new);
+        expected.sort();
+        assert_eq!(all(&kv), expected);
+
+        // the upper half was not touched
+        let after: HashSet<u64> = kv.inspect().iter().map(|node| node.ptr).collect();
+        assert!(before.intersection(&after).count() > 10);
+        let stats = kv.stats();
+        assert_eq!(stats.live_pages() + stats.free_pages, stats.total_pages);
+    }
+
+    #[test]
+    fn test_bulk_load_rejects_bad_input() {
+        let mut kv = KV::open_in_memory().unwrap();
+        let data = items(0..1000, 1);
+        kv.bulk_load(data.clone(), BULK_LOAD_FILL).unwrap();
+        let stats = kv.stats();
+
+        let mut unsorted = items(2000..3000, 1);
+        unsorted.swap(500, 501);
+        assert!(kv.bulk_load(unsorted, BULK_LOAD_FILL).is_err());
+        assert!(kv.bulk_load(items(500..501, 1), BULK_LOAD_FILL).is_err());
+        assert!(kv
+            .bulk_load(vec![(vec![], vec![])], BULK_LOAD_FILL)
+            .is_err());
+        assert!(kv.bulk_load(items(5000..5001, 1), 0.0).is_err());
+
+        // the pages written before each failure are free again
+        assert_eq!(all(&kv), data);
+        let after = kv.stats();
+        assert_eq!(after.leaf_nodes, stats.leaf_nodes);
+        assert_eq!(after.internal_nodes, stats.internal_nodes);
+        assert_eq!(after.live_pages() + after.free_pages, after.total_pages);
+        assert_eq!(kv.bulk_load(vec![], BULK_LOAD_FILL).unwrap(), 0);
+    }
+}
@@ -0,0 +1,258 @@
+use std::fmt::Write;
+
+use super::{
+    b_node::{NodeType, BTREE_PAGE_SIZE},
+    BTree, BTreePageManager,
+};
+
+/// Longest key shown in full when rendering, longer keys are cut short
+const MAX_RENDERED_KEY: usize = 24;
+
+/// Layout of one node of the tree
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeInfo {
+    pub ptr: u64,
+    /// Distance from the root, which is at depth 0
+    pub depth: usize,
+    pub node_type: NodeType,
+    pub num_keys: u16,
+    /// Bytes used out of `BTREE_PAGE_SIZE`
+    pub num_bytes: u16,
+    /// Length of the key prefix a leaf stores once instead of in every key
+    pub prefix_len: usize,
+    /// Smallest key in the node
+    pub first_key: Vec<u8>,
+    /// Largest key in the node
+    pub last_key: Vec<u8>,
+    /// Pointer and smallest key of each child, empty for leaves
+    pub children: Vec<(u64, Vec<u8>)>,
+}
+
+impl NodeInfo {
+    /// Fraction of the page in use
+    pub fn fill(&self) -> f64 {
+        self.num_bytes as f64 / BTREE_PAGE_SIZE as f64
+    }
+}
+
+impl<B: BTreePageManager> BTree<B> {
+    /** Describes every node of the tree, parents before their children */
+    pub fn inspect(&self) -> Vec<NodeInfo> {
+        let mut nodes = Vec::new();
+        self.walk(&mut |ptr, node, depth| {
+            let num_keys = node.num_keys();
+            let children = match node.b_type() {
+                NodeType::Leaf => vec![],
+                NodeType::Node => (0..num_keys)
+                    .map(|i| (node.get_ptr(i), node.get_key(i).to_vec()))
+                    .collect(),
+            };
+            nodes.push(NodeInfo {
+                ptr,
+                depth,
+                node_type: node.b_type(),
+                num_keys,
+                num_bytes: node.num_bytes(),
+                prefix_len: node.prefix().len(),
+                first_key: node.get_key(0).to_vec(),
+                last_key: node.get_key(num_keys - 1).to_vec(),
+                children,
+            });
+        });
+        nodes
+    }
+}
+
+fn render_key(key: &[u8]) -> String {
+    if key.len() > MAX_RENDERED_KEY {
+        format!("\"{}\"...", key[..MAX_RENDERED_KEY].escape_ascii())
+    } else {
+        format!("\"{}\"", key.escape_ascii())
+    }
+}
+
+fn node_summary(node: &NodeInfo) -> String {
+    let node_type = match node.node_type {
+        NodeType::Node => "node",
+        NodeType::Leaf => "leaf",
+    };
+    let mut summary = format!(
+        "page {} {} keys={} bytes={}/{} ({:.0}%)",
+        node.ptr,
+        node_type,
+        node.num_keys,
+        node.num_bytes,
+        BTREE_PAGE_SIZE,
+        node.fill() * 100.0
+    );
+    if node.prefix_len > 0 {
+        write!(summary, " prefix={}", node.prefix_len).unwrap();
+    }
+    summary
+}
+
+/** Key range `[low, high)` covered by each child of `node`, `None` for an open upper bound */
+fn child_ranges(node: &NodeInfo) -> Vec<(&[u8], Option<&[u8]>)> {
+    (0..node.children.len())
+        .map(|i| {
+            let high = node.children.get(i + 1).map(|(_, key)| key.as_slice());
+            (node.children[i].1.as_slice(), high)
+        })
+        .collect()
+}
+
+fn render_range(low: &[u8], high: Option<&[u8]>) -> String {
+    match high {
+        Some(high) => format!("[{}, {})", render_key(low), render_key(high)),
+        None => format!("[{}, ...)", render_key(low)),
+    }
+}
+
+/** Renders the output of `inspect` as an indented outline. Nodes deeper than `max_depth` are
+ * left out */
+pub fn render_text(nodes: &[NodeInfo], max_depth: Option<usize>) -> String {
+    let mut out = String::new();
+    // the range each node covers, as given by its parent
+    let mut ranges = std::collections::HashMap::new();
+    for node in nodes {
+        if max_depth.is_some_and(|max_depth| node.depth > max_depth) {
+            continue;
+        }
+        let indent = "  ".repeat(node.depth);
+        let range = ranges.remove(&node.ptr).unwrap_or_default();
+        writeln!(out, "{}{}{}", indent, range, node_summary(node)).unwrap();
+        if node.node_type == NodeType::Leaf {
+            writeln!(
+                out,
+                "{}  keys {} to {}",
+                indent,
+                render_key(&node.first_key),
+                render_key(&node.last_key)
+            )
+            .unwrap();
+        }
+        for ((ptr, _), (low, high)) in node.children.iter().zip(child_ranges(node)) {
+            ranges.insert(*ptr, format!("{} ", render_range(low, high)));
+        }
+    }
+    out
+}
+
+fn dot_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/** Renders the output of `inspect` as a Graphviz DOT graph. Nodes deeper than `max_depth` are
+ * left out */
+pub fn render_dot(nodes: &[NodeInfo], max_depth: Option<usize>) -> String {
+    let mut out = String::from("digraph btree {\n  node [shape=box, fontname=monospace];\n");
+    for node in nodes {
+        if max_depth.is_some_and(|max_depth| node.depth > max_depth) {
+            continue;
+        }
+        let mut label = node_summary(node);
+        if node.node_type == NodeType::Leaf {
+            let keys = format!(
+                "\\n{} to {}",
+                dot_escape(&render_key(&node.first_key)),
+                dot_escape(&render_key(&node.last_key))
+            );
+            label = dot_escape(&label) + &keys;
+        } else {
+            label = dot_escape(&label);
+        }
+        writeln!(out, "  p{} [label=\"{}\"];", node.ptr, label).unwrap();
+
+        if max_depth.is_some_and(|max_depth| node.depth + 1 > max_depth) {
+            continue;
+        }
+        for ((ptr, _), (low, high)) in node.children.iter().zip(child_ranges(node)) {
+            writeln!(
+                out,
+                "  p{} -> p{} [label=\"{}\"];",
+                node.ptr,
+                ptr,
+                dot_escape(&render_range(low, high))
+            )
+            .unwrap();
+        }
+    }
+    out.push_str("}\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv_store::KV;
+
+    fn new_kv(count: u32) -> KV {
+        let mut kv = KV::open_in_memory().unwrap();
+        for i in 0..count {
+            kv.set(format!("key{:05}", i).as_bytes(), &[b'v'; 100])
+                .unwrap();
+        }
+        kv
+    }
+
+    #[test]
+    fn test_inspect() {
+        let kv = new_kv(500);
+        let nodes = kv.inspect();
+
+        let root = &nodes[0];
+        assert_eq!(root.depth, 0);
+        assert_eq!(root.node_type, NodeType::Node);
+        assert_eq!(root.first_key, b"");
+        assert_eq!(root.children.len(), root.num_keys as usize);
+
+        let leaves: Vec<&NodeInfo> = nodes
+            .iter()
+            .filter(|node| node.node_type == NodeType::Leaf)
+            .collect();
+        let keys: u32 = leaves.iter().map(|node| node.num_keys as u32).sum();
+        // plus the sentinel empty key
+        assert_eq!(keys, 501);
+        assert!(leaves.iter().all(|node| node.depth == 1));
+        // every key starts with "key00"
+        assert!(leaves.iter().skip(1).all(|node| node.prefix_len >= 5));
+        assert!(nodes
+            .iter()
+            .all(|node| 0.0 < node.fill() && node.fill() <= 1.0));
+    }
+
+    #[test]
+    fn test_render_text() {
+        let kv = new_kv(500);
+        let text = render_text(&kv.inspect(), None);
+        let mut lines = text.lines();
+        assert!(lines.next().unwrap().starts_with("page "));
+        assert!(lines.next().unwrap().starts_with("  [\"\", \"key"));
+        assert!(text.contains(" to \"key00499\"\n"));
+
+        let root_only = render_text(&kv.inspect(), Some(0));
+        assert_eq!(root_only.lines().count(), 1);
+    }
+
+    #[test]
+    fn test_render_dot() {
+        let kv = new_kv(500);
+        let nodes = kv.inspect();
+        let dot = render_dot(&nodes, None);
+        assert!(dot.starts_with("digraph btree {\n"));
+        assert!(dot.ends_with("}\n"));
+        assert_eq!(dot.matches(" -> ").count(), nodes.len() - 1);
+        assert!(dot.contains("[label=\"[\\\"\\\", \\\"key"));
+
+        let empty = render_dot(&KV::open_in_memory().unwrap().inspect(), None);
+        assert_eq!(empty.lines().count(), 3);
+    }
+
+    #[test]
+    fn test_render_long_and_binary_keys() {
+        assert_eq!(render_key(&[0, b'a']), "\"\\x00a\"");
+        let long = render_key(&[b'k'; 100]);
+        assert!(long.ends_with("\"..."));
+        assert!(long.len() < 40);
+    }
+}
@@ -1,11 +1,13 @@
 pub mod b_node;
 pub mod btree_iter;
+pub mod bulk;
+pub mod inspect;
 
 use self::{
-    b_node::{BNode, NodeType, BTREE_MAX_KEY_SIZE, BTREE_MAX_VAL_SIZE, BTREE_PAGE_SIZE, HEADER},
+    b_node::{BNode, NodeType, BTREE_MAX_KEY_SIZE, BTREE_MAX_VAL_SIZE, BTREE_PAGE_SIZE},
     btree_iter::BTreeIterator,
 };
-use std::cmp::Ordering;
+use std::{cmp::Ordering, collections::HashSet};
 
 enum MergeDirection {
     Left(BNode),
@@ -13,6 +15,7 @@ enum MergeDirection {
     None,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum CmpOption {
     GT,
     GE,
@@ -20,7 +23,7 @@ pub enum CmpOption {
     LE,
 }
 
-#[derive(PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum InsertMode {
     Upsert,     // insert or replace
     UpdateOnly, // update existing keys
@@ -89,7 +92,7 @@ impl<'a, B: BTreePageManager> BTree<B> {
 
         match node_to_have_key.b_type() {
             NodeType::Leaf => {
-                match node_to_have_key.get_key(idx).cmp(&request.key) {
+                match node_to_have_key.cmp_key(idx, &request.key) {
                     Ordering::Equal => {
                         if request.mode == InsertMode::InsertOnly {
                             // Key already in the tree and mode is insert only. Don't insert.
@@ -121,7 +124,7 @@ impl<'a, B: BTreePageManager> BTree<B> {
         let idx = node_with_key.node_lookup_le(key);
 
         match node_with_key.b_type() {
-            NodeType::Leaf => match node_with_key.get_key(idx).cmp(key) {
+            NodeType::Leaf => match node_with_key.cmp_key(idx, key) {
                 Ordering::Equal => Some(node_with_key.leaf_delete(idx)),
                 _ => None,
             },
@@ -167,7 +170,7 @@ impl<'a, B: BTreePageManager> BTree<B> {
             MergeDirection::Left(sibling) => {
                 let merged = sibling.node_merge(updated_node);
                 self.page_manager.page_del(node_with_key.get_ptr(idx - 1));
-                let merged_first_key = merged.get_key(0).to_owned();
+                let merged_first_key = merged.get_key(0).to_vec();
                 node_with_key.node_replace_2_kid(
                     idx - 1,
                     self.page_manager.page_new(merged),
@@ -177,7 +180,7 @@ impl<'a, B: BTreePageManager> BTree<B> {
             MergeDirection::Right(sibling) => {
                 let merged = updated_node.node_merge(sibling);
                 self.page_manager.page_del(node_with_key.get_ptr(idx + 1));
-                let merged_first_key = merged.get_key(0).to_owned();
+                let merged_first_key = merged.get_key(0).to_vec();
                 node_with_key.node_replace_2_kid(
                     idx,
                     self.page_manager.page_new(merged),
@@ -216,7 +219,7 @@ impl<'a, B: BTreePageManager> BTree<B> {
             BNode::new_with_size(NodeType::Node, old_num_keys - 1 + num_new, new_node_size);
         new_node.node_append_range(&old_node, 0, 0, idx);
         for (i, node) in new_children.into_iter().enumerate() {
-            let node_first_key = node.get_key(0).to_owned();
+            let node_first_key = node.get_key(0).to_vec();
             new_node.node_append_kv(
                 idx + i as u16,
                 self.page_manager.page_new(node),
@@ -241,18 +244,18 @@ impl<'a, B: BTreePageManager> BTree<B> {
 
         if idx > 0 {
             let sibling: BNode = self.page_manager.page_get(node_with_key.get_ptr(idx - 1));
-            let merged_size = sibling.num_bytes() + updated_node.num_bytes() - HEADER;
+            let merged_size = sibling.merge_size(updated_node);
 
-            if merged_size <= BTREE_PAGE_SIZE as u16 {
+            if merged_size <= BTREE_PAGE_SIZE {
                 return MergeDirection::Left(sibling);
             };
         }
 
         if idx + 1 < node_with_key.num_keys() {
             let sibling: BNode = self.page_manager.page_get(node_with_key.get_ptr(idx + 1));
-            let merged_size = sibling.num_bytes() + updated_node.num_bytes() - HEADER;
+            let merged_size = updated_node.merge_size(&sibling);
 
-            if merged_size <= BTREE_PAGE_SIZE as u16 {
+            if merged_size <= BTREE_PAGE_SIZE {
                 return MergeDirection::Right(sibling);
             };
         }
@@ -297,6 +300,10 @@ impl<'a, B: BTreePageManager> BTree<B> {
         assert!(request.val.len() <= BTREE_MAX_VAL_SIZE);
 
         if self.root == 0 {
+            if request.mode == InsertMode::UpdateOnly {
+                // Nothing to update in an empty tree
+                return request;
+            }
             let mut root = BNode::new(NodeType::Leaf, 2);
 
             root.node_append_kv(0, 0, &[], &[]);
@@ -322,7 +329,7 @@ impl<'a, B: BTreePageManager> BTree<B> {
             // the root was split, add a new level
             let mut root = BNode::new(NodeType::Node, n_split);
             for (i, k_node) in splitted.into_iter().enumerate() {
-                let key = k_node.get_key(0).to_owned();
+                let key = k_node.get_key(0).to_vec();
                 let ptr = self.page_manager.page_new(k_node);
                 root.node_append_kv(i as u16, ptr, &key, &[]);
             }
@@ -335,18 +342,24 @@ impl<'a, B: BTreePageManager> BTree<B> {
     }
 
     pub fn get_value(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.get_value_at(self.root, key)
+    }
+
+    /** Looks `key` up in the version of the tree rooted at `root`. Older roots stay readable for
+     * as long as their pages are not reused */
+    pub fn get_value_at(&self, root: u64, key: &[u8]) -> Option<Vec<u8>> {
         assert!(!key.is_empty());
         assert!(key.len() <= BTREE_MAX_KEY_SIZE);
 
-        if self.root == 0 {
+        if root == 0 {
             return None;
         };
 
-        let mut node = self.page_manager.page_get(self.root);
+        let mut node = self.page_manager.page_get(root);
         loop {
             let idx = node.node_lookup_le(key);
             match node.b_type() {
-                NodeType::Leaf => match node.get_key(idx).cmp(key) {
+                NodeType::Leaf => match node.cmp_key(idx, key) {
                     Ordering::Equal => return Some(node.get_val(idx).to_vec()),
                     _ => return None,
                 },
@@ -358,11 +371,11 @@ impl<'a, B: BTreePageManager> BTree<B> {
         }
     }
 
-    fn seek_le(&'a mut self, key: &[u8]) -> BTreeIterator<'a, B> {
+    fn seek_le(&'a self, root: u64, key: &[u8]) -> BTreeIterator<'a, B> {
         let mut path = Vec::new();
         let mut positions = Vec::new();
 
-        let mut ptr = self.root;
+        let mut ptr = root;
         while ptr != 0 {
             let node = self.page_manager.page_get(ptr);
             let node_type = node.b_type();
@@ -379,23 +392,130 @@ impl<'a, B: BTreePageManager> BTree<B> {
         BTreeIterator::new(self, path, positions)
     }
 
-    pub fn seek(&'a mut self, key: &[u8], compare: CmpOption) -> BTreeIterator<'a, B> {
-        let mut iter = self.seek_le(key);
+    /** Finds the closest position to `key` that satisfies `compare`. The iterator is not valid
+     * when no key satisfies it */
+    pub fn seek(&'a self, key: &[u8], compare: CmpOption) -> BTreeIterator<'a, B> {
+        self.seek_at(self.root, key, compare)
+    }
+
+    /** Like `seek`, in the version of the tree rooted at `root` */
+    pub fn seek_at(&'a self, root: u64, key: &[u8], compare: CmpOption) -> BTreeIterator<'a, B> {
+        let mut iter = self.seek_le(root, key);
+        if root == 0 {
+            return iter;
+        }
         if let CmpOption::LE = compare {
         } else {
             let (current_key, _) = iter.deref();
-            if !Self::cmp_ok(&current_key, &compare, key) {
+            // the sentinel empty key satisfies `GE` an empty key but is not a position
+            if !Self::cmp_ok(&current_key, &compare, key) || current_key.is_empty() {
                 // Off by one
-                match compare {
+                let moved = match compare {
                     CmpOption::GE | CmpOption::GT => iter.next(),
                     CmpOption::LE | CmpOption::LT => iter.prev(),
                 };
+                if !moved {
+                    // every key is smaller than the reference
+                    iter.invalidate();
+                }
             };
         };
 
         iter
     }
 
+    /** Calls `visit` with the pointer, node and depth of every node in the tree, parents before
+     * their children */
+    pub fn walk(&self, visit: &mut impl FnMut(u64, &BNode, usize)) {
+        self.walk_at(self.root, visit);
+    }
+
+    /** Like `walk`, in the version of the tree rooted at `root` */
+    pub fn walk_at(&self, root: u64, visit: &mut impl FnMut(u64, &BNode, usize)) {
+        if root != 0 {
+            self.node_walk(root, 0, visit);
+        }
+    }
+
+    fn node_walk(&self, ptr: u64, depth: usize, visit: &mut impl FnMut(u64, &BNode, usize)) {
+        let node = self.page_manager.page_get(ptr);
+        visit(ptr, &node, depth);
+        if node.b_type() == NodeType::Node {
+            for i in 0..node.num_keys() {
+                self.node_walk(node.get_ptr(i), depth + 1, visit);
+            }
+        }
+    }
+
+    /** Rewrites every node in `targets` to a newly allocated page, along with their ancestors
+     * so the parent pointers stay correct. Returns true if the root moved */
+    pub fn relocate(&mut self, targets: &HashSet<u64>) -> bool {
+        if self.root == 0 {
+            return false;
+        }
+        match self.node_relocate(self.root, targets) {
+            Some(new_root) => {
+                self.root = new_root;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn node_relocate(&mut self, ptr: u64, targets: &HashSet<u64>) -> Option<u64> {
+        let node = self.page_manager.page_get(ptr);
+        let mut moved = targets.contains(&ptr);
+
+        let new_node = match node.b_type() {
+            NodeType::Leaf => node,
+            NodeType::Node => {
+                let num_keys = node.num_keys();
+                let mut new_node = BNode::new(NodeType::Node, num_keys);
+                for i in 0..num_keys {
+                    let kid = match self.node_relocate(node.get_ptr(i), targets) {
+                        Some(new_kid) => {
+                            moved = true;
+                            new_kid
+                        }
+                        None => node.get_ptr(i),
+                    };
+                    new_node.node_append_kv(i, kid, &node.get_key(i), &[]);
+                }
+                new_node
+            }
+        };
+
+        if !moved {
+            return None;
+        }
+        self.page_manager.page_del(ptr);
+        Some(self.page_manager.page_new(new_node))
+    }
+
+    /** Copies the whole tree into another page manager, children before their parents.
+     * Returns the root pointer in the destination */
+    pub fn copy_to<P: BTreePageManager>(&self, dst: &mut P) -> u64 {
+        if self.root == 0 {
+            return 0;
+        }
+        self.node_copy_to(self.root, dst)
+    }
+
+    fn node_copy_to<P: BTreePageManager>(&self, ptr: u64, dst: &mut P) -> u64 {
+        let node = self.page_manager.page_get(ptr);
+        if node.b_type() == NodeType::Leaf {
+            return dst.page_new(node);
+        }
+
+        let num_keys = node.num_keys();
+        let mut new_node = BNode::new(NodeType::Node, num_keys);
+        for i in 0..num_keys {
+            let kid = self.node_copy_to(node.get_ptr(i), dst);
+            new_node.node_append_kv(i, kid, &node.get_key(i), &[]);
+        }
+        dst.page_new(new_node)
+    }
+
     fn cmp_ok(key: &[u8], compare: &CmpOption, reference: &[u8]) -> bool {
         match compare {
             CmpOption::GT => key > reference,
@@ -591,6 +711,34 @@ mod tests {
         c.verify();
     }
 
+    #[test]
+    fn test_relocate_and_copy() {
+        let mut c = C::new();
+        for i in 0..2000u32 {
+            c.add(&format!("key{}", fmix32(i)), &format!("val{}", i));
+        }
+
+        let mut pages = Vec::new();
+        c.tree.walk(&mut |ptr, _, _| pages.push(ptr));
+        assert_eq!(pages.len(), c.tree.page_manager.pages.len());
+
+        // moving one leaf rewrites every node on its path to the root
+        let leaf = *pages.last().unwrap();
+        assert!(c.tree.relocate(&HashSet::from([leaf])));
+        assert!(!c.tree.page_manager.pages.contains_key(&leaf));
+        assert!(!c.tree.relocate(&HashSet::new()));
+        c.verify();
+
+        let mut copy = PageManager::new();
+        let root = c.tree.copy_to(&mut copy);
+        assert_eq!(copy.pages.len(), c.tree.page_manager.pages.len());
+        c.tree = BTree {
+            root,
+            page_manager: copy,
+        };
+        c.verify();
+    }
+
     #[test]
     fn test_insert_two_items() {
         let mut c = C::new();
@@ -696,15 +844,7 @@ mod tests {
             let vlen = l - klen;
 
             let factor = BTREE_PAGE_SIZE / l;
-            let mut size = factor * factor * 2;
-
-            if size > 2000 {
-                size = 2000;
-            }
-
-            if size < 10 {
-                size = 10;
-            }
+            let size = (factor * factor * 2).clamp(10, 2000);
 
             let mut kv_pairs: HashMap<String, String> = HashMap::new();
             for _ in 0..size {
@@ -734,6 +874,33 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_shared_key_prefixes() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut c = C::new();
+        let prefixes = ["", "table", &"long".repeat(50)];
+        for i in 0..3000u32 {
+            let prefix = prefixes[rng.gen_range(0..prefixes.len())];
+            c.add(&format!("{}{}", prefix, fmix32(i)), &format!("val{}", i));
+        }
+        c.verify();
+
+        let mut prefixed = 0;
+        c.tree.walk(&mut |_, node, _| {
+            if !node.prefix().is_empty() {
+                prefixed += 1;
+            }
+        });
+        assert!(prefixed > 0);
+
+        let mut keys: Vec<String> = c.reference.keys().cloned().collect();
+        keys.sort();
+        for key in keys.iter().step_by(2) {
+            assert!(c.delete(key));
+        }
+        c.verify();
+    }
+
     #[test]
     fn insert_exec_mode_upsert() {
         let mut c = C::new();
@@ -799,6 +966,17 @@ mod tests {
         assert_eq!(c.get("new_key"), None);
     }
 
+    #[test]
+    fn insert_exec_mode_update_only_empty_tree() {
+        let mut c = C::new();
+        let request = InsertRequest::new("key".as_bytes().to_vec(), "val".as_bytes().to_vec())
+            .mode(InsertMode::UpdateOnly);
+        let response = c.tree.insert_exec(request);
+        assert!(!response.added);
+        assert_eq!(c.get("key"), None);
+        c.verify();
+    }
+
     #[test]
     fn seek_le_test_small_equal_to() {
         let mut c = C::new();
@@ -809,7 +987,7 @@ mod tests {
         c.add("key5", "val5");
 
         // Test seek_le with existing key
-        let mut iter = c.tree.seek_le("key3".as_bytes());
+        let mut iter = c.tree.seek_le(c.tree.root, "key3".as_bytes());
         assert_eq!(
             iter.deref(),
             ("key3".as_bytes().to_vec(), "val3".as_bytes().to_vec())
@@ -836,7 +1014,7 @@ mod tests {
         c.add("key5", "val5");
 
         // Test seek_le with existing key
-        let mut iter = c.tree.seek_le("key3".as_bytes());
+        let mut iter = c.tree.seek_le(c.tree.root, "key3".as_bytes());
         assert_eq!(
             iter.deref(),
             ("key2".as_bytes().to_vec(), "val2".as_bytes().to_vec())
@@ -872,7 +1050,7 @@ mod tests {
         ordered_items.sort();
 
         // Test seek_le with existing key
-        let mut iter = c.tree.seek_le("key50".as_bytes());
+        let mut iter = c.tree.seek_le(c.tree.root, "key50".as_bytes());
 
         let index = ordered_items
             .iter()
@@ -910,7 +1088,7 @@ mod tests {
         randomised_items.shuffle(&mut rng);
 
         for (key, value) in randomised_items.iter() {
-            let iter = c.tree.seek_le(key);
+            let iter = c.tree.seek_le(c.tree.root, key);
             assert_eq!(iter.deref(), (key.clone(), value.clone()));
         }
     }
@@ -924,7 +1102,7 @@ mod tests {
         c.add("key5", "val5");
 
         // Test seek_le with key larger than any key in the tree
-        let mut iter = c.tree.seek_le("key6".as_bytes());
+        let mut iter = c.tree.seek_le(c.tree.root, "key6".as_bytes());
         assert_eq!(
             iter.deref(),
             ("key5".as_bytes().to_vec(), "val5".as_bytes().to_vec())
@@ -1006,4 +1184,22 @@ mod tests {
             ("key2".as_bytes().to_vec(), "val2".as_bytes().to_vec())
         );
     }
+
+    #[test]
+    fn seek_test_empty_key() {
+        let mut c = C::new();
+        c.add("key1", "val1");
+        c.add("key2", "val2");
+
+        // the sentinel is skipped, the first key is the smallest one >= ""
+        let iter = c.tree.seek(&[], CmpOption::GE);
+        assert!(iter.valid());
+        assert_eq!(iter.deref().0, "key1".as_bytes().to_vec());
+        let iter = c.tree.seek(&[], CmpOption::GT);
+        assert_eq!(iter.deref().0, "key1".as_bytes().to_vec());
+
+        // nothing is <= ""
+        assert!(!c.tree.seek(&[], CmpOption::LE).valid());
+        assert!(!c.tree.seek(&[], CmpOption::LT).valid());
+    }
 }
@@ -0,0 +1,62 @@
+use std::{env, process};
+
+use database_from_scratch::{
+    kv_store::KV,
+    relational_db::DB,
+    server::{postgres::PgServer, Address, Protocol, Server},
+};
+
+const USAGE: &str = "\
+usage: kv_server [--resp | --postgres] <database file> <address>
+Serves the raw keys of a database until killed. The address is `host:port`, or `unix:<path>` for
+a Unix socket. Connect with `server::Client`, or with Redis tools such as redis-cli when --resp
+is given. With --postgres the tables are served to psql and Postgres drivers instead.";
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let flag = match args.first().map(String::as_str) {
+        Some(flag @ ("--resp" | "--postgres")) => Some(flag.to_string()),
+        _ => None,
+    };
+    if flag.is_some() {
+        args.remove(0);
+    }
+    if args.len() != 2 || args[0] == "-h" || args[0] == "--help" {
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    }
+
+    let address = Address::parse(&args[1]);
+    let result = match flag.as_deref() {
+        Some("--postgres") => serve_tables(&args[0], &address),
+        Some(_) => serve_keys(&args[0], &address, Protocol::Resp),
+        None => serve_keys(&args[0], &address, Protocol::Native),
+    };
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
+
+fn serve_keys(path: &str, address: &Address, protocol: Protocol) -> Result<(), String> {
+    let kv =
+        KV::open(path.to_string()).map_err(|err| format!("failed to open {}: {}", path, err))?;
+    let server = Server::bind(address, kv)
+        .map_err(|err| format!("failed to listen on {}: {}", address, err))?
+        .protocol(protocol);
+    if let Ok(address) = server.local_address() {
+        eprintln!("listening on {}", address);
+    }
+    server.serve().map_err(|err| format!("error: {}", err))
+}
+
+fn serve_tables(path: &str, address: &Address) -> Result<(), String> {
+    let db =
+        DB::open(path.to_string()).map_err(|err| format!("failed to open {}: {}", path, err))?;
+    let server = PgServer::bind(address, db)
+        .map_err(|err| format!("failed to listen on {}: {}", address, err))?;
+    if let Ok(address) = server.local_address() {
+        eprintln!("listening on {}", address);
+    }
+    server.serve().map_err(|err| format!("error: {}", err))
+}
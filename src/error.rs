@@ -9,6 +9,10 @@ pub enum Error {
     #[error("Static error: {0}")]
     Static(&'static str),
 
+    /// A transaction lost to a concurrent one and was rolled back, running it again can succeed
+    #[error("Conflict: {0}")]
+    Conflict(String),
+
     #[error(transparent)]
     IO(#[from] std::io::Error),
 }
@@ -0,0 +1,104 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+use crate::b_tree::b_node::BTREE_PAGE_SIZE;
+use crate::prelude::*;
+
+use super::{master_page::MasterPage, storage::Storage, FreeList};
+
+/// A copy of the database in progress. The pages still to be copied are pinned in the source
+/// until it is dropped
+pub struct Backup {
+    pin: u64,
+    /// Where the pin goes on drop, for the source to release it on its next flush
+    released: Arc<Mutex<Vec<u64>>>,
+    dst: Box<dyn Storage>,
+    master: MasterPage,
+    /// Tree pages still to be copied, the lowest last
+    pending: Vec<u64>,
+    /// Free list nodes as they were when the backup started, the writer recycles them in place
+    list_nodes: Vec<(u64, [u8; BTREE_PAGE_SIZE])>,
+}
+
+impl Backup {
+    /// Number of pages left to copy
+    pub fn remaining(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+impl Drop for Backup {
+    fn drop(&mut self) {
+        self.released.lock().unwrap().push(self.pin);
+    }
+}
+
+impl FreeList {
+    /// Starts copying the version of the database rooted at `btree_root` into `dst`, keeping
+    /// every page at the same position. `tree_pages` stay pinned until the backup is finished
+    pub fn backup_begin(
+        &mut self,
+        btree_root: u64,
+        tree_pages: HashSet<u64>,
+        mut dst: Box<dyn Storage>,
+    ) -> Result<Backup> {
+        assert!(self.page_manager.updates.is_empty() && self.nfree == 0);
+
+        let total_pages = self.total_pages();
+        let list_nodes = self
+            .list_nodes()
+            .into_iter()
+            .map(|ptr| {
+                let data = self.page_manager.storage.read_page(ptr);
+                (ptr, data.as_ref().try_into().unwrap())
+            })
+            .collect();
+
+        // the free pages are never read, they only have to exist
+        dst.set_len(total_pages * BTREE_PAGE_SIZE as u64)?;
+
+        let mut pending: Vec<u64> = tree_pages.iter().copied().collect();
+        pending.sort_unstable_by(|a, b| b.cmp(a));
+
+        Ok(Backup {
+            pin: self.pin(tree_pages),
+            released: self.released.clone(),
+            dst,
+            master: MasterPage::new(btree_root, total_pages, self.head),
+            pending,
+            list_nodes,
+        })
+    }
+
+    /// Copies up to `npages` pages. Returns true once every page has been copied
+    pub fn backup_step(&self, backup: &mut Backup, npages: usize) -> Result<bool> {
+        for _ in 0..npages {
+            let Some(ptr) = backup.pending.pop() else {
+                break;
+            };
+            let data = self.page_manager.storage.read_page(ptr);
+            backup.dst.write_page(ptr, &data)?;
+        }
+        Ok(backup.pending.is_empty())
+    }
+
+    /// Copies whatever is left and writes the master page of the copy. The pin is released by
+    /// the next flush
+    pub fn backup_finish(&self, mut backup: Backup) -> Result<()> {
+        self.backup_write(&mut backup)
+    }
+
+    fn backup_write(&self, backup: &mut Backup) -> Result<()> {
+        while !self.backup_step(backup, usize::MAX)? {}
+        for (ptr, data) in &backup.list_nodes {
+            backup.dst.write_page(*ptr, data)?;
+        }
+
+        // same order as a commit, the master page goes last
+        backup.dst.sync()?;
+        backup.master.master_save(backup.dst.as_mut())?;
+        backup.dst.sync()
+    }
+}
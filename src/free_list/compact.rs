@@ -0,0 +1,104 @@
+use std::collections::HashSet;
+
+use crate::b_tree::b_node::BTREE_PAGE_SIZE;
+use crate::prelude::*;
+
+use super::{
+    fl_node::{FLNode, MAX_FREE_LIST_IN_PAGE},
+    FreeList,
+};
+
+impl FreeList {
+    /// Number of pages used by the database, including the master page
+    pub fn total_pages(&self) -> u64 {
+        self.page_manager.flushed + self.page_manager.nappend as u64
+    }
+
+    /// Pointers of the pages holding the free list nodes
+    pub fn list_nodes(&self) -> Vec<u64> {
+        let mut nodes = Vec::new();
+        let mut head = self.head;
+        while head != 0 {
+            nodes.push(head);
+            head = self.page_manager.page_get::<FLNode>(head).next();
+        }
+        nodes
+    }
+
+    /// Starts a compaction pass. Until `compact_commit`, new pages are taken from `slots`
+    /// lowest first. The slots must not be reachable from the current master page
+    pub fn compact_begin(&mut self, mut slots: Vec<u64>) {
+        assert!(self.page_manager.updates.is_empty() && self.nfree == 0);
+        slots.sort_unstable();
+        self.compact_slots = Some(slots.into());
+    }
+
+    /// Finishes a compaction pass. Every page outside `tree_pages` is free afterwards, so the
+    /// free list is rebuilt from them and the file is truncated after the last page in use
+    pub fn compact_commit(&mut self, btree_root: u64, tree_pages: &HashSet<u64>) -> Result<()> {
+        let slots: Vec<u64> = self
+            .compact_slots
+            .take()
+            .expect("no compaction in progress")
+            .into();
+        let last_tree_page = tree_pages.iter().max().copied().unwrap_or(0);
+
+        // the list nodes go in pages that are free under the current master page, so a crash
+        // before the new master page is written leaves the old version intact.
+        // find the fewest nodes that can hold every free page before the new end of the file
+        let mut num_nodes = 0;
+        let new_total = loop {
+            assert!(
+                num_nodes <= slots.len(),
+                "not enough free pages to rebuild the free list"
+            );
+            let last_node = if num_nodes == 0 {
+                0
+            } else {
+                slots[num_nodes - 1]
+            };
+            let new_total = last_tree_page.max(last_node) + 1;
+            let num_free = new_total as usize - 1 - tree_pages.len() - num_nodes;
+            if num_free <= num_nodes * MAX_FREE_LIST_IN_PAGE {
+                break new_total;
+            }
+            num_nodes += 1;
+        };
+
+        let nodes = &slots[..num_nodes];
+        let free: Vec<u64> = (1..new_total)
+            .filter(|ptr| !tree_pages.contains(ptr) && nodes.binary_search(ptr).is_err())
+            .collect();
+
+        // build the list back to front so each node can link to the next one
+        self.head = 0;
+        for (i, &node_ptr) in nodes.iter().enumerate().rev() {
+            let start = (i * MAX_FREE_LIST_IN_PAGE).min(free.len());
+            let end = ((i + 1) * MAX_FREE_LIST_IN_PAGE).min(free.len());
+            let mut node = FLNode::new((end - start) as u16, self.head);
+            for (j, &ptr) in free[start..end].iter().enumerate() {
+                node.set_ptr(j as u16, ptr);
+            }
+            self.page_manager.page_reuse(node_ptr, node);
+            self.head = node_ptr;
+        }
+        if self.head != 0 {
+            let fl_head = self.page_manager.page_get_raw_mut(self.head);
+            FLNode::set_total(fl_head, free.len() as u64);
+        }
+
+        self.page_manager.write_pages()?;
+        self.page_manager.flush()?;
+        self.nfree = 0;
+
+        self.page_manager.flushed = new_total;
+        self.set_master_page(btree_root)?;
+        self.page_manager.flush()?;
+        self.committed(btree_root);
+
+        // only safe once the master page no longer refers to anything past the new end
+        let storage = &mut self.page_manager.storage;
+        storage.set_len(new_total * BTREE_PAGE_SIZE as u64)?;
+        storage.sync()
+    }
+}
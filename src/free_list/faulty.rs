@@ -0,0 +1,232 @@
+use std::{
+    borrow::Cow,
+    io,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use crate::b_tree::b_node::BTREE_PAGE_SIZE;
+use crate::prelude::*;
+
+use super::storage::Storage;
+
+/// Disks write whole sectors atomically, a write cut off part way keeps only its full sectors
+pub const SECTOR_SIZE: usize = 512;
+
+/// Where an injected crash cuts the process off, counted from when it is injected
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    /// The given number of calls succeed and every call after them fails. Writes, syncs and
+    /// resizes are counted, reads are not
+    Call(u64),
+    /// The given number of bytes are written and every write after them fails. The write that
+    /// crosses the limit is cut short
+    Byte(u64),
+}
+
+#[derive(Default)]
+struct DiskState {
+    /// The file as the process sees it
+    current: Vec<u8>,
+    /// The file as of the last sync, what survives a crash
+    durable: Vec<u8>,
+    fault: Option<Fault>,
+    calls: u64,
+    written: u64,
+    crashed: bool,
+}
+
+impl DiskState {
+    /// Counts a call, fails it once the fault is reached
+    fn call(&mut self) -> Result<()> {
+        if self.crashed || self.fault == Some(Fault::Call(self.calls)) {
+            return self.crash();
+        }
+        self.calls += 1;
+        Ok(())
+    }
+
+    fn crash<T>(&mut self) -> Result<T> {
+        self.crashed = true;
+        Err(Error::IO(io::Error::other("injected crash")))
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+        self.call()?;
+        let allowed = match self.fault {
+            Some(Fault::Byte(limit)) => {
+                (limit.saturating_sub(self.written) as usize).min(data.len())
+            }
+            _ => data.len(),
+        };
+        self.written += allowed as u64;
+        if allowed < data.len() {
+            // only the sectors the write covered in full reach the disk
+            let end = (offset + allowed) / SECTOR_SIZE * SECTOR_SIZE;
+            let kept = end.saturating_sub(offset);
+            self.current[offset..offset + kept].copy_from_slice(&data[..kept]);
+            return self.crash();
+        }
+        self.current[offset..offset + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+}
+
+/** A simulated file that can be made to crash at a chosen call or byte, and then be reopened with
+ * only what a real disk would have kept. Clones share the same file */
+#[derive(Clone, Default)]
+pub struct FaultyDisk {
+    state: Arc<Mutex<DiskState>>,
+}
+
+impl FaultyDisk {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, DiskState> {
+        self.state.lock().unwrap()
+    }
+
+    /// A storage backend writing to this disk
+    pub fn storage(&self) -> Box<dyn Storage> {
+        Box::new(FaultyStorage { disk: self.clone() })
+    }
+
+    /// Arms a crash, counted from now
+    pub fn inject(&self, fault: Fault) {
+        let mut state = self.lock();
+        state.fault = Some(fault);
+        state.calls = 0;
+        state.written = 0;
+    }
+
+    /// Calls made since the last `inject`, or since the disk was created
+    pub fn calls(&self) -> u64 {
+        self.lock().calls
+    }
+
+    /// Bytes written since the last `inject`, or since the disk was created
+    pub fn written(&self) -> u64 {
+        self.lock().written
+    }
+
+    /// Whether the injected crash has happened
+    pub fn crashed(&self) -> bool {
+        self.lock().crashed
+    }
+
+    /** The disk as found after the machine restarts. Writes since the last sync are lost, unless
+     * `keep_unsynced` is set to model them having reached the disk anyway */
+    pub fn restart(&self, keep_unsynced: bool) -> FaultyDisk {
+        let state = self.lock();
+        let image = if keep_unsynced {
+            state.current.clone()
+        } else {
+            state.durable.clone()
+        };
+        FaultyDisk {
+            state: Arc::new(Mutex::new(DiskState {
+                current: image.clone(),
+                durable: image,
+                ..DiskState::default()
+            })),
+        }
+    }
+}
+
+/// Storage backend over a `FaultyDisk`
+struct FaultyStorage {
+    disk: FaultyDisk,
+}
+
+impl Storage for FaultyStorage {
+    fn file_size(&self) -> u64 {
+        self.disk.lock().current.len() as u64
+    }
+
+    fn set_len(&mut self, size: u64) -> Result<()> {
+        let mut state = self.disk.lock();
+        state.call()?;
+        state.current.resize(size as usize, 0);
+        Ok(())
+    }
+
+    fn read_page(&self, ptr: u64) -> Cow<'_, [u8]> {
+        let state = self.disk.lock();
+        let start = ptr as usize * BTREE_PAGE_SIZE;
+        Cow::Owned(state.current[start..start + BTREE_PAGE_SIZE].to_vec())
+    }
+
+    fn write_page(&mut self, ptr: u64, data: &[u8]) -> Result<()> {
+        self.disk.lock().write(ptr as usize * BTREE_PAGE_SIZE, data)
+    }
+
+    fn write_master(&mut self, data: &[u8]) -> Result<()> {
+        self.disk.lock().write(0, data)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        let mut state = self.disk.lock();
+        state.call()?;
+        state.durable = state.current.clone();
+        Ok(())
+    }
+
+    fn close(self: Box<Self>) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: u64 = BTREE_PAGE_SIZE as u64;
+
+    #[test]
+    fn test_unsynced_writes_are_lost() {
+        let disk = FaultyDisk::new();
+        let mut storage = disk.storage();
+        storage.set_len(2 * PAGE).unwrap();
+        storage.write_page(1, &[1; BTREE_PAGE_SIZE]).unwrap();
+        storage.sync().unwrap();
+        storage.write_page(1, &[2; BTREE_PAGE_SIZE]).unwrap();
+        storage.set_len(3 * PAGE).unwrap();
+        assert_eq!(disk.calls(), 5);
+
+        let lost = disk.restart(false).storage();
+        assert_eq!(lost.file_size(), 2 * PAGE);
+        assert_eq!(lost.read_page(1).as_ref(), &[1; BTREE_PAGE_SIZE]);
+        let kept = disk.restart(true).storage();
+        assert_eq!(kept.file_size(), 3 * PAGE);
+        assert_eq!(kept.read_page(1).as_ref(), &[2; BTREE_PAGE_SIZE]);
+    }
+
+    #[test]
+    fn test_call_fault() {
+        let disk = FaultyDisk::new();
+        let mut storage = disk.storage();
+        disk.inject(Fault::Call(2));
+        storage.set_len(PAGE).unwrap();
+        storage.write_master(&[1; 40]).unwrap();
+        assert!(!disk.crashed());
+        assert!(storage.sync().is_err());
+        assert!(disk.crashed());
+        // nothing gets through once crashed
+        assert!(storage.write_master(&[2; 40]).is_err());
+        assert_eq!(disk.restart(true).storage().read_page(0)[0], 1);
+    }
+
+    #[test]
+    fn test_byte_fault_keeps_whole_sectors() {
+        let disk = FaultyDisk::new();
+        let mut storage = disk.storage();
+        storage.set_len(2 * PAGE).unwrap();
+        disk.inject(Fault::Byte(PAGE + 1000));
+        storage.write_page(0, &[1; BTREE_PAGE_SIZE]).unwrap();
+        assert!(storage.write_page(1, &[2; BTREE_PAGE_SIZE]).is_err());
+        assert_eq!(disk.written(), PAGE + 1000);
+
+        let page = disk.restart(true).storage().read_page(1).to_vec();
+        assert!(page[..SECTOR_SIZE].iter().all(|byte| *byte == 2));
+        assert!(page[SECTOR_SIZE..].iter().all(|byte| *byte == 0));
+    }
+}
@@ -3,14 +3,17 @@ use std::{fs::File, os::unix::prelude::FileExt};
 use crate::prelude::*;
 
 use byteorder::{ByteOrder, LittleEndian};
-use fs2::FileExt as OtherFileExt;
 
 use crate::b_tree::b_node::BTREE_PAGE_SIZE;
 
-use super::mmap::MMap;
+use super::storage::Storage;
 
 const DB_SIG: &str = "BuildYourOwnDB00";
 
+/// Size of the master page record at the start of page 0
+pub const MASTER_PAGE_SIZE: usize = 40;
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct MasterPage {
     pub btree_root: u64,
     pub total_used_pages: u64,
@@ -29,9 +32,11 @@ impl MasterPage {
     /// Loads the master page. If the file is empty, the master page will be created on the first write.
     /// If the master page is invalid, an error is returned.
     /// Returns the root of the BTree, and the head of the free list
-    pub fn master_load(mmap: &MMap) -> Result<MasterPage> {
-        if mmap.file == 0 {
-            // empty file, the master page will be create on the first write
+    pub fn master_load(storage: &dyn Storage) -> Result<MasterPage> {
+        let file_size = storage.file_size();
+        // empty file, the master page will be create on the first write. The file can also have
+        // grown before the first commit was cut off, leaving the master page unwritten
+        if file_size == 0 || storage.read_page(0)[..40].iter().all(|byte| *byte == 0) {
             return Ok(MasterPage {
                 btree_root: 0,
                 total_used_pages: 1, // reserved for the master page
@@ -39,21 +44,18 @@ impl MasterPage {
             });
         }
 
-        let data = mmap.chunks[0].as_ref();
-        let btree_root = LittleEndian::read_u64(&data[16..]);
-        let total_used_pages = LittleEndian::read_u64(&data[24..]);
-        let free_list_head = LittleEndian::read_u64(&data[32..]);
-
-        // Check that the master page is valid
-        if &data[..16] != DB_SIG.as_bytes() {
-            return Err(Error::Static("bad signature"));
-        }
+        let MasterPage {
+            btree_root,
+            total_used_pages,
+            free_list_head,
+        } = MasterPage::from_bytes(&storage.read_page(0))?;
 
         let mut bad =
-            !(1 <= total_used_pages && total_used_pages <= mmap.file / BTREE_PAGE_SIZE as u64);
+            !(1 <= total_used_pages && total_used_pages <= file_size / BTREE_PAGE_SIZE as u64);
         bad = bad || btree_root >= total_used_pages;
         bad = bad || free_list_head >= total_used_pages;
-        bad = bad || free_list_head < 1 || free_list_head == btree_root;
+        // the free list is empty until the first page is freed
+        bad = bad || (free_list_head != 0 && free_list_head == btree_root);
 
         if bad {
             return Err(Error::Static("bad master page"));
@@ -66,25 +68,39 @@ impl MasterPage {
         })
     }
 
-    /// Saves the master page
-    pub fn master_save(&self, file_pointer: &mut File) -> Result<()> {
-        let mut data = [0; 40];
+    /// Parses a master page record, checking only its signature
+    pub fn from_bytes(data: &[u8]) -> Result<MasterPage> {
+        if data.len() < MASTER_PAGE_SIZE || &data[..16] != DB_SIG.as_bytes() {
+            return Err(Error::Static("bad signature"));
+        }
+        Ok(MasterPage {
+            btree_root: LittleEndian::read_u64(&data[16..]),
+            total_used_pages: LittleEndian::read_u64(&data[24..]),
+            free_list_head: LittleEndian::read_u64(&data[32..]),
+        })
+    }
+
+    /// The master page record as written to the file
+    pub fn to_bytes(&self) -> [u8; MASTER_PAGE_SIZE] {
+        let mut data = [0; MASTER_PAGE_SIZE];
         // Convert signature to bytes
         assert!(DB_SIG.len() == 16, "const DG_SIG must be 16 bytes");
         data[..16].copy_from_slice(DB_SIG.as_bytes());
         LittleEndian::write_u64(&mut data[16..], self.btree_root);
         LittleEndian::write_u64(&mut data[24..], self.total_used_pages);
         LittleEndian::write_u64(&mut data[32..], self.free_list_head);
+        data
+    }
 
-        // Atomic write to the master page
-        file_pointer.lock_exclusive()?;
-        let result = file_pointer.write_at(&data, 0);
-        if let Err(err) = result {
-            file_pointer.unlock()?;
-            return Err(Error::IO(err));
-        }
-        file_pointer.unlock()?;
-
-        Ok(())
+    /// Saves the master page
+    pub fn master_save(&self, storage: &mut dyn Storage) -> Result<()> {
+        storage.write_master(&self.to_bytes())
     }
 }
+
+/// Writes the master page record to the start of the file. The record fits in one sector so the
+/// write is atomic, readers in other processes see either the old record or the new one
+pub fn write_master_at(file_pointer: &File, data: &[u8]) -> Result<()> {
+    file_pointer.write_all_at(data, 0)?;
+    Ok(())
+}
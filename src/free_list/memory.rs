@@ -0,0 +1,96 @@
+use std::borrow::Cow;
+
+use crate::b_tree::b_node::BTREE_PAGE_SIZE;
+use crate::prelude::*;
+
+use super::storage::Storage;
+
+/// Storage backend keeping every page in memory. Nothing survives the handle being closed
+pub struct MemoryStorage {
+    pages: Vec<[u8; BTREE_PAGE_SIZE]>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self { pages: Vec::new() }
+    }
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn file_size(&self) -> u64 {
+        (self.pages.len() * BTREE_PAGE_SIZE) as u64
+    }
+
+    fn set_len(&mut self, size: u64) -> Result<()> {
+        assert!(size.is_multiple_of(BTREE_PAGE_SIZE as u64));
+        self.pages.resize(
+            (size / BTREE_PAGE_SIZE as u64) as usize,
+            [0; BTREE_PAGE_SIZE],
+        );
+        Ok(())
+    }
+
+    fn read_page(&self, ptr: u64) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.pages[ptr as usize])
+    }
+
+    fn write_page(&mut self, ptr: u64, data: &[u8]) -> Result<()> {
+        self.pages[ptr as usize].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn write_master(&mut self, data: &[u8]) -> Result<()> {
+        self.pages[0][..data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn close(self: Box<Self>) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_len() {
+        let mut storage = MemoryStorage::new();
+        assert_eq!(storage.file_size(), 0);
+
+        storage.set_len(3 * BTREE_PAGE_SIZE as u64).unwrap();
+        assert_eq!(storage.file_size(), 3 * BTREE_PAGE_SIZE as u64);
+
+        storage.set_len(BTREE_PAGE_SIZE as u64).unwrap();
+        assert_eq!(storage.file_size(), BTREE_PAGE_SIZE as u64);
+    }
+
+    #[test]
+    fn test_write_and_read_page() {
+        let mut storage = MemoryStorage::new();
+        storage.set_len(2 * BTREE_PAGE_SIZE as u64).unwrap();
+
+        storage.write_page(1, &[7; BTREE_PAGE_SIZE]).unwrap();
+        assert_eq!(storage.read_page(1).as_ref(), &[7; BTREE_PAGE_SIZE]);
+        assert_eq!(storage.read_page(0).as_ref(), &[0; BTREE_PAGE_SIZE]);
+    }
+
+    #[test]
+    fn test_write_master() {
+        let mut storage = MemoryStorage::new();
+        storage.set_len(BTREE_PAGE_SIZE as u64).unwrap();
+
+        storage.write_master(&[1; 40]).unwrap();
+        let page = storage.read_page(0);
+        assert_eq!(&page[..40], &[1; 40]);
+        assert_eq!(page[40], 0);
+    }
+}
@@ -1,22 +1,40 @@
 extern crate libc;
 extern crate memmap2; // Use the memmap2 crate for memory-mapped file support // Use the libc crate for the mmap flags
 
-use crate::b_tree::b_node::{Node, BTREE_PAGE_SIZE};
+use crate::b_tree::b_node::BTREE_PAGE_SIZE;
 use crate::prelude::*;
 use memmap2::{MmapMut, MmapOptions};
+use std::borrow::Cow;
 use std::fs::File;
 
+use super::master_page::write_master_at;
+use super::storage::Storage;
+
 pub struct MMap {
+    /// Pointer to the database file
+    pub file_pointer: File,
     /** file size, can be larger than the database size */
     pub file: u64,
     /** mmap size, can be larger than the file size */
     pub total: usize,
     /** multiple mmaps, can be non-continuous */
     pub chunks: Vec<MmapMut>,
+    /** whether the file was opened for writing, the chunks of read-only files are private */
+    pub writable: bool,
 }
 
 impl MMap {
-    pub fn new(file_pointer: &File) -> Result<MMap> {
+    pub fn new(file_pointer: File) -> Result<MMap> {
+        MMap::map(file_pointer, true)
+    }
+
+    /** Maps a file opened without write access. The mapping is private, the database must not
+     * write to it */
+    pub fn new_read_only(file_pointer: File) -> Result<MMap> {
+        MMap::map(file_pointer, false)
+    }
+
+    fn map(file_pointer: File, writable: bool) -> Result<MMap> {
         let metadata = file_pointer.metadata()?;
         let file_size = metadata.len();
 
@@ -25,40 +43,56 @@ impl MMap {
         }
 
         let mut mmap_size: usize = 64 << 20; // 64 MiB
-        assert!(mmap_size % BTREE_PAGE_SIZE == 0);
+        assert!(mmap_size.is_multiple_of(BTREE_PAGE_SIZE));
 
         while mmap_size < file_size as usize {
             mmap_size *= 2;
         }
 
         // mmap_size can be larger than the file
-        let mmap = unsafe { MmapOptions::new().len(mmap_size).map_mut(file_pointer)? };
+        let mut options = MmapOptions::new();
+        options.len(mmap_size);
+        let mmap = if writable {
+            unsafe { options.map_mut(&file_pointer)? }
+        } else {
+            unsafe { options.map_copy(&file_pointer)? }
+        };
 
         Ok(MMap {
+            file_pointer,
             file: file_size,
             total: mmap_size,
             chunks: vec![mmap],
+            writable,
         })
     }
 
-    pub fn extend_mmap(&mut self, file_pointer: &File, npages: usize) -> Result<()> {
-        if self.total >= npages * BTREE_PAGE_SIZE {
-            return Ok(());
+    pub fn extend_mmap(&mut self, npages: usize) -> Result<()> {
+        while self.total < npages * BTREE_PAGE_SIZE {
+            let mut options = MmapOptions::new();
+            options.offset(self.total as u64).len(self.total);
+            let chunk = if self.writable {
+                unsafe { options.map_mut(&self.file_pointer)? }
+            } else {
+                unsafe { options.map_copy(&self.file_pointer)? }
+            };
+
+            self.total *= 2;
+            self.chunks.push(chunk);
         }
 
-        let chunk = unsafe {
-            memmap2::MmapOptions::new()
-                .offset(self.total as u64)
-                .len(self.total)
-                .map_mut(file_pointer)
-        }?;
-
-        self.total *= 2;
-        self.chunks.push(chunk);
-
         Ok(())
     }
 
+    /** Unmaps the chunks that lie entirely past the first `npages` pages */
+    pub fn shrink_mmap(&mut self, npages: usize) {
+        // each extra chunk doubles the mapping, so the last one starts half way through
+        while self.chunks.len() > 1 && self.total / 2 >= npages * BTREE_PAGE_SIZE {
+            self.chunks.pop();
+            self.total /= 2;
+        }
+    }
+
     /** returns the chunk index and then the offset of the page the ptr is referring to */
     fn get_offset_of_ptr(&self, ptr: u64) -> (usize, u64) {
         let mut start: u64 = 0;
@@ -73,25 +107,74 @@ impl MMap {
         panic!("bad pointer");
     }
 
-    pub fn page_get_mapped<T: Node>(&self, ptr: u64) -> T {
+    pub fn page_get_mapped_raw(&self, ptr: u64) -> &[u8] {
         let (chunk_index, offset) = self.get_offset_of_ptr(ptr);
         let chunk = &self.chunks[chunk_index];
-        T::from(&chunk[offset as usize..offset as usize + BTREE_PAGE_SIZE])
+        &chunk[offset as usize..offset as usize + BTREE_PAGE_SIZE]
     }
 
-    pub fn page_get_mapped_raw_mut(&mut self, ptr: u64) -> &mut [u8] {
+    pub fn page_set(&mut self, ptr: u64, value: &[u8]) {
         let (chunk_index, offset) = self.get_offset_of_ptr(ptr);
         let chunk = &mut self.chunks[chunk_index];
-        &mut chunk[offset as usize..offset as usize + BTREE_PAGE_SIZE]
+        chunk[offset as usize..offset as usize + BTREE_PAGE_SIZE].copy_from_slice(value);
     }
+}
 
-    pub fn page_set(&mut self, ptr: u64, value: &[u8; BTREE_PAGE_SIZE]) {
-        let (chunk_index, offset) = self.get_offset_of_ptr(ptr);
-        let chunk = &mut self.chunks[chunk_index];
-        chunk[offset as usize..offset as usize + BTREE_PAGE_SIZE].copy_from_slice(value);
+impl Storage for MMap {
+    fn file_size(&self) -> u64 {
+        self.file
+    }
+
+    fn set_len(&mut self, size: u64) -> Result<()> {
+        let result = self.file_pointer.set_len(size);
+        if let Err(err) = result {
+            return Err(Error::Generic(format!("failed to resize file: {:?}", err)));
+        }
+        self.file = size;
+
+        let npages = (size / BTREE_PAGE_SIZE as u64) as usize;
+        self.shrink_mmap(npages);
+        self.extend_mmap(npages)
+    }
+
+    fn read_page(&self, ptr: u64) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.page_get_mapped_raw(ptr))
+    }
+
+    fn reload(&mut self) -> Result<()> {
+        // the mappings show the pages as they are in the file, only the new ones need mapping
+        self.file = self.file_pointer.metadata()?.len();
+        self.extend_mmap((self.file / BTREE_PAGE_SIZE as u64) as usize)
+    }
+
+    fn write_page(&mut self, ptr: u64, data: &[u8]) -> Result<()> {
+        self.page_set(ptr, data);
+        Ok(())
+    }
+
+    fn write_master(&mut self, data: &[u8]) -> Result<()> {
+        write_master_at(&self.file_pointer, data)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        // pages are written through the mappings, the master page with pwrite
+        let mut start = 0;
+        for chunk in &self.chunks {
+            let len = (self.file.saturating_sub(start) as usize).min(chunk.len());
+            if len > 0 {
+                chunk.flush_range(0, len)?;
+            }
+            start += chunk.len() as u64;
+        }
+        self.file_pointer.sync_data()?;
+        Ok(())
+    }
+
+    fn mapped_chunks(&self) -> usize {
+        self.chunks.len()
     }
 
-    pub fn close(mut self) {
+    fn close(mut self: Box<Self>) {
         self.chunks.clear();
     }
 }
@@ -1,7 +1,16 @@
+pub mod backup;
+mod compact;
+#[cfg(test)]
+pub mod faulty;
 pub mod fl_node;
-mod master_page;
+pub mod master_page;
+pub mod memory;
 pub mod mmap;
 pub mod page_manager;
+pub mod paged_file;
+pub mod replica;
+pub mod savepoint;
+pub mod storage;
 use crate::prelude::*;
 
 use crate::{
@@ -9,34 +18,71 @@ use crate::{
     free_list::fl_node::MAX_FREE_LIST_IN_PAGE,
 };
 
-use std::{collections::VecDeque, fs::File};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    mem,
+    sync::{Arc, Mutex},
+};
 
-use self::{fl_node::FLNode, master_page::MasterPage, page_manager::PageManager};
+use self::{
+    fl_node::FLNode, master_page::MasterPage, page_manager::PageManager, savepoint::Undo,
+    storage::Storage,
+};
 pub struct FreeList {
     /// Pointer to first node of the free list
     head: u64,
     /// Number of pages taken from the free list
     nfree: i64,
     page_manager: PageManager,
+    /// Free pages handed out by `page_new` while a compaction is in progress, lowest first
+    compact_slots: Option<VecDeque<u64>>,
+    /// Pages that must not be reused, keyed by the id of the pin holding them
+    pins: HashMap<u64, HashSet<u64>>,
+    next_pin: u64,
+    /// Pinned pages deleted from the tree, freed once no pin holds them
+    withheld: HashSet<u64>,
+    /// Pins of the backups dropped since the last flush, released by it
+    released: Arc<Mutex<Vec<u64>>>,
+    /// What changed since the last commit, newest last
+    undo: Vec<Undo>,
+    /// The master page of the last commit
+    last_commit: MasterPage,
 }
 
 impl FreeList {
-    pub fn new(file_pointer: File) -> Result<Self> {
-        Ok(Self {
+    pub fn new(storage: Box<dyn Storage>) -> Self {
+        Self {
             head: 0,
             nfree: 0,
-            page_manager: PageManager::new(file_pointer)?,
-        })
+            page_manager: PageManager::new(storage),
+            compact_slots: None,
+            pins: HashMap::new(),
+            next_pin: 1,
+            withheld: HashSet::new(),
+            released: Arc::new(Mutex::new(Vec::new())),
+            undo: Vec::new(),
+            last_commit: MasterPage::new(0, 1, 0),
+        }
     }
 
     pub fn master_load(&mut self) -> Result<MasterPage> {
         let master_page = self.page_manager.master_load()?;
         self.head = master_page.free_list_head;
+        self.last_commit = master_page.clone();
         Ok(master_page)
     }
 
+    /// Picks up the file as another handle left it. Returns whether its master page is not the
+    /// one of the last commit loaded, `master_load` loads it
+    pub fn reload(&mut self) -> Result<bool> {
+        self.page_manager.storage.reload()?;
+        let master_page = MasterPage::master_load(self.page_manager.storage.as_ref())?;
+        Ok(master_page != self.last_commit)
+    }
+
     pub fn set_master_page(&mut self, btree_root: u64) -> Result<()> {
-        self.page_manager.set_master_page(btree_root, self.head)
+        self.page_manager
+            .set_master_page(btree_root, self.head, &self.last_commit)
     }
 
     pub fn close(self) {
@@ -54,6 +100,21 @@ impl FreeList {
             .unwrap()
     }
 
+    /// Size of the underlying file in bytes
+    pub fn file_size(&self) -> u64 {
+        self.page_manager.storage.file_size()
+    }
+
+    /// Number of times the storage was synced since it was opened
+    pub fn syncs(&self) -> u64 {
+        self.page_manager.syncs
+    }
+
+    /// Number of memory mapped chunks backing the file
+    pub fn mapped_chunks(&self) -> usize {
+        self.page_manager.storage.mapped_chunks()
+    }
+
     pub fn get(&self, mut topn: i64) -> u64 {
         assert!(0 <= topn && topn < self.total());
         assert!(self.head != 0);
@@ -70,7 +131,9 @@ impl FreeList {
     pub fn page_new(&mut self, node: BNode) -> u64 {
         let ptr: u64;
         let total = self.total();
-        if self.nfree < total {
+        if let Some(slots) = &mut self.compact_slots {
+            ptr = slots.pop_front().expect("compaction ran out of free pages");
+        } else if self.nfree < total {
             // reuse deallocated page
             ptr = self.get(self.nfree);
             self.nfree += 1;
@@ -79,11 +142,77 @@ impl FreeList {
             ptr = self.page_manager.flushed + self.page_manager.nappend as u64;
             self.page_manager.nappend += 1;
         }
-        self.page_manager.updates.insert(ptr, Some(node.get_data()));
+        self.set_update(ptr, Some(node.get_data()));
         ptr
     }
 
+    /// Keeps `pages` from being reused until `unpin` is called with the returned id
+    pub fn pin(&mut self, pages: HashSet<u64>) -> u64 {
+        let id = self.next_pin;
+        self.next_pin += 1;
+        self.pins.insert(id, pages);
+        id
+    }
+
+    /// Releases a pin. Pages deleted while pinned are freed on the next flush
+    pub fn unpin(&mut self, id: u64) {
+        self.pins.remove(&id);
+        self.release_withheld();
+    }
+
+    fn release_withheld(&mut self) {
+        let released: Vec<u64> = self
+            .withheld
+            .iter()
+            .copied()
+            .filter(|ptr| !self.is_pinned(*ptr))
+            .collect();
+        for ptr in released {
+            self.withheld.remove(&ptr);
+            self.undo.push(Undo::Release(ptr));
+            self.set_update(ptr, None);
+        }
+    }
+
+    /// Counts `pages` as deleted from the tree while pinned, so they are freed once no pin holds
+    /// them. Pins only live in memory, this puts back what they withheld after reopening
+    pub fn withhold(&mut self, pages: impl IntoIterator<Item = u64>) {
+        self.withheld.extend(pages);
+    }
+
+    /// The master page as of the last commit
+    pub fn master_page(&self, btree_root: u64) -> MasterPage {
+        MasterPage::new(btree_root, self.page_manager.flushed, self.head)
+    }
+
+    /// Every node of the free list along with the free pages it holds, starting at the head
+    pub fn list_contents(&self) -> Vec<(u64, Vec<u64>)> {
+        let mut contents = Vec::new();
+        let mut head = self.head;
+        while head != 0 {
+            let node: FLNode = self.page_manager.page_get(head);
+            contents.push((head, (0..node.size()).map(|i| node.get_ptr(i)).collect()));
+            head = node.next();
+        }
+        contents
+    }
+
+    pub fn has_pins(&self) -> bool {
+        !self.pins.is_empty()
+    }
+
+    fn is_pinned(&self, ptr: u64) -> bool {
+        self.pins.values().any(|pages| pages.contains(&ptr))
+    }
+
     pub fn flush_pages(&mut self, btree_root: u64) -> Result<()> {
+        // also frees the pages a failed commit withheld again after their pin was gone
+        for id in mem::take(&mut *self.released.lock().unwrap()) {
+            self.pins.remove(&id);
+        }
+        if !self.withheld.is_empty() {
+            self.release_withheld();
+        }
         self.write_pages()?;
         self.sync_pages(btree_root)?;
         Ok(())
@@ -97,9 +226,21 @@ impl FreeList {
         self.set_master_page(btree_root)?;
         self.page_manager.flush()?;
 
+        self.committed(btree_root);
         Ok(())
     }
 
+    /// Called once a commit is durable, there is nothing left to roll back
+    fn committed(&mut self, btree_root: u64) {
+        self.undo.clear();
+        self.last_commit = self.master_page(btree_root);
+    }
+
+    /// Whether anything changed since the last commit
+    pub fn has_updates(&self) -> bool {
+        !self.page_manager.updates.is_empty() || !self.released.lock().unwrap().is_empty()
+    }
+
     fn write_pages(&mut self) -> Result<()> {
         // update the free list
         let freed_ptrs = self.page_manager.get_freed_ptrs();
@@ -119,7 +260,10 @@ impl FreeList {
         // prepare to construct new list
         let mut total = self.total();
         let mut reuse: VecDeque<u64> = VecDeque::new();
-        while self.head != 0 && reuse.len() * MAX_FREE_LIST_IN_PAGE < freed_ptrs.len() {
+        // keep going while pointers taken by `page_new` are still in the list, even if nothing
+        // was freed
+        while self.head != 0 && (popn > 0 || reuse.len() * MAX_FREE_LIST_IN_PAGE < freed_ptrs.len())
+        {
             let node: FLNode = self.page_manager.page_get(self.head);
             freed_ptrs.push_back(self.head); // recycle the head node
             if popn >= node.size() as i64 {
@@ -197,7 +341,13 @@ impl BTreePageManager for FreeList {
     }
 
     fn page_del(&mut self, ptr: u64) {
-        self.page_manager.page_del(ptr)
+        if self.is_pinned(ptr) {
+            if self.withheld.insert(ptr) {
+                self.undo.push(Undo::Withhold(ptr));
+            }
+        } else {
+            self.set_update(ptr, None)
+        }
     }
 }
 
@@ -1,65 +1,85 @@
 use crate::prelude::*;
-use std::{
-    collections::{HashMap, VecDeque},
-    fs::File,
-    io::Write,
-};
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc::Sender;
 
 use crate::{
     b_tree::b_node::{Node, BTREE_PAGE_SIZE},
     free_list::fl_node::FLNode,
 };
 
-use super::{master_page::MasterPage, mmap::MMap};
+use super::{master_page::MasterPage, replica::PageDelta, storage::Storage};
 pub struct PageManager {
-    /// Mapped memory of the database file
-    pub mmap: MMap,
-    /// Pointer to the database file
-    pub file_pointer: File,
+    /// I/O backend holding the database file
+    pub storage: Box<dyn Storage>,
     /// Database size in number of pages
     pub flushed: u64,
     /// Number of pages appended to the database
     pub nappend: i64,
     /// newly allocated or deallocated pages keyed by the pointer. empty vector means the page is deallocated
     pub updates: HashMap<u64, Option<[u8; BTREE_PAGE_SIZE]>>,
+    /// Number of times the storage was synced since it was opened
+    pub syncs: u64,
+    /// Followers that every commit is shipped to
+    pub replicas: Vec<Sender<PageDelta>>,
+    /// Pages written since the last commit was shipped, kept only while there are followers
+    written: Vec<(u64, Vec<u8>)>,
+    /// The master page written by the commit in progress, after the one of the commit before
+    master: Option<(MasterPage, MasterPage)>,
+    /// Number of commits shipped
+    shipped: u64,
 }
 
 impl PageManager {
-    pub fn new(file_pointer: File) -> Result<Self> {
-        Ok(Self {
-            mmap: MMap::new(&file_pointer)?,
-            file_pointer,
+    pub fn new(storage: Box<dyn Storage>) -> Self {
+        Self {
+            storage,
             flushed: 0,
             nappend: 0,
             updates: HashMap::new(),
-        })
+            syncs: 0,
+            replicas: Vec::new(),
+            written: Vec::new(),
+            master: None,
+            shipped: 0,
+        }
     }
 
     pub fn master_load(&mut self) -> Result<MasterPage> {
-        let master_page = MasterPage::master_load(&self.mmap)?;
+        let master_page = MasterPage::master_load(self.storage.as_ref())?;
         self.flushed = master_page.total_used_pages;
         Ok(master_page)
     }
 
-    pub fn set_master_page(&mut self, btree_root: u64, free_list_head: u64) -> Result<()> {
+    pub fn set_master_page(
+        &mut self,
+        btree_root: u64,
+        free_list_head: u64,
+        base: &MasterPage,
+    ) -> Result<()> {
         let master_page = MasterPage::new(btree_root, self.flushed, free_list_head);
-        master_page.master_save(&mut self.file_pointer)
+        master_page.master_save(self.storage.as_mut())?;
+        if !self.replicas.is_empty() {
+            self.master = Some((master_page, base.clone()));
+        }
+        Ok(())
     }
 
     pub fn page_get<T: Node>(&self, ptr: u64) -> T {
         // Get from temp pages if it exists
         match self.updates.get(&ptr) {
             Some(data) => T::from(data.as_ref().unwrap()),
-            None => self.mmap.page_get_mapped(ptr),
+            None => T::from(&self.storage.read_page(ptr)),
         }
     }
 
+    /// Returns a mutable copy of the page, the change is written out with the other updates
     pub fn page_get_raw_mut(&mut self, ptr: u64) -> &mut [u8] {
-        // Get from temp pages if it exists
-        match self.updates.get_mut(&ptr) {
-            Some(data) => &mut data.as_mut().unwrap()[..],
-            None => self.mmap.page_get_mapped_raw_mut(ptr),
+        if !self.updates.contains_key(&ptr) {
+            let data: [u8; BTREE_PAGE_SIZE] =
+                self.storage.read_page(ptr).as_ref().try_into().unwrap();
+            self.updates.insert(ptr, Some(data));
         }
+        &mut self.updates.get_mut(&ptr).unwrap().as_mut().unwrap()[..]
     }
 
     // callback for free list, allocate a new page
@@ -75,10 +95,6 @@ impl PageManager {
         self.updates.insert(ptr, Some(node.get_data()));
     }
 
-    pub fn page_del(&mut self, ptr: u64) {
-        self.updates.insert(ptr, None);
-    }
-
     pub fn get_freed_ptrs(&mut self) -> VecDeque<u64> {
         let mut freed_ptrs = VecDeque::new();
         for (ptr, data) in self.updates.iter() {
@@ -91,12 +107,14 @@ impl PageManager {
 
     pub fn write_pages(&mut self) -> Result<()> {
         self.extend_file()?;
-        self.extend_mmap()?;
 
-        // copy temp data to mmap
+        // copy temp data to the storage
         for (ptr, temp_page) in self.updates.iter() {
-            if temp_page.is_some() {
-                self.mmap.page_set(*ptr, temp_page.as_ref().unwrap());
+            if let Some(data) = temp_page {
+                self.storage.write_page(*ptr, data)?;
+                if !self.replicas.is_empty() {
+                    self.written.push((*ptr, data.to_vec()));
+                }
             }
         }
 
@@ -105,18 +123,45 @@ impl PageManager {
 
     pub fn flush(&mut self) -> Result<()> {
         // Flush data to the disk. Must be done before updating the master page.
-        self.file_pointer.flush()?;
+        self.storage.sync()?;
+        self.syncs += 1;
 
         self.flushed += self.nappend as u64;
         self.nappend = 0;
         self.updates.clear();
 
+        // the sync after the master page ends the commit
+        if let Some((master, base)) = self.master.take() {
+            self.ship(master, base);
+        }
         Ok(())
     }
 
+    /** Sends the pages and master page of a durable commit to the followers still listening */
+    fn ship(&mut self, master: MasterPage, base: MasterPage) {
+        let delta = PageDelta {
+            seq: self.shipped,
+            pages: std::mem::take(&mut self.written),
+            master,
+            base,
+        };
+        self.shipped += 1;
+        self.replicas
+            .retain(|replica| replica.send(delta.clone()).is_ok());
+    }
+
+    /// Forgets the pages written since the last commit, which left the database at `flushed` pages
+    pub fn reset(&mut self, flushed: u64) {
+        self.flushed = flushed;
+        self.nappend = 0;
+        self.updates.clear();
+        self.written.clear();
+        self.master = None;
+    }
+
     pub fn extend_file(&mut self) -> Result<()> {
         let npages = self.flushed + self.nappend as u64;
-        let mut file_pages = self.mmap.file / BTREE_PAGE_SIZE as u64;
+        let mut file_pages = self.storage.file_size() / BTREE_PAGE_SIZE as u64;
         if file_pages >= npages {
             return Ok(());
         }
@@ -131,25 +176,10 @@ impl PageManager {
             file_pages += inc;
         }
 
-        let file_size = file_pages * BTREE_PAGE_SIZE as u64;
-        let result = self.file_pointer.set_len(file_size);
-        if result.is_err() {
-            return Err(Error::Generic(format!(
-                "failed to extend file: {:?}",
-                result.unwrap_err()
-            )));
-        }
-
-        self.mmap.file = file_size;
-        Ok(())
-    }
-
-    pub fn extend_mmap(&mut self) -> Result<()> {
-        let npages = self.flushed + self.nappend as u64;
-        self.mmap.extend_mmap(&self.file_pointer, npages as usize)
+        self.storage.set_len(file_pages * BTREE_PAGE_SIZE as u64)
     }
 
     pub fn close(self) {
-        self.mmap.close();
+        self.storage.close();
     }
 }
@@ -0,0 +1,269 @@
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    os::unix::prelude::FileExt,
+};
+
+use crate::b_tree::b_node::BTREE_PAGE_SIZE;
+use crate::prelude::*;
+
+use super::master_page::write_master_at;
+use super::storage::Storage;
+
+/// Default number of pages held by the buffer pool (16 MiB)
+pub const DEFAULT_POOL_PAGES: usize = 4096;
+
+/// Least recently used cache of pages read from the file
+pub struct BufferPool {
+    /// Maximum number of pages held in the pool
+    capacity: usize,
+    /// Cached pages keyed by pointer, along with the tick of their last use
+    pages: HashMap<u64, (Box<[u8; BTREE_PAGE_SIZE]>, u64)>,
+    /// Pointers ordered by the tick of their last use, oldest first
+    lru: BTreeMap<u64, u64>,
+    tick: u64,
+}
+
+impl BufferPool {
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity >= 1);
+        Self {
+            capacity,
+            pages: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    /// Returns a cached page and marks it as most recently used
+    pub fn get(&mut self, ptr: u64) -> Option<&[u8; BTREE_PAGE_SIZE]> {
+        self.tick += 1;
+        let (data, last_used) = self.pages.get_mut(&ptr)?;
+        self.lru.remove(last_used);
+        *last_used = self.tick;
+        self.lru.insert(self.tick, ptr);
+        Some(data)
+    }
+
+    /// Adds or replaces a page, evicting the least recently used page if the pool is full
+    pub fn insert(&mut self, ptr: u64, data: &[u8]) {
+        self.tick += 1;
+        if let Some((cached, last_used)) = self.pages.get_mut(&ptr) {
+            cached.copy_from_slice(data);
+            self.lru.remove(last_used);
+            *last_used = self.tick;
+            self.lru.insert(self.tick, ptr);
+            return;
+        }
+
+        if self.pages.len() >= self.capacity {
+            let (_, evicted) = self.lru.pop_first().unwrap();
+            self.pages.remove(&evicted);
+        }
+
+        let mut page = Box::new([0; BTREE_PAGE_SIZE]);
+        page.copy_from_slice(data);
+        self.pages.insert(ptr, (page, self.tick));
+        self.lru.insert(self.tick, ptr);
+    }
+
+    /// Replaces a page only if it is already cached
+    pub fn update(&mut self, ptr: u64, data: &[u8]) {
+        if let Some((cached, _)) = self.pages.get_mut(&ptr) {
+            cached.copy_from_slice(data);
+        }
+    }
+
+    /// Drops every page at or after `ptr`
+    pub fn truncate(&mut self, ptr: u64) {
+        let lru = &mut self.lru;
+        self.pages.retain(|&cached_ptr, (_, last_used)| {
+            if cached_ptr >= ptr {
+                lru.remove(last_used);
+            }
+            cached_ptr < ptr
+        });
+    }
+
+    pub fn remove(&mut self, ptr: u64) {
+        if let Some((_, last_used)) = self.pages.remove(&ptr) {
+            self.lru.remove(&last_used);
+        }
+    }
+}
+
+/// Storage backend using positioned reads and writes, memory use is bounded by the buffer pool
+pub struct PagedFile {
+    /// Pointer to the database file
+    pub file_pointer: File,
+    /// file size, can be larger than the database size
+    pub file: u64,
+    pool: RefCell<BufferPool>,
+}
+
+impl PagedFile {
+    pub fn new(file_pointer: File, pool_pages: usize) -> Result<PagedFile> {
+        let file_size = file_pointer.metadata()?.len();
+
+        if file_size % BTREE_PAGE_SIZE as u64 != 0 {
+            return Err(Error::Static("File size is not a multiple of page size."));
+        }
+
+        Ok(PagedFile {
+            file_pointer,
+            file: file_size,
+            pool: RefCell::new(BufferPool::new(pool_pages)),
+        })
+    }
+}
+
+impl Storage for PagedFile {
+    fn file_size(&self) -> u64 {
+        self.file
+    }
+
+    fn set_len(&mut self, size: u64) -> Result<()> {
+        let result = self.file_pointer.set_len(size);
+        if let Err(err) = result {
+            return Err(Error::Generic(format!("failed to resize file: {:?}", err)));
+        }
+        self.file = size;
+
+        self.pool.get_mut().truncate(size / BTREE_PAGE_SIZE as u64);
+        Ok(())
+    }
+
+    fn read_page(&self, ptr: u64) -> Cow<'_, [u8]> {
+        assert!(
+            (ptr + 1) * BTREE_PAGE_SIZE as u64 <= self.file,
+            "bad pointer"
+        );
+
+        let mut pool = self.pool.borrow_mut();
+        if let Some(data) = pool.get(ptr) {
+            return Cow::Owned(data.to_vec());
+        }
+
+        let mut data = vec![0; BTREE_PAGE_SIZE];
+        self.file_pointer
+            .read_exact_at(&mut data, ptr * BTREE_PAGE_SIZE as u64)
+            .unwrap_or_else(|err| panic!("failed to read page {}: {:?}", ptr, err));
+        pool.insert(ptr, &data);
+        Cow::Owned(data)
+    }
+
+    fn reload(&mut self) -> Result<()> {
+        self.file = self.file_pointer.metadata()?.len();
+        self.pool.get_mut().truncate(0);
+        Ok(())
+    }
+
+    fn write_page(&mut self, ptr: u64, data: &[u8]) -> Result<()> {
+        assert!(data.len() == BTREE_PAGE_SIZE);
+        self.file_pointer
+            .write_all_at(data, ptr * BTREE_PAGE_SIZE as u64)?;
+        self.pool.get_mut().update(ptr, data);
+        Ok(())
+    }
+
+    fn write_master(&mut self, data: &[u8]) -> Result<()> {
+        write_master_at(&self.file_pointer, data)?;
+        self.pool.get_mut().remove(0);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.file_pointer.sync_data()?;
+        Ok(())
+    }
+
+    fn close(self: Box<Self>) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(byte: u8) -> [u8; BTREE_PAGE_SIZE] {
+        [byte; BTREE_PAGE_SIZE]
+    }
+
+    #[test]
+    fn test_pool_get_and_insert() {
+        let mut pool = BufferPool::new(2);
+        assert!(pool.get(1).is_none());
+
+        pool.insert(1, &page(1));
+        assert_eq!(pool.get(1).unwrap()[0], 1);
+        assert_eq!(pool.pages.len(), 1);
+    }
+
+    #[test]
+    fn test_pool_evicts_least_recently_used() {
+        let mut pool = BufferPool::new(2);
+        pool.insert(1, &page(1));
+        pool.insert(2, &page(2));
+
+        // touch page 1 so page 2 is the oldest
+        pool.get(1);
+        pool.insert(3, &page(3));
+
+        assert_eq!(pool.pages.len(), 2);
+        assert!(pool.get(2).is_none());
+        assert_eq!(pool.get(1).unwrap()[0], 1);
+        assert_eq!(pool.get(3).unwrap()[0], 3);
+    }
+
+    #[test]
+    fn test_pool_insert_replaces_existing() {
+        let mut pool = BufferPool::new(2);
+        pool.insert(1, &page(1));
+        pool.insert(1, &page(5));
+
+        assert_eq!(pool.pages.len(), 1);
+        assert_eq!(pool.get(1).unwrap()[0], 5);
+    }
+
+    #[test]
+    fn test_pool_update_only_cached() {
+        let mut pool = BufferPool::new(2);
+        pool.update(1, &page(1));
+        assert!(pool.pages.is_empty());
+
+        pool.insert(1, &page(1));
+        pool.update(1, &page(2));
+        assert_eq!(pool.get(1).unwrap()[0], 2);
+    }
+
+    #[test]
+    fn test_pool_remove() {
+        let mut pool = BufferPool::new(2);
+        pool.insert(1, &page(1));
+        pool.insert(2, &page(2));
+        pool.remove(1);
+
+        assert_eq!(pool.pages.len(), 1);
+        assert!(pool.get(1).is_none());
+    }
+
+    #[test]
+    fn test_pool_truncate() {
+        let mut pool = BufferPool::new(4);
+        for i in 0..4 {
+            pool.insert(i, &page(i as u8));
+        }
+        pool.truncate(2);
+
+        assert_eq!(pool.pages.len(), 2);
+        assert!(pool.get(2).is_none());
+        assert!(pool.get(3).is_none());
+
+        // the lru order is still consistent after a truncate
+        pool.insert(5, &page(5));
+        pool.insert(6, &page(6));
+        pool.insert(7, &page(7));
+        assert_eq!(pool.pages.len(), 4);
+    }
+}
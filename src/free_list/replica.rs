@@ -0,0 +1,152 @@
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{self, Receiver};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::b_tree::b_node::BTREE_PAGE_SIZE;
+use crate::prelude::*;
+
+use super::{
+    master_page::{MasterPage, MASTER_PAGE_SIZE},
+    FreeList,
+};
+
+/// Size of the fixed part of a delta in a stream, before its pages
+const HEADER_SIZE: usize = 8 + 2 * MASTER_PAGE_SIZE + 4;
+
+/// What one commit wrote: the pages of the `write_pages` pass and the master page that made them
+/// part of the database. A follower applying the deltas in order ends up with the same file
+#[derive(Debug, Clone, PartialEq)]
+pub struct PageDelta {
+    /// Commits shipped before this one since the leader was opened
+    pub seq: u64,
+    pub pages: Vec<(u64, Vec<u8>)>,
+    pub master: MasterPage,
+    /// The master page of the commit before, which a follower must have to apply this one
+    pub base: MasterPage,
+}
+
+impl PageDelta {
+    /** Writes the delta to a stream: the sequence number, the master page, the base master page,
+     * the page count and then each page after its pointer, all little endian */
+    pub fn write_to(&self, stream: &mut impl Write) -> Result<()> {
+        let mut header = [0; HEADER_SIZE];
+        LittleEndian::write_u64(&mut header, self.seq);
+        header[8..8 + MASTER_PAGE_SIZE].copy_from_slice(&self.master.to_bytes());
+        header[8 + MASTER_PAGE_SIZE..8 + 2 * MASTER_PAGE_SIZE]
+            .copy_from_slice(&self.base.to_bytes());
+        LittleEndian::write_u32(
+            &mut header[8 + 2 * MASTER_PAGE_SIZE..],
+            self.pages.len() as u32,
+        );
+        stream.write_all(&header)?;
+        for (ptr, data) in &self.pages {
+            stream.write_all(&ptr.to_le_bytes())?;
+            stream.write_all(data)?;
+        }
+        stream.flush()?;
+        Ok(())
+    }
+
+    /** Reads a delta written by `write_to`. Returns `None` if the stream ends before it starts */
+    pub fn read_from(stream: &mut impl Read) -> Result<Option<PageDelta>> {
+        let mut header = [0; HEADER_SIZE];
+        match stream.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err.into()),
+        }
+        let seq = LittleEndian::read_u64(&header);
+        let master = MasterPage::from_bytes(&header[8..8 + MASTER_PAGE_SIZE])?;
+        let base = MasterPage::from_bytes(&header[8 + MASTER_PAGE_SIZE..])?;
+        let count = LittleEndian::read_u32(&header[8 + 2 * MASTER_PAGE_SIZE..]);
+        let mut pages = Vec::new();
+        for _ in 0..count {
+            let mut ptr = [0; 8];
+            stream.read_exact(&mut ptr)?;
+            let mut data = vec![0; BTREE_PAGE_SIZE];
+            stream.read_exact(&mut data)?;
+            pages.push((u64::from_le_bytes(ptr), data));
+        }
+        Ok(Some(PageDelta {
+            seq,
+            pages,
+            master,
+            base,
+        }))
+    }
+}
+
+impl FreeList {
+    /// Ships every commit from now on to the returned receiver. Nothing is shipped once it is
+    /// dropped
+    pub fn replicate(&mut self) -> Receiver<PageDelta> {
+        let (sender, receiver) = mpsc::channel();
+        self.page_manager.replicas.push(sender);
+        receiver
+    }
+
+    /// Writes the pages of a delta and then its master page, syncing after each like a commit.
+    /// The pages are free under the current master page so a crash keeps the last version
+    pub fn apply_delta(&mut self, delta: &PageDelta) -> Result<MasterPage> {
+        assert!(self.page_manager.updates.is_empty() && self.nfree == 0);
+        let last_page = delta.pages.iter().map(|(ptr, _)| *ptr).max().unwrap_or(0);
+        if last_page == 0 && !delta.pages.is_empty() {
+            return Err(Error::Static("a delta cannot write the master page"));
+        }
+        let npages = delta.master.total_used_pages.max(last_page + 1);
+        let storage = &mut self.page_manager.storage;
+        if storage.file_size() < npages * BTREE_PAGE_SIZE as u64 {
+            storage.set_len(npages * BTREE_PAGE_SIZE as u64)?;
+        }
+
+        for (ptr, data) in &delta.pages {
+            if data.len() != BTREE_PAGE_SIZE {
+                return Err(Error::Static("bad page size in delta"));
+            }
+            storage.write_page(*ptr, data)?;
+        }
+        storage.sync()?;
+        delta.master.master_save(storage.as_mut())?;
+        storage.sync()?;
+        self.master_load()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delta_stream() {
+        let deltas = vec![
+            PageDelta {
+                seq: 0,
+                pages: vec![(3, vec![7; BTREE_PAGE_SIZE]), (1, vec![0; BTREE_PAGE_SIZE])],
+                master: MasterPage::new(3, 4, 1),
+                base: MasterPage::new(0, 1, 0),
+            },
+            PageDelta {
+                seq: 1,
+                pages: vec![],
+                master: MasterPage::new(2, 4, 0),
+                base: MasterPage::new(3, 4, 1),
+            },
+        ];
+        let mut stream = Vec::new();
+        for delta in &deltas {
+            delta.write_to(&mut stream).unwrap();
+        }
+
+        let mut reader = &stream[..];
+        for delta in &deltas {
+            assert_eq!(
+                PageDelta::read_from(&mut reader).unwrap().as_ref(),
+                Some(delta)
+            );
+        }
+        assert!(PageDelta::read_from(&mut reader).unwrap().is_none());
+        // cut off part way through a page
+        assert!(PageDelta::read_from(&mut &stream[..100]).is_err());
+    }
+}
@@ -0,0 +1,81 @@
+use crate::b_tree::b_node::BTREE_PAGE_SIZE;
+
+use super::FreeList;
+
+/// A change to the pages since the last commit, undone when rolling back
+pub(super) enum Undo {
+    /// The pending update of a page was replaced, this is what it was before
+    Update(u64, Option<Option<Box<[u8; BTREE_PAGE_SIZE]>>>),
+    /// A pinned page deleted from the tree was withheld
+    Withhold(u64),
+    /// A withheld page was freed once no pin held it
+    Release(u64),
+}
+
+/// Where the pages were at some point since the last commit, see `FreeList::savepoint`
+#[derive(Debug, Clone, Copy)]
+pub struct Savepoint {
+    nfree: i64,
+    nappend: i64,
+    undo: usize,
+}
+
+impl FreeList {
+    /// Records a pending update of a page so it can be rolled back
+    pub(super) fn set_update(&mut self, ptr: u64, data: Option<[u8; BTREE_PAGE_SIZE]>) {
+        let before = self.page_manager.updates.insert(ptr, data);
+        self.undo
+            .push(Undo::Update(ptr, before.map(|data| data.map(Box::new))));
+    }
+
+    /// The state `rollback` goes back to. Only valid until the next commit
+    pub fn savepoint(&self) -> Savepoint {
+        assert!(self.compact_slots.is_none());
+        Savepoint {
+            nfree: self.nfree,
+            nappend: self.page_manager.nappend,
+            undo: self.undo.len(),
+        }
+    }
+
+    /// Drops the page updates made since `savepoint`
+    pub fn rollback(&mut self, savepoint: Savepoint) {
+        self.undo_to(savepoint.undo);
+        self.nfree = savepoint.nfree;
+        self.page_manager.nappend = savepoint.nappend;
+    }
+
+    /// Drops everything since the last commit after it failed, and puts its master page back in
+    /// case the failed one was written. Returns the root of the tree as of that commit
+    pub fn reset(&mut self) -> u64 {
+        self.undo_to(0);
+        let master = self.last_commit.clone();
+        self.head = master.free_list_head;
+        self.nfree = 0;
+        self.page_manager.reset(master.total_used_pages);
+        // unsynced like the one it replaces, the next commit writes its own over it anyway
+        let _ = master.master_save(self.page_manager.storage.as_mut());
+        master.btree_root
+    }
+
+    fn undo_to(&mut self, len: usize) {
+        while self.undo.len() > len {
+            match self.undo.pop().unwrap() {
+                Undo::Update(ptr, Some(data)) => {
+                    self.page_manager
+                        .updates
+                        .insert(ptr, data.map(|data| *data));
+                }
+                Undo::Update(ptr, None) => {
+                    self.page_manager.updates.remove(&ptr);
+                }
+                Undo::Withhold(ptr) => {
+                    self.withheld.remove(&ptr);
+                }
+                Undo::Release(ptr) => {
+                    self.withheld.insert(ptr);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,37 @@
+use std::borrow::Cow;
+
+use crate::prelude::*;
+
+/// The I/O backend underneath the `PageManager`. Pages are addressed by their page number,
+/// page 0 holds the master page.
+pub trait Storage: Send {
+    /// Size of the underlying file in bytes, can be larger than the database size
+    fn file_size(&self) -> u64;
+
+    /// Grows or shrinks the underlying file to `size` bytes
+    fn set_len(&mut self, size: u64) -> Result<()>;
+
+    /// Returns the contents of a page. The page must be inside the file
+    fn read_page(&self, ptr: u64) -> Cow<'_, [u8]>;
+
+    /// Writes a full page. The page must be inside the file
+    fn write_page(&mut self, ptr: u64, data: &[u8]) -> Result<()>;
+
+    /// Writes the master page record at the start of page 0
+    fn write_master(&mut self, data: &[u8]) -> Result<()>;
+
+    /// Makes all previous writes durable
+    fn sync(&mut self) -> Result<()>;
+
+    /// Picks up what another handle wrote to the file since: its size and the pages it changed
+    fn reload(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Number of memory mapped chunks, 0 for backends that do not map the file
+    fn mapped_chunks(&self) -> usize {
+        0
+    }
+
+    fn close(self: Box<Self>);
+}
@@ -0,0 +1,225 @@
+use std::collections::HashSet;
+
+use crate::free_list::{backup::Backup, paged_file::PagedFile};
+use crate::prelude::*;
+
+use super::KV;
+
+impl KV {
+    /** Copies the current version of the database to a new file at `path` */
+    pub fn backup_to(&mut self, path: String) -> Result<()> {
+        let backup = self.backup_begin(path)?;
+        self.backup_finish(backup)
+    }
+
+    /** Starts a hot backup of the current version of the database into a new file at `path`.
+     * The pages of this version are pinned, so writes can carry on while `backup_step` copies
+     * them a few at a time. Pages deleted meanwhile are only reused after `backup_finish`.
+     * Snapshots are copied along with it */
+    pub fn backup_begin(&mut self, path: String) -> Result<Backup> {
+        if self.batching {
+            return Err(Error::Static("cannot back up while writes are batched"));
+        }
+        let file_pointer = KV::open_empty_file(path)?;
+        // written with pwrite so the copy is synced to disk before its master page
+        let dst = Box::new(PagedFile::new(file_pointer, 1)?);
+
+        let mut tree_pages = HashSet::new();
+        self.tree.walk(&mut |ptr, _, _| {
+            tree_pages.insert(ptr);
+        });
+        for root in self.snapshot_roots() {
+            self.tree.walk_at(root, &mut |ptr, _, _| {
+                tree_pages.insert(ptr);
+            });
+        }
+        self.tree
+            .page_manager
+            .backup_begin(self.tree.root, tree_pages, dst)
+    }
+
+    /** Copies up to `npages` pages of the backup. Returns true once every page has been copied */
+    pub fn backup_step(&self, backup: &mut Backup, npages: usize) -> Result<bool> {
+        self.tree.page_manager.backup_step(backup, npages)
+    }
+
+    /** Completes the backup and frees the pages that were held back for it */
+    pub fn backup_finish(&mut self, backup: Backup) -> Result<()> {
+        let result = self.tree.page_manager.backup_finish(backup);
+        self.flush_pages()?;
+        result
+    }
+
+    /** Gives up on a backup. Dropping it does the same, its pages are freed by the next commit */
+    pub fn backup_abort(&mut self, backup: Backup) -> Result<()> {
+        drop(backup);
+        self.flush_pages()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, fs};
+
+    use super::*;
+    use crate::test_util::test_file;
+
+    fn key(i: u32) -> Vec<u8> {
+        format!("key{:06}", i).into_bytes()
+    }
+
+    fn check_backup(file_name: String, expected: &BTreeMap<Vec<u8>, Vec<u8>>) {
+        let kv = KV::open(file_name).unwrap();
+        for (key, val) in expected {
+            assert_eq!(kv.get(key).as_ref(), Some(val));
+        }
+
+        // and nothing else
+        let mut count = 0;
+        let mut iter = kv.seek(&[], crate::kv_store::CmpOption::GT);
+        while iter.valid() {
+            count += 1;
+            if !iter.next() {
+                break;
+            }
+        }
+        assert_eq!(count, expected.len());
+        kv.close();
+    }
+
+    #[test]
+    fn test_backup_to() {
+        let mut kv = KV::open_in_memory().unwrap();
+        let mut expected = BTreeMap::new();
+        for i in 0..1000 {
+            kv.set(&key(i), &[i as u8; 100]).unwrap();
+            expected.insert(key(i), vec![i as u8; 100]);
+        }
+        for i in (0..1000).step_by(3) {
+            kv.del(&key(i)).unwrap();
+            expected.remove(&key(i));
+        }
+
+        let file_name = test_file("test_backup_to.db");
+        kv.backup_to(file_name.clone()).unwrap();
+        assert!(kv.backup_to(file_name.clone()).is_err());
+        kv.begin_batch();
+        let batched = test_file("test_backup_to_batched.db");
+        assert!(kv.backup_to(batched.clone()).is_err());
+        assert!(fs::metadata(batched).is_err());
+        kv.commit_batch().unwrap();
+        check_backup(file_name.clone(), &expected);
+
+        // the backup is a working database, including its free list
+        let mut copy = KV::open(file_name).unwrap();
+        for i in 0..1000 {
+            copy.set(&key(i), b"new").unwrap();
+        }
+        assert_eq!(copy.get(&key(0)), Some(b"new".to_vec()));
+        copy.close();
+    }
+
+    #[test]
+    fn test_backup_while_writing() {
+        let file_name = test_file("test_backup_while_writing_source.db");
+        let mut kv = KV::open(file_name).unwrap();
+        let mut expected = BTreeMap::new();
+        for i in 0..2000 {
+            kv.set(&key(i), &[i as u8; 100]).unwrap();
+            expected.insert(key(i), vec![i as u8; 100]);
+        }
+
+        let backup_name = test_file("test_backup_while_writing.db");
+        let mut backup = kv.backup_begin(backup_name.clone()).unwrap();
+        assert!(kv.compact().is_err());
+
+        // rewrite and delete everything between the steps
+        let mut i = 0;
+        while !kv.backup_step(&mut backup, 10).unwrap() {
+            for _ in 0..50 {
+                if i < 2000 {
+                    kv.set(&key(i), b"changed").unwrap();
+                } else {
+                    kv.del(&key(i - 2000)).unwrap();
+                }
+                i += 1;
+            }
+        }
+        assert_eq!(backup.remaining(), 0);
+        kv.backup_finish(backup).unwrap();
+        check_backup(backup_name, &expected);
+
+        for j in 0..2000 {
+            let val = if j + 2000 < i {
+                None
+            } else if j < i {
+                Some(b"changed".to_vec())
+            } else {
+                Some(vec![j as u8; 100])
+            };
+            assert_eq!(kv.get(&key(j)), val);
+        }
+
+        // the pages held back for the backup are free again
+        let pages = kv.tree.page_manager.total_pages();
+        for j in 0..2000 {
+            kv.set(&key(j), &[j as u8; 100]).unwrap();
+        }
+        assert!(kv.tree.page_manager.total_pages() <= pages + 5);
+        kv.compact().unwrap();
+        kv.close();
+    }
+
+    #[test]
+    fn test_backup_abort() {
+        let mut kv = KV::open_in_memory().unwrap();
+        kv.set(&key(0), b"val").unwrap();
+
+        let file_name = test_file("test_backup_abort.db");
+        let backup = kv.backup_begin(file_name).unwrap();
+        kv.del(&key(0)).unwrap();
+        kv.backup_abort(backup).unwrap();
+
+        assert!(!kv.tree.page_manager.has_pins());
+        assert_eq!(kv.get(&key(0)), None);
+        kv.compact().unwrap();
+    }
+
+    #[test]
+    fn test_backup_dropped() {
+        let mut kv = KV::open_in_memory().unwrap();
+        for i in 0..100 {
+            kv.set(&key(i), b"val").unwrap();
+        }
+
+        let file_name = test_file("test_backup_dropped.db");
+        let backup = kv.backup_begin(file_name).unwrap();
+        for i in 0..100 {
+            kv.del(&key(i)).unwrap();
+        }
+        let size = kv.tree.page_manager.file_size();
+        drop(backup);
+
+        // released by the next commit, the pages it held are reused
+        kv.set(&key(0), b"val").unwrap();
+        assert!(!kv.tree.page_manager.has_pins());
+        for i in 1..100 {
+            kv.set(&key(i), b"val").unwrap();
+        }
+        assert_eq!(kv.tree.page_manager.file_size(), size);
+        kv.compact().unwrap();
+    }
+
+    #[test]
+    fn test_compact_after_backup_dropped() {
+        let mut kv = KV::open_in_memory().unwrap();
+        kv.set(&key(0), b"val").unwrap();
+
+        let file_name = test_file("test_compact_after_backup_dropped.db");
+        let backup = kv.backup_begin(file_name).unwrap();
+        kv.del(&key(0)).unwrap();
+        drop(backup);
+        kv.compact().unwrap();
+        assert!(!kv.tree.page_manager.has_pins());
+    }
+}
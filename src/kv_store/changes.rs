@@ -0,0 +1,408 @@
+//! The change feed. Once enabled, every put and delete is appended to a log of system keys in
+//! the same commit as the write itself, numbered in commit order. Subscribers get each change
+//! once it is durable, and the log lets a consumer that was away replay what it missed from a
+//! cursor saved in the cursor table.
+
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+
+use crate::b_tree::b_node::BTREE_MAX_VAL_SIZE;
+use crate::prelude::*;
+
+use super::{system_key, CmpOption, KV};
+
+/// Marks the feed as enabled, holds the lowest sequence number still in the log
+const FEED_TAG: u8 = b'f';
+/// Changes by sequence number
+const LOG_TAG: u8 = b'c';
+/// Saved cursors by consumer name
+const CURSOR_TAG: u8 = b'k';
+/// Values too large to share a log entry with their key, by sequence number
+const LOG_VALUE_TAG: u8 = b'v';
+
+const OP_DELETE: u8 = 0;
+const OP_PUT: u8 = 1;
+/// A put whose value is logged under `LOG_VALUE_TAG`
+const OP_PUT_SPLIT: u8 = 2;
+
+/// A committed write
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    /// Position in commit order, starting at 1
+    pub seq: u64,
+    pub key: Vec<u8>,
+    /// The new value, `None` when the key was deleted
+    pub value: Option<Vec<u8>>,
+}
+
+impl Change {
+    /** The log entry, along with the value when it is too large to fit in the entry */
+    fn encode(&self) -> (Vec<u8>, Option<&[u8]>) {
+        let mut out = Vec::with_capacity(5 + self.key.len());
+        let split = match &self.value {
+            Some(value) if 5 + self.key.len() + value.len() > BTREE_MAX_VAL_SIZE => {
+                out.push(OP_PUT_SPLIT);
+                Some(value.as_slice())
+            }
+            Some(_) => {
+                out.push(OP_PUT);
+                None
+            }
+            None => {
+                out.push(OP_DELETE);
+                None
+            }
+        };
+        out.extend_from_slice(&(self.key.len() as u32).to_le_bytes());
+        out.extend_from_slice(&self.key);
+        if let (Some(value), None) = (&self.value, split) {
+            out.extend_from_slice(value);
+        }
+        (out, split)
+    }
+
+    /** The value of an `OP_PUT_SPLIT` entry is left empty, to be filled in by the caller */
+    fn decode(seq: u64, data: &[u8]) -> Change {
+        let len = LittleEndian::read_u32(&data[1..5]) as usize;
+        let key = data[5..5 + len].to_vec();
+        let value = match data[0] {
+            OP_DELETE => None,
+            _ => Some(data[5 + len..].to_vec()),
+        };
+        Change { seq, key, value }
+    }
+}
+
+/// The state of an enabled feed
+pub(super) struct ChangeLog {
+    next_seq: u64,
+    /// Lowest sequence number still in the log, the ones before it were trimmed
+    first_seq: u64,
+    /// Recorded but not committed yet
+    pending: Vec<Change>,
+    subscribers: Vec<Sender<Change>>,
+}
+
+/// Where the log was at a savepoint
+#[derive(Debug, Clone, Copy)]
+pub(super) struct LogMark {
+    next_seq: u64,
+    first_seq: u64,
+}
+
+impl ChangeLog {
+    pub(super) fn mark(&self) -> LogMark {
+        LogMark {
+            next_seq: self.next_seq,
+            first_seq: self.first_seq,
+        }
+    }
+
+    /** Forgets the changes recorded since `mark` */
+    pub(super) fn rewind(&mut self, mark: LogMark) {
+        let recorded = (self.next_seq - mark.next_seq) as usize;
+        self.pending.truncate(self.pending.len() - recorded);
+        self.next_seq = mark.next_seq;
+        self.first_seq = mark.first_seq;
+    }
+
+    /** Hands the committed changes to the subscribers, forgetting the ones that went away */
+    pub(super) fn deliver(&mut self) {
+        for change in self.pending.drain(..) {
+            self.subscribers
+                .retain(|subscriber| subscriber.send(change.clone()).is_ok());
+        }
+    }
+}
+
+fn log_key(seq: u64) -> Vec<u8> {
+    system_key(LOG_TAG, &seq.to_be_bytes())
+}
+
+fn log_value_key(seq: u64) -> Vec<u8> {
+    system_key(LOG_VALUE_TAG, &seq.to_be_bytes())
+}
+
+impl KV {
+    /** Loads the feed if it was enabled before */
+    pub(super) fn load_change_log(&mut self) -> Option<ChangeLog> {
+        let first_seq = BigEndian::read_u64(&self.tree.get_value(&system_key(FEED_TAG, &[]))?);
+        let prefix = system_key(LOG_TAG, &[]);
+        // the last change logged is the one just before the first key after the log
+        let iter = self.tree.seek(&system_key(LOG_TAG + 1, &[]), CmpOption::LT);
+        let mut next_seq = first_seq;
+        if iter.valid() && iter.key().starts_with(&prefix) {
+            next_seq = BigEndian::read_u64(&iter.key()[prefix.len()..]) + 1;
+        }
+        Some(ChangeLog {
+            next_seq,
+            first_seq,
+            pending: Vec::new(),
+            subscribers: Vec::new(),
+        })
+    }
+
+    /** Loads the feed again after the tree went back to the last commit, keeping the
+     * subscribers. The changes recorded since are forgotten */
+    pub(super) fn reload_change_log(&mut self) {
+        let subscribers = self.changes.take().map(|log| log.subscribers);
+        self.changes = self.load_change_log();
+        if let (Some(log), Some(subscribers)) = (&mut self.changes, subscribers) {
+            log.subscribers = subscribers;
+        }
+    }
+
+    /** Starts logging every write, from the next commit on. Stays enabled across reopening */
+    pub fn enable_change_feed(&mut self) -> Result<()> {
+        self.check_writable()?;
+        if self.changes.is_some() {
+            return Ok(());
+        }
+        self.system_insert(&system_key(FEED_TAG, &[]), &1u64.to_be_bytes());
+        self.changes = self.load_change_log();
+        self.flush_pages()
+    }
+
+    pub fn change_feed_enabled(&self) -> bool {
+        self.changes.is_some()
+    }
+
+    /** The sequence number the next change will get */
+    pub fn next_change_seq(&self) -> Option<u64> {
+        self.changes.as_ref().map(|log| log.next_seq)
+    }
+
+    /** Subscribes to the changes committed from now on. Changes arrive in order, with nothing
+     * skipped. A change recorded but not committed when subscribing is delivered too, so a
+     * consumer replaying with `changes_since` should skip the sequence numbers it has seen */
+    pub fn subscribe(&mut self) -> Result<Receiver<Change>> {
+        let Some(log) = &mut self.changes else {
+            return Err(Error::Static("the change feed is not enabled"));
+        };
+        let (sender, receiver) = mpsc::channel();
+        log.subscribers.push(sender);
+        Ok(receiver)
+    }
+
+    /** Appends a write to the log. Called by every write that changes an entry */
+    pub(super) fn record_change(&mut self, key: &[u8], value: Option<&[u8]>) {
+        let Some(log) = &mut self.changes else {
+            return;
+        };
+        let change = Change {
+            seq: log.next_seq,
+            key: key.to_vec(),
+            value: value.map(<[u8]>::to_vec),
+        };
+        log.next_seq += 1;
+        // the prefix was marked when the feed was enabled
+        let (entry, split) = change.encode();
+        if let Some(value) = split {
+            self.tree.insert(&log_value_key(change.seq), value);
+        }
+        self.tree.insert(&log_key(change.seq), &entry);
+        log.pending.push(change);
+    }
+
+    /** Up to `limit` committed changes after `cursor`, in order. Fails if some of them have been
+     * trimmed from the log */
+    pub fn changes_since(&self, cursor: u64, limit: usize) -> Result<Vec<Change>> {
+        let Some(log) = &self.changes else {
+            return Err(Error::Static("the change feed is not enabled"));
+        };
+        if cursor + 1 < log.first_seq {
+            return Err(Error::Generic(format!(
+                "changes before {} have been trimmed",
+                log.first_seq
+            )));
+        }
+        let prefix = system_key(LOG_TAG, &[]);
+        let mut changes = Vec::new();
+        let mut iter = self.tree.seek(&log_key(cursor), CmpOption::GT);
+        while iter.valid() && changes.len() < limit {
+            let (key, val) = iter.deref();
+            if !key.starts_with(&prefix) {
+                break;
+            }
+            let seq = BigEndian::read_u64(&key[prefix.len()..]);
+            let mut change = Change::decode(seq, &val);
+            if val[0] == OP_PUT_SPLIT {
+                change.value = self.tree.get_value(&log_value_key(seq));
+            }
+            changes.push(change);
+            if !iter.next() {
+                break;
+            }
+        }
+        Ok(changes)
+    }
+
+    /** Drops the changes up to and including `seq` from the log. Consumers whose cursor is
+     * behind it can no longer replay */
+    pub fn trim_changes(&mut self, seq: u64) -> Result<()> {
+        self.check_writable()?;
+        let Some(log) = &self.changes else {
+            return Err(Error::Static("the change feed is not enabled"));
+        };
+        // the changes recorded since the last commit stay
+        let last = seq.min(log.next_seq - 1 - log.pending.len() as u64);
+        for seq in log.first_seq..=last {
+            self.tree.delete(&log_key(seq));
+            self.tree.delete(&log_value_key(seq));
+        }
+        let first_seq = log.first_seq.max(last + 1);
+        self.system_insert(&system_key(FEED_TAG, &[]), &first_seq.to_be_bytes());
+        if let Some(log) = &mut self.changes {
+            log.first_seq = first_seq;
+        }
+        self.flush_pages()
+    }
+
+    /** Saves the position of a consumer in the cursor table */
+    pub fn save_cursor(&mut self, name: &str, seq: u64) -> Result<()> {
+        self.check_writable()?;
+        self.system_insert(&system_key(CURSOR_TAG, name.as_bytes()), &seq.to_be_bytes());
+        self.flush_pages()
+    }
+
+    /** The saved position of a consumer, 0 if it has none so replaying starts at the beginning */
+    pub fn cursor(&self, name: &str) -> u64 {
+        self.tree
+            .get_value(&system_key(CURSOR_TAG, name.as_bytes()))
+            .map_or(0, |seq| BigEndian::read_u64(&seq))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, time::Duration};
+
+    use super::*;
+    use crate::{b_tree::b_node::BTREE_MAX_KEY_SIZE, kv_store::InsertMode};
+
+    fn put(seq: u64, key: &[u8], value: &[u8]) -> Change {
+        Change {
+            seq,
+            key: key.to_vec(),
+            value: Some(value.to_vec()),
+        }
+    }
+
+    fn delete(seq: u64, key: &[u8]) -> Change {
+        Change {
+            seq,
+            key: key.to_vec(),
+            value: None,
+        }
+    }
+
+    #[test]
+    fn test_subscribers_get_committed_changes() {
+        let mut kv = KV::open_in_memory().unwrap();
+        assert!(kv.subscribe().is_err());
+        kv.set(b"before", b"0").unwrap();
+        kv.enable_change_feed().unwrap();
+        let changes = kv.subscribe().unwrap();
+
+        kv.set(b"a", b"1").unwrap();
+        assert!(!kv.del(b"missing").unwrap());
+        kv.del(b"before").unwrap();
+        assert!(!kv.update(b"a", b"2", InsertMode::InsertOnly).unwrap());
+        kv.update(b"a", b"2", InsertMode::UpdateOnly).unwrap();
+        kv.bulk_load(vec![(b"b".to_vec(), b"3".to_vec())], 1.0)
+            .unwrap();
+        kv.set_with_ttl(b"c", b"4", Duration::ZERO).unwrap();
+        kv.sweep_expired().unwrap();
+
+        let expected = vec![
+            put(1, b"a", b"1"),
+            delete(2, b"before"),
+            put(3, b"a", b"2"),
+            put(4, b"b", b"3"),
+            put(5, b"c", b"4"),
+            delete(6, b"c"),
+        ];
+        assert_eq!(changes.try_iter().collect::<Vec<_>>(), expected);
+        assert_eq!(kv.changes_since(0, 100).unwrap(), expected);
+        assert_eq!(kv.changes_since(4, 1).unwrap(), expected[4..5]);
+        assert_eq!(kv.next_change_seq(), Some(7));
+        // the log is hidden like every other system key
+        assert_eq!(kv.seek(&[], CmpOption::GT).deref().0, b"a");
+
+        // nothing is delivered before the commit
+        kv.begin_batch();
+        kv.set(b"d", b"5").unwrap();
+        assert!(changes.try_recv().is_err());
+        kv.commit_batch().unwrap();
+        assert_eq!(changes.try_recv().unwrap(), put(7, b"d", b"5"));
+    }
+
+    #[test]
+    fn test_replay_from_saved_cursor() {
+        let file_name = "test_run_dir/test_replay_from_saved_cursor.db";
+        fs::create_dir_all("test_run_dir").unwrap();
+        fs::remove_file(file_name).unwrap_or(());
+        let mut kv = KV::open(file_name.to_string()).unwrap();
+        kv.enable_change_feed().unwrap();
+        for i in 0..10u8 {
+            kv.set(&[b'k', i], &[i]).unwrap();
+        }
+        assert_eq!(kv.cursor("consumer"), 0);
+        kv.save_cursor("consumer", 4).unwrap();
+        kv.close();
+
+        // the feed, its numbering and the cursor survive reopening
+        let mut kv = KV::open(file_name.to_string()).unwrap();
+        assert!(kv.change_feed_enabled());
+        let cursor = kv.cursor("consumer");
+        let missed = kv.changes_since(cursor, 100).unwrap();
+        assert_eq!(missed.len(), 6);
+        assert_eq!(missed[0], put(5, &[b'k', 4], &[4]));
+        kv.del(&[b'k', 0]).unwrap();
+        assert_eq!(
+            kv.changes_since(10, 100).unwrap(),
+            vec![delete(11, &[b'k', 0])]
+        );
+
+        kv.trim_changes(cursor).unwrap();
+        assert!(kv.changes_since(0, 100).is_err());
+        assert_eq!(kv.changes_since(cursor, 100).unwrap().len(), 7);
+        kv.trim_changes(100).unwrap();
+        kv.close();
+
+        // numbering carries on after the whole log was trimmed
+        let mut kv = KV::open(file_name.to_string()).unwrap();
+        assert!(kv.changes_since(11, 100).unwrap().is_empty());
+        kv.set(b"x", b"y").unwrap();
+        assert_eq!(
+            kv.changes_since(11, 100).unwrap(),
+            vec![put(12, b"x", b"y")]
+        );
+        kv.close();
+        fs::remove_file(file_name).unwrap();
+    }
+
+    #[test]
+    fn test_large_values_are_logged() {
+        let mut kv = KV::open_in_memory().unwrap();
+        kv.enable_change_feed().unwrap();
+        let key = [b'k'; BTREE_MAX_KEY_SIZE];
+        let value = [b'v'; BTREE_MAX_VAL_SIZE];
+        kv.set(&key, &value).unwrap();
+        kv.set(b"small", b"1").unwrap();
+        assert_eq!(
+            kv.changes_since(0, 100).unwrap(),
+            vec![put(1, &key, &value), put(2, b"small", b"1")]
+        );
+
+        // trimming drops the value along with its entry
+        assert!(kv.tree.get_value(&log_value_key(1)).is_some());
+        kv.trim_changes(1).unwrap();
+        assert!(kv.tree.get_value(&log_value_key(1)).is_none());
+        assert_eq!(
+            kv.changes_since(1, 100).unwrap(),
+            vec![put(2, b"small", b"1")]
+        );
+    }
+}
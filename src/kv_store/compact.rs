@@ -0,0 +1,284 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::b_tree::BTree;
+use crate::free_list::{fl_node::MAX_FREE_LIST_IN_PAGE, mmap::MMap, FreeList};
+use crate::prelude::*;
+
+use super::{snapshot::snapshot_key, KV};
+
+/// Upper bound on the number of relocation rounds in one call to `compact`
+const MAX_COMPACT_ROUNDS: usize = 16;
+
+impl KV {
+    /** Shrinks the database file in place. Tree pages near the end of the file are moved into
+     * free pages nearer the start, then the file is truncated after the last page in use */
+    pub fn compact(&mut self) -> Result<()> {
+        self.check_writable()?;
+        if self.batching {
+            // each round has to be committed before the next one reuses its free pages
+            return Err(Error::Static("cannot compact while writes are batched"));
+        }
+        if self.tree.page_manager.has_updates() {
+            // commits the release of backups dropped since the last write
+            self.flush_pages()?;
+        }
+        if self.tree.page_manager.has_pins() {
+            // pinned pages look free from the current tree but are still being read
+            return Err(Error::Static(
+                "cannot compact while a backup or snapshot holds pages",
+            ));
+        }
+        if let Some(readers) = &self.readers {
+            // truncating the file would take pages from under their mappings, this keeps new
+            // ones out until it is done
+            if KV::lock_file(readers, false, None).is_err() {
+                return Err(Error::Static(
+                    "cannot compact while read-only handles have the file open",
+                ));
+            }
+        }
+        let result = self.compact_rounds();
+        if let Some(readers) = &self.readers {
+            fs2::FileExt::unlock(readers)?;
+        }
+        result
+    }
+
+    fn compact_rounds(&mut self) -> Result<()> {
+        for _ in 0..MAX_COMPACT_ROUNDS {
+            if !self.compact_round()? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /** Writes a compacted copy of the database to a new file at `path`. The tree is laid out
+     * from the start of the file and the free list is empty. Snapshots are left out */
+    pub fn compact_to(&self, path: String) -> Result<()> {
+        let file_pointer = KV::open_empty_file(path)?;
+        let mut free_list = FreeList::new(Box::new(MMap::new(file_pointer)?));
+        free_list.master_load()?;
+        let mut copy = BTree::new(free_list);
+        copy.root = self.tree.copy_to(&mut copy.page_manager);
+        // their pages are not copied
+        for name in self.snapshot_names() {
+            copy.delete(&snapshot_key(&name));
+        }
+        copy.page_manager.flush_pages(copy.root)?;
+        copy.page_manager.close();
+        Ok(())
+    }
+
+    /** Moves as many of the highest tree pages as the free pages allow into lower slots and
+     * rebuilds the free list. Returns false once no more progress can be made */
+    fn compact_round(&mut self) -> Result<bool> {
+        let mut tree_pages = Vec::new();
+        let mut parents = HashMap::new();
+        let mut path: Vec<u64> = Vec::new();
+        self.tree.walk(&mut |ptr, _, depth| {
+            path.truncate(depth);
+            if let Some(&parent) = path.last() {
+                parents.insert(ptr, parent);
+            }
+            path.push(ptr);
+            tree_pages.push(ptr);
+        });
+
+        let free_list = &self.tree.page_manager;
+        let total_pages = free_list.total_pages();
+        if total_pages - 1 == tree_pages.len() as u64 {
+            // no free pages at all
+            return Ok(false);
+        }
+
+        let in_tree: HashSet<u64> = tree_pages.iter().copied().collect();
+        let list_nodes: HashSet<u64> = free_list.list_nodes().into_iter().collect();
+        let slots: Vec<u64> = (1..total_pages)
+            .filter(|ptr| !in_tree.contains(ptr) && !list_nodes.contains(ptr))
+            .collect();
+
+        // keep enough slots back to hold the rebuilt free list
+        let reserve = (total_pages as usize - 1 - tree_pages.len()).div_ceil(MAX_FREE_LIST_IN_PAGE);
+        if slots.len() < reserve {
+            return Ok(false);
+        }
+        let budget = slots.len() - reserve;
+
+        // moving a page rewrites its ancestors too, so pick the highest pages while the
+        // rewritten set still fits in the budget and each page lands lower than it is now
+        let packed_end = tree_pages.len() as u64 + 1;
+        let mut candidates: Vec<u64> = tree_pages
+            .into_iter()
+            .filter(|&ptr| ptr >= packed_end)
+            .collect();
+        candidates.sort_unstable_by(|a, b| b.cmp(a));
+
+        let mut targets = HashSet::new();
+        let mut rewritten = HashSet::new();
+        for ptr in candidates {
+            let mut cost = Vec::new();
+            let mut current = Some(ptr);
+            while let Some(page) = current {
+                if rewritten.contains(&page) {
+                    break;
+                }
+                cost.push(page);
+                current = parents.get(&page).copied();
+            }
+
+            let count = rewritten.len() + cost.len();
+            if count > budget || slots[count - 1] > ptr {
+                break;
+            }
+            rewritten.extend(cost);
+            targets.insert(ptr);
+        }
+
+        self.tree.page_manager.compact_begin(slots);
+        self.tree.relocate(&targets);
+
+        let mut new_pages = HashSet::new();
+        self.tree.walk(&mut |ptr, _, _| {
+            new_pages.insert(ptr);
+        });
+        self.tree
+            .page_manager
+            .compact_commit(self.tree.root, &new_pages)?;
+
+        Ok(!targets.is_empty() || self.tree.page_manager.total_pages() < total_pages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{b_tree::b_node::BTREE_PAGE_SIZE, test_util::test_file};
+
+    fn key(i: u32) -> Vec<u8> {
+        format!("key{:06}", i).into_bytes()
+    }
+
+    fn val(i: u32) -> Vec<u8> {
+        vec![i as u8; 200]
+    }
+
+    // Fills the database then deletes most of it so the live pages are spread over the file
+    fn fill_and_thin(kv: &mut KV) {
+        for i in 0..3000 {
+            kv.set(&key(i), &val(i)).unwrap();
+        }
+        for i in 0..3000 {
+            if i % 10 != 0 {
+                kv.del(&key(i)).unwrap();
+            }
+        }
+    }
+
+    fn check_contents(kv: &KV) {
+        for i in 0..3000 {
+            let expected = if i % 10 == 0 { Some(val(i)) } else { None };
+            assert_eq!(kv.get(&key(i)), expected);
+        }
+    }
+
+    // Every page is either the master page, a tree page, a free list node or listed as free
+    fn check_no_leaks(kv: &KV) {
+        let free_list = &kv.tree.page_manager;
+        let mut pages = 1;
+        kv.tree.walk(&mut |_, _, _| pages += 1);
+        pages += free_list.list_nodes().len() as u64;
+        pages += free_list.get_free_list_total();
+        assert_eq!(pages, free_list.total_pages());
+    }
+
+    #[test]
+    fn test_compact_shrinks_file() {
+        let file_name = test_file("test_compact_shrinks_file.db");
+        let mut kv = KV::open(file_name.clone()).unwrap();
+        fill_and_thin(&mut kv);
+        let pages_before = kv.tree.page_manager.total_pages();
+
+        kv.compact().unwrap();
+        let pages_after = kv.tree.page_manager.total_pages();
+        assert!(pages_after < pages_before / 2);
+        assert_eq!(
+            fs::metadata(&file_name).unwrap().len(),
+            pages_after * BTREE_PAGE_SIZE as u64
+        );
+        check_contents(&kv);
+        check_no_leaks(&kv);
+
+        // the file keeps working after being truncated
+        kv.set(&key(1), &val(1)).unwrap();
+        kv.close();
+
+        let mut kv = KV::open(file_name).unwrap();
+        assert_eq!(kv.get(&key(1)), Some(val(1)));
+        kv.del(&key(1)).unwrap();
+        check_contents(&kv);
+        check_no_leaks(&kv);
+        kv.close();
+    }
+
+    #[test]
+    fn test_compact_buffered() {
+        let file_name = test_file("test_compact_buffered.db");
+        let mut kv = KV::open_buffered(file_name.clone(), 16).unwrap();
+        fill_and_thin(&mut kv);
+        kv.compact().unwrap();
+        check_contents(&kv);
+        check_no_leaks(&kv);
+        kv.close();
+
+        let kv = KV::open_buffered(file_name, 16).unwrap();
+        check_contents(&kv);
+        kv.close();
+    }
+
+    #[test]
+    fn test_compact_in_memory() {
+        let mut kv = KV::open_in_memory().unwrap();
+        fill_and_thin(&mut kv);
+        let pages_before = kv.tree.page_manager.total_pages();
+
+        kv.compact().unwrap();
+        assert!(kv.tree.page_manager.total_pages() < pages_before);
+        check_contents(&kv);
+        check_no_leaks(&kv);
+    }
+
+    #[test]
+    fn test_compact_empty_and_already_compact() {
+        let mut kv = KV::open_in_memory().unwrap();
+        kv.compact().unwrap();
+        assert_eq!(kv.tree.page_manager.total_pages(), 1);
+
+        kv.set(&key(0), &val(0)).unwrap();
+        kv.compact().unwrap();
+        let pages = kv.tree.page_manager.total_pages();
+        kv.compact().unwrap();
+        assert_eq!(kv.tree.page_manager.total_pages(), pages);
+        assert_eq!(kv.get(&key(0)), Some(val(0)));
+    }
+
+    #[test]
+    fn test_compact_to() {
+        let mut kv = KV::open_in_memory().unwrap();
+        fill_and_thin(&mut kv);
+
+        let file_name = test_file("test_compact_to.db");
+        kv.compact_to(file_name.clone()).unwrap();
+        assert!(kv.compact_to(file_name.clone()).is_err());
+
+        let copy = KV::open(file_name).unwrap();
+        check_contents(&copy);
+        check_no_leaks(&copy);
+        let mut tree_pages = 0;
+        copy.tree.walk(&mut |_, _, _| tree_pages += 1);
+        assert_eq!(copy.tree.page_manager.total_pages(), tree_pages + 1);
+        copy.close();
+    }
+}
@@ -0,0 +1,135 @@
+//! Crash tests. A workload runs on a `FaultyDisk` that cuts the process off at every possible
+//! call or at points along the bytes written, then the database is reopened from what the disk
+//! kept. Every acknowledged commit must be there and the interrupted one either fully applied or
+//! not at all.
+
+use std::collections::BTreeMap;
+
+use crate::free_list::faulty::{Fault, FaultyDisk};
+
+use super::{CmpOption, KV};
+
+enum Op {
+    Set(Vec<u8>, Vec<u8>),
+    Del(Vec<u8>),
+}
+
+fn key(i: usize) -> Vec<u8> {
+    format!("key{:03}", i).into_bytes()
+}
+
+/// Enough commits to split leaves, grow the tree, free pages and reuse them
+fn workload() -> Vec<Op> {
+    let mut ops = Vec::new();
+    for i in 0..40 {
+        ops.push(Op::Set(key(i), vec![i as u8; 300]));
+    }
+    for i in (0..40).step_by(3) {
+        ops.push(Op::Set(key(i), vec![b'u'; 500]));
+    }
+    for i in (0..40).step_by(2) {
+        ops.push(Op::Del(key(i)));
+    }
+    ops
+}
+
+fn expected(ops: &[Op]) -> BTreeMap<Vec<u8>, Vec<u8>> {
+    let mut map = BTreeMap::new();
+    for op in ops {
+        match op {
+            Op::Set(key, val) => map.insert(key.clone(), val.clone()),
+            Op::Del(key) => map.remove(key),
+        };
+    }
+    map
+}
+
+/// Runs the workload until a call fails, returns the number of acknowledged operations
+fn run(disk: &FaultyDisk, ops: &[Op]) -> usize {
+    let mut kv = match KV::open_storage(disk.storage()) {
+        Ok(kv) => kv,
+        Err(_) => return 0,
+    };
+    for (i, op) in ops.iter().enumerate() {
+        let result = match op {
+            Op::Set(key, val) => kv.set(key, val),
+            Op::Del(key) => kv.del(key).map(|_| ()),
+        };
+        if result.is_err() {
+            return i;
+        }
+    }
+    ops.len()
+}
+
+fn contents(kv: &KV) -> BTreeMap<Vec<u8>, Vec<u8>> {
+    let mut map = BTreeMap::new();
+    let mut iter = kv.seek(&[], CmpOption::GT);
+    while iter.valid() {
+        let (key, val) = iter.deref();
+        map.insert(key, val);
+        if !iter.next() {
+            break;
+        }
+    }
+    map
+}
+
+/// Reopens the crashed disk, with and without the writes that were never synced
+fn check_recovery(disk: &FaultyDisk, ops: &[Op], acked: usize, fault: Fault) {
+    let before = expected(&ops[..acked]);
+    let after = expected(&ops[..(acked + 1).min(ops.len())]);
+    for keep_unsynced in [false, true] {
+        let mut kv = KV::open_storage(disk.restart(keep_unsynced).storage())
+            .unwrap_or_else(|err| panic!("{:?} keep_unsynced={}: {}", fault, keep_unsynced, err));
+        let found = contents(&kv);
+        assert!(
+            found == before || found == after,
+            "{:?} keep_unsynced={}: {} operations acknowledged, {} keys found",
+            fault,
+            keep_unsynced,
+            acked,
+            found.len()
+        );
+
+        // the recovered database is whole and keeps working
+        let stats = kv.stats();
+        assert_eq!(stats.live_pages() + stats.free_pages, stats.total_pages);
+        kv.set(b"after", b"crash").unwrap();
+        kv.del(&key(1)).unwrap();
+        assert_eq!(kv.get(b"after").unwrap(), b"crash");
+    }
+}
+
+#[test]
+fn test_crash_at_every_call() {
+    let ops = workload();
+    let disk = FaultyDisk::new();
+    assert_eq!(run(&disk, &ops), ops.len());
+    let calls = disk.calls();
+
+    for cut in 0..=calls {
+        let disk = FaultyDisk::new();
+        disk.inject(Fault::Call(cut));
+        let acked = run(&disk, &ops);
+        assert_eq!(disk.crashed(), cut < calls);
+        check_recovery(&disk, &ops, acked, Fault::Call(cut));
+    }
+}
+
+#[test]
+fn test_crash_part_way_through_writes() {
+    let ops = workload();
+    let disk = FaultyDisk::new();
+    run(&disk, &ops);
+    let written = disk.written();
+
+    // a step that is not a multiple of the sector size, so writes are cut at every offset
+    for cut in (0..written).step_by(4093) {
+        let disk = FaultyDisk::new();
+        disk.inject(Fault::Byte(cut));
+        let acked = run(&disk, &ops);
+        assert!(disk.crashed());
+        check_recovery(&disk, &ops, acked, Fault::Byte(cut));
+    }
+}
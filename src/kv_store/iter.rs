@@ -0,0 +1,93 @@
+use crate::b_tree::btree_iter::BTreeIterator;
+use crate::free_list::FreeList;
+
+use super::{CmpOption, Version, KV, SYSTEM_PREFIX};
+
+/// Iterates over the entries callers can see, stepping over system keys and expired entries.
+/// Moves the same way as the tree iterator underneath
+pub struct KVIterator<'a> {
+    kv: &'a KV,
+    /// The version iterated over, the last commit or a snapshot
+    version: Version,
+    iter: BTreeIterator<'a, FreeList>,
+    /// Entries that expire by this time are hidden, fixed when the iterator is made
+    now: u64,
+}
+
+impl<'a> KVIterator<'a> {
+    pub(super) fn seek(
+        kv: &'a KV,
+        version: Version,
+        key: &[u8],
+        compare: CmpOption,
+    ) -> KVIterator<'a> {
+        let mut iter = KVIterator {
+            kv,
+            version,
+            iter: kv.tree.seek_at(version.root, key, compare),
+            now: version.now(),
+        };
+        if iter.iter.valid() && iter.hidden() {
+            let moved = match compare {
+                CmpOption::GE | CmpOption::GT => iter.next(),
+                CmpOption::LE | CmpOption::LT => iter.prev(),
+            };
+            if !moved {
+                iter.invalidate();
+            }
+        }
+        iter
+    }
+
+    fn hidden(&self) -> bool {
+        let key = self.iter.key();
+        key.starts_with(SYSTEM_PREFIX) || self.kv.is_expired(self.version, &key, self.now)
+    }
+
+    /** Whether the iterator points at an entry */
+    pub fn valid(&self) -> bool {
+        self.iter.valid()
+    }
+
+    /** Moves the iterator past the end of the entries */
+    pub fn invalidate(&mut self) {
+        self.iter.invalidate();
+    }
+
+    /** Gets the current key value pair */
+    pub fn deref(&self) -> (Vec<u8>, Vec<u8>) {
+        self.iter.deref()
+    }
+
+    /** Moves to the next entry. Stays put and returns false when there is none */
+    #[allow(clippy::should_implement_trait)] // a cursor move, not `Iterator::next`
+    pub fn next(&mut self) -> bool {
+        let mut steps = 0;
+        while self.iter.next() {
+            steps += 1;
+            if !self.hidden() {
+                return true;
+            }
+        }
+        // only hidden keys are left, go back to where we were
+        for _ in 0..steps {
+            self.iter.prev();
+        }
+        false
+    }
+
+    /** Moves to the previous entry, or before the first one where the iterator is not valid */
+    pub fn prev(&mut self) -> bool {
+        let mut steps = 0;
+        while self.iter.prev() {
+            steps += 1;
+            if !self.iter.valid() || !self.hidden() {
+                return true;
+            }
+        }
+        for _ in 0..steps {
+            self.iter.next();
+        }
+        false
+    }
+}
@@ -1,43 +1,334 @@
-use std::fs::OpenOptions;
+mod backup;
+mod changes;
+mod compact;
+#[cfg(test)]
+mod crash;
+mod iter;
+mod replica;
+mod snapshot;
+mod stats;
+mod ttl;
+
+#[cfg(test)]
+mod model;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{File, OpenOptions},
+    thread,
+    time::{Duration, Instant},
+};
 
 extern crate byteorder;
 
 use crate::prelude::*;
 use crate::{
-    b_tree::{BTree, InsertMode, InsertRequest},
-    free_list::FreeList,
+    b_tree::{
+        b_node::{BTREE_MAX_KEY_SIZE, BTREE_MAX_VAL_SIZE},
+        BTree, InsertRequest,
+    },
+    free_list::{
+        self, memory::MemoryStorage, mmap::MMap, paged_file::PagedFile, storage::Storage, FreeList,
+    },
 };
 
+pub use crate::b_tree::{
+    b_node::{NodeType, BTREE_PAGE_SIZE},
+    btree_iter::BTreeIterator,
+    bulk::BULK_LOAD_FILL,
+    inspect::{render_dot, render_text, NodeInfo},
+    CmpOption, InsertMode,
+};
+pub use crate::free_list::{
+    backup::Backup, master_page::MasterPage, paged_file::DEFAULT_POOL_PAGES, replica::PageDelta,
+};
+pub use changes::Change;
+pub use iter::KVIterator;
+pub use replica::Follower;
+pub use snapshot::{SnapshotInfo, SnapshotView};
+pub use stats::KVStats;
+pub use ttl::MAX_TTL_KEY_SIZE;
+
+/// Keys starting with this belong to the store itself. Callers cannot write them and iteration
+/// skips them. No table prefix of a `DB` encodes to it
+pub const SYSTEM_PREFIX: &[u8] = &[0, 0, 0, 0];
+
+/// Value of the entry under `SYSTEM_PREFIX` itself, written along with the first system key. Tells
+/// the system keys apart from keys a database made before them could have under the prefix
+const SYSTEM_MARKER: &[u8] = b"system keys";
+
+/// How often a handle waiting for the file lock tries again
+const LOCK_RETRY: Duration = Duration::from_millis(10);
+
+/// How `KV::open_with` opens a database file
+#[derive(Debug, Clone, Default)]
+pub struct KVOptions {
+    /// Reject writes. Any number of read-only handles can have the file open, alongside one
+    /// writer
+    pub read_only: bool,
+    /// How long to wait for other handles to release the file. Opening fails straight away when
+    /// it is `None`
+    pub lock_timeout: Option<Duration>,
+    /// Read pages with `pread` through a buffer pool of this many pages instead of memory
+    /// mapping the file
+    pub pool_pages: Option<usize>,
+}
+
 pub struct KV {
     tree: BTree<FreeList>,
+    read_only: bool,
+    /// Writes are left uncommitted until `commit_batch`
+    batching: bool,
+    /// Whether the entry marking the system keys as the store's is written
+    system_marked: bool,
+    /// Whether any entry may have a deadline, so reads without one skip looking it up
+    deadlines: bool,
+    /// The change feed, `None` until it is enabled
+    changes: Option<changes::ChangeLog>,
+    /// Pins keeping the pages of each named snapshot, by name. Empty for read-only handles
+    snapshots: HashMap<String, u64>,
+    /// The file next to the database that read-only handles hold a shared lock on, `None` when
+    /// the storage is not a file
+    readers: Option<File>,
+}
+
+/// The state of the store to go back to, see `KV::savepoint`
+pub struct Savepoint {
+    root: u64,
+    pages: free_list::savepoint::Savepoint,
+    system_marked: bool,
+    deadlines: bool,
+    /// Where the change feed was, `None` if it was not enabled
+    changes: Option<changes::LogMark>,
+}
+
+/// A version of the tree reads can go to, the last commit or a snapshot
+#[derive(Debug, Clone, Copy)]
+struct Version {
+    root: u64,
+    /// Whether any entry of this version may have a deadline
+    deadlines: bool,
+    /// Entries are read as they were at this time, `None` to read them as they are now
+    as_of: Option<u64>,
+}
+
+impl Version {
+    /** The time deadlines are checked against */
+    fn now(&self) -> u64 {
+        self.as_of.unwrap_or_else(ttl::now_millis)
+    }
+}
+
+/** A key kept by the store itself, `tag` tells apart what it is for */
+pub(crate) fn system_key(tag: u8, rest: &[u8]) -> Vec<u8> {
+    let mut key = SYSTEM_PREFIX.to_vec();
+    key.push(tag);
+    key.extend_from_slice(rest);
+    key
 }
 
 impl KV {
     /** Opens the database. Callers responsiblity to close even if open results in an error */
     pub fn open(path: String) -> Result<KV> {
+        KV::open_with(path, KVOptions::default())
+    }
+
+    /** Opens the database without memory mapping the file. Pages are read with `pread` and at most
+     * `pool_pages` of them are cached, which bounds the memory used for large databases */
+    pub fn open_buffered(path: String, pool_pages: usize) -> Result<KV> {
+        KV::open_with(
+            path,
+            KVOptions {
+                pool_pages: Some(pool_pages),
+                ..KVOptions::default()
+            },
+        )
+    }
+
+    /** Opens the database for reading only. Other read-only handles and a writer can have it open
+     * at the same time, `refresh` picks up what the writer commits */
+    pub fn open_read_only(path: String) -> Result<KV> {
+        KV::open_with(
+            path,
+            KVOptions {
+                read_only: true,
+                ..KVOptions::default()
+            },
+        )
+    }
+
+    /** Opens the database with the given options. A writer locks the file exclusively and a
+     * read-only handle takes a shared lock on the `.readers` file next to it, both until the
+     * handle is closed */
+    pub fn open_with(path: String, options: KVOptions) -> Result<KV> {
+        let file_pointer = if options.read_only {
+            match File::open(&path) {
+                Ok(file_pointer) => file_pointer,
+                Err(err) => return Err(Error::Generic(format!("failed to open file: {:?}", err))),
+            }
+        } else {
+            KV::open_file(path.clone())?
+        };
+        let readers = KV::open_file(format!("{}.readers", path))?;
+        if options.read_only {
+            KV::lock_file(&readers, true, options.lock_timeout)?;
+        } else {
+            KV::lock_file(&file_pointer, false, options.lock_timeout)?;
+        }
+
+        let storage: Box<dyn Storage> = match (options.pool_pages, options.read_only) {
+            (Some(pool_pages), _) => Box::new(PagedFile::new(file_pointer, pool_pages)?),
+            (None, false) => Box::new(MMap::new(file_pointer)?),
+            (None, true) => Box::new(MMap::new_read_only(file_pointer)?),
+        };
+        let mut kv = KV::open_storage(storage)?;
+        kv.read_only = options.read_only;
+        kv.readers = Some(readers);
+        Ok(kv)
+    }
+
+    /** Locks a file, waiting up to `timeout` for other handles to let go */
+    fn lock_file(file_pointer: &File, shared: bool, timeout: Option<Duration>) -> Result<()> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let locked = if shared {
+                fs2::FileExt::try_lock_shared(file_pointer)
+            } else {
+                fs2::FileExt::try_lock_exclusive(file_pointer)
+            };
+            match locked {
+                Ok(()) => return Ok(()),
+                Err(err) if err.kind() != fs2::lock_contended_error().kind() => {
+                    return Err(Error::IO(err))
+                }
+                Err(_) => {}
+            }
+            match deadline {
+                Some(deadline) if Instant::now() < deadline => thread::sleep(LOCK_RETRY),
+                _ => return Err(Error::Static("database is locked")),
+            }
+        }
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /** Fails for keys callers cannot write */
+    pub(crate) fn check_key(key: &[u8]) -> Result<()> {
+        if key.is_empty() {
+            return Err(Error::Static("keys must not be empty"));
+        }
+        if key.len() > BTREE_MAX_KEY_SIZE {
+            return Err(Error::Generic(format!(
+                "keys can be at most {} bytes",
+                BTREE_MAX_KEY_SIZE
+            )));
+        }
+        if key.starts_with(SYSTEM_PREFIX) {
+            return Err(Error::Static(
+                "keys starting with four zero bytes are reserved",
+            ));
+        }
+        Ok(())
+    }
+
+    /** Fails for values too large to store */
+    pub(crate) fn check_value(value: &[u8]) -> Result<()> {
+        if value.len() > BTREE_MAX_VAL_SIZE {
+            return Err(Error::Generic(format!(
+                "values can be at most {} bytes",
+                BTREE_MAX_VAL_SIZE
+            )));
+        }
+        Ok(())
+    }
+
+    /** Fails if setting `key` to `value`, or deleting it for `None`, would be rejected */
+    fn check_write(&self, key: &[u8], value: Option<&[u8]>) -> Result<()> {
+        self.check_writable()?;
+        KV::check_key(key)?;
+        value.map_or(Ok(()), KV::check_value)
+    }
+
+    /** Sets each key to its value, or deletes it for `None`, in one commit along with the batch
+     * it runs in if any. Every write is checked first, so none is applied if one is rejected */
+    pub(crate) fn apply_writes(
+        &mut self,
+        writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    ) -> Result<()> {
+        for (key, write) in &writes {
+            self.check_write(key, write.as_deref())?;
+        }
+        let nested = self.batching;
+        self.begin_batch();
+        for (key, write) in writes {
+            match write {
+                Some(val) => self.set(&key, &val)?,
+                None => {
+                    self.del(&key)?;
+                }
+            }
+        }
+        if nested {
+            return Ok(());
+        }
+        self.commit_batch()
+    }
+
+    /** Fails for read-only handles, called before anything in the tree changes */
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            return Err(Error::Static("database is read-only"));
+        }
+        Ok(())
+    }
+
+    /** Opens an empty database held in memory. Pages are still allocated through the free list so
+     * deleted pages are reused, but everything is lost once the handle is closed */
+    pub fn open_in_memory() -> Result<KV> {
+        KV::open_storage(Box::new(MemoryStorage::new()))
+    }
+
+    fn open_file(path: String) -> Result<File> {
         // Open or create the file
         let file_open = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open(path);
 
-        if file_open.is_err() {
-            return Err(Error::Generic(format!(
-                "failed to open file: {:?}",
-                file_open.unwrap_err()
-            )));
+        match file_open {
+            Ok(file_pointer) => Ok(file_pointer),
+            Err(err) => Err(Error::Generic(format!("failed to open file: {:?}", err))),
         }
+    }
 
-        let file_pointer = file_open.unwrap();
-
-        let free = FreeList::new(file_pointer)?;
+    /** Opens a file that is about to be filled with a copy of the database, it must be empty */
+    fn open_empty_file(path: String) -> Result<File> {
+        let file_pointer = KV::open_file(path)?;
+        if file_pointer.metadata()?.len() != 0 {
+            return Err(Error::Static("target file is not empty"));
+        }
+        Ok(file_pointer)
+    }
 
+    pub(crate) fn open_storage(storage: Box<dyn Storage>) -> Result<KV> {
         let mut kv = KV {
-            tree: BTree::new(free),
+            tree: BTree::new(FreeList::new(storage)),
+            read_only: false,
+            batching: false,
+            system_marked: false,
+            deadlines: false,
+            changes: None,
+            snapshots: HashMap::new(),
+            readers: None,
         };
 
         kv.master_load()?;
+        kv.check_system_keys()?;
+        kv.reload_system_state();
 
         // done
         Ok(kv)
@@ -47,20 +338,153 @@ impl KV {
         self.tree.page_manager.close();
     }
 
+    /** The value of `key`, `None` once it has expired */
     pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        self.tree.get_value(key)
+        self.get_in(self.current(), key)
+    }
+
+    fn get_in(&self, version: Version, key: &[u8]) -> Option<Vec<u8>> {
+        // no key that fails the check can have been written
+        if KV::check_key(key).is_err() || self.is_expired(version, key, version.now()) {
+            return None;
+        }
+        self.tree.get_value_at(version.root, key)
+    }
+
+    /** Returns an iterator positioned at the closest key to `key` that satisfies `compare` */
+    pub fn seek(&self, key: &[u8], compare: CmpOption) -> KVIterator<'_> {
+        KVIterator::seek(self, self.current(), key, compare)
+    }
+
+    /** The version of the last commit, along with the writes not committed yet */
+    fn current(&self) -> Version {
+        Version {
+            root: self.tree.root,
+            deadlines: self.deadlines,
+            as_of: None,
+        }
     }
 
     pub fn set(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.check_writable()?;
+        KV::check_key(key)?;
+        KV::check_value(value)?;
         self.tree.insert(key, value);
+        self.clear_deadline(key);
+        self.record_change(key, Some(value));
         self.flush_pages()
     }
 
     pub fn del(&mut self, key: &[u8]) -> Result<bool> {
+        self.check_writable()?;
+        KV::check_key(key)?;
+        let expired = self.purge_expired(key);
         let deleted = self.tree.delete(key);
+        self.clear_deadline(key);
+        if deleted {
+            self.record_change(key, None);
+        }
+        self.flush_pages()?;
+
+        Ok(deleted && !expired)
+    }
+
+    /** Adds keys given in ascending order in a single commit, building the tree bottom up with
+     * each page filled to `fill`. The keys must not exist yet. Returns the number of keys added */
+    pub fn bulk_load<I>(&mut self, items: I, fill: f64) -> Result<usize>
+    where
+        I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    {
+        self.bulk_load_records(items.into_iter().map(Ok), fill)
+    }
+
+    /** Like `bulk_load`, for input that can fail part way. Nothing is added if it does */
+    pub fn bulk_load_records<I>(&mut self, items: I, fill: f64) -> Result<usize>
+    where
+        I: IntoIterator<Item = Result<(Vec<u8>, Vec<u8>)>>,
+    {
+        self.check_writable()?;
+        // the keys loaded are logged once they are all in
+        let logging = self.changes.is_some();
+        let mut loaded = Vec::new();
+        let items = items.into_iter().map(|item| {
+            let (key, val) = item?;
+            KV::check_key(&key)?;
+            if logging {
+                loaded.push((key.clone(), val.clone()));
+            }
+            Ok((key, val))
+        });
+        let result = self.tree.bulk_load(items, fill);
+        if result.is_ok() {
+            for (key, val) in loaded {
+                self.record_change(&key, Some(&val));
+            }
+        }
         self.flush_pages()?;
+        result
+    }
 
-        Ok(deleted)
+    /** The master page as of the last commit */
+    pub fn master_page(&self) -> MasterPage {
+        self.tree.page_manager.master_page(self.tree.root)
+    }
+
+    /** Describes every node of the B-tree, parents before their children */
+    pub fn inspect(&self) -> Vec<NodeInfo> {
+        self.tree.inspect()
+    }
+
+    /** Every free list node, starting at the head, along with the free pages it holds */
+    pub fn free_list(&self) -> Vec<(u64, Vec<u64>)> {
+        self.tree.page_manager.list_contents()
+    }
+
+    /** Fails for a database with keys under `SYSTEM_PREFIX` that the store did not write. Callers
+     * could no longer read or delete them */
+    fn check_system_keys(&self) -> Result<()> {
+        let iter = self.tree.seek(SYSTEM_PREFIX, CmpOption::GE);
+        if !iter.valid() || !iter.key().starts_with(SYSTEM_PREFIX) {
+            return Ok(());
+        }
+        match iter.deref() {
+            (key, val) if key == SYSTEM_PREFIX && val == SYSTEM_MARKER => Ok(()),
+            _ => Err(Error::Static(
+                "the database has keys starting with four zero bytes, which are now reserved",
+            )),
+        }
+    }
+
+    /** Writes a system key, marking the prefix as the store's the first time */
+    pub(super) fn system_insert(&mut self, key: &[u8], val: &[u8]) {
+        if !self.system_marked {
+            self.tree.insert(SYSTEM_PREFIX, SYSTEM_MARKER);
+            self.system_marked = true;
+        }
+        self.tree.insert(key, val);
+    }
+
+    /** Looks up what the system keys say about the entries, after the tree was loaded */
+    fn reload_system_state(&mut self) {
+        self.system_marked = self.tree.get_value(SYSTEM_PREFIX).is_some();
+        self.deadlines = self.has_deadlines(self.tree.root);
+        self.changes = self.load_change_log();
+        if !self.read_only {
+            self.pin_snapshots();
+        }
+    }
+
+    /** Picks up what the writer committed since this handle was opened or last refreshed.
+     * Returns whether there was anything. The writer reuses the pages its commits free, so a
+     * read-only handle should refresh before reading */
+    pub fn refresh(&mut self) -> Result<bool> {
+        // a writer is the only one changing the file
+        if !self.read_only || !self.tree.page_manager.reload()? {
+            return Ok(false);
+        }
+        self.master_load()?;
+        self.reload_system_state();
+        Ok(true)
     }
 
     fn master_load(&mut self) -> Result<()> {
@@ -69,14 +493,92 @@ impl KV {
         Ok(())
     }
 
+    /** Defers commits. Writes change the tree straight away but are only made durable, all
+     * together, by `commit_batch` */
+    pub(crate) fn begin_batch(&mut self) {
+        self.batching = true;
+    }
+
+    /** Whether writes are left uncommitted until `commit_batch` */
+    pub(crate) fn is_batching(&self) -> bool {
+        self.batching
+    }
+
+    /** Commits every write since `begin_batch` with a single pair of syncs. A batch that wrote
+     * nothing has nothing to commit */
+    pub(crate) fn commit_batch(&mut self) -> Result<()> {
+        self.batching = false;
+        if !self.tree.page_manager.has_updates() {
+            return Ok(());
+        }
+        self.flush_pages()
+    }
+
+    /** The state `rollback` goes back to. Only valid until the next commit, so it is taken
+     * inside a batch. One taken when the batch began can still be rolled back to after its
+     * commit failed */
+    pub(crate) fn savepoint(&self) -> Savepoint {
+        Savepoint {
+            root: self.tree.root,
+            pages: self.tree.page_manager.savepoint(),
+            system_marked: self.system_marked,
+            deadlines: self.deadlines,
+            changes: self.changes.as_ref().map(changes::ChangeLog::mark),
+        }
+    }
+
+    /** Drops every write made since `savepoint` */
+    pub(crate) fn rollback(&mut self, savepoint: Savepoint) {
+        self.tree.root = savepoint.root;
+        self.tree.page_manager.rollback(savepoint.pages);
+        self.system_marked = savepoint.system_marked;
+        self.deadlines = savepoint.deadlines;
+        match (savepoint.changes, &mut self.changes) {
+            (Some(mark), Some(log)) => log.rewind(mark),
+            // enabled since
+            (_, changes) => *changes = None,
+        }
+    }
+
     fn flush_pages(&mut self) -> Result<()> {
-        self.tree.page_manager.flush_pages(self.tree.root)?;
+        // writes are rejected by read-only handles before they change anything, and the ones in a
+        // batch are committed with it
+        if self.read_only || self.batching {
+            return Ok(());
+        }
+        if let Err(err) = self.tree.page_manager.flush_pages(self.tree.root) {
+            // the tree in memory would keep writes that are not durable
+            self.tree.root = self.tree.page_manager.reset();
+            self.system_marked = self.tree.get_value(SYSTEM_PREFIX).is_some();
+            self.deadlines = self.has_deadlines(self.tree.root);
+            self.reload_change_log();
+            return Err(err);
+        }
+        if let Some(changes) = &mut self.changes {
+            changes.deliver();
+        }
         Ok(())
     }
 
     pub fn update(&mut self, key: &[u8], value: &[u8], mode: InsertMode) -> Result<bool> {
+        self.check_writable()?;
+        KV::check_key(key)?;
+        KV::check_value(value)?;
+        self.purge_expired(key);
+        let existed = (self.deadlines || self.changes.is_some())
+            && mode == InsertMode::UpdateOnly
+            && self.tree.get_value(key).is_some();
         let req = InsertRequest::new(key.to_vec(), value.to_vec()).mode(mode);
         let res = self.tree.insert_exec(req);
+        let written = match mode {
+            InsertMode::Upsert => true,
+            InsertMode::UpdateOnly => existed,
+            InsertMode::InsertOnly => res.added,
+        };
+        if written {
+            self.clear_deadline(key);
+            self.record_change(key, Some(value));
+        }
         self.flush_pages()?;
         Ok(res.added)
     }
@@ -87,18 +589,35 @@ mod tests {
     use std::{collections::HashSet, fs};
 
     use super::*;
+    use crate::b_tree::b_node::BTREE_PAGE_SIZE;
     extern crate rand;
 
     use rand::rngs::StdRng;
     use rand::{Rng, SeedableRng};
 
-    fn new_kv(path: &str, delete_old: bool) -> KV {
+    type OpenKV = fn(&str, bool) -> KV;
+
+    fn test_file(path: &str, delete_old: bool) -> String {
         fs::create_dir_all("test_run_dir").unwrap();
         let file_name = format!("test_run_dir/{}", path);
         if delete_old {
             fs::remove_file(&file_name).unwrap_or(());
         }
-        KV::open(file_name).unwrap()
+        file_name
+    }
+
+    fn new_kv(path: &str, delete_old: bool) -> KV {
+        KV::open(test_file(path, delete_old)).unwrap()
+    }
+
+    fn new_in_memory_kv(_path: &str, _delete_old: bool) -> KV {
+        KV::open_in_memory().unwrap()
+    }
+
+    // A small pool so the tests exercise eviction
+    fn new_buffered_kv(path: &str, delete_old: bool) -> KV {
+        let file_name = test_file(&format!("buffered_{}", path), delete_old);
+        KV::open_buffered(file_name, 16).unwrap()
     }
 
     fn debug_free_list(kv: &KV) {
@@ -111,7 +630,21 @@ mod tests {
 
     #[test]
     fn test_kv_single_set() {
-        let mut kv = new_kv("test_kv_single_set.db", true);
+        kv_single_set(new_kv);
+    }
+
+    #[test]
+    fn test_kv_single_set_buffered() {
+        kv_single_set(new_buffered_kv);
+    }
+
+    #[test]
+    fn test_kv_single_set_in_memory() {
+        kv_single_set(new_in_memory_kv);
+    }
+
+    fn kv_single_set(open: OpenKV) {
+        let mut kv = open("test_kv_single_set.db", true);
 
         let key = "key".as_bytes().to_vec();
         let value = "value".as_bytes().to_vec();
@@ -125,7 +658,21 @@ mod tests {
 
     #[test]
     fn test_kv_small_set_get() {
-        let mut kv = new_kv("test_kv_small_set_get.db", true);
+        kv_small_set_get(new_kv);
+    }
+
+    #[test]
+    fn test_kv_small_set_get_buffered() {
+        kv_small_set_get(new_buffered_kv);
+    }
+
+    #[test]
+    fn test_kv_small_set_get_in_memory() {
+        kv_small_set_get(new_in_memory_kv);
+    }
+
+    fn kv_small_set_get(open: OpenKV) {
+        let mut kv = open("test_kv_small_set_get.db", true);
 
         for i in 0..100 {
             let key = format!("key{}", i).as_bytes().to_vec();
@@ -144,7 +691,16 @@ mod tests {
     // With page resuse the database size is 590 KB (10000 as loop)
     #[test]
     fn test_kv() {
-        let mut kv = new_kv("test_kv.db", true);
+        kv_set_del_reopen(new_kv);
+    }
+
+    #[test]
+    fn test_kv_buffered() {
+        kv_set_del_reopen(new_buffered_kv);
+    }
+
+    fn kv_set_del_reopen(open: OpenKV) {
+        let mut kv = open("test_kv.db", true);
 
         let mut deleted_keys: HashSet<i32> = HashSet::new();
         let mut rng = StdRng::seed_from_u64(675127398);
@@ -171,7 +727,7 @@ mod tests {
 
         kv.close();
 
-        let mut kv = new_kv("test_kv.db", false);
+        let mut kv = open("test_kv.db", false);
         for i in 0..10000 {
             println!("Step 2: {}", i);
             let key = format!("key{}", i).as_bytes().to_vec();
@@ -187,7 +743,7 @@ mod tests {
         }
 
         kv.close();
-        let kv = new_kv("test_kv.db", false);
+        let kv = open("test_kv.db", false);
         for i in 0..10000 {
             println!("Step 3: {}", i);
             let key = format!("key{}", i).as_bytes().to_vec();
@@ -197,6 +753,115 @@ mod tests {
         kv.close();
     }
 
+    #[test]
+    fn test_kv_reopen_before_first_free() {
+        let mut kv = new_kv("test_kv_reopen_before_first_free.db", true);
+        kv.set("key".as_bytes(), "value".as_bytes()).unwrap();
+        kv.close();
+
+        // nothing has been freed yet so the free list is still empty
+        let kv = new_kv("test_kv_reopen_before_first_free.db", false);
+        assert_eq!(kv.get("key".as_bytes()).unwrap(), "value".as_bytes());
+        kv.close();
+    }
+
+    #[test]
+    fn test_kv_backends_share_file_format() {
+        let mut kv = new_kv("test_kv_backends_share_file_format.db", true);
+        for i in 0..1000 {
+            let key = format!("key{}", i).as_bytes().to_vec();
+            let value = format!("value{}", i).as_bytes().to_vec();
+            kv.set(&key, &value).unwrap();
+        }
+        kv.close();
+
+        let file_name = test_file("test_kv_backends_share_file_format.db", false);
+        let mut kv = KV::open_buffered(file_name, 4).unwrap();
+        for i in 0..1000 {
+            let key = format!("key{}", i).as_bytes().to_vec();
+            let value = format!("value{}", i).as_bytes().to_vec();
+            assert_eq!(kv.get(&key).unwrap(), value);
+            if i % 2 == 0 {
+                kv.del(&key).unwrap();
+            }
+        }
+        kv.close();
+
+        let kv = new_kv("test_kv_backends_share_file_format.db", false);
+        for i in 0..1000 {
+            let key = format!("key{}", i).as_bytes().to_vec();
+            assert_eq!(kv.get(&key).is_some(), i % 2 == 1);
+        }
+        kv.close();
+    }
+
+    #[test]
+    fn test_kv_in_memory_reuses_pages() {
+        let mut kv = KV::open_in_memory().unwrap();
+        for round in 0..20 {
+            for i in 0..500 {
+                let key = format!("key{}", i).as_bytes().to_vec();
+                let value = format!("value{}-{}", round, i).as_bytes().to_vec();
+                kv.set(&key, &value).unwrap();
+            }
+            for i in 0..500 {
+                let key = format!("key{}", i).as_bytes().to_vec();
+                assert!(kv.del(&key).unwrap());
+            }
+        }
+
+        // without page reuse this would be thousands of pages
+        let pages = kv.tree.page_manager.file_size() / BTREE_PAGE_SIZE as u64;
+        assert!(pages < 100, "{} pages allocated", pages);
+        assert!(get_free_list_total(&kv) > 0);
+    }
+
+    #[test]
+    fn test_kv_seek() {
+        let mut kv = KV::open_in_memory().unwrap();
+        assert!(!kv.seek("key".as_bytes(), CmpOption::GE).valid());
+
+        for i in 0..1000 {
+            let key = format!("key{:04}", i * 2).as_bytes().to_vec();
+            kv.set(&key, &i.to_string().into_bytes()).unwrap();
+        }
+
+        let iter = kv.seek("key0100".as_bytes(), CmpOption::GE);
+        assert!(iter.valid());
+        assert_eq!(iter.deref().0, "key0100".as_bytes());
+
+        let iter = kv.seek("key0100".as_bytes(), CmpOption::GT);
+        assert_eq!(iter.deref().0, "key0102".as_bytes());
+
+        let iter = kv.seek("key0101".as_bytes(), CmpOption::LT);
+        assert_eq!(iter.deref().0, "key0100".as_bytes());
+
+        let iter = kv.seek("key0100".as_bytes(), CmpOption::LE);
+        assert_eq!(iter.deref().0, "key0100".as_bytes());
+
+        // Past either end of the keys
+        assert!(!kv.seek("key1998".as_bytes(), CmpOption::GT).valid());
+        assert!(!kv.seek("key9".as_bytes(), CmpOption::GE).valid());
+        let mut iter = kv.seek("a".as_bytes(), CmpOption::LE);
+        assert!(!iter.valid());
+        assert!(iter.next());
+        assert_eq!(iter.deref().0, "key0000".as_bytes());
+
+        // Full scan in order
+        let mut iter = kv.seek("key".as_bytes(), CmpOption::GE);
+        let mut count = 0;
+        while iter.valid() {
+            assert_eq!(iter.deref().0, format!("key{:04}", count * 2).as_bytes());
+            count += 1;
+            iter.next();
+            if count == 1000 {
+                assert!(!iter.next());
+                break;
+            }
+        }
+        assert_eq!(count, 1000);
+    }
+
     // #[test]
     // fn test_fl_full_node() {
     //     let mut kv = new_kv("test_fl_full_node.db", true);
@@ -226,7 +891,21 @@ mod tests {
 
     #[test]
     fn test_database_merging_ability() {
-        let mut kv = new_kv("test_database_merging_ability.db", true);
+        database_merging_ability(new_kv);
+    }
+
+    #[test]
+    fn test_database_merging_ability_buffered() {
+        database_merging_ability(new_buffered_kv);
+    }
+
+    #[test]
+    fn test_database_merging_ability_in_memory() {
+        database_merging_ability(new_in_memory_kv);
+    }
+
+    fn database_merging_ability(open: OpenKV) {
+        let mut kv = open("test_database_merging_ability.db", true);
 
         let mut rng = StdRng::seed_from_u64(2131);
         let mut keys: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
@@ -258,4 +937,193 @@ mod tests {
             }
         }
     }
+
+    fn error_message<T>(result: Result<T>) -> String {
+        match result {
+            Ok(_) => panic!("expected an error"),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_open_locks_the_file() {
+        let file_name = test_file("test_open_locks_the_file.db", true);
+        let mut kv = KV::open(file_name.clone()).unwrap();
+        kv.set(b"key", b"val").unwrap();
+
+        // a writer keeps out other writers but not readers
+        assert!(error_message(KV::open(file_name.clone())).contains("database is locked"));
+        assert!(error_message(KV::open_buffered(file_name.clone(), 16)).contains("locked"));
+        let mut reader = KV::open_read_only(file_name.clone()).unwrap();
+        let mut other = KV::open_with(
+            file_name.clone(),
+            KVOptions {
+                read_only: true,
+                pool_pages: Some(16),
+                ..KVOptions::default()
+            },
+        )
+        .unwrap();
+        assert!(reader.is_read_only());
+        assert_eq!(reader.get(b"key").unwrap(), b"val");
+        assert_eq!(other.get(b"key").unwrap(), b"val");
+        assert!(error_message(reader.set(b"key", b"new")).contains("read-only"));
+        assert!(error_message(reader.del(b"key")).contains("read-only"));
+        assert!(error_message(reader.compact()).contains("read-only"));
+
+        // readers see the commits of the writer once they refresh, the file growing included
+        kv.set(b"key", b"new").unwrap();
+        assert_eq!(reader.get(b"key").unwrap(), b"val");
+        kv.begin_batch();
+        for i in 0..2000u32 {
+            kv.set(format!("key{}", i).as_bytes(), &[b'v'; 1000])
+                .unwrap();
+        }
+        kv.commit_batch().unwrap();
+        assert!(reader.refresh().unwrap());
+        assert!(!reader.refresh().unwrap());
+        assert!(other.refresh().unwrap());
+        for handle in [&reader, &other] {
+            assert_eq!(handle.get(b"key").unwrap(), b"new");
+            assert_eq!(handle.get(b"key1999").unwrap(), [b'v'; 1000]);
+        }
+        assert!(!kv.refresh().unwrap());
+
+        // the writer cannot shrink the file under them
+        kv.begin_batch();
+        for i in 0..2000u32 {
+            kv.del(format!("key{}", i).as_bytes()).unwrap();
+        }
+        kv.commit_batch().unwrap();
+        assert!(error_message(kv.compact()).contains("read-only handles"));
+        reader.close();
+        other.close();
+        let size = kv.stats().file_size;
+        kv.compact().unwrap();
+        assert!(kv.stats().file_size < size);
+        kv.close();
+
+        let kv = KV::open(file_name.clone()).unwrap();
+        assert!(!kv.is_read_only());
+        kv.close();
+        fs::remove_file(file_name).unwrap();
+    }
+
+    #[test]
+    fn test_open_waits_for_the_lock() {
+        let file_name = test_file("test_open_waits_for_the_lock.db", true);
+        let kv = KV::open(file_name.clone()).unwrap();
+        let waiting = |timeout| {
+            KV::open_with(
+                file_name.clone(),
+                KVOptions {
+                    lock_timeout: Some(Duration::from_millis(timeout)),
+                    ..KVOptions::default()
+                },
+            )
+        };
+        assert!(error_message(waiting(20)).contains("database is locked"));
+
+        let holder = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            kv.close();
+        });
+        let kv = waiting(10_000).unwrap();
+        holder.join().unwrap();
+        kv.close();
+        fs::remove_file(file_name).unwrap();
+    }
+
+    #[test]
+    fn test_open_read_only_missing_file() {
+        let file_name = test_file("test_open_read_only_missing_file.db", true);
+        assert!(KV::open_read_only(file_name.clone()).is_err());
+        assert!(fs::metadata(file_name).is_err());
+    }
+
+    #[test]
+    fn test_kv_rejects_oversized_writes() {
+        let mut kv = KV::open_in_memory().unwrap();
+        let long_key = [b'k'; BTREE_MAX_KEY_SIZE + 1];
+        let long_val = [b'v'; BTREE_MAX_VAL_SIZE + 1];
+        assert!(error_message(kv.set(&long_key, b"v")).contains("keys can be at most"));
+        assert!(error_message(kv.set(b"k", &long_val)).contains("values can be at most"));
+        assert!(kv.update(&long_key, b"v", InsertMode::Upsert).is_err());
+        assert!(kv.update(b"k", &long_val, InsertMode::InsertOnly).is_err());
+        assert!(kv.del(&long_key).is_err());
+        assert!(kv.set(b"", b"v").is_err());
+        assert!(kv.get(b"k").is_none());
+        assert!(kv.get(&long_key).is_none());
+        assert!(kv.get(b"").is_none());
+
+        // the limits themselves are fine
+        kv.set(&long_key[1..], &long_val[1..]).unwrap();
+        assert_eq!(kv.get(&long_key[1..]).unwrap().len(), BTREE_MAX_VAL_SIZE);
+    }
+
+    #[test]
+    fn test_rollback_to_savepoint() {
+        let mut kv = new_kv("test_rollback_to_savepoint.db", true);
+        kv.enable_change_feed().unwrap();
+        for i in 0..200u32 {
+            kv.set(format!("key{}", i).as_bytes(), &[1; 100]).unwrap();
+        }
+        let committed = kv.master_page();
+        let next_seq = kv.next_change_seq();
+
+        kv.begin_batch();
+        kv.set(b"kept", b"1").unwrap();
+        let savepoint = kv.savepoint();
+        for i in 0..100u32 {
+            kv.del(format!("key{}", i).as_bytes()).unwrap();
+        }
+        kv.set_with_ttl(b"gone", b"2", Duration::from_secs(60))
+            .unwrap();
+        kv.rollback(savepoint);
+        kv.commit_batch().unwrap();
+
+        assert_eq!(kv.next_change_seq(), next_seq.map(|seq| seq + 1));
+        assert_eq!(kv.changes_since(0, 1000).unwrap().len(), 201);
+        kv.close();
+        let kv = new_kv("test_rollback_to_savepoint.db", false);
+        assert_eq!(kv.get(b"kept").unwrap(), b"1");
+        assert!(kv.get(b"gone").is_none());
+        assert!((0..200u32).all(|i| kv.get(format!("key{}", i).as_bytes()).is_some()));
+        // the pages the dropped writes took were given back
+        assert!(kv.master_page().total_used_pages <= committed.total_used_pages + 4);
+        kv.close();
+
+        // a batch rolled back to its start commits nothing
+        let mut kv = new_kv("test_rollback_to_savepoint.db", false);
+        let syncs = kv.stats().syncs;
+        kv.begin_batch();
+        let savepoint = kv.savepoint();
+        kv.set(b"gone", b"2").unwrap();
+        kv.rollback(savepoint);
+        kv.commit_batch().unwrap();
+        assert_eq!(kv.stats().syncs, syncs);
+        kv.close();
+    }
+
+    #[test]
+    fn test_open_refuses_keys_under_the_system_prefix() {
+        let file_name = test_file("test_open_refuses_keys_under_the_system_prefix.db", true);
+        let mut kv = KV::open(file_name.clone()).unwrap();
+        kv.set(b"a", b"1").unwrap();
+        // as stored by a version that did not reserve the prefix yet
+        kv.tree.insert(&[0, 0, 0, 0, b'k'], b"old");
+        kv.flush_pages().unwrap();
+        kv.close();
+        assert!(error_message(KV::open(file_name.clone())).contains("now reserved"));
+
+        let file_name = test_file("test_open_refuses_keys_under_the_system_prefix.db", true);
+        let mut kv = KV::open(file_name.clone()).unwrap();
+        kv.set_with_ttl(b"a", b"1", Duration::from_secs(60))
+            .unwrap();
+        kv.close();
+        let kv = KV::open(file_name.clone()).unwrap();
+        assert_eq!(kv.get(b"a"), Some(b"1".to_vec()));
+        kv.close();
+        fs::remove_file(file_name).unwrap();
+    }
 }
@@ -0,0 +1,261 @@
+//! Model-based tests. Arbitrary sequences of operations run against the database and against a
+//! `BTreeMap`, and after every step the two must agree and the file must be well formed. Failing
+//! sequences are shrunk by proptest to a minimal reproduction.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    ops::Bound,
+};
+
+use proptest::prelude::*;
+
+use crate::b_tree::b_node::{NodeType, BTREE_PAGE_SIZE};
+
+use super::{CmpOption, InsertMode, KV};
+
+#[derive(Debug, Clone)]
+enum Op {
+    Set(Vec<u8>, Vec<u8>),
+    Update(Vec<u8>, Vec<u8>, InsertMode),
+    Delete(Vec<u8>),
+    Seek(Vec<u8>, CmpOption),
+    Reopen,
+}
+
+/// Short keys over a small alphabet, so operations often hit keys that exist and neighbouring
+/// keys share prefixes
+fn key() -> impl Strategy<Value = Vec<u8>> {
+    prop::collection::vec(b'a'..=b'c', 1..6)
+}
+
+/// Values up to a quarter of a page, so a few dozen keys spread over several leaves
+fn value() -> impl Strategy<Value = Vec<u8>> {
+    (0..BTREE_PAGE_SIZE / 4, any::<u8>()).prop_map(|(len, byte)| vec![byte; len])
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    let mode = prop_oneof![
+        Just(InsertMode::Upsert),
+        Just(InsertMode::UpdateOnly),
+        Just(InsertMode::InsertOnly),
+    ];
+    let compare = prop_oneof![
+        Just(CmpOption::GT),
+        Just(CmpOption::GE),
+        Just(CmpOption::LT),
+        Just(CmpOption::LE),
+    ];
+    prop_oneof![
+        4 => (key(), value()).prop_map(|(key, val)| Op::Set(key, val)),
+        3 => (key(), value(), mode).prop_map(|(key, val, mode)| Op::Update(key, val, mode)),
+        3 => key().prop_map(Op::Delete),
+        2 => (key(), compare).prop_map(|(key, compare)| Op::Seek(key, compare)),
+        1 => Just(Op::Reopen),
+    ]
+}
+
+/// The database under test next to the map it must agree with
+struct Model {
+    path: String,
+    open: fn(String) -> KV,
+    kv: Option<KV>,
+    map: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl Model {
+    fn new(name: &str, open: fn(String) -> KV) -> Model {
+        fs::create_dir_all("test_run_dir").unwrap();
+        let path = format!("test_run_dir/{}", name);
+        let _ = fs::remove_file(&path);
+        Model {
+            kv: Some(open(path.clone())),
+            path,
+            open,
+            map: BTreeMap::new(),
+        }
+    }
+
+    fn kv(&mut self) -> &mut KV {
+        self.kv.as_mut().unwrap()
+    }
+
+    fn apply(&mut self, op: &Op) {
+        match op {
+            Op::Set(key, val) => {
+                self.kv().set(key, val).unwrap();
+                self.map.insert(key.clone(), val.clone());
+            }
+            Op::Update(key, val, mode) => {
+                let exists = self.map.contains_key(key);
+                let added = self.kv().update(key, val, *mode).unwrap();
+                assert_eq!(added, !exists && *mode != InsertMode::UpdateOnly);
+                let write = match mode {
+                    InsertMode::Upsert => true,
+                    InsertMode::UpdateOnly => exists,
+                    InsertMode::InsertOnly => !exists,
+                };
+                if write {
+                    self.map.insert(key.clone(), val.clone());
+                }
+            }
+            Op::Delete(key) => {
+                let deleted = self.kv().del(key).unwrap();
+                assert_eq!(deleted, self.map.remove(key).is_some());
+            }
+            Op::Seek(key, compare) => self.check_seek(key, *compare),
+            Op::Reopen => {
+                self.kv.take().unwrap().close();
+                self.kv = Some((self.open)(self.path.clone()));
+            }
+        }
+    }
+
+    /// The iterator must start at the key the map picks and visit the same keys from there
+    fn check_seek(&self, key: &[u8], compare: CmpOption) {
+        let bounds = match compare {
+            CmpOption::GT => (Bound::Excluded(key), Bound::Unbounded),
+            CmpOption::GE => (Bound::Included(key), Bound::Unbounded),
+            CmpOption::LT => (Bound::Unbounded, Bound::Excluded(key)),
+            CmpOption::LE => (Bound::Unbounded, Bound::Included(key)),
+        };
+        let range = self.map.range::<[u8], _>(bounds);
+        let expected: Vec<(Vec<u8>, Vec<u8>)> = match compare {
+            CmpOption::GT | CmpOption::GE => range.take(3).map(clone_pair).collect(),
+            CmpOption::LT | CmpOption::LE => range.rev().take(3).map(clone_pair).collect(),
+        };
+
+        let kv = self.kv.as_ref().unwrap();
+        let mut iter = kv.seek(key, compare);
+        let mut found = Vec::new();
+        while iter.valid() && found.len() < expected.len() {
+            found.push(iter.deref());
+            let moved = match compare {
+                CmpOption::GT | CmpOption::GE => iter.next(),
+                CmpOption::LT | CmpOption::LE => iter.prev(),
+            };
+            if !moved {
+                break;
+            }
+        }
+        assert_eq!(found, expected, "seek {:?} {:?}", key, compare);
+    }
+
+    fn check(&self) {
+        let kv = self.kv.as_ref().unwrap();
+        check_contents(kv, &self.map);
+        check_structure(kv);
+        check_pages(kv);
+    }
+}
+
+fn clone_pair((key, val): (&Vec<u8>, &Vec<u8>)) -> (Vec<u8>, Vec<u8>) {
+    (key.clone(), val.clone())
+}
+
+fn check_contents(kv: &KV, map: &BTreeMap<Vec<u8>, Vec<u8>>) {
+    let mut found = Vec::new();
+    let mut iter = kv.seek(&[], CmpOption::GT);
+    while iter.valid() {
+        found.push(iter.deref());
+        if !iter.next() {
+            break;
+        }
+    }
+    let expected: Vec<(Vec<u8>, Vec<u8>)> = map.iter().map(clone_pair).collect();
+    assert_eq!(found, expected);
+}
+
+/// Keys are sorted within and across nodes, every node fits in a page, internal keys are the
+/// first key of their child and all leaves are at the same depth
+fn check_structure(kv: &KV) {
+    let mut first_keys = HashMap::new();
+    let mut links = Vec::new();
+    let mut leaf_depths = HashSet::new();
+    let mut last_leaf_key: Option<Vec<u8>> = None;
+    kv.tree.walk(&mut |ptr, node, depth| {
+        let num_keys = node.num_keys();
+        assert!(num_keys >= 1, "empty node {}", ptr);
+        assert!(node.num_bytes() as usize <= BTREE_PAGE_SIZE);
+        let keys: Vec<Vec<u8>> = (0..num_keys).map(|i| node.get_key(i).to_vec()).collect();
+        assert!(
+            keys.windows(2).all(|pair| pair[0] < pair[1]),
+            "unsorted node {}",
+            ptr
+        );
+        first_keys.insert(ptr, keys[0].clone());
+
+        match node.b_type() {
+            NodeType::Node => {
+                for (i, key) in keys.into_iter().enumerate() {
+                    links.push((node.get_ptr(i as u16), key));
+                }
+            }
+            NodeType::Leaf => {
+                leaf_depths.insert(depth);
+                // the walk visits leaves in key order
+                if let Some(last) = &last_leaf_key {
+                    assert!(*last < keys[0], "leaf {} overlaps the one before", ptr);
+                }
+                last_leaf_key = keys.last().cloned();
+            }
+        }
+    });
+    for (child, key) in links {
+        assert_eq!(
+            first_keys[&child], key,
+            "child {} of an internal node",
+            child
+        );
+    }
+    assert!(leaf_depths.len() <= 1, "leaves at depths {:?}", leaf_depths);
+}
+
+/// Every page is used exactly once: by the master page, the tree, the free list nodes or as a
+/// free page. A page used twice was freed while still in use or freed twice, a page used by
+/// nothing leaked
+fn check_pages(kv: &KV) {
+    let free_list = &kv.tree.page_manager;
+    let mut pages = vec![0];
+    kv.tree.walk(&mut |ptr, _, _| pages.push(ptr));
+    let contents = free_list.list_contents();
+    let mut free_pages = 0;
+    for (node, free) in contents {
+        pages.push(node);
+        free_pages += free.len() as u64;
+        pages.extend(free);
+    }
+    assert_eq!(free_pages, free_list.get_free_list_total());
+
+    let total = free_list.total_pages();
+    let unique: HashSet<u64> = pages.iter().copied().collect();
+    assert_eq!(unique.len(), pages.len(), "a page is used twice");
+    assert!(
+        pages.iter().all(|ptr| *ptr < total),
+        "a page is past the end"
+    );
+    assert_eq!(pages.len() as u64, total, "a page leaked");
+}
+
+fn run(name: &str, open: fn(String) -> KV, ops: &[Op]) {
+    let mut model = Model::new(name, open);
+    for op in ops {
+        model.apply(op);
+        model.check();
+    }
+    model.kv.take().unwrap().close();
+    fs::remove_file(&model.path).unwrap();
+}
+
+proptest! {
+    #[test]
+    fn test_model_mmap(ops in prop::collection::vec(op(), 1..100)) {
+        run("model_mmap.db", |path| KV::open(path).unwrap(), &ops);
+    }
+
+    #[test]
+    fn test_model_buffered(ops in prop::collection::vec(op(), 1..100)) {
+        // a small pool so pages are evicted and read back
+        run("model_buffered.db", |path| KV::open_buffered(path, 4).unwrap(), &ops);
+    }
+}
@@ -0,0 +1,216 @@
+//! Page level replication. A leader ships the pages and master page of every commit to its
+//! followers, and a follower writes them to its own file in the same order. Reads on the follower
+//! see each commit once it is applied, and promoting it turns it into an ordinary writer.
+
+use std::sync::mpsc::Receiver;
+
+use crate::prelude::*;
+
+use super::{KVOptions, PageDelta, KV};
+
+impl KV {
+    /** Starts a follower at `path`, which must not exist or be empty. The database is copied
+     * there as it is now, and every commit after the copy is sent to the returned receiver, to be
+     * applied with `Follower::apply` */
+    pub fn add_follower(&mut self, path: String) -> Result<Receiver<PageDelta>> {
+        self.check_writable()?;
+        if self.batching {
+            return Err(Error::Static(
+                "cannot add a follower while writes are batched",
+            ));
+        }
+        // nothing is committed between the copy and the first delta
+        self.backup_to(path)?;
+        Ok(self.tree.page_manager.replicate())
+    }
+}
+
+/// A read-only copy of a database that follows a leader
+pub struct Follower {
+    kv: KV,
+    /// Sequence number of the last delta applied since the follower was opened
+    applied: Option<u64>,
+}
+
+impl Follower {
+    /** Opens the copy made by `KV::add_follower`. The file is locked like a writer's */
+    pub fn open(path: String) -> Result<Follower> {
+        let mut kv = KV::open_with(path, KVOptions::default())?;
+        kv.read_only = true;
+        Ok(Follower { kv, applied: None })
+    }
+
+    /** Writes the commit of a delta to the file. Deltas must be applied in the order they were
+     * shipped, without gaps: each one builds on the commit the follower has, even across
+     * reopening either side */
+    pub fn apply(&mut self, delta: &PageDelta) -> Result<()> {
+        if delta.base != self.kv.master_page() {
+            return Err(Error::Generic(format!(
+                "delta {} builds on a commit the follower does not have",
+                delta.seq
+            )));
+        }
+        let master_page = self.kv.tree.page_manager.apply_delta(delta)?;
+        self.applied = Some(delta.seq);
+        self.kv.tree.root = master_page.btree_root;
+        self.kv.reload_system_state();
+        Ok(())
+    }
+
+    /** Applies every delta waiting on `deltas` without blocking. Returns how many */
+    pub fn catch_up(&mut self, deltas: &Receiver<PageDelta>) -> Result<usize> {
+        let mut count = 0;
+        while let Ok(delta) = deltas.try_recv() {
+            self.apply(&delta)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /** Sequence number of the last delta applied since the follower was opened */
+    pub fn applied(&self) -> Option<u64> {
+        self.applied
+    }
+
+    /** The database as of the last delta applied. Writes to it are rejected */
+    pub fn kv(&self) -> &KV {
+        &self.kv
+    }
+
+    pub fn close(self) {
+        self.kv.close();
+    }
+
+    /** Stops following and returns the database as a writer. The leader must not ship to it any
+     * more */
+    pub fn promote(self) -> KV {
+        let mut kv = self.kv;
+        kv.read_only = false;
+        kv.pin_snapshots();
+        kv
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, thread, time::Duration};
+
+    use super::*;
+    use crate::kv_store::CmpOption;
+    use crate::test_util::test_file;
+
+    fn key(i: u32) -> Vec<u8> {
+        format!("key{:05}", i).into_bytes()
+    }
+
+    fn contents(kv: &KV) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut items = Vec::new();
+        let mut iter = kv.seek(&[], CmpOption::GT);
+        while iter.valid() {
+            items.push(iter.deref());
+            if !iter.next() {
+                break;
+            }
+        }
+        items
+    }
+
+    #[test]
+    fn test_follower_tracks_leader() {
+        let leader_file = test_file("test_follower_tracks_leader.db");
+        let follower_file = test_file("test_follower_tracks_leader_follower.db");
+        let mut leader = KV::open(leader_file.clone()).unwrap();
+        for i in 0..500 {
+            leader.set(&key(i), &[1; 100]).unwrap();
+        }
+
+        let deltas = leader.add_follower(follower_file.clone()).unwrap();
+        assert!(leader.add_follower(follower_file.clone()).is_err());
+        let mut follower = Follower::open(follower_file.clone()).unwrap();
+        assert_eq!(contents(follower.kv()), contents(&leader));
+
+        // deletes free pages that later commits reuse, and compaction moves pages around
+        for i in 0..500 {
+            if i % 3 == 0 {
+                leader.del(&key(i)).unwrap();
+            }
+        }
+        assert_eq!(follower.catch_up(&deltas).unwrap(), 167);
+        assert_eq!(contents(follower.kv()), contents(&leader));
+        for i in 500..800 {
+            leader.set(&key(i), &[2; 100]).unwrap();
+        }
+        leader.compact().unwrap();
+        leader
+            .set_with_ttl(b"session", b"s", Duration::ZERO)
+            .unwrap();
+        follower.catch_up(&deltas).unwrap();
+        assert_eq!(contents(follower.kv()), contents(&leader));
+        assert_eq!(follower.kv().master_page(), leader.master_page());
+        assert_eq!(follower.kv().get(b"session"), None);
+        assert!(follower.kv().expires_at(&key(1)).is_none());
+
+        // deltas out of order are refused
+        leader.set(b"a", b"1").unwrap();
+        leader.set(b"b", b"2").unwrap();
+        let first = deltas.recv().unwrap();
+        let second = deltas.recv().unwrap();
+        assert!(follower.apply(&second).is_err());
+        follower.apply(&first).unwrap();
+        assert!(follower.apply(&first).is_err());
+        follower.close();
+
+        // a reopened follower still knows which commit it has
+        let mut follower = Follower::open(follower_file.clone()).unwrap();
+        assert!(follower.apply(&first).is_err());
+        follower.apply(&second).unwrap();
+        assert_eq!(follower.applied(), Some(second.seq));
+        assert_eq!(follower.kv().get(b"b"), Some(b"2".to_vec()));
+        leader.close();
+        follower.close();
+
+        // promoting makes it a writer of its own
+        let mut follower_kv = Follower::open(follower_file.clone()).unwrap().promote();
+        follower_kv.set(b"c", b"3").unwrap();
+        follower_kv.close();
+        let follower_kv = KV::open(follower_file.clone()).unwrap();
+        assert_eq!(follower_kv.get(b"a"), Some(b"1".to_vec()));
+        assert_eq!(follower_kv.get(b"c"), Some(b"3".to_vec()));
+        follower_kv.close();
+        fs::remove_file(leader_file).unwrap();
+        fs::remove_file(follower_file).unwrap();
+    }
+
+    #[test]
+    fn test_follower_over_a_stream() {
+        let follower_file = test_file("test_follower_over_a_stream.db");
+        let mut leader = KV::open_in_memory().unwrap();
+        leader.set(b"before", b"0").unwrap();
+        let deltas = leader.add_follower(follower_file.clone()).unwrap();
+        let mut follower = Follower::open(follower_file.clone()).unwrap();
+        assert!(follower.kv().is_read_only());
+
+        // the leader streams on a thread while the follower reads them back
+        let (reader, mut writer) = std::io::pipe().unwrap();
+        let shipper = thread::spawn(move || {
+            for delta in deltas {
+                delta.write_to(&mut writer).unwrap();
+            }
+        });
+        for i in 0..50 {
+            leader.set(&key(i), b"v").unwrap();
+        }
+        drop(leader);
+
+        let mut reader = reader;
+        while let Some(delta) = PageDelta::read_from(&mut reader).unwrap() {
+            follower.apply(&delta).unwrap();
+        }
+        shipper.join().unwrap();
+        assert_eq!(follower.applied(), Some(49));
+        assert_eq!(contents(follower.kv()).len(), 51);
+        assert!(follower.kv().get(b"before").is_some());
+        follower.promote().close();
+        fs::remove_file(follower_file).unwrap();
+    }
+}
@@ -0,0 +1,408 @@
+//! Named snapshots. A snapshot is the root of a committed version of the tree, saved under a
+//! system key. Copy-on-write never changes the pages of that version, so it stays readable for as
+//! long as they are not reused: a pin keeps them out of the free list until the snapshot is
+//! dropped. Pins only live in memory and are put back when the database is opened.
+
+use std::{
+    collections::HashSet,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::b_tree::b_node::BTREE_MAX_KEY_SIZE;
+use crate::prelude::*;
+
+use super::{system_key, ttl::now_millis, CmpOption, KVIterator, Version, KV};
+
+/// Snapshots by name, holding the root of the version and when it was taken
+const SNAPSHOT_TAG: u8 = b's';
+
+pub(super) fn snapshot_key(name: &str) -> Vec<u8> {
+    system_key(SNAPSHOT_TAG, name.as_bytes())
+}
+
+/** The key of snapshot `name`, failing for names too long to be a key */
+fn checked_snapshot_key(name: &str) -> Result<Vec<u8>> {
+    let key = snapshot_key(name);
+    if key.len() > BTREE_MAX_KEY_SIZE {
+        return Err(Error::Generic(format!(
+            "snapshot names can be at most {} bytes",
+            BTREE_MAX_KEY_SIZE - (key.len() - name.len())
+        )));
+    }
+    Ok(key)
+}
+
+/// A snapshot as listed by `KV::list_snapshots`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotInfo {
+    pub name: String,
+    pub created: SystemTime,
+}
+
+/// Reads the database as it was when a snapshot was taken
+pub struct SnapshotView<'a> {
+    kv: &'a KV,
+    version: Version,
+    created: SystemTime,
+}
+
+impl<'a> SnapshotView<'a> {
+    /** The value of `key` in the snapshot. Entries that had expired when it was taken are
+     * hidden, the ones that expired since are not */
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.kv.get_in(self.version, key)
+    }
+
+    /** Returns an iterator over the snapshot, positioned at the closest key to `key` that
+     * satisfies `compare` */
+    pub fn seek(&self, key: &[u8], compare: CmpOption) -> KVIterator<'a> {
+        KVIterator::seek(self.kv, self.version, key, compare)
+    }
+
+    pub fn created(&self) -> SystemTime {
+        self.created
+    }
+}
+
+struct SnapshotRecord {
+    name: String,
+    root: u64,
+    /// Milliseconds since the Unix epoch
+    created: u64,
+}
+
+impl KV {
+    /** Keeps the version of the last commit as snapshot `name`. Its pages are not reused until
+     * the snapshot is dropped, so the file grows with what is written meanwhile */
+    pub fn create_snapshot(&mut self, name: &str) -> Result<()> {
+        self.check_writable()?;
+        if self.batching {
+            // the root has to be committed to be kept
+            return Err(Error::Static(
+                "cannot create a snapshot while writes are batched",
+            ));
+        }
+        let key = checked_snapshot_key(name)?;
+        if self.tree.get_value(&key).is_some() {
+            return Err(Error::Generic(format!("snapshot {} already exists", name)));
+        }
+
+        let root = self.tree.root;
+        // pinned first, saving the record replaces pages of this version
+        let pin = self.tree.page_manager.pin(self.tree_pages(root));
+        let mut record = root.to_be_bytes().to_vec();
+        record.extend_from_slice(&now_millis().to_be_bytes());
+        self.system_insert(&key, &record);
+        self.snapshots.insert(name.to_string(), pin);
+        if let Err(err) = self.flush_pages() {
+            // the record is gone with the failed commit
+            self.snapshots.remove(name);
+            self.tree.page_manager.unpin(pin);
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /** Opens a read-only view of snapshot `name` */
+    pub fn snapshot(&self, name: &str) -> Result<SnapshotView<'_>> {
+        let key = checked_snapshot_key(name)?;
+        let Some(record) = self.tree.get_value(&key) else {
+            return Err(Error::Generic(format!("no snapshot named {}", name)));
+        };
+        let record = SnapshotRecord::decode(name.to_string(), &record);
+        Ok(SnapshotView {
+            kv: self,
+            version: Version {
+                root: record.root,
+                deadlines: self.has_deadlines(record.root),
+                as_of: Some(record.created),
+            },
+            created: record.created_time(),
+        })
+    }
+
+    /** Every snapshot, by name */
+    pub fn list_snapshots(&self) -> Vec<SnapshotInfo> {
+        self.snapshot_records()
+            .into_iter()
+            .map(|record| SnapshotInfo {
+                created: record.created_time(),
+                name: record.name,
+            })
+            .collect()
+    }
+
+    /** Drops snapshot `name`, freeing the pages only it was keeping. Returns false if there was
+     * no such snapshot */
+    pub fn drop_snapshot(&mut self, name: &str) -> Result<bool> {
+        self.check_writable()?;
+        if self.batching {
+            // the pages can only be freed once the record is gone for good
+            return Err(Error::Static(
+                "cannot drop a snapshot while writes are batched",
+            ));
+        }
+        let key = checked_snapshot_key(name)?;
+        if !self.tree.delete(&key) {
+            return Ok(false);
+        }
+        self.flush_pages()?;
+        if let Some(pin) = self.snapshots.remove(name) {
+            self.tree.page_manager.unpin(pin);
+            self.flush_pages()?;
+        }
+        Ok(true)
+    }
+
+    pub(super) fn snapshot_names(&self) -> Vec<String> {
+        self.snapshot_records()
+            .into_iter()
+            .map(|record| record.name)
+            .collect()
+    }
+
+    pub(super) fn snapshot_roots(&self) -> Vec<u64> {
+        self.snapshot_records()
+            .iter()
+            .map(|record| record.root)
+            .collect()
+    }
+
+    /** Pins the pages of every snapshot. The pages they hold that the tree no longer uses were
+     * deleted from it while pinned, they are withheld again */
+    pub(super) fn pin_snapshots(&mut self) {
+        assert!(self.snapshots.is_empty());
+        let mut snapshot_pages = HashSet::new();
+        for record in self.snapshot_records() {
+            let pages = self.tree_pages(record.root);
+            snapshot_pages.extend(pages.iter().copied());
+            let pin = self.tree.page_manager.pin(pages);
+            self.snapshots.insert(record.name, pin);
+        }
+        let live = self.tree_pages(self.tree.root);
+        self.tree
+            .page_manager
+            .withhold(snapshot_pages.into_iter().filter(|ptr| !live.contains(ptr)));
+    }
+
+    fn snapshot_records(&self) -> Vec<SnapshotRecord> {
+        let prefix = system_key(SNAPSHOT_TAG, &[]);
+        let mut records = Vec::new();
+        let mut iter = self.tree.seek(&prefix, CmpOption::GE);
+        while iter.valid() {
+            let (key, val) = iter.deref();
+            if !key.starts_with(&prefix) {
+                break;
+            }
+            let name = String::from_utf8_lossy(&key[prefix.len()..]).into_owned();
+            records.push(SnapshotRecord::decode(name, &val));
+            if !iter.next() {
+                break;
+            }
+        }
+        records
+    }
+
+    fn tree_pages(&self, root: u64) -> HashSet<u64> {
+        let mut pages = HashSet::new();
+        self.tree.walk_at(root, &mut |ptr, _, _| {
+            pages.insert(ptr);
+        });
+        pages
+    }
+}
+
+impl SnapshotRecord {
+    fn decode(name: String, record: &[u8]) -> SnapshotRecord {
+        SnapshotRecord {
+            name,
+            root: BigEndian::read_u64(&record[..8]),
+            created: BigEndian::read_u64(&record[8..16]),
+        }
+    }
+
+    fn created_time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.created)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, thread};
+
+    use super::*;
+    use crate::free_list::faulty::{Fault, FaultyDisk};
+    use crate::kv_store::KVStats;
+    use crate::test_util::test_file;
+
+    fn key(i: u32) -> Vec<u8> {
+        format!("key{:05}", i).into_bytes()
+    }
+
+    fn contents(mut iter: KVIterator) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut items = Vec::new();
+        while iter.valid() {
+            items.push(iter.deref());
+            if !iter.next() {
+                break;
+            }
+        }
+        items
+    }
+
+    fn no_leaks(stats: KVStats) {
+        assert_eq!(stats.live_pages() + stats.free_pages, stats.total_pages);
+    }
+
+    #[test]
+    fn test_snapshot_keeps_its_version() {
+        let file_name = test_file("test_snapshot_keeps_its_version.db");
+        let backup_file = test_file("test_snapshot_keeps_its_version_backup.db");
+        let mut kv = KV::open(file_name.clone()).unwrap();
+        for i in 0..1000 {
+            kv.set(&key(i), &[1; 100]).unwrap();
+        }
+        kv.create_snapshot("yesterday").unwrap();
+        let before = contents(kv.seek(&[], CmpOption::GT));
+        assert!(kv.create_snapshot("yesterday").is_err());
+        assert!(kv.compact().is_err());
+
+        // enough churn that unpinned pages would be reused many times over
+        for round in 0..3 {
+            for i in 0..1000 {
+                if i % 2 == 0 {
+                    kv.del(&key(i)).unwrap();
+                } else {
+                    kv.set(&key(i), &[round; 100]).unwrap();
+                }
+            }
+        }
+        kv.set(b"new", b"v").unwrap();
+        let view = kv.snapshot("yesterday").unwrap();
+        assert_eq!(view.get(&key(0)), Some(vec![1; 100]));
+        assert_eq!(view.get(b"new"), None);
+        assert_eq!(contents(view.seek(&[], CmpOption::GT)), before);
+        assert!(kv.snapshot("today").is_err());
+        kv.close();
+
+        // the pages stay held after reopening, and the ones only the snapshot uses are freed
+        // once it is dropped
+        let mut kv = KV::open(file_name.clone()).unwrap();
+        for i in 0..1000 {
+            kv.set(&key(i), &[9; 100]).unwrap();
+        }
+        assert_eq!(
+            contents(kv.snapshot("yesterday").unwrap().seek(&[], CmpOption::GT)),
+            before
+        );
+        kv.create_snapshot("today").unwrap();
+        let names: Vec<String> = kv.list_snapshots().into_iter().map(|s| s.name).collect();
+        assert_eq!(names, vec!["today", "yesterday"]);
+
+        // backups carry the snapshots along
+        kv.backup_to(backup_file.clone()).unwrap();
+        let copy = KV::open(backup_file.clone()).unwrap();
+        assert_eq!(
+            contents(copy.snapshot("yesterday").unwrap().seek(&[], CmpOption::GT)),
+            before
+        );
+        copy.close();
+
+        let free_pages = kv.stats().free_pages;
+        assert!(kv.drop_snapshot("yesterday").unwrap());
+        assert!(!kv.drop_snapshot("yesterday").unwrap());
+        assert!(kv.stats().free_pages > free_pages);
+        assert!(kv.drop_snapshot("today").unwrap());
+        assert!(kv.list_snapshots().is_empty());
+        no_leaks(kv.stats());
+        kv.compact().unwrap();
+        kv.close();
+        fs::remove_file(file_name).unwrap();
+        fs::remove_file(backup_file).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_reads_as_of_creation() {
+        let file_name = test_file("test_snapshot_reads_as_of_creation.db");
+        let copy_file = test_file("test_snapshot_reads_as_of_creation_copy.db");
+        let mut kv = KV::open_in_memory().unwrap();
+        kv.create_snapshot("empty").unwrap();
+        kv.set(b"a", b"1").unwrap();
+        kv.set_with_ttl(b"gone", b"2", Duration::ZERO).unwrap();
+        kv.set_with_ttl(b"soon", b"3", Duration::from_millis(100))
+            .unwrap();
+        kv.create_snapshot("then").unwrap();
+        thread::sleep(Duration::from_millis(150));
+        kv.sweep_expired().unwrap();
+
+        // what was live when it was taken, without the system keys
+        let view = kv.snapshot("then").unwrap();
+        assert_eq!(kv.get(b"soon"), None);
+        assert_eq!(view.get(b"soon"), Some(b"3".to_vec()));
+        assert_eq!(view.get(b"gone"), None);
+        let keys: Vec<Vec<u8>> = contents(view.seek(&[], CmpOption::GT))
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(keys, vec![b"a".to_vec(), b"soon".to_vec()]);
+        assert!(view.created() <= SystemTime::now());
+        assert!(!kv
+            .snapshot("empty")
+            .unwrap()
+            .seek(&[], CmpOption::GT)
+            .valid());
+
+        // compacted copies leave the snapshots out
+        kv.compact_to(copy_file.clone()).unwrap();
+        let copy = KV::open(copy_file.clone()).unwrap();
+        assert!(copy.list_snapshots().is_empty());
+        assert_eq!(copy.get(b"a"), Some(b"1".to_vec()));
+        copy.close();
+
+        // read-only handles can read snapshots but not make them
+        kv.backup_to(file_name.clone()).unwrap();
+        let kv = KV::open_read_only(file_name.clone()).unwrap();
+        assert!(kv.snapshot("then").unwrap().get(b"a").is_some());
+        let mut kv = kv;
+        assert!(kv.create_snapshot("now").is_err());
+        assert!(kv.drop_snapshot("then").is_err());
+        assert!(kv.create_snapshot(&"s".repeat(BTREE_MAX_KEY_SIZE)).is_err());
+        kv.close();
+        fs::remove_file(file_name).unwrap();
+        fs::remove_file(copy_file).unwrap();
+    }
+
+    #[test]
+    fn test_failed_snapshot_commits_keep_pins_and_records_together() {
+        let disk = FaultyDisk::new();
+        let mut kv = KV::open_storage(disk.storage()).unwrap();
+        for i in 0..100 {
+            kv.set(&key(i), &[1; 100]).unwrap();
+        }
+        kv.create_snapshot("kept").unwrap();
+        for i in 0..100 {
+            kv.del(&key(i)).unwrap();
+        }
+
+        kv.begin_batch();
+        assert!(kv.drop_snapshot("kept").is_err());
+        kv.commit_batch().unwrap();
+
+        // the record is back after the failed commit, so is the pin on its pages
+        disk.inject(Fault::Call(0));
+        assert!(kv.drop_snapshot("kept").is_err());
+        assert_eq!(kv.list_snapshots().len(), 1);
+        assert!(kv.tree.page_manager.has_pins());
+        let view = kv.snapshot("kept").unwrap();
+        assert!((0..100).all(|i| view.get(&key(i)) == Some(vec![1; 100])));
+
+        let disk = disk.restart(false);
+        let mut kv = KV::open_storage(disk.storage()).unwrap();
+        disk.inject(Fault::Call(0));
+        assert!(kv.create_snapshot("gone").is_err());
+        assert!(kv.snapshot("gone").is_err());
+        assert_eq!(kv.snapshots.len(), 1);
+    }
+}
@@ -0,0 +1,132 @@
+use crate::b_tree::b_node::{NodeType, BTREE_PAGE_SIZE};
+
+use super::{KV, SYSTEM_PREFIX};
+
+/// Size and shape of the database as of the last commit
+#[derive(Debug, Clone, PartialEq)]
+pub struct KVStats {
+    /// Levels in the B-tree, 0 when it is empty
+    pub depth: usize,
+    pub internal_nodes: u64,
+    pub leaf_nodes: u64,
+    /// Keys stored, not counting the sentinel empty key or the system keys
+    pub keys: u64,
+    /// Average fraction of a page used by a B-tree node
+    pub avg_fill: f64,
+    /// Pages held in the free list
+    pub free_pages: u64,
+    /// Pages holding the free list itself
+    pub free_list_nodes: u64,
+    /// Pages used by the database, including the master page and free pages
+    pub total_pages: u64,
+    /// Size of the file in bytes, can be larger than `total_pages` while it grows
+    pub file_size: u64,
+    /// Memory mapped chunks, 0 when the file is not memory mapped
+    pub mapped_chunks: usize,
+    /// Times the storage was synced since the database was opened, two per commit
+    pub syncs: u64,
+}
+
+impl KVStats {
+    /// Pages reachable from the master page: the master page, the tree and the free list
+    pub fn live_pages(&self) -> u64 {
+        1 + self.internal_nodes + self.leaf_nodes + self.free_list_nodes
+    }
+}
+
+impl KV {
+    /** Walks the tree and the free list to describe how the file is used */
+    pub fn stats(&self) -> KVStats {
+        let mut depth = 0;
+        let mut internal_nodes = 0;
+        let mut leaf_nodes = 0;
+        let mut keys = 0;
+        let mut bytes = 0;
+        self.tree.walk(&mut |_, node, node_depth| {
+            depth = depth.max(node_depth + 1);
+            bytes += node.num_bytes() as u64;
+            match node.b_type() {
+                NodeType::Node => internal_nodes += 1,
+                NodeType::Leaf => {
+                    leaf_nodes += 1;
+                    keys += (0..node.num_keys())
+                        .filter(|&i| !node.get_key(i).starts_with(SYSTEM_PREFIX))
+                        .count() as u64;
+                }
+            }
+        });
+        let nodes = internal_nodes + leaf_nodes;
+        let avg_fill = if nodes == 0 {
+            0.0
+        } else {
+            bytes as f64 / (nodes * BTREE_PAGE_SIZE as u64) as f64
+        };
+
+        let free_list = &self.tree.page_manager;
+        KVStats {
+            depth,
+            internal_nodes,
+            leaf_nodes,
+            // the first leaf starts with the sentinel
+            keys: keys.saturating_sub(1),
+            avg_fill,
+            free_pages: free_list.total() as u64,
+            free_list_nodes: free_list.list_nodes().len() as u64,
+            total_pages: free_list.total_pages(),
+            file_size: free_list.file_size(),
+            mapped_chunks: free_list.mapped_chunks(),
+            syncs: free_list.syncs(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn test_stats_empty() {
+        let kv = KV::open_in_memory().unwrap();
+        let stats = kv.stats();
+        assert_eq!(stats.depth, 0);
+        assert_eq!(stats.keys, 0);
+        assert_eq!(stats.avg_fill, 0.0);
+        assert_eq!(stats.mapped_chunks, 0);
+        assert_eq!(stats.live_pages(), 1);
+        assert_eq!(stats.syncs, 0);
+    }
+
+    #[test]
+    fn test_stats() {
+        fs::create_dir_all("test_run_dir").unwrap();
+        let path = "test_run_dir/test_kv_stats.db";
+        let _ = fs::remove_file(path);
+        let mut kv = KV::open(path.to_string()).unwrap();
+        for i in 0..1000 {
+            kv.set(format!("key{:05}", i).as_bytes(), &[b'v'; 100])
+                .unwrap();
+        }
+        for i in 0..500 {
+            kv.del(format!("key{:05}", i).as_bytes()).unwrap();
+        }
+
+        let stats = kv.stats();
+        assert_eq!(stats.keys, 500);
+        assert_eq!(stats.depth, 2);
+        assert_eq!(stats.internal_nodes, 1);
+        assert!(stats.leaf_nodes > 1);
+        assert!(0.0 < stats.avg_fill && stats.avg_fill <= 1.0);
+        assert!(stats.free_pages > 0);
+        assert!(stats.mapped_chunks >= 1);
+        // two syncs per commit
+        assert_eq!(stats.syncs, 2 * 1500);
+        // every page is either reachable or free
+        assert_eq!(stats.live_pages() + stats.free_pages, stats.total_pages);
+        assert!(stats.file_size >= stats.total_pages * BTREE_PAGE_SIZE as u64);
+
+        kv.close();
+        fs::remove_file(path).unwrap();
+    }
+}
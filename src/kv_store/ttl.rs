@@ -0,0 +1,263 @@
+//! Entries that expire. The deadline of an entry is kept next to it under a system key, and an
+//! index of system keys ordered by deadline lets a sweep find the expired entries without
+//! scanning the rest. Until they are swept, expired entries are hidden from reads.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::b_tree::b_node::BTREE_MAX_KEY_SIZE;
+use crate::prelude::*;
+
+use super::{system_key, CmpOption, Version, KV, SYSTEM_PREFIX};
+
+/// Maps a key to its deadline
+const DEADLINE_TAG: u8 = b'd';
+/// Deadlines followed by their key, in expiry order
+const EXPIRY_TAG: u8 = b'x';
+
+/// Longest key that can be given a deadline, leaving room for the tag and deadline in front of
+/// it in the expiry index
+pub const MAX_TTL_KEY_SIZE: usize = BTREE_MAX_KEY_SIZE - SYSTEM_PREFIX.len() - 1 - 8;
+
+/** Milliseconds since the Unix epoch, the unit deadlines are stored in */
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+fn expiry_key(deadline: u64, key: &[u8]) -> Vec<u8> {
+    let mut rest = deadline.to_be_bytes().to_vec();
+    rest.extend_from_slice(key);
+    system_key(EXPIRY_TAG, &rest)
+}
+
+impl KV {
+    /** Sets `key` to `value` until `ttl` has passed. Afterwards reads no longer see it and a
+     * sweep removes it. Writing the key again without a TTL keeps it for good */
+    pub fn set_with_ttl(&mut self, key: &[u8], value: &[u8], ttl: Duration) -> Result<()> {
+        self.check_writable()?;
+        KV::check_key(key)?;
+        KV::check_value(value)?;
+        if key.len() > MAX_TTL_KEY_SIZE {
+            return Err(Error::Generic(format!(
+                "keys with a TTL can be at most {} bytes",
+                MAX_TTL_KEY_SIZE
+            )));
+        }
+        let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+        let deadline = now_millis().saturating_add(ttl);
+
+        self.tree.insert(key, value);
+        self.clear_deadline(key);
+        self.system_insert(&system_key(DEADLINE_TAG, key), &deadline.to_be_bytes());
+        self.system_insert(&expiry_key(deadline, key), &[]);
+        self.deadlines = true;
+        self.record_change(key, Some(value));
+        self.flush_pages()
+    }
+
+    /** When a live entry expires, `None` if it does not or there is no such entry */
+    pub fn expires_at(&self, key: &[u8]) -> Option<SystemTime> {
+        self.get(key)?;
+        self.deadline(self.current(), key)
+            .map(|deadline| UNIX_EPOCH + Duration::from_millis(deadline))
+    }
+
+    /** Removes every entry whose deadline has passed, in one commit. Returns how many */
+    pub fn sweep_expired(&mut self) -> Result<usize> {
+        self.check_writable()?;
+        if !self.deadlines {
+            return Ok(0);
+        }
+        let now = now_millis();
+        let prefix = system_key(EXPIRY_TAG, &[]);
+        let mut expired = Vec::new();
+        let mut iter = self.tree.seek(&prefix, CmpOption::GE);
+        while iter.valid() {
+            let index_key = iter.key().to_vec();
+            if !index_key.starts_with(&prefix) {
+                break;
+            }
+            let deadline = BigEndian::read_u64(&index_key[prefix.len()..]);
+            if deadline > now {
+                break;
+            }
+            expired.push(index_key);
+            if !iter.next() {
+                break;
+            }
+        }
+
+        for index_key in &expired {
+            let key = &index_key[prefix.len() + 8..];
+            self.tree.delete(key);
+            self.tree.delete(&system_key(DEADLINE_TAG, key));
+            self.tree.delete(index_key);
+            self.record_change(key, None);
+        }
+        self.deadlines = self.has_deadlines(self.tree.root);
+        self.flush_pages()?;
+        Ok(expired.len())
+    }
+
+    /** Whether the expiry index of the version rooted at `root` holds any entry */
+    pub(super) fn has_deadlines(&self, root: u64) -> bool {
+        let prefix = system_key(EXPIRY_TAG, &[]);
+        let iter = self.tree.seek_at(root, &prefix, CmpOption::GE);
+        iter.valid() && iter.key().starts_with(&prefix)
+    }
+
+    fn deadline(&self, version: Version, key: &[u8]) -> Option<u64> {
+        if !version.deadlines {
+            return None;
+        }
+        self.tree
+            .get_value_at(version.root, &system_key(DEADLINE_TAG, key))
+            .map(|deadline| BigEndian::read_u64(&deadline))
+    }
+
+    /** Whether `key` has a deadline at or before `now` in `version` */
+    pub(super) fn is_expired(&self, version: Version, key: &[u8], now: u64) -> bool {
+        self.deadline(version, key)
+            .is_some_and(|deadline| deadline <= now)
+    }
+
+    /** Makes `key` permanent. Called whenever it is written or deleted */
+    pub(super) fn clear_deadline(&mut self, key: &[u8]) {
+        if let Some(deadline) = self.deadline(self.current(), key) {
+            self.tree.delete(&system_key(DEADLINE_TAG, key));
+            self.tree.delete(&expiry_key(deadline, key));
+        }
+    }
+
+    /** Removes `key` if it has expired, so writes treat it as missing */
+    pub(super) fn purge_expired(&mut self, key: &[u8]) -> bool {
+        if !self.is_expired(self.current(), key, now_millis()) {
+            return false;
+        }
+        self.tree.delete(key);
+        self.clear_deadline(key);
+        self.record_change(key, None);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, thread};
+
+    use super::*;
+    use crate::kv_store::InsertMode;
+
+    const LONG: Duration = Duration::from_secs(3600);
+
+    fn keys(kv: &KV) -> Vec<Vec<u8>> {
+        let mut keys = Vec::new();
+        let mut iter = kv.seek(&[], CmpOption::GT);
+        while iter.valid() {
+            keys.push(iter.deref().0);
+            if !iter.next() {
+                break;
+            }
+        }
+        keys
+    }
+
+    #[test]
+    fn test_expired_entries_are_hidden() {
+        let mut kv = KV::open_in_memory().unwrap();
+        kv.set(b"a", b"1").unwrap();
+        kv.set_with_ttl(b"b", b"2", Duration::ZERO).unwrap();
+        kv.set_with_ttl(b"c", b"3", LONG).unwrap();
+        kv.set_with_ttl(b"d", b"4", Duration::ZERO).unwrap();
+
+        assert_eq!(kv.get(b"b"), None);
+        assert_eq!(kv.get(b"c"), Some(b"3".to_vec()));
+        assert_eq!(keys(&kv), vec![b"a".to_vec(), b"c".to_vec()]);
+        assert!(kv.expires_at(b"a").is_none());
+        assert!(kv.expires_at(b"b").is_none());
+        assert!(kv.expires_at(b"c").unwrap() > SystemTime::now());
+
+        // seeking lands on the nearest live key in either direction
+        let iter = kv.seek(b"b", CmpOption::GE);
+        assert_eq!(iter.deref().0, b"c");
+        let mut iter = kv.seek(b"d", CmpOption::LE);
+        assert_eq!(iter.deref().0, b"c");
+        assert!(!iter.next());
+        assert_eq!(iter.deref().0, b"c");
+        assert!(iter.prev());
+        assert_eq!(iter.deref().0, b"a");
+        assert!(iter.prev());
+        assert!(!iter.valid());
+        assert!(!kv.seek(b"d", CmpOption::GE).valid());
+
+        // expired keys count as missing for writes
+        assert!(!kv.del(b"b").unwrap());
+        assert!(kv.update(b"d", b"5", InsertMode::InsertOnly).unwrap());
+        assert_eq!(kv.get(b"d"), Some(b"5".to_vec()));
+        assert!(kv.expires_at(b"d").is_none());
+    }
+
+    #[test]
+    fn test_writes_clear_the_deadline() {
+        let mut kv = KV::open_in_memory().unwrap();
+        kv.set_with_ttl(b"a", b"1", Duration::ZERO).unwrap();
+        kv.set(b"a", b"2").unwrap();
+        kv.set_with_ttl(b"b", b"1", Duration::ZERO).unwrap();
+        kv.set_with_ttl(b"b", b"2", LONG).unwrap();
+        assert_eq!(kv.sweep_expired().unwrap(), 0);
+        assert_eq!(kv.get(b"a"), Some(b"2".to_vec()));
+        assert_eq!(kv.get(b"b"), Some(b"2".to_vec()));
+
+        kv.del(b"b").unwrap();
+        assert!(!kv.has_deadlines(kv.tree.root));
+        assert!(kv.set(&system_key(DEADLINE_TAG, b"a"), b"").is_err());
+        assert!(kv
+            .set_with_ttl(&[b'k'; BTREE_MAX_KEY_SIZE], b"", LONG)
+            .is_err());
+
+        // a TTL too long to count in milliseconds never runs out
+        kv.set_with_ttl(b"c", b"1", Duration::MAX).unwrap();
+        assert_eq!(kv.deadline(kv.current(), b"c"), Some(u64::MAX));
+        assert_eq!(kv.get(b"c"), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn test_sweep_expired() {
+        let file_name = "test_run_dir/test_sweep_expired.db";
+        fs::create_dir_all("test_run_dir").unwrap();
+        fs::remove_file(file_name).unwrap_or(());
+        let mut kv = KV::open(file_name.to_string()).unwrap();
+        for i in 0..500 {
+            let ttl = if i % 2 == 0 { Duration::ZERO } else { LONG };
+            kv.set_with_ttl(format!("key{:03}", i).as_bytes(), b"v", ttl)
+                .unwrap();
+        }
+        kv.set_with_ttl(b"soon", b"v", Duration::from_millis(20))
+            .unwrap();
+        kv.close();
+
+        // deadlines survive reopening
+        let mut kv = KV::open(file_name.to_string()).unwrap();
+        assert_eq!(keys(&kv).len(), 251);
+        let deadline = kv.expires_at(b"key001").unwrap();
+        assert_eq!(kv.sweep_expired().unwrap(), 250);
+        // the deadlines and expiry index are not counted
+        assert_eq!(kv.stats().keys, 251);
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(kv.get(b"soon"), None);
+        assert_eq!(kv.sweep_expired().unwrap(), 1);
+        assert_eq!(kv.expires_at(b"key001"), Some(deadline));
+
+        for i in (1..500).step_by(2) {
+            kv.set(format!("key{:03}", i).as_bytes(), b"v").unwrap();
+        }
+        // only the entries themselves are left
+        assert_eq!(kv.stats().keys, 250);
+        assert_eq!(kv.sweep_expired().unwrap(), 0);
+        kv.close();
+        fs::remove_file(file_name).unwrap();
+    }
+}
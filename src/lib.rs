@@ -7,3 +7,8 @@ mod free_list;
 pub mod kv_store;
 pub mod prelude;
 pub mod relational_db;
+pub mod server;
+pub mod shell;
+#[cfg(test)]
+mod test_util;
+pub mod worker;
@@ -1,16 +1,118 @@
-// #![allow(unused)] // For beginning only.
+use std::{env, os::unix::ffi::OsStringExt, path::PathBuf, process};
 
-use database_from_scratch::kv_store::KV;
+use database_from_scratch::{kv_store::KVOptions, relational_db::DB, shell::Shell};
+use rustyline::{error::ReadlineError, DefaultEditor};
+
+const USAGE: &str = "\
+usage: database_from_scratch [--read-only] <database file> [command [args...]]
+Runs one command, or starts an interactive shell when no command is given. Type `help` in the
+shell for the list of commands. With --read-only the file is opened without write access and
+other read-only shells can open it at the same time.";
 
 fn main() {
-    let kv = KV::open("main.db".to_string());
-    let mut kv = kv.unwrap_or_else(|_| panic!("Failed to open database"));
-
-    kv.set("hello".as_bytes(), "world".as_bytes()).unwrap();
-    println!(
-        "hello {}",
-        String::from_utf8(kv.get("hello".as_bytes()).unwrap()).unwrap()
-    );
-    kv.del("hello".as_bytes()).unwrap();
-    kv.close();
+    let mut args: Vec<Vec<u8>> = env::args_os().skip(1).map(|arg| arg.into_vec()).collect();
+    if args.is_empty() || args[0] == b"-h" || args[0] == b"--help" {
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    }
+
+    let read_only = args[0] == b"--read-only";
+    if read_only {
+        args.remove(0);
+        if args.is_empty() {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    }
+
+    let path = String::from_utf8_lossy(&args.remove(0)).to_string();
+    let options = KVOptions {
+        read_only,
+        ..KVOptions::default()
+    };
+    let db = match DB::open_with(path.clone(), options) {
+        Ok(db) => db,
+        Err(err) => {
+            eprintln!("failed to open {}: {}", path, err);
+            process::exit(1);
+        }
+    };
+
+    let mut shell = Shell::new(db);
+    let code = if args.is_empty() {
+        repl(&mut shell)
+    } else {
+        match shell.run(args) {
+            Ok(out) => {
+                print_output(&out);
+                0
+            }
+            Err(err) => {
+                eprintln!("error: {}", err);
+                1
+            }
+        }
+    };
+
+    shell.into_db().close();
+    process::exit(code);
+}
+
+fn repl(shell: &mut Shell) -> i32 {
+    let mut editor = match DefaultEditor::new() {
+        Ok(editor) => editor,
+        Err(err) => {
+            eprintln!("failed to start the shell: {}", err);
+            return 1;
+        }
+    };
+    let history = history_path();
+    if let Some(history) = &history {
+        // there is no history on the first run
+        let _ = editor.load_history(history);
+    }
+
+    loop {
+        match editor.readline("db> ") {
+            Ok(line) => {
+                let line = line.trim();
+                if line.is_empty() {
+                    continue;
+                }
+                let _ = editor.add_history_entry(line);
+                if line == "quit" || line == "exit" {
+                    break;
+                }
+                match shell.execute(line) {
+                    Ok(out) => print_output(&out),
+                    Err(err) => eprintln!("error: {}", err),
+                }
+            }
+            // ctrl-c drops the current line, ctrl-d leaves
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(err) => {
+                eprintln!("error: {}", err);
+                break;
+            }
+        }
+    }
+
+    if let Some(history) = &history {
+        if let Err(err) = editor.save_history(history) {
+            eprintln!("failed to save the history: {}", err);
+        }
+    }
+    0
+}
+
+fn history_path() -> Option<PathBuf> {
+    let home = env::var_os("HOME")?;
+    Some(PathBuf::from(home).join(".database_from_scratch_history"))
+}
+
+fn print_output(out: &str) {
+    if !out.is_empty() {
+        println!("{}", out);
+    }
 }
@@ -0,0 +1,97 @@
+use crate::kv_store::BULK_LOAD_FILL;
+use crate::prelude::*;
+
+use super::{records::Record, value::Value, DB};
+
+impl DB {
+    /** Inserts rows in a single commit by building the tree bottom up. The rows must be in key
+     * order, which is the order of the encoded primary key, and must not exist yet. Returns the
+     * number of rows added */
+    pub fn bulk_load<I>(&mut self, table: &str, records: I) -> Result<usize>
+    where
+        I: IntoIterator<Item = Record>,
+    {
+        let table_def = match self.get_table_def(table) {
+            Some(table_def) => table_def,
+            None => return Err(Error::Generic(format!("Table not found {}", table))),
+        };
+        if self.transactions_open() {
+            // the rows would be loaded without the versions transactions read
+            return Err(Error::Static(
+                "cannot bulk load while transactions are open",
+            ));
+        }
+
+        let primary_keys = table_def.primary_keys;
+        let items = records.into_iter().map(|record| {
+            let values: Vec<Value> = table_def.check_record(&record, table_def.columns.len())?;
+            DB::check_row_size(&table_def, &values)?;
+            let key = DB::encode_key(None, table_def.prefix, &values[..primary_keys]);
+            let val = DB::encode_values(None, &values[primary_keys..]);
+            Ok((key, val))
+        });
+        self.kv.bulk_load_records(items, BULK_LOAD_FILL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relational_db::tables::TableDef;
+
+    fn new_db() -> DB {
+        let mut db = DB::open_in_memory().unwrap();
+        db.table_new(TableDef {
+            name: "people".to_string(),
+            types: vec![Value::INT64_TYPE, Value::BYTES_TYPE],
+            columns: vec!["id".to_string(), "name".to_string()],
+            primary_keys: 1,
+            prefix: 0,
+        })
+        .unwrap();
+        db
+    }
+
+    fn row(id: i64, name: &str) -> Record {
+        let mut record = Record::new();
+        record
+            .add_int64("id".to_string(), id)
+            .add_bytes("name".to_string(), name.as_bytes().to_vec());
+        record
+    }
+
+    #[test]
+    fn test_bulk_load_table() {
+        let mut db = new_db();
+        let rows: Vec<Record> = (0..5000).map(|id| row(id, &format!("n{}", id))).collect();
+        assert_eq!(db.bulk_load("people", rows.clone()).unwrap(), 5000);
+
+        let scanned: Vec<Record> = db.scan("people").unwrap().collect();
+        assert_eq!(scanned, rows);
+        let mut record = Record::new();
+        record.add_int64("id".to_string(), 1234);
+        assert!(db.get("people", &mut record).unwrap());
+        assert_eq!(record, row(1234, "n1234"));
+
+        // the table definitions are still there
+        assert_eq!(db.table_stats("@table").unwrap().rows, 1);
+    }
+
+    #[test]
+    fn test_bulk_load_rejects_bad_rows() {
+        let mut db = new_db();
+        db.insert("people", row(5, "existing")).unwrap();
+
+        assert!(db.bulk_load("people", vec![row(5, "again")]).is_err());
+        assert!(db
+            .bulk_load("people", vec![row(2, "b"), row(1, "a")])
+            .is_err());
+        let mut missing_column = Record::new();
+        missing_column.add_int64("id".to_string(), 1);
+        assert!(db.bulk_load("people", vec![missing_column]).is_err());
+        assert!(db.bulk_load("missing", vec![row(1, "a")]).is_err());
+
+        let scanned: Vec<Record> = db.scan("people").unwrap().collect();
+        assert_eq!(scanned, vec![row(5, "existing")]);
+    }
+}
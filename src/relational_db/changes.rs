@@ -0,0 +1,211 @@
+use std::{collections::HashMap, sync::mpsc::Receiver};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::kv_store::Change;
+use crate::prelude::*;
+
+use super::{records::Record, tables::TableDef, value::Value, DB, TABLE_DEF_TABLE};
+
+/// A committed write to a row of a table
+#[derive(Debug, Clone, PartialEq)]
+pub struct RowChange {
+    /// Sequence number of the change in the feed of the underlying `KV`
+    pub seq: u64,
+    pub table: String,
+    /// Every column of the row written, or only the primary key of the row deleted
+    pub record: Record,
+    pub deleted: bool,
+}
+
+/// Turns key value changes into row changes, knowing tables by their key prefix
+struct RowDecoder {
+    tables: HashMap<u32, TableDef>,
+}
+
+impl RowDecoder {
+    fn decode(&mut self, change: &Change) -> Option<RowChange> {
+        if change.key.len() < 4 {
+            return None;
+        }
+        let prefix = LittleEndian::read_u32(&change.key);
+        if prefix == TABLE_DEF_TABLE.prefix {
+            // tables created after the decoder are known from here on
+            if let Some(value) = &change.value {
+                let mut def = [Value::Bytes(None)];
+                DB::decode_values(value, &mut def);
+                let table_def = TableDef::from_json(def[0].bytes_to_string().ok()?);
+                self.tables.insert(table_def.prefix, table_def);
+            }
+            return None;
+        }
+        let table_def = self.tables.get(&prefix)?;
+
+        let mut values: Vec<Value> = table_def
+            .types
+            .iter()
+            .map(|&value_type| Value::u32_to_empty_value(value_type))
+            .collect();
+        let primary_keys = table_def.primary_keys;
+        DB::decode_values(&change.key[4..], &mut values[..primary_keys]);
+        let columns = match &change.value {
+            Some(value) => {
+                DB::decode_values(value, &mut values[primary_keys..]);
+                table_def.columns.len()
+            }
+            None => primary_keys,
+        };
+        values.truncate(columns);
+        Some(RowChange {
+            seq: change.seq,
+            table: table_def.name.clone(),
+            record: Record {
+                columns: table_def.columns[..columns].to_vec(),
+                values,
+            },
+            deleted: change.value.is_none(),
+        })
+    }
+}
+
+/// The row changes committed after subscribing, see `DB::subscribe_rows`
+pub struct RowFeed {
+    changes: Receiver<Change>,
+    decoder: RowDecoder,
+}
+
+impl RowFeed {
+    /** The next row change already committed, without waiting for one */
+    pub fn try_next(&mut self) -> Option<RowChange> {
+        while let Ok(change) = self.changes.try_recv() {
+            if let Some(row) = self.decoder.decode(&change) {
+                return Some(row);
+            }
+        }
+        None
+    }
+}
+
+impl Iterator for RowFeed {
+    type Item = RowChange;
+
+    /** Waits for the next row change, ends once the database is closed */
+    fn next(&mut self) -> Option<RowChange> {
+        while let Ok(change) = self.changes.recv() {
+            if let Some(row) = self.decoder.decode(&change) {
+                return Some(row);
+            }
+        }
+        None
+    }
+}
+
+impl DB {
+    fn row_decoder(&mut self) -> Result<RowDecoder> {
+        let mut tables = HashMap::new();
+        for record in self.scan(&TABLE_DEF_TABLE.name)? {
+            let json = record
+                .get("def")
+                .unwrap()
+                .bytes_to_string()
+                .map_err(|_| Error::Static("bad table definition"))?;
+            let table_def = TableDef::from_json(json);
+            tables.insert(table_def.prefix, table_def);
+        }
+        Ok(RowDecoder { tables })
+    }
+
+    /** Subscribes to the writes to table rows committed from now on, see `KV::subscribe`.
+     * Writes made to the key value store directly are left out */
+    pub fn subscribe_rows(&mut self) -> Result<RowFeed> {
+        let decoder = self.row_decoder()?;
+        Ok(RowFeed {
+            changes: self.kv.subscribe()?,
+            decoder,
+        })
+    }
+
+    /** Up to `limit` committed row changes after `cursor`, in order, see `KV::changes_since` */
+    pub fn row_changes_since(&mut self, mut cursor: u64, limit: usize) -> Result<Vec<RowChange>> {
+        let mut decoder = self.row_decoder()?;
+        let mut rows = Vec::new();
+        while rows.len() < limit {
+            let changes = self.kv.changes_since(cursor, limit)?;
+            let Some(last) = changes.last() else {
+                break;
+            };
+            cursor = last.seq;
+            rows.extend(
+                changes
+                    .iter()
+                    .filter_map(|change| decoder.decode(change))
+                    .take(limit - rows.len()),
+            );
+        }
+        Ok(rows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(db: &mut DB, name: &str) {
+        db.table_new(TableDef {
+            name: name.to_string(),
+            types: vec![Value::INT64_TYPE, Value::BYTES_TYPE],
+            columns: vec!["id".to_string(), "name".to_string()],
+            primary_keys: 1,
+            prefix: 0,
+        })
+        .unwrap();
+    }
+
+    fn row(id: i64, name: &str) -> Record {
+        let mut record = Record::new();
+        record
+            .add_int64("id".to_string(), id)
+            .add_bytes("name".to_string(), name.as_bytes().to_vec());
+        record
+    }
+
+    fn key(id: i64) -> Record {
+        let mut record = Record::new();
+        record.add_int64("id".to_string(), id);
+        record
+    }
+
+    #[test]
+    fn test_row_changes() {
+        let mut db = DB::open_in_memory().unwrap();
+        table(&mut db, "users");
+        db.kv_mut().enable_change_feed().unwrap();
+        let mut feed = db.subscribe_rows().unwrap();
+
+        db.insert("users", row(1, "ada")).unwrap();
+        // a table made after subscribing is picked up from the feed
+        table(&mut db, "teams");
+        db.insert("teams", row(7, "core")).unwrap();
+        db.update("users", row(1, "bob")).unwrap();
+        db.delete("users", key(1)).unwrap();
+        db.kv_mut().set(b"raw", b"value").unwrap();
+
+        let rows: Vec<(String, Record, bool)> = std::iter::from_fn(|| feed.try_next())
+            .map(|change| (change.table, change.record, change.deleted))
+            .collect();
+        let expected = vec![
+            ("users".to_string(), row(1, "ada"), false),
+            ("teams".to_string(), row(7, "core"), false),
+            ("users".to_string(), row(1, "bob"), false),
+            ("users".to_string(), key(1), true),
+        ];
+        assert_eq!(rows, expected);
+
+        // replaying gives the same rows, with the same sequence numbers
+        let replayed = db.row_changes_since(0, 100).unwrap();
+        assert_eq!(replayed.len(), 4);
+        assert_eq!(replayed[2].record, row(1, "bob"));
+        let rest = db.row_changes_since(replayed[1].seq, 1).unwrap();
+        assert_eq!(rest, replayed[2..3]);
+    }
+}
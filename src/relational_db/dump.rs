@@ -0,0 +1,448 @@
+use std::io::{BufRead, Write};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde_json::{json, Map, Value as JsonValue};
+
+use crate::b_tree::InsertMode;
+use crate::prelude::*;
+
+use super::{records::Record, tables::TableDef, value::Value, DB};
+
+/// Text formats for moving rows in and out of a table
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DumpFormat {
+    /// One JSON object per line keyed by column name, bytes are base64 encoded
+    JsonLines,
+    /// A header row of column names, bytes are written as is
+    Csv,
+}
+
+impl DB {
+    /** Writes every row of `table` to `out` in key order. Returns the number of rows */
+    pub fn export_table(
+        &mut self,
+        table: &str,
+        format: DumpFormat,
+        out: &mut dyn Write,
+    ) -> Result<usize> {
+        match format {
+            DumpFormat::JsonLines => {
+                let mut count = 0;
+                for record in self.scan(table)? {
+                    writeln!(out, "{}", JsonValue::Object(record_to_json(&record)))?;
+                    count += 1;
+                }
+                Ok(count)
+            }
+            DumpFormat::Csv => {
+                let scanner = self.scan(table)?;
+                let mut writer = csv::Writer::from_writer(out);
+                writer
+                    .write_record(&scanner.table_def().columns)
+                    .map_err(csv_error)?;
+
+                let mut count = 0;
+                for record in scanner {
+                    let fields = record.values.iter().map(|value| match value {
+                        Value::Int64(i) => i.unwrap().to_string().into_bytes(),
+                        Value::Bytes(b) => b.clone().unwrap(),
+                        Value::Error => panic!("Error encoding value"),
+                    });
+                    writer.write_record(fields).map_err(csv_error)?;
+                    count += 1;
+                }
+                writer.flush()?;
+                Ok(count)
+            }
+        }
+    }
+
+    /** Upserts the rows read from `input` into `table` in one commit, every column of the table
+     * has to be present. Nothing is written if a row is rejected. Returns the number of rows */
+    pub fn import_table(
+        &mut self,
+        table: &str,
+        format: DumpFormat,
+        input: &mut dyn BufRead,
+    ) -> Result<usize> {
+        let table_def = match self.get_table_def(table) {
+            Some(table_def) => table_def,
+            None => return Err(Error::Generic(format!("Table not found {}", table))),
+        };
+
+        let records = match format {
+            DumpFormat::JsonLines => {
+                let mut records = Vec::new();
+                for (i, line) in input.lines().enumerate() {
+                    let line = line?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let object = parse_object(&line, i + 1)?;
+                    records.push(record_from_json(&table_def, &object, i + 1)?);
+                }
+                records
+            }
+            DumpFormat::Csv => read_csv(&table_def, input)?,
+        };
+
+        self.batched(|db| {
+            for record in &records {
+                db.db_update(&table_def, record, InsertMode::Upsert)?;
+            }
+            Ok(records.len())
+        })
+    }
+
+    /** Writes every user table as JSON Lines: the `@table` definition of each table followed by
+     * its rows. `restore` rebuilds the tables from this in another database */
+    pub fn dump(&mut self, out: &mut dyn Write) -> Result<()> {
+        let table_defs: Vec<TableDef> = self
+            .scan("@table")?
+            .map(|record| {
+                TableDef::from_json(record.get("def").unwrap().bytes_to_string().unwrap())
+            })
+            .collect();
+
+        for table_def in table_defs {
+            let definition: JsonValue = serde_json::from_str(&table_def.to_json()?).unwrap();
+            writeln!(out, "{}", json!({ "table_def": definition }))?;
+            for record in self.scan(&table_def.name)? {
+                let row = JsonValue::Object(record_to_json(&record));
+                writeln!(out, "{}", json!({ "table": table_def.name, "row": row }))?;
+            }
+        }
+        Ok(())
+    }
+
+    /** Loads the output of `dump` in one commit. The tables must not exist yet, they are given
+     * new prefixes. Nothing is loaded if an entry is rejected */
+    pub fn restore(&mut self, input: &mut dyn BufRead) -> Result<()> {
+        self.batched(|db| {
+            for (i, line) in input.lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let object = parse_object(&line, i + 1)?;
+
+                if let Some(definition) = object.get("table_def") {
+                    let mut table_def = TableDef::from_json(definition.to_string());
+                    table_def.prefix = 0;
+                    db.table_new(table_def)?;
+                } else if let (Some(JsonValue::String(table)), Some(JsonValue::Object(row))) =
+                    (object.get("table"), object.get("row"))
+                {
+                    let table_def = match db.get_table_def(table) {
+                        Some(table_def) => table_def,
+                        None => return Err(Error::Generic(format!("Table not found {}", table))),
+                    };
+                    let record = record_from_json(&table_def, row, i + 1)?;
+                    db.db_update(&table_def, &record, InsertMode::Upsert)?;
+                } else {
+                    return Err(Error::Generic(format!("line {}: unknown entry", i + 1)));
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+fn csv_error(err: csv::Error) -> Error {
+    Error::Generic(format!("csv: {}", err))
+}
+
+fn parse_object(line: &str, line_number: usize) -> Result<Map<String, JsonValue>> {
+    match serde_json::from_str(line) {
+        Ok(JsonValue::Object(object)) => Ok(object),
+        Ok(_) => Err(Error::Generic(format!(
+            "line {}: expected a JSON object",
+            line_number
+        ))),
+        Err(err) => Err(Error::Generic(format!("line {}: {}", line_number, err))),
+    }
+}
+
+fn record_to_json(record: &Record) -> Map<String, JsonValue> {
+    let mut object = Map::new();
+    for (column, value) in record.columns.iter().zip(&record.values) {
+        let value = match value {
+            Value::Int64(i) => json!(i.unwrap()),
+            Value::Bytes(b) => json!(BASE64.encode(b.as_ref().unwrap())),
+            Value::Error => panic!("Error encoding value"),
+        };
+        object.insert(column.clone(), value);
+    }
+    object
+}
+
+fn record_from_json(
+    table_def: &TableDef,
+    object: &Map<String, JsonValue>,
+    line_number: usize,
+) -> Result<Record> {
+    if let Some(column) = object.keys().find(|key| !table_def.columns.contains(key)) {
+        return Err(Error::Generic(format!(
+            "line {}: unknown column: {}",
+            line_number, column
+        )));
+    }
+
+    let mut record = Record::new();
+    for (column, &value_type) in table_def.columns.iter().zip(&table_def.types) {
+        let value = match object.get(column) {
+            Some(value) => value,
+            None => {
+                return Err(Error::Generic(format!(
+                    "line {}: missing column: {}",
+                    line_number, column
+                )))
+            }
+        };
+        let bad_value = || {
+            Error::Generic(format!(
+                "line {}: bad value for column: {}",
+                line_number, column
+            ))
+        };
+
+        match value_type {
+            Value::INT64_TYPE => {
+                record.add_int64(column.clone(), value.as_i64().ok_or_else(bad_value)?);
+            }
+            Value::BYTES_TYPE => {
+                let encoded = value.as_str().ok_or_else(bad_value)?;
+                let bytes = BASE64.decode(encoded).map_err(|_| bad_value())?;
+                record.add_bytes(column.clone(), bytes);
+            }
+            _ => return Err(bad_value()),
+        }
+    }
+    Ok(record)
+}
+
+fn read_csv(table_def: &TableDef, input: &mut dyn BufRead) -> Result<Vec<Record>> {
+    let mut reader = csv::Reader::from_reader(input);
+    let headers = reader.byte_headers().map_err(csv_error)?.clone();
+
+    // position of each column of the table in the file
+    let mut positions = Vec::new();
+    for column in &table_def.columns {
+        match headers
+            .iter()
+            .position(|header| header == column.as_bytes())
+        {
+            Some(position) => positions.push(position),
+            None => return Err(Error::Generic(format!("missing column: {}", column))),
+        }
+    }
+    if headers.len() != table_def.columns.len() {
+        return Err(Error::Static("unknown column in the csv header"));
+    }
+
+    let mut records = Vec::new();
+    for row in reader.byte_records() {
+        let row = row.map_err(csv_error)?;
+        let line_number = row.position().map_or(0, |position| position.line());
+
+        let mut record = Record::new();
+        for (i, column) in table_def.columns.iter().enumerate() {
+            let field = &row[positions[i]];
+            match table_def.types[i] {
+                Value::INT64_TYPE => {
+                    let parsed = std::str::from_utf8(field)
+                        .ok()
+                        .and_then(|text| text.parse().ok());
+                    match parsed {
+                        Some(int) => record.add_int64(column.clone(), int),
+                        None => {
+                            return Err(Error::Generic(format!(
+                                "line {}: bad value for column: {}",
+                                line_number, column
+                            )))
+                        }
+                    };
+                }
+                _ => {
+                    record.add_bytes(column.clone(), field.to_vec());
+                }
+            }
+        }
+        records.push(record);
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn new_db() -> DB {
+        let mut db = DB::open_in_memory().unwrap();
+        db.table_new(TableDef {
+            name: "people".to_string(),
+            types: vec![Value::INT64_TYPE, Value::BYTES_TYPE, Value::BYTES_TYPE],
+            columns: vec!["id".to_string(), "name".to_string(), "bio".to_string()],
+            primary_keys: 1,
+            prefix: 0,
+        })
+        .unwrap();
+        db
+    }
+
+    fn row(id: i64, name: &str, bio: &[u8]) -> Record {
+        let mut record = Record::new();
+        record
+            .add_int64("id".to_string(), id)
+            .add_bytes("name".to_string(), name.as_bytes().to_vec())
+            .add_bytes("bio".to_string(), bio.to_vec());
+        record
+    }
+
+    fn rows() -> Vec<Record> {
+        vec![
+            row(-5, "negative", b""),
+            row(1, "plain", b"hello"),
+            row(2, "quoted, \"comma\"", b"line\nbreak"),
+            row(3, "binary", &[0, 1, 2, 0xff]),
+        ]
+    }
+
+    // rows come back in key order, where negative integers sort last
+    fn scan_by_id(db: &mut DB) -> Vec<Record> {
+        let mut records: Vec<Record> = db.scan("people").unwrap().collect();
+        records.sort_by_key(|record| record.get("id").unwrap().get_int64().unwrap());
+        records
+    }
+
+    fn fill(db: &mut DB) {
+        for record in rows() {
+            db.insert("people", record).unwrap();
+        }
+    }
+
+    fn round_trip(format: DumpFormat) {
+        let mut db = new_db();
+        fill(&mut db);
+
+        let mut out = Vec::new();
+        assert_eq!(db.export_table("people", format, &mut out).unwrap(), 4);
+
+        let mut copy = new_db();
+        let syncs = copy.kv.stats().syncs;
+        let count = copy
+            .import_table("people", format, &mut Cursor::new(out))
+            .unwrap();
+        assert_eq!(count, 4);
+        // in a single commit
+        assert_eq!(copy.kv.stats().syncs, syncs + 2);
+        assert_eq!(scan_by_id(&mut copy), rows());
+    }
+
+    #[test]
+    fn test_json_lines_round_trip() {
+        round_trip(DumpFormat::JsonLines);
+    }
+
+    #[test]
+    fn test_csv_round_trip() {
+        round_trip(DumpFormat::Csv);
+    }
+
+    #[test]
+    fn test_export_json_lines() {
+        let mut db = new_db();
+        db.insert("people", row(1, "a", b"hi")).unwrap();
+
+        let mut out = Vec::new();
+        db.export_table("people", DumpFormat::JsonLines, &mut out)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "{\"bio\":\"aGk=\",\"id\":1,\"name\":\"YQ==\"}\n"
+        );
+    }
+
+    #[test]
+    fn test_import_csv_with_reordered_header() {
+        let mut db = new_db();
+        let input = "name,bio,id\nx,y,7\n";
+        db.import_table("people", DumpFormat::Csv, &mut Cursor::new(input))
+            .unwrap();
+
+        let mut record = Record::new();
+        record.add_int64("id".to_string(), 7);
+        assert!(db.get("people", &mut record).unwrap());
+        assert_eq!(record.get("name"), Some(&Value::Bytes(Some(b"x".to_vec()))));
+    }
+
+    #[test]
+    fn test_import_errors() {
+        let mut db = new_db();
+        let bad_inputs = [
+            (DumpFormat::JsonLines, "{\"id\":1,\"name\":\"YQ==\"}"),
+            (
+                DumpFormat::JsonLines,
+                "{\"id\":\"1\",\"name\":\"YQ==\",\"bio\":\"\"}",
+            ),
+            (
+                DumpFormat::JsonLines,
+                "{\"id\":1,\"name\":\"not base64!\",\"bio\":\"\"}",
+            ),
+            (
+                DumpFormat::JsonLines,
+                "{\"id\":1,\"name\":\"\",\"bio\":\"\",\"age\":3}",
+            ),
+            (DumpFormat::JsonLines, "[1, 2, 3]"),
+            (DumpFormat::Csv, "id,name\n1,a\n"),
+            (DumpFormat::Csv, "id,name,bio,age\n1,a,b,3\n"),
+            (DumpFormat::Csv, "id,name,bio\none,a,b\n"),
+        ];
+        for (format, input) in bad_inputs {
+            let result = db.import_table("people", format, &mut Cursor::new(input));
+            assert!(result.is_err(), "{}", input);
+        }
+        assert!(db
+            .import_table("missing", DumpFormat::Csv, &mut Cursor::new(""))
+            .is_err());
+        // a row rejected while writing drops the ones before it too
+        let too_large = format!("id,name,bio\n1,a,b\n2,a,{}\n", "b".repeat(3000));
+        assert!(db
+            .import_table("people", DumpFormat::Csv, &mut Cursor::new(too_large))
+            .is_err());
+        assert_eq!(db.scan("people").unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_dump_and_restore() {
+        let mut db = new_db();
+        fill(&mut db);
+        db.table_new(TableDef {
+            name: "empty".to_string(),
+            types: vec![Value::BYTES_TYPE],
+            columns: vec!["key".to_string()],
+            primary_keys: 1,
+            prefix: 0,
+        })
+        .unwrap();
+
+        let mut out = Vec::new();
+        db.dump(&mut out).unwrap();
+
+        // a bad entry at the end leaves nothing behind
+        let mut copy = DB::open_in_memory().unwrap();
+        let mut broken = out.clone();
+        broken.extend_from_slice(b"{}\n");
+        assert!(copy.restore(&mut Cursor::new(&broken)).is_err());
+        assert!(copy.scan("people").is_err());
+
+        copy.restore(&mut Cursor::new(&out)).unwrap();
+        assert_eq!(scan_by_id(&mut copy), rows());
+        assert_eq!(copy.scan("empty").unwrap().count(), 0);
+
+        // the tables already exist now
+        assert!(copy.restore(&mut Cursor::new(&out)).is_err());
+    }
+}
@@ -1,16 +1,28 @@
 use std::collections::HashMap;
 
 use crate::prelude::*;
-use crate::{b_tree::InsertMode, kv_store::KV};
-
+use crate::{
+    b_tree::{
+        b_node::{BTREE_MAX_KEY_SIZE, BTREE_MAX_VAL_SIZE},
+        InsertMode,
+    },
+    kv_store::{KVOptions, KV},
+};
+
+pub mod bulk;
+pub mod changes;
+pub mod dump;
+pub mod mvcc;
 pub mod records;
+pub mod scanner;
+pub mod stats;
 pub mod tables;
 pub mod value;
 
 use byteorder::{BigEndian, ByteOrder, LittleEndian};
 use records::Record;
 
-use self::{tables::TableDef, value::Value};
+use self::{mvcc::Versions, tables::TableDef, value::Value};
 
 lazy_static! {
     pub static ref TABLE_DEF_META: TableDef = TableDef {
@@ -27,10 +39,32 @@ lazy_static! {
         columns: vec!["name".to_string(), "def".to_string()],
         primary_keys: 1,
     };
+    /// Row values replaced while transactions were open, by row key and the version of the commit
+    /// that replaced them, split into parts that each fit a row, see `mvcc`
+    pub static ref TABLE_DEF_HISTORY: TableDef = TableDef {
+        prefix: 3,
+        name: "@history".to_string(),
+        types: vec![
+            Value::BYTES_TYPE,
+            Value::INT64_TYPE,
+            Value::INT64_TYPE,
+            Value::INT64_TYPE,
+            Value::BYTES_TYPE
+        ],
+        columns: vec![
+            "key".to_string(),
+            "until".to_string(),
+            "part".to_string(),
+            "deleted".to_string(),
+            "val".to_string()
+        ],
+        primary_keys: 3,
+    };
     pub static ref INTERNAL_TABLES: HashMap<String, TableDef> = {
         let mut m = HashMap::new();
         m.insert(TABLE_DEF_META.name.clone(), TABLE_DEF_META.clone());
         m.insert(TABLE_DEF_TABLE.name.clone(), TABLE_DEF_TABLE.clone());
+        m.insert(TABLE_DEF_HISTORY.name.clone(), TABLE_DEF_HISTORY.clone());
         m
     };
 }
@@ -42,9 +76,59 @@ pub struct DB {
     // internals
     kv: KV,
     tables: HashMap<String, TableDef>,
+    /// What open transactions need to read the rows as of when they started
+    versions: Versions,
 }
 
 impl DB {
+    pub fn open(path: String) -> Result<DB> {
+        DB::open_with(path, KVOptions::default())
+    }
+
+    /** Opens the database with the given options, see `KV::open_with` */
+    pub fn open_with(path: String, options: KVOptions) -> Result<DB> {
+        let mut db = DB {
+            kv: KV::open_with(path.clone(), options)?,
+            path,
+            tables: HashMap::new(),
+            versions: Versions::default(),
+        };
+        if !db.kv.is_read_only() {
+            // left behind by transactions open when the database was last closed
+            db.clear_history()?;
+        }
+        Ok(db)
+    }
+
+    /** Opens an empty database held in memory, see `KV::open_in_memory` */
+    pub fn open_in_memory() -> Result<DB> {
+        Ok(DB {
+            path: ":memory:".to_string(),
+            kv: KV::open_in_memory()?,
+            tables: HashMap::new(),
+            versions: Versions::default(),
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn close(self) {
+        self.kv.close();
+    }
+
+    /** The underlying key value store */
+    pub fn kv(&self) -> &KV {
+        &self.kv
+    }
+
+    /** The underlying key value store. Writing to it directly bypasses the table definitions and
+     * the versions open transactions read */
+    pub fn kv_mut(&mut self) -> &mut KV {
+        &mut self.kv
+    }
+
     pub fn get(&mut self, table: &str, record: &mut Record) -> Result<bool> {
         match self.get_table_def(table) {
             Some(table_def) => self.db_get(&table_def, record),
@@ -55,7 +139,7 @@ impl DB {
     /// Retrieve value from kv store itself
     /// TODO: Don't return bool, return Record (make Record immutable)
     fn db_get(&self, table_def: &TableDef, record: &mut Record) -> Result<bool> {
-        let mut values: Vec<Value> = table_def.check_record(record, table_def.primary_keys)?;
+        let values: Vec<Value> = table_def.check_record(record, table_def.primary_keys)?;
 
         let key: Vec<u8> =
             DB::encode_key(None, table_def.prefix, &values[..table_def.primary_keys]);
@@ -63,20 +147,28 @@ impl DB {
         if value_raw.is_none() {
             return Ok(false);
         }
-        let value_raw = value_raw.unwrap();
+        DB::decode_columns(table_def, record, values, &value_raw.unwrap());
+        Ok(true)
+    }
 
+    /** Adds the columns after the primary key to `record`, decoded from the value of its row */
+    fn decode_columns(
+        table_def: &TableDef,
+        record: &mut Record,
+        mut values: Vec<Value>,
+        value_raw: &[u8],
+    ) {
         (table_def.primary_keys..table_def.columns.len()).for_each(|i| {
             values[i] = Value::u32_to_empty_value(table_def.types[i]);
         });
 
-        DB::decode_values(&value_raw, &mut values[table_def.primary_keys..]);
+        DB::decode_values(value_raw, &mut values[table_def.primary_keys..]);
         record
             .columns
             .extend(table_def.columns[table_def.primary_keys..].iter().cloned());
         record
             .values
             .extend(values[table_def.primary_keys..].iter().cloned());
-        Ok(true)
     }
 
     pub fn db_update(
@@ -86,6 +178,7 @@ impl DB {
         mode: InsertMode,
     ) -> Result<bool> {
         let values: Vec<Value> = table_def.check_record(record, table_def.columns.len())?;
+        DB::check_row_size(table_def, &values)?;
 
         let key = DB::encode_key(
             None,
@@ -94,6 +187,18 @@ impl DB {
         );
 
         let value = DB::encode_values(None, &values[table_def.primary_keys..]);
+        if self.versioned(table_def) {
+            let existed = self.kv.get(&key).is_some();
+            let written = match mode {
+                InsertMode::Upsert => true,
+                InsertMode::UpdateOnly => existed,
+                InsertMode::InsertOnly => !existed,
+            };
+            if written {
+                self.commit_writes([(key, Some(value))].into())?;
+            }
+            return Ok(written && !existed);
+        }
         self.kv.update(&key, &value, mode)
     }
 
@@ -125,6 +230,13 @@ impl DB {
             values[..table_def.primary_keys].as_ref(),
         );
 
+        if self.versioned(table_def) {
+            let existed = self.kv.get(&key).is_some();
+            if existed {
+                self.commit_writes([(key, None)].into())?;
+            }
+            return Ok(existed);
+        }
         self.kv.del(&key)
     }
 
@@ -143,7 +255,22 @@ impl DB {
         DB::encode_values(Some(out), values)
     }
 
-    fn decode_values(in_bytes: &Vec<u8>, values_out: &mut [Value]) {
+    /** Fails for a row, given as every value in table order, whose encoded key or value is too
+     * large to store */
+    pub fn check_row_size(table_def: &TableDef, values: &[Value]) -> Result<()> {
+        let (key, value) = values.split_at(table_def.primary_keys);
+        let key_len = DB::encode_key(None, table_def.prefix, key).len();
+        let value_len = DB::encode_values(None, value).len();
+        if key_len > BTREE_MAX_KEY_SIZE || value_len > BTREE_MAX_VAL_SIZE {
+            return Err(Error::Generic(format!(
+                "row of {} is too large: its key takes {} of {} bytes and its value {} of {}",
+                table_def.name, key_len, BTREE_MAX_KEY_SIZE, value_len, BTREE_MAX_VAL_SIZE
+            )));
+        }
+        Ok(())
+    }
+
+    fn decode_values(in_bytes: &[u8], values_out: &mut [Value]) {
         let mut pos = 0;
         for value in values_out.iter_mut() {
             match value {
@@ -189,6 +316,11 @@ impl DB {
         out
     }
 
+    /** Returns the definition of `table`, including the internal tables */
+    pub fn table_def(&mut self, table: &str) -> Option<TableDef> {
+        self.get_table_def(table)
+    }
+
     /** Checks if the table definition is loaded in the DB, if it is not in memory then it trys to pull the table from storage  */
     fn get_table_def(&mut self, table: &str) -> Option<TableDef> {
         // Expose internal tables
@@ -214,7 +346,7 @@ impl DB {
         record.add_bytes("name".to_string(), table.as_bytes().to_vec());
 
         let get_result = self.db_get(&TABLE_DEF_TABLE, &mut record);
-        if get_result.is_err() {
+        if !matches!(get_result, Ok(true)) {
             return None;
         }
 
@@ -278,7 +410,7 @@ mod tests {
 
     use crate::{b_tree::InsertMode, kv_store::KV, relational_db::value::Value};
 
-    use super::{records::Record, tables::TableDef, DB, TABLE_DEF_META};
+    use super::{mvcc::Versions, records::Record, tables::TableDef, DB, TABLE_DEF_META};
     use std::collections::HashMap;
 
     struct R {
@@ -298,12 +430,20 @@ mod tests {
                 path: file_name.clone(),
                 kv: KV::open(file_name).unwrap(),
                 tables: HashMap::new(),
+                versions: Versions::default(),
             };
             let reference = HashMap::new();
 
             R { db, reference }
         }
 
+        fn new_in_memory() -> R {
+            R {
+                db: DB::open_in_memory().unwrap(),
+                reference: HashMap::new(),
+            }
+        }
+
         pub fn create(&mut self, table_def: TableDef) {
             let result = self.db.table_new(table_def);
             assert!(result.is_ok());
@@ -553,6 +693,44 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_table_in_memory() {
+        let mut r = R::new_in_memory();
+        assert_eq!(r.db.path(), ":memory:");
+
+        let table_def = TableDef {
+            name: "tbl_test".to_string(),
+            columns: vec!["k".to_string(), "v".to_string()],
+            types: vec![2, 1],
+            primary_keys: 1,
+            prefix: 0,
+        };
+        r.create(table_def);
+
+        for i in 0..100 {
+            let mut rec = Record::new();
+            rec.add_int64("k".to_string(), i)
+                .add_bytes("v".to_string(), format!("value{}", i).into_bytes());
+            assert!(r.add("tbl_test", rec));
+        }
+
+        for i in 0..100 {
+            let mut rec = Record::new();
+            rec.add_int64("k".to_string(), i);
+            assert!(r.get("tbl_test", &mut rec));
+        }
+
+        for i in (0..100).step_by(2) {
+            let mut rec = Record::new();
+            rec.add_int64("k".to_string(), i);
+            assert!(r.del("tbl_test", rec));
+        }
+
+        let mut rec = Record::new();
+        rec.add_int64("k".to_string(), 2);
+        assert!(!r.get("tbl_test", &mut rec));
+    }
+
     // func TestTableEncoding(t *testing.T) {
     // 	input := []int{-1, 0, +1, math.MinInt64, math.MaxInt64}
     // 	sort.Ints(input)
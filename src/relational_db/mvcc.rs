@@ -0,0 +1,921 @@
+//! Transactions over table rows with multi-version concurrency control. A transaction reads the
+//! rows as of the last commit before it started, however many commits follow, and keeps its
+//! writes to itself until it commits. Commits are numbered, and while any transaction is open
+//! each commit saves the values it replaces to the `@history` table, keyed by the row key and the
+//! version of the commit. A value is saved in parts, since escaping it for the table can make it
+//! larger than a row. What a commit saved is deleted once every open transaction started after
+//! it.
+
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use crate::b_tree::{
+    b_node::{BTREE_MAX_KEY_SIZE, BTREE_MAX_VAL_SIZE},
+    InsertMode,
+};
+use crate::kv_store::{self, CmpOption, KVIterator};
+use crate::prelude::*;
+
+use super::{
+    records::Record, scanner::decode_row, tables::TableDef, value::Value, DB, TABLE_DEF_HISTORY,
+};
+
+/// What a transaction checks for before committing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Isolation {
+    /// Fails if another transaction committed a row it writes since it started
+    #[default]
+    Snapshot,
+    /// Also fails if another committed a row it read, or a row of a table it scanned. Its
+    /// commit then has the same effect as running it alone at that point
+    Serializable,
+}
+
+/// Start versions of the open transactions, with how many started at each
+type Readers = Arc<Mutex<BTreeMap<u64, usize>>>;
+
+/// Bytes of a replaced value saved in each history row. Escaping at most doubles them, and the
+/// row adds the deleted flag and a terminator
+const HISTORY_PART_SIZE: usize = (BTREE_MAX_VAL_SIZE - 8 - 1) / 2;
+
+/// A commit made while transactions were open
+#[derive(Clone)]
+struct Commit {
+    version: u64,
+    /// Keys written, along with the number of history rows the value they replaced was saved in
+    keys: Vec<(Vec<u8>, usize)>,
+}
+
+/// The state of the database to go back to, see `DB::savepoint`
+pub struct Savepoint {
+    kv: kv_store::Savepoint,
+    version: u64,
+    latest: BTreeMap<Vec<u8>, u64>,
+    commits: VecDeque<Commit>,
+}
+
+/// The versions open transactions read the rows at
+#[derive(Default)]
+pub(super) struct Versions {
+    /// Version of the last commit
+    version: u64,
+    readers: Readers,
+    /// Version of the last commit of each key written since the oldest open transaction started
+    latest: BTreeMap<Vec<u8>, u64>,
+    /// Commits some open transaction started before, oldest first
+    commits: VecDeque<Commit>,
+}
+
+impl Versions {
+    fn oldest_reader(&self) -> Option<u64> {
+        self.readers.lock().unwrap().keys().next().copied()
+    }
+
+    /** Whether a transaction open now started at or after `version` */
+    fn read_since(&self, version: u64) -> bool {
+        self.readers
+            .lock()
+            .unwrap()
+            .range(version..)
+            .next()
+            .is_some()
+    }
+
+    /** Whether `key` was committed by a transaction that started after `start` */
+    fn committed_after(&self, key: &[u8], start: u64) -> bool {
+        self.latest.get(key).is_some_and(|&version| version > start)
+    }
+}
+
+/// Counts a transaction as open until it is dropped
+struct Reader {
+    start: u64,
+    readers: Readers,
+}
+
+impl Drop for Reader {
+    fn drop(&mut self) {
+        let mut readers = self.readers.lock().unwrap();
+        let count = readers.get_mut(&self.start).unwrap();
+        *count -= 1;
+        if *count == 0 {
+            readers.remove(&self.start);
+        }
+    }
+}
+
+/// Reads and writes of table rows isolated from other transactions, see `DB::begin`. Dropping it
+/// without committing rolls it back
+pub struct Transaction {
+    isolation: Isolation,
+    reader: Reader,
+    /// Pending writes by row key, `None` deletes the row
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    /// Row keys read from the database, only kept when serializable
+    reads: HashSet<Vec<u8>>,
+    /// Key prefixes of the tables scanned, only kept when serializable
+    scans: Vec<Vec<u8>>,
+}
+
+impl Transaction {
+    /** Version of the last commit the transaction sees */
+    pub fn start(&self) -> u64 {
+        self.reader.start
+    }
+
+    pub fn isolation(&self) -> Isolation {
+        self.isolation
+    }
+
+    /** The value of a row as the transaction sees it, its own writes included */
+    fn read(&mut self, db: &DB, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(write) = self.writes.get(key) {
+            return Ok(write.clone());
+        }
+        if self.isolation == Isolation::Serializable {
+            self.reads.insert(key.to_vec());
+        }
+        db.read_version(key, self.start())
+    }
+
+    /** Looks up the row with the primary key of `record`, see `DB::get` */
+    pub fn get(&mut self, db: &mut DB, table: &str, record: &mut Record) -> Result<bool> {
+        let table_def = match db.get_table_def(table) {
+            Some(table_def) => table_def,
+            None => return Err(Error::Generic(format!("Table not found {}", table))),
+        };
+        let values = table_def.check_record(record, table_def.primary_keys)?;
+        let key = DB::encode_key(None, table_def.prefix, &values[..table_def.primary_keys]);
+        match self.read(db, &key)? {
+            Some(value_raw) => {
+                DB::decode_columns(&table_def, record, values, &value_raw);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn set(&mut self, db: &mut DB, table: &str, record: Record, mode: InsertMode) -> Result<bool> {
+        let table_def = match db.get_table_def(table) {
+            Some(table_def) => table_def,
+            None => return Err(Error::Generic(format!("Table not found {}", table))),
+        };
+        let values = table_def.check_record(&record, table_def.columns.len())?;
+        DB::check_row_size(&table_def, &values)?;
+        let key = DB::encode_key(None, table_def.prefix, &values[..table_def.primary_keys]);
+        let value = DB::encode_values(None, &values[table_def.primary_keys..]);
+
+        let existed = self.read(db, &key)?.is_some();
+        let written = match mode {
+            InsertMode::Upsert => true,
+            InsertMode::UpdateOnly => existed,
+            InsertMode::InsertOnly => !existed,
+        };
+        if written {
+            self.writes.insert(key, Some(value));
+        }
+        Ok(written && !existed)
+    }
+
+    pub fn insert(&mut self, db: &mut DB, table: &str, record: Record) -> Result<bool> {
+        self.set(db, table, record, InsertMode::InsertOnly)
+    }
+
+    pub fn update(&mut self, db: &mut DB, table: &str, record: Record) -> Result<bool> {
+        self.set(db, table, record, InsertMode::UpdateOnly)
+    }
+
+    pub fn upsert(&mut self, db: &mut DB, table: &str, record: Record) -> Result<bool> {
+        self.set(db, table, record, InsertMode::Upsert)
+    }
+
+    pub fn delete(&mut self, db: &mut DB, table: &str, record: Record) -> Result<bool> {
+        let table_def = match db.get_table_def(table) {
+            Some(table_def) => table_def,
+            None => return Err(Error::Generic(format!("Table not found {}", table))),
+        };
+        let values = table_def.check_record(&record, table_def.primary_keys)?;
+        let key = DB::encode_key(None, table_def.prefix, &values[..table_def.primary_keys]);
+
+        let existed = self.read(db, &key)?.is_some();
+        if existed {
+            self.writes.insert(key, None);
+        }
+        Ok(existed)
+    }
+
+    /** Returns an iterator over the rows of `table` as the transaction sees them */
+    pub fn scan<'a>(&'a mut self, db: &'a mut DB, table: &str) -> Result<TxScanner<'a>> {
+        let table_def = match db.get_table_def(table) {
+            Some(table_def) => table_def,
+            None => return Err(Error::Generic(format!("Table not found {}", table))),
+        };
+        let prefix = DB::encode_key(None, table_def.prefix, &[]);
+        if self.isolation == Isolation::Serializable {
+            self.scans.push(prefix.clone());
+        }
+
+        let (db, tx): (&DB, &Transaction) = (db, self);
+        let start = tx.start();
+        let in_table = |key: &&Vec<u8>| key.starts_with(&prefix);
+        let mut changed: Vec<Vec<u8>> = db
+            .versions
+            .latest
+            .range(prefix.clone()..)
+            .map(|(key, _)| key)
+            .take_while(in_table)
+            .filter(|key| db.versions.committed_after(key, start))
+            .chain(tx.writes.range(prefix.clone()..).map(|(key, _)| key))
+            .take_while(in_table)
+            .cloned()
+            .collect();
+        changed.sort_unstable();
+        changed.dedup();
+
+        Ok(TxScanner {
+            db,
+            tx,
+            iter: db.kv.seek(&prefix, CmpOption::GE),
+            table_def,
+            prefix,
+            changed: changed.into(),
+        })
+    }
+
+    /** Applies the writes in a single commit and returns its version, or the version the
+     * transaction started at when it wrote nothing. Fails with `Error::Conflict` and writes
+     * nothing if a commit made since it started breaks its isolation */
+    pub fn commit(self, db: &mut DB) -> Result<u64> {
+        let start = self.start();
+        if self.writes.is_empty() {
+            // what it read is the database as of a commit
+            return Ok(start);
+        }
+
+        let versions = &db.versions;
+        let mut conflict = self
+            .writes
+            .keys()
+            .any(|key| versions.committed_after(key, start));
+        if self.isolation == Isolation::Serializable {
+            conflict = conflict
+                || self
+                    .reads
+                    .iter()
+                    .any(|key| versions.committed_after(key, start))
+                || self.scans.iter().any(|prefix| {
+                    versions
+                        .latest
+                        .range(prefix.clone()..)
+                        .take_while(|(key, _)| key.starts_with(prefix))
+                        .any(|(_, &version)| version > start)
+                });
+        }
+        if conflict {
+            return Err(Error::Conflict(format!(
+                "rows the transaction depends on were committed after version {}",
+                start
+            )));
+        }
+
+        // only the other transactions still read the values this commit replaces
+        let Transaction { reader, writes, .. } = self;
+        drop(reader);
+        db.commit_writes(writes)
+    }
+}
+
+/// Iterates over the rows of a table as a transaction sees them, see `Transaction::scan`
+pub struct TxScanner<'a> {
+    db: &'a DB,
+    tx: &'a Transaction,
+    iter: KVIterator<'a>,
+    table_def: TableDef,
+    /// Key prefix shared by every row of the table
+    prefix: Vec<u8>,
+    /// Rows the transaction wrote or that were committed after it started, in key order. The
+    /// tree does not hold them as the transaction sees them
+    changed: VecDeque<Vec<u8>>,
+}
+
+impl TxScanner<'_> {
+    /** The next row in the tree, `None` past the last row of the table */
+    fn stored(&self) -> Option<(Vec<u8>, Vec<u8>)> {
+        if !self.iter.valid() {
+            return None;
+        }
+        let (key, val) = self.iter.deref();
+        key.starts_with(&self.prefix).then_some((key, val))
+    }
+
+    fn advance(&mut self) {
+        if !self.iter.next() {
+            self.iter.invalidate();
+        }
+    }
+}
+
+impl Iterator for TxScanner<'_> {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Result<Record>> {
+        loop {
+            let stored = self.stored();
+            let (key, val) = match (stored, self.changed.front()) {
+                (None, None) => return None,
+                (Some((key, val)), changed) if changed.is_none_or(|changed| key < *changed) => {
+                    self.advance();
+                    (key, Some(val))
+                }
+                (stored, _) => {
+                    let key = self.changed.pop_front().unwrap();
+                    if stored.is_some_and(|(stored, _)| stored == key) {
+                        self.advance();
+                    }
+                    let val = match self.tx.writes.get(&key) {
+                        Some(write) => write.clone(),
+                        None => match self.db.read_version(&key, self.tx.start()) {
+                            Ok(val) => val,
+                            Err(err) => return Some(Err(err)),
+                        },
+                    };
+                    (key, val)
+                }
+            };
+            if let Some(val) = val {
+                return Some(Ok(decode_row(&self.table_def, &key, &val)));
+            }
+        }
+    }
+}
+
+fn history_prefix(key: &[u8]) -> Vec<u8> {
+    DB::encode_key(
+        None,
+        TABLE_DEF_HISTORY.prefix,
+        &[Value::Bytes(Some(key.to_vec()))],
+    )
+}
+
+fn history_key(key: &[u8], until: u64, part: usize) -> Vec<u8> {
+    DB::encode_values(
+        Some(history_prefix(key)),
+        &[
+            Value::Int64(Some(until as i64)),
+            Value::Int64(Some(part as i64)),
+        ],
+    )
+}
+
+/** Fails for a row key too long to key the history of the row with. Only writes that save the
+ * value they replace need it */
+fn check_history_key(key: &[u8]) -> Result<()> {
+    let size = history_prefix(key).len() + 16;
+    if size > BTREE_MAX_KEY_SIZE {
+        return Err(Error::Generic(format!(
+            "row key of {} bytes is too large to replace while transactions read it: its history \
+             key takes {} of {} bytes",
+            key.len(),
+            size,
+            BTREE_MAX_KEY_SIZE
+        )));
+    }
+    Ok(())
+}
+
+impl DB {
+    /** Starts a transaction with snapshot isolation */
+    pub fn begin(&self) -> Transaction {
+        self.begin_with(Isolation::Snapshot)
+    }
+
+    /** Starts a transaction. It sees every commit made before and none made after, and the
+     * values it needs are kept for as long as it is open */
+    pub fn begin_with(&self, isolation: Isolation) -> Transaction {
+        let start = self.versions.version;
+        *self
+            .versions
+            .readers
+            .lock()
+            .unwrap()
+            .entry(start)
+            .or_default() += 1;
+        Transaction {
+            isolation,
+            reader: Reader {
+                start,
+                readers: self.versions.readers.clone(),
+            },
+            writes: BTreeMap::new(),
+            reads: HashSet::new(),
+            scans: Vec::new(),
+        }
+    }
+
+    /** Whether any transaction is open */
+    pub fn transactions_open(&self) -> bool {
+        self.versions.oldest_reader().is_some()
+    }
+
+    /** Whether writes to the rows of `table_def` go through the versions, to keep the values
+     * open transactions read or to collect the ones they no longer do */
+    pub(super) fn versioned(&self, table_def: &TableDef) -> bool {
+        table_def.prefix != TABLE_DEF_HISTORY.prefix
+            && (self.transactions_open() || !self.versions.commits.is_empty())
+    }
+
+    /** The value of a row as of version `start` */
+    fn read_version(&self, key: &[u8], start: u64) -> Result<Option<Vec<u8>>> {
+        if !self.versions.committed_after(key, start) {
+            return Ok(self.kv.get(key));
+        }
+        // the value at `start` was replaced by the first commit after it
+        let prefix = history_prefix(key);
+        let mut iter = self.kv.seek(&history_key(key, start + 1, 0), CmpOption::GE);
+        if !iter.valid() || !iter.deref().0.starts_with(&prefix) {
+            return Err(Error::Static(
+                "missing history for a row read by an open transaction",
+            ));
+        }
+        // the parts saved by that commit
+        let commit_prefix = iter.deref().0[..prefix.len() + 8].to_vec();
+        let mut saved = Vec::new();
+        while iter.valid() {
+            let (history_key, val) = iter.deref();
+            if !history_key.starts_with(&commit_prefix) {
+                break;
+            }
+            let mut values = [Value::Int64(None), Value::Bytes(None)];
+            DB::decode_values(&val, &mut values);
+            match values {
+                [Value::Int64(Some(0)), Value::Bytes(Some(part))] => saved.extend(part),
+                _ => return Ok(None),
+            }
+            if !iter.next() {
+                break;
+            }
+        }
+        Ok(Some(saved))
+    }
+
+    /** Saves the value of the row at `key` that a commit at `version` replaces. Returns the
+     * number of history rows it takes */
+    fn save_history(&mut self, key: &[u8], version: u64) -> Result<usize> {
+        let replaced = self.kv.get(key);
+        let deleted = Value::Int64(Some(replaced.is_none() as i64));
+        let replaced = replaced.unwrap_or_default();
+        let mut parts: Vec<&[u8]> = replaced.chunks(HISTORY_PART_SIZE).collect();
+        if parts.is_empty() {
+            parts.push(&[]);
+        }
+        for (part, val) in parts.iter().enumerate() {
+            let history = [deleted.clone(), Value::Bytes(Some(val.to_vec()))];
+            let history = DB::encode_values(None, &history);
+            self.kv.set(&history_key(key, version, part), &history)?;
+        }
+        Ok(parts.len())
+    }
+
+    /** Writes rows in a single commit, saving the values they replace for the open transactions
+     * that see them. Returns the version of the commit */
+    pub(super) fn commit_writes(
+        &mut self,
+        writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    ) -> Result<u64> {
+        self.batched(|db| {
+            let version = db.versions.version + 1;
+            let mut keys = Vec::with_capacity(writes.len());
+            for (key, val) in writes {
+                let replaced_at = db.versions.latest.get(&key).copied().unwrap_or(0);
+                let mut saved = 0;
+                if db.versions.read_since(replaced_at) {
+                    check_history_key(&key)?;
+                    saved = db.save_history(&key, version)?;
+                }
+                match val {
+                    Some(val) => db.kv.set(&key, &val)?,
+                    None => {
+                        db.kv.del(&key)?;
+                    }
+                }
+                keys.push((key, saved));
+            }
+
+            db.versions.version = version;
+            if db.versions.oldest_reader().is_some() {
+                for (key, _) in &keys {
+                    db.versions.latest.insert(key.clone(), version);
+                }
+                db.versions.commits.push_back(Commit { version, keys });
+            }
+            db.collect_versions()?;
+            Ok(version)
+        })
+    }
+
+    /** Forgets the commits no open transaction started before, deleting what they saved */
+    fn collect_versions(&mut self) -> Result<()> {
+        let oldest = self.versions.oldest_reader();
+        while let Some(commit) = self.versions.commits.front() {
+            if oldest.is_some_and(|oldest| commit.version > oldest) {
+                break;
+            }
+            let commit = self.versions.commits.pop_front().unwrap();
+            for (key, saved) in commit.keys {
+                if self.versions.latest.get(&key) == Some(&commit.version) {
+                    self.versions.latest.remove(&key);
+                }
+                for part in 0..saved {
+                    self.kv.del(&history_key(&key, commit.version, part))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /** Deletes the whole history, which no transaction can read once the database is reopened */
+    pub(super) fn clear_history(&mut self) -> Result<()> {
+        let prefix = DB::encode_key(None, TABLE_DEF_HISTORY.prefix, &[]);
+        let mut keys = Vec::new();
+        let mut iter = self.kv.seek(&prefix, CmpOption::GE);
+        while iter.valid() {
+            let (key, _) = iter.deref();
+            if !key.starts_with(&prefix) {
+                break;
+            }
+            keys.push(key);
+            if !iter.next() {
+                break;
+            }
+        }
+        if keys.is_empty() {
+            return Ok(());
+        }
+        self.batched(|db| {
+            for key in keys {
+                db.kv.del(&key)?;
+            }
+            Ok(())
+        })
+    }
+
+    /** The state `rollback` goes back to, see `KV::savepoint` */
+    pub(crate) fn savepoint(&self) -> Savepoint {
+        Savepoint {
+            kv: self.kv.savepoint(),
+            version: self.versions.version,
+            latest: self.versions.latest.clone(),
+            commits: self.versions.commits.clone(),
+        }
+    }
+
+    /** Drops every write made since `savepoint`, along with the commits the open transactions
+     * were told about */
+    pub(crate) fn rollback(&mut self, savepoint: Savepoint) {
+        self.kv.rollback(savepoint.kv);
+        self.versions.version = savepoint.version;
+        self.versions.latest = savepoint.latest;
+        self.versions.commits = savepoint.commits;
+        // tables created since are gone
+        self.tables.clear();
+    }
+
+    /** Runs `f` with its writes committed together, along with the batch it runs in if any.
+     * Nothing it wrote is kept if it fails */
+    pub(crate) fn batched<R>(&mut self, f: impl FnOnce(&mut DB) -> Result<R>) -> Result<R> {
+        let nested = self.kv.is_batching();
+        if !nested {
+            self.kv.begin_batch();
+        }
+        let savepoint = self.savepoint();
+        let mut result = f(self);
+        if !nested && result.is_ok() {
+            result = self.kv.commit_batch().and(result);
+        }
+        if result.is_err() {
+            // also right after a failed commit, see `KV::savepoint`
+            self.rollback(savepoint);
+            if self.kv.is_batching() && !nested {
+                // ends the batch, there is nothing left to commit
+                self.kv.commit_batch()?;
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn new_db() -> DB {
+        let mut db = DB::open_in_memory().unwrap();
+        db.table_new(TableDef {
+            name: "accounts".to_string(),
+            types: vec![Value::INT64_TYPE, Value::INT64_TYPE],
+            columns: vec!["id".to_string(), "balance".to_string()],
+            primary_keys: 1,
+            prefix: 0,
+        })
+        .unwrap();
+        db
+    }
+
+    fn row(id: i64, balance: i64) -> Record {
+        let mut record = Record::new();
+        record
+            .add_int64("id".to_string(), id)
+            .add_int64("balance".to_string(), balance);
+        record
+    }
+
+    fn key(id: i64) -> Record {
+        let mut record = Record::new();
+        record.add_int64("id".to_string(), id);
+        record
+    }
+
+    fn balance(tx: &mut Transaction, db: &mut DB, id: i64) -> Option<i64> {
+        let mut record = key(id);
+        tx.get(db, "accounts", &mut record)
+            .unwrap()
+            .then(|| record.get("balance").unwrap().get_int64().unwrap().unwrap())
+    }
+
+    fn history(db: &mut DB) -> usize {
+        db.scan("@history").unwrap().count()
+    }
+
+    #[test]
+    fn test_transaction_reads_its_snapshot() {
+        let mut db = new_db();
+        for id in 1..=3 {
+            db.insert("accounts", row(id, 100)).unwrap();
+        }
+        let mut tx = db.begin();
+
+        // plain writes are commits of their own
+        db.update("accounts", row(1, 50)).unwrap();
+        db.delete("accounts", key(2)).unwrap();
+        db.insert("accounts", row(4, 10)).unwrap();
+        let mut later = db.begin();
+        db.update("accounts", row(1, 25)).unwrap();
+
+        assert_eq!(balance(&mut tx, &mut db, 1), Some(100));
+        assert_eq!(balance(&mut tx, &mut db, 2), Some(100));
+        assert_eq!(balance(&mut tx, &mut db, 4), None);
+        assert_eq!(balance(&mut later, &mut db, 1), Some(50));
+        assert_eq!(balance(&mut later, &mut db, 2), None);
+        let mut record = key(1);
+        assert!(db.get("accounts", &mut record).unwrap());
+        assert_eq!(record, row(1, 25));
+
+        // its own writes are seen by it alone
+        assert!(tx.insert(&mut db, "accounts", row(5, 1)).unwrap());
+        assert!(tx.delete(&mut db, "accounts", key(3)).unwrap());
+        assert!(!tx.update(&mut db, "accounts", row(4, 1)).unwrap());
+        let rows: Vec<Record> = tx
+            .scan(&mut db, "accounts")
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(rows, vec![row(1, 100), row(2, 100), row(5, 1)]);
+        let rows: Vec<Record> = later
+            .scan(&mut db, "accounts")
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(rows, vec![row(1, 50), row(3, 100), row(4, 10)]);
+        assert_eq!(db.scan("accounts").unwrap().count(), 3);
+
+        let version = tx.commit(&mut db).unwrap();
+        assert!(version > later.start());
+        let rows: Vec<Record> = db.scan("accounts").unwrap().collect();
+        assert_eq!(rows, vec![row(1, 25), row(4, 10), row(5, 1)]);
+        assert_eq!(balance(&mut later, &mut db, 3), Some(100));
+        assert_eq!(balance(&mut later, &mut db, 5), None);
+    }
+
+    #[test]
+    fn test_conflicting_writes() {
+        let mut db = new_db();
+        db.insert("accounts", row(1, 100)).unwrap();
+        db.insert("accounts", row(2, 100)).unwrap();
+
+        let mut first = db.begin();
+        let mut second = db.begin();
+        first.update(&mut db, "accounts", row(1, 90)).unwrap();
+        second.update(&mut db, "accounts", row(1, 80)).unwrap();
+        first.commit(&mut db).unwrap();
+        assert!(matches!(second.commit(&mut db), Err(Error::Conflict(_))));
+
+        // rows written by no one else commit, and so do rows written before it started
+        let mut retry = db.begin();
+        let mut other = db.begin();
+        retry.update(&mut db, "accounts", row(1, 80)).unwrap();
+        other.update(&mut db, "accounts", row(2, 80)).unwrap();
+        other.commit(&mut db).unwrap();
+        retry.commit(&mut db).unwrap();
+
+        // so do inserts of the same new row
+        let mut first = db.begin();
+        let mut second = db.begin();
+        assert!(first.insert(&mut db, "accounts", row(3, 1)).unwrap());
+        assert!(second.insert(&mut db, "accounts", row(3, 2)).unwrap());
+        first.commit(&mut db).unwrap();
+        assert!(second.commit(&mut db).is_err());
+        let mut record = key(3);
+        db.get("accounts", &mut record).unwrap();
+        assert_eq!(record, row(3, 1));
+    }
+
+    #[test]
+    fn test_serializable_prevents_write_skew() {
+        for isolation in [Isolation::Snapshot, Isolation::Serializable] {
+            let mut db = new_db();
+            db.insert("accounts", row(1, 100)).unwrap();
+            db.insert("accounts", row(2, 100)).unwrap();
+
+            // both keep the total positive by reading both rows and writing one
+            let mut first = db.begin_with(isolation);
+            let mut second = db.begin_with(isolation);
+            let total =
+                balance(&mut first, &mut db, 1).unwrap() + balance(&mut first, &mut db, 2).unwrap();
+            first
+                .update(&mut db, "accounts", row(1, 100 - total))
+                .unwrap();
+            let total = balance(&mut second, &mut db, 1).unwrap()
+                + balance(&mut second, &mut db, 2).unwrap();
+            second
+                .update(&mut db, "accounts", row(2, 100 - total))
+                .unwrap();
+            first.commit(&mut db).unwrap();
+            let committed = second.commit(&mut db);
+            assert_eq!(committed.is_ok(), isolation == Isolation::Snapshot);
+
+            // rows added to a table it scanned count as well
+            let mut tx = db.begin_with(isolation);
+            let count = tx.scan(&mut db, "accounts").unwrap().count() as i64;
+            tx.insert(&mut db, "accounts", row(10, count)).unwrap();
+            db.insert("accounts", row(3, 0)).unwrap();
+            let committed = tx.commit(&mut db);
+            assert_eq!(committed.is_ok(), isolation == Isolation::Snapshot);
+
+            // reading only always commits
+            let mut tx = db.begin_with(isolation);
+            balance(&mut tx, &mut db, 1);
+            db.update("accounts", row(1, 0)).unwrap();
+            assert!(tx.commit(&mut db).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_history_is_collected() {
+        let mut db = new_db();
+        db.insert("accounts", row(1, 0)).unwrap();
+        // nothing is saved while no transaction is open
+        db.update("accounts", row(1, 1)).unwrap();
+        assert_eq!(history(&mut db), 0);
+
+        let mut oldest = db.begin();
+        for i in 2..10 {
+            db.update("accounts", row(1, i)).unwrap();
+            let tx = db.begin();
+            drop(tx);
+        }
+        let mut newer = db.begin();
+        db.update("accounts", row(1, 10)).unwrap();
+        // only the values some transaction sees are saved
+        assert_eq!(history(&mut db), 2);
+        assert_eq!(balance(&mut oldest, &mut db, 1), Some(1));
+        assert_eq!(balance(&mut newer, &mut db, 1), Some(9));
+
+        // the history moves forward with the oldest transaction
+        drop(oldest);
+        db.update("accounts", row(1, 11)).unwrap();
+        assert_eq!(history(&mut db), 1);
+        assert_eq!(balance(&mut newer, &mut db, 1), Some(9));
+        drop(newer);
+        db.update("accounts", row(1, 12)).unwrap();
+        assert_eq!(history(&mut db), 0);
+        assert!(db.versions.latest.is_empty() && db.versions.commits.is_empty());
+    }
+
+    #[test]
+    fn test_history_of_rows_at_the_size_limit() {
+        let mut db = new_db();
+        db.table_new(TableDef {
+            name: "blobs".to_string(),
+            types: vec![Value::BYTES_TYPE, Value::BYTES_TYPE],
+            columns: vec!["name".to_string(), "data".to_string()],
+            primary_keys: 1,
+            prefix: 0,
+        })
+        .unwrap();
+        let blob = |name: &[u8], data: Vec<u8>| {
+            let mut record = Record::new();
+            record
+                .add_bytes("name".to_string(), name.to_vec())
+                .add_bytes("data".to_string(), data);
+            record
+        };
+
+        // escaping doubles zeros, so this value is as large as a row gets too
+        let values = [vec![b'x'; 2990], vec![0; 1499], vec![b'y'; 2990]];
+        db.insert("blobs", blob(b"a", values[0].clone())).unwrap();
+        let mut tx = db.begin();
+        db.update("blobs", blob(b"a", values[1].clone())).unwrap();
+        let mut later = db.begin();
+        db.update("blobs", blob(b"a", values[2].clone())).unwrap();
+        // each value replaced is saved in three parts
+        assert_eq!(history(&mut db), 6);
+        for (tx, expected) in [(&mut tx, &values[0]), (&mut later, &values[1])] {
+            let mut record = blob(b"a", Vec::new());
+            record.values.pop();
+            record.columns.pop();
+            assert!(tx.get(&mut db, "blobs", &mut record).unwrap());
+            assert_eq!(record, blob(b"a", expected.clone()));
+        }
+        drop((tx, later));
+        db.update("blobs", blob(b"a", Vec::new())).unwrap();
+        assert_eq!(history(&mut db), 0);
+
+        // a row whose key leaves no room for the history key is only written while no
+        // transaction reads it
+        db.insert("blobs", blob(&[b'k'; 990], Vec::new())).unwrap();
+        db.insert("blobs", blob(&[b'j'; 970], Vec::new())).unwrap();
+        let mut tx = db.begin();
+        let err = db
+            .update("blobs", blob(&[b'k'; 990], vec![b'z']))
+            .unwrap_err();
+        assert!(err.to_string().contains("history key"), "{}", err);
+        db.update("blobs", blob(&[b'j'; 970], vec![b'z'])).unwrap();
+        let mut record = blob(&[b'k'; 990], Vec::new());
+        record.values.pop();
+        record.columns.pop();
+        assert!(db.get("blobs", &mut record).unwrap());
+        assert_eq!(record, blob(&[b'k'; 990], Vec::new()));
+        assert!(tx
+            .insert(&mut db, "blobs", blob(b"b", vec![b'z'; 3000]))
+            .is_err());
+
+        // a commit that fails part way writes nothing
+        let mut writer = db.begin();
+        for name in [&[b'j'; 970][..], &[b'k'; 990]] {
+            writer
+                .update(&mut db, "blobs", blob(name, vec![b'y']))
+                .unwrap();
+        }
+        assert!(writer.commit(&mut db).is_err());
+        let mut record = blob(&[b'j'; 970], Vec::new());
+        record.values.pop();
+        record.columns.pop();
+        assert!(db.get("blobs", &mut record).unwrap());
+        assert_eq!(record, blob(&[b'j'; 970], vec![b'z']));
+        assert_eq!(history(&mut db), 1);
+
+        // a transaction whose history is gone fails to read instead of panicking
+        let prefix = DB::encode_key(None, TABLE_DEF_HISTORY.prefix, &[]);
+        let saved = db.kv.seek(&prefix, CmpOption::GE).deref().0;
+        db.kv.del(&saved).unwrap();
+        let mut record = blob(&[b'j'; 970], Vec::new());
+        record.values.pop();
+        record.columns.pop();
+        assert!(tx.get(&mut db, "blobs", &mut record).is_err());
+    }
+
+    #[test]
+    fn test_history_cleared_on_open() {
+        fs::create_dir_all("test_run_dir").unwrap();
+        let file_name = "test_run_dir/test_history_cleared_on_open.db";
+        fs::remove_file(file_name).unwrap_or(());
+        let mut db = DB::open(file_name.to_string()).unwrap();
+        db.table_new(TableDef {
+            name: "accounts".to_string(),
+            types: vec![Value::INT64_TYPE, Value::INT64_TYPE],
+            columns: vec!["id".to_string(), "balance".to_string()],
+            primary_keys: 1,
+            prefix: 0,
+        })
+        .unwrap();
+        db.insert("accounts", row(1, 0)).unwrap();
+        let tx = db.begin();
+        db.update("accounts", row(1, 1)).unwrap();
+        assert!(db.bulk_load("accounts", vec![row(2, 0)]).is_err());
+        assert_eq!(history(&mut db), 1);
+        db.close();
+        drop(tx);
+
+        let mut db = DB::open(file_name.to_string()).unwrap();
+        assert_eq!(history(&mut db), 0);
+        let mut record = key(1);
+        db.get("accounts", &mut record).unwrap();
+        assert_eq!(record, row(1, 1));
+        db.close();
+        fs::remove_file(file_name).unwrap();
+    }
+}
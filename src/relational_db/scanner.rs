@@ -0,0 +1,124 @@
+use crate::kv_store::{CmpOption, KVIterator};
+use crate::prelude::*;
+
+use super::{records::Record, tables::TableDef, value::Value, DB};
+
+/// Iterates over the rows of a table in key order
+pub struct Scanner<'a> {
+    table_def: TableDef,
+    iter: KVIterator<'a>,
+    /// Key prefix shared by every row of the table
+    prefix: Vec<u8>,
+}
+
+/** Decodes a row of a table from its key and value */
+pub(super) fn decode_row(table_def: &TableDef, key: &[u8], val: &[u8]) -> Record {
+    let mut values: Vec<Value> = table_def
+        .types
+        .iter()
+        .map(|&value_type| Value::u32_to_empty_value(value_type))
+        .collect();
+
+    let primary_keys = table_def.primary_keys;
+    // after the table prefix
+    DB::decode_values(&key[4..], &mut values[..primary_keys]);
+    DB::decode_values(val, &mut values[primary_keys..]);
+
+    Record {
+        columns: table_def.columns.clone(),
+        values,
+    }
+}
+
+impl Scanner<'_> {
+    pub fn table_def(&self) -> &TableDef {
+        &self.table_def
+    }
+
+    /** Returns the encoded key and value of the next row without decoding it */
+    pub fn next_raw(&mut self) -> Option<(Vec<u8>, Vec<u8>)> {
+        if !self.iter.valid() {
+            return None;
+        }
+        let (key, val) = self.iter.deref();
+        if !key.starts_with(&self.prefix) {
+            // past the last row of the table
+            self.iter.invalidate();
+            return None;
+        }
+        if !self.iter.next() {
+            self.iter.invalidate();
+        }
+        Some((key, val))
+    }
+}
+
+impl Iterator for Scanner<'_> {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Record> {
+        let (key, val) = self.next_raw()?;
+        Some(decode_row(&self.table_def, &key, &val))
+    }
+}
+
+impl DB {
+    /** Returns an iterator over every row of `table` */
+    pub fn scan(&mut self, table: &str) -> Result<Scanner<'_>> {
+        let table_def = match self.get_table_def(table) {
+            Some(table_def) => table_def,
+            None => return Err(Error::Generic(format!("Table not found {}", table))),
+        };
+
+        let prefix = DB::encode_key(None, table_def.prefix, &[]);
+        let iter = self.kv.seek(&prefix, CmpOption::GE);
+        Ok(Scanner {
+            table_def,
+            iter,
+            prefix,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{new_db, row};
+
+    #[test]
+    fn test_scan_table() {
+        let mut db = new_db();
+        for id in [3, 1, 2] {
+            db.insert("first", row(id, &format!("first{}", id)))
+                .unwrap();
+            db.insert("second", row(id * 10, "second")).unwrap();
+        }
+
+        let records: Vec<Record> = db.scan("first").unwrap().collect();
+        assert_eq!(
+            records,
+            vec![row(1, "first1"), row(2, "first2"), row(3, "first3")]
+        );
+        assert_eq!(db.scan("second").unwrap().count(), 3);
+    }
+
+    #[test]
+    fn test_scan_empty_and_missing_table() {
+        let mut db = new_db();
+        assert_eq!(db.scan("first").unwrap().count(), 0);
+        db.insert("second", row(1, "second")).unwrap();
+        assert_eq!(db.scan("first").unwrap().count(), 0);
+        assert!(db.scan("missing").is_err());
+    }
+
+    #[test]
+    fn test_scan_internal_table() {
+        let mut db = new_db();
+        let names: Vec<String> = db
+            .scan("@table")
+            .unwrap()
+            .map(|record| record.get("name").unwrap().bytes_to_string().unwrap())
+            .collect();
+        assert_eq!(names, vec!["first", "second"]);
+    }
+}
@@ -0,0 +1,62 @@
+use crate::prelude::*;
+
+use super::DB;
+
+/// Space taken by the rows of one table
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TableStats {
+    pub rows: u64,
+    /// Encoded primary keys, including the table prefix
+    pub key_bytes: u64,
+    /// Encoded columns outside the primary key
+    pub value_bytes: u64,
+}
+
+impl DB {
+    /** Counts the rows of `table` and the bytes they take by scanning it */
+    pub fn table_stats(&mut self, table: &str) -> Result<TableStats> {
+        let mut scanner = self.scan(table)?;
+        let mut stats = TableStats::default();
+        while let Some((key, val)) = scanner.next_raw() {
+            stats.rows += 1;
+            stats.key_bytes += key.len() as u64;
+            stats.value_bytes += val.len() as u64;
+        }
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{new_db, row};
+
+    #[test]
+    fn test_table_stats() {
+        let mut db = new_db();
+        for id in 0..10 {
+            db.insert("first", row(id, "abc")).unwrap();
+        }
+        db.insert("second", row(1, "a longer name")).unwrap();
+
+        // 4 byte prefix and 8 byte id, 3 bytes and a terminator
+        let stats = db.table_stats("first").unwrap();
+        assert_eq!(
+            stats,
+            TableStats {
+                rows: 10,
+                key_bytes: 120,
+                value_bytes: 40,
+            }
+        );
+        assert_eq!(db.table_stats("second").unwrap().rows, 1);
+        assert_eq!(db.table_stats("@table").unwrap().rows, 2);
+    }
+
+    #[test]
+    fn test_table_stats_empty_and_missing() {
+        let mut db = new_db();
+        assert_eq!(db.table_stats("first").unwrap(), TableStats::default());
+        assert!(db.table_stats("missing").is_err());
+    }
+}
@@ -0,0 +1,114 @@
+use std::{net::TcpStream, os::unix::net::UnixStream};
+
+use crate::prelude::*;
+
+use super::{
+    protocol::{read_frame, write_frame, Request, Response},
+    Address, Stream,
+};
+
+/// A connection to a `Server`. Requests are answered in order, one at a time
+pub struct Client {
+    stream: Box<dyn Stream>,
+}
+
+impl Client {
+    pub fn connect(address: &Address) -> Result<Client> {
+        let stream: Box<dyn Stream> = match address {
+            Address::Tcp(address) => {
+                let stream = TcpStream::connect(address)?;
+                stream.set_nodelay(true)?;
+                Box::new(stream)
+            }
+            Address::Unix(path) => Box::new(UnixStream::connect(path)?),
+        };
+        Ok(Client { stream })
+    }
+
+    /** Sends a request and waits for its response. Errors reported by the server are returned as
+     * errors */
+    fn call(&mut self, request: Request) -> Result<Response> {
+        write_frame(&mut self.stream, &request.encode())?;
+        let frame = read_frame(&mut self.stream)?
+            .ok_or(Error::Static("the server closed the connection"))?;
+        match Response::decode(&frame)? {
+            Response::Error(message) => Err(Error::Generic(message)),
+            response => Ok(response),
+        }
+    }
+
+    fn call_ok(&mut self, request: Request) -> Result<()> {
+        match self.call(request)? {
+            Response::Ok => Ok(()),
+            _ => Err(Error::Static("unexpected response")),
+        }
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.call(Request::Get(key.to_vec()))? {
+            Response::Value(val) => Ok(val),
+            _ => Err(Error::Static("unexpected response")),
+        }
+    }
+
+    pub fn set(&mut self, key: &[u8], val: &[u8]) -> Result<()> {
+        self.call_ok(Request::Set(key.to_vec(), val.to_vec()))
+    }
+
+    /** Deletes a key, returns whether it was there */
+    pub fn del(&mut self, key: &[u8]) -> Result<bool> {
+        match self.call(Request::Del(key.to_vec()))? {
+            Response::Bool(existed) => Ok(existed),
+            _ => Err(Error::Static("unexpected response")),
+        }
+    }
+
+    /** Returns up to `limit` pairs in key order, starting from `start` and stopping before `end` */
+    pub fn scan(
+        &mut self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: u32,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let request = Request::Scan {
+            start: start.to_vec(),
+            end: end.map(<[u8]>::to_vec),
+            limit,
+        };
+        match self.call(request)? {
+            Response::Items(items) => Ok(items),
+            _ => Err(Error::Static("unexpected response")),
+        }
+    }
+
+    /** Starts a transaction. Later writes on this connection are held back until `commit`, and
+     * reads see them. The commit fails if another connection wrote what the transaction read */
+    pub fn begin(&mut self) -> Result<()> {
+        self.call_ok(Request::Begin)
+    }
+
+    /** Applies the writes of the transaction in one commit */
+    pub fn commit(&mut self) -> Result<()> {
+        self.call_ok(Request::Commit)
+    }
+
+    /** Drops the writes of the transaction */
+    pub fn abort(&mut self) -> Result<()> {
+        self.call_ok(Request::Abort)
+    }
+
+    /** Runs `f` in a transaction, committing if it succeeds and aborting if it fails */
+    pub fn transaction<R>(&mut self, f: impl FnOnce(&mut Client) -> Result<R>) -> Result<R> {
+        self.begin()?;
+        match f(self) {
+            Ok(result) => {
+                self.commit()?;
+                Ok(result)
+            }
+            Err(err) => {
+                self.abort()?;
+                Err(err)
+            }
+        }
+    }
+}
@@ -0,0 +1,476 @@
+//! Serves one `KV` over TCP or a Unix socket. Each connection gets its own thread, and their
+//! writes go through a `WriteQueue` so connections writing at the same time share commits.
+//! See `protocol` for the wire format and `Client` for the matching client, or `resp` for the
+//! Redis compatible front end. `postgres` serves the tables of a `DB` to Postgres clients.
+
+pub mod client;
+pub mod postgres;
+pub mod protocol;
+pub mod resp;
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    os::unix::net::{UnixListener, UnixStream},
+    path::PathBuf,
+    sync::Arc,
+    thread,
+};
+
+use crate::prelude::*;
+use crate::{
+    kv_store::KV,
+    worker::{scan, WriteQueue},
+};
+
+use self::protocol::{read_frame, write_frame, Request, Response};
+
+pub use client::Client;
+
+/// Where a server listens, `host:port` or `unix:<path>`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Address {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl Address {
+    pub fn parse(address: &str) -> Address {
+        match address.strip_prefix("unix:") {
+            Some(path) => Address::Unix(PathBuf::from(path)),
+            None => Address::Tcp(address.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Tcp(address) => write!(f, "{}", address),
+            Address::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// A connection of either kind
+pub(crate) trait Stream: Read + Write + Send {}
+
+impl Stream for TcpStream {}
+impl Stream for UnixStream {}
+
+/// What connections speak
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Protocol {
+    /// The framed protocol of `Client`
+    #[default]
+    Native,
+    /// A subset of the Redis protocol
+    Resp,
+}
+
+/// A bound socket of either kind
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    pub(crate) fn bind(address: &Address) -> Result<Listener> {
+        Ok(match address {
+            Address::Tcp(address) => Listener::Tcp(TcpListener::bind(address)?),
+            Address::Unix(path) => Listener::Unix(UnixListener::bind(path)?),
+        })
+    }
+
+    pub(crate) fn local_address(&self) -> Result<Address> {
+        match self {
+            Listener::Tcp(listener) => Ok(Address::Tcp(listener.local_addr()?.to_string())),
+            Listener::Unix(listener) => match listener.local_addr()?.as_pathname() {
+                Some(path) => Ok(Address::Unix(path.to_path_buf())),
+                None => Err(Error::Static("the socket has no path")),
+            },
+        }
+    }
+
+    pub(crate) fn accept(&self) -> Result<Box<dyn Stream>> {
+        Ok(match self {
+            Listener::Tcp(listener) => {
+                let (stream, _) = listener.accept()?;
+                // requests and responses are small, don't hold them back
+                stream.set_nodelay(true)?;
+                Box::new(stream)
+            }
+            Listener::Unix(listener) => Box::new(listener.accept()?.0),
+        })
+    }
+}
+
+pub struct Server {
+    listener: Listener,
+    queue: Arc<WriteQueue>,
+    protocol: Protocol,
+}
+
+impl Server {
+    /** Listens on `address`, serving `kv` once `serve` is called. A TCP port of 0 picks a free
+     * port, see `local_address` */
+    pub fn bind(address: &Address, kv: KV) -> Result<Server> {
+        Ok(Server {
+            listener: Listener::bind(address)?,
+            queue: Arc::new(WriteQueue::new(kv)),
+            protocol: Protocol::default(),
+        })
+    }
+
+    pub fn protocol(mut self, protocol: Protocol) -> Server {
+        self.protocol = protocol;
+        self
+    }
+
+    /** The address clients can connect to */
+    pub fn local_address(&self) -> Result<Address> {
+        self.listener.local_address()
+    }
+
+    /** Accepts connections until the listener fails, serving each on its own thread */
+    pub fn serve(self) -> Result<()> {
+        loop {
+            let stream = self.listener.accept()?;
+            let queue = self.queue.clone();
+            let protocol = self.protocol;
+            thread::spawn(move || {
+                // the connection is dropped on errors, the client sees it closed
+                let _ = match protocol {
+                    Protocol::Native => handle(stream, &queue),
+                    Protocol::Resp => resp::handle(stream, &queue),
+                };
+            });
+        }
+    }
+}
+
+/** Answers the requests of one connection until it closes */
+fn handle(mut stream: impl Read + Write, queue: &WriteQueue) -> Result<()> {
+    let mut session = Session { queue, tx: None };
+    while let Some(frame) = read_frame(&mut stream)? {
+        let request = match Request::decode(&frame) {
+            Ok(request) => request,
+            Err(err) => {
+                // the stream can't be trusted after a bad frame
+                write_frame(&mut stream, &Response::Error(err.to_string()).encode())?;
+                return Err(err);
+            }
+        };
+        let response = session
+            .execute(request)
+            .unwrap_or_else(|err| Response::Error(err.to_string()));
+        write_frame(&mut stream, &response.encode())?;
+    }
+    Ok(())
+}
+
+/// The state of one connection
+struct Session<'a> {
+    queue: &'a WriteQueue,
+    /// The open transaction, if any
+    tx: Option<Transaction>,
+}
+
+/// A transaction open on a connection. Its writes are held back until it commits, which only
+/// happens if what it read from the store is still there
+#[derive(Default)]
+struct Transaction {
+    /// Pending writes, `None` deletes the key
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    /// Values read from the store, `None` for keys that were not there
+    reads: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    /// Scans of the store, along with what they returned
+    scans: Vec<ScanRead>,
+}
+
+struct ScanRead {
+    start: Vec<u8>,
+    end: Option<Vec<u8>>,
+    limit: usize,
+    items: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Transaction {
+    /** Whether a write committed since changed something the transaction read */
+    fn conflicts(&self, kv: &KV) -> bool {
+        self.reads.iter().any(|(key, val)| kv.get(key) != *val)
+            || self
+                .scans
+                .iter()
+                .any(|read| scan(kv, &read.start, read.end.as_deref(), read.limit) != read.items)
+    }
+}
+
+impl Session<'_> {
+    fn execute(&mut self, request: Request) -> Result<Response> {
+        Ok(match request {
+            Request::Get(key) => Response::Value(self.get(&key)?),
+            Request::Set(key, val) => {
+                match &mut self.tx {
+                    Some(tx) => {
+                        tx.writes.insert(key, Some(val));
+                    }
+                    None => self.queue.set(&key, &val)?,
+                }
+                Response::Ok
+            }
+            Request::Del(key) => {
+                if self.tx.is_none() {
+                    return Ok(Response::Bool(self.queue.del(&key)?));
+                }
+                let existed = self.get(&key)?.is_some();
+                self.tx.as_mut().unwrap().writes.insert(key, None);
+                Response::Bool(existed)
+            }
+            Request::Scan { start, end, limit } => {
+                Response::Items(self.scan(&start, end.as_deref(), limit as usize)?)
+            }
+            Request::Begin => {
+                if self.tx.is_some() {
+                    return Err(Error::Static("a transaction is already open"));
+                }
+                self.tx = Some(Transaction::default());
+                Response::Ok
+            }
+            Request::Commit => {
+                let tx = self.take_tx()?;
+                self.queue.run(move |kv| {
+                    if tx.conflicts(kv) {
+                        return Err(Error::Conflict(
+                            "keys the transaction read were written since".to_string(),
+                        ));
+                    }
+                    kv.apply_writes(tx.writes)
+                })?;
+                Response::Ok
+            }
+            Request::Abort => {
+                self.take_tx()?;
+                Response::Ok
+            }
+        })
+    }
+
+    fn take_tx(&mut self) -> Result<Transaction> {
+        self.tx
+            .take()
+            .ok_or(Error::Static("no transaction is open"))
+    }
+
+    /** Reads see the writes of the open transaction */
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let Some(tx) = &mut self.tx else {
+            return self.queue.get(key);
+        };
+        if let Some(write) = tx.writes.get(key) {
+            return Ok(write.clone());
+        }
+        let val = self.queue.get(key)?;
+        tx.reads.entry(key.to_vec()).or_insert_with(|| val.clone());
+        Ok(val)
+    }
+
+    fn scan(
+        &mut self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let Some(tx) = &mut self.tx else {
+            return self.queue.scan(start, end, limit);
+        };
+        let in_range: Vec<_> = tx
+            .writes
+            .range(start.to_vec()..)
+            .take_while(|(key, _)| end.is_none_or(|end| key.as_slice() < end))
+            .collect();
+        // each pending delete can hide one stored pair
+        let read = ScanRead {
+            start: start.to_vec(),
+            end: end.map(<[u8]>::to_vec),
+            limit: limit + in_range.len(),
+            items: self.queue.scan(start, end, limit + in_range.len())?,
+        };
+        let mut merged: BTreeMap<Vec<u8>, Vec<u8>> = read.items.iter().cloned().collect();
+        for (key, write) in in_range {
+            match write {
+                Some(val) => merged.insert(key.clone(), val.clone()),
+                None => merged.remove(key),
+            };
+        }
+        tx.scans.push(read);
+        Ok(merged.into_iter().take(limit).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    /// Starts a server on `address` in the background and returns where to reach it
+    fn start(address: Address) -> Address {
+        let server = Server::bind(&address, KV::open_in_memory().unwrap()).unwrap();
+        let address = server.local_address().unwrap();
+        thread::spawn(move || server.serve());
+        address
+    }
+
+    fn exercise(client: &mut Client) {
+        client.set(b"a", b"1").unwrap();
+        client.set(b"b", b"2").unwrap();
+        assert_eq!(client.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(client.get(b"zz").unwrap(), None);
+        assert!(client.del(b"a").unwrap());
+        assert!(!client.del(b"a").unwrap());
+        client.set(b"c", b"3").unwrap();
+        let items = client.scan(b"", Some(b"c"), 10).unwrap();
+        assert_eq!(items, vec![(b"b".to_vec(), b"2".to_vec())]);
+    }
+
+    #[test]
+    fn test_tcp() {
+        let address = start(Address::parse("127.0.0.1:0"));
+        let mut client = Client::connect(&address).unwrap();
+        exercise(&mut client);
+    }
+
+    #[test]
+    fn test_unix() {
+        fs::create_dir_all("test_run_dir").unwrap();
+        let path = "test_run_dir/test_unix.sock";
+        let _ = fs::remove_file(path);
+        let address = start(Address::parse(&format!("unix:{}", path)));
+        assert_eq!(address, Address::Unix(PathBuf::from(path)));
+        let mut client = Client::connect(&address).unwrap();
+        exercise(&mut client);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_transactions() {
+        let address = start(Address::parse("127.0.0.1:0"));
+        let mut client = Client::connect(&address).unwrap();
+        let mut other = Client::connect(&address).unwrap();
+        for key in [b"a", b"b", b"c"] {
+            client.set(key, b"old").unwrap();
+        }
+
+        client.begin().unwrap();
+        assert!(client.begin().is_err());
+        client.set(b"a", b"new").unwrap();
+        assert!(client.del(b"b").unwrap());
+        client.set(b"d", b"new").unwrap();
+        // the transaction sees its own writes, other connections don't until it commits
+        assert_eq!(client.get(b"a").unwrap(), Some(b"new".to_vec()));
+        let keys: Vec<Vec<u8>> = client
+            .scan(b"", None, 10)
+            .unwrap()
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(keys, vec![b"a".to_vec(), b"c".to_vec(), b"d".to_vec()]);
+        assert_eq!(client.scan(b"", None, 1).unwrap().len(), 1);
+        assert_eq!(other.get(b"a").unwrap(), Some(b"old".to_vec()));
+        client.commit().unwrap();
+        assert_eq!(other.get(b"a").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(other.get(b"b").unwrap(), None);
+
+        client.begin().unwrap();
+        client.set(b"e", b"dropped").unwrap();
+        client.abort().unwrap();
+        assert_eq!(client.get(b"e").unwrap(), None);
+        assert!(client.commit().is_err());
+
+        // a closure that fails aborts
+        let failed: Result<()> = client.transaction(|tx| {
+            tx.set(b"f", b"dropped")?;
+            Err(Error::Static("abort"))
+        });
+        assert!(failed.is_err());
+        client.transaction(|tx| tx.set(b"g", b"kept")).unwrap();
+        assert_eq!(other.get(b"f").unwrap(), None);
+        assert_eq!(other.get(b"g").unwrap(), Some(b"kept".to_vec()));
+
+        // a transaction whose reads were written by another connection does not commit
+        client.begin().unwrap();
+        let balance = client.get(b"a").unwrap().unwrap();
+        other.set(b"a", b"changed").unwrap();
+        client.set(b"copy", &balance).unwrap();
+        let err = client.commit().unwrap_err();
+        assert!(err.to_string().contains("written since"), "{}", err);
+        assert_eq!(other.get(b"copy").unwrap(), None);
+        client.begin().unwrap();
+        assert_eq!(client.scan(b"g", None, 10).unwrap().len(), 1);
+        other.set(b"h", b"new").unwrap();
+        client.set(b"copy", b"1").unwrap();
+        assert!(client.commit().is_err());
+        // or by itself
+        client.begin().unwrap();
+        client.get(b"a").unwrap();
+        client.set(b"a", b"mine").unwrap();
+        client.commit().unwrap();
+        assert_eq!(other.get(b"a").unwrap(), Some(b"mine".to_vec()));
+    }
+
+    #[test]
+    fn test_failed_commit_applies_nothing() {
+        let address = start(Address::parse("127.0.0.1:0"));
+        let mut client = Client::connect(&address).unwrap();
+        client.begin().unwrap();
+        client.set(b"\0", b"1").unwrap();
+        client.set(b"\0\0\0\0x", b"reserved").unwrap();
+        assert!(client.commit().is_err());
+        assert_eq!(client.get(b"\0").unwrap(), None);
+
+        client.begin().unwrap();
+        client.set(b"a", b"1").unwrap();
+        client.set(b"b", &[b'v'; 3001]).unwrap();
+        assert!(client.commit().is_err());
+        assert_eq!(client.get(b"a").unwrap(), None);
+
+        // oversized writes outside a transaction fail without closing the connection
+        assert!(client.set(&[b'k'; 1001], b"v").is_err());
+        client.set(b"a", b"1").unwrap();
+        assert_eq!(client.get(b"a").unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn test_many_clients() {
+        let address = start(Address::parse("127.0.0.1:0"));
+        thread::scope(|scope| {
+            for i in 0..8u8 {
+                let address = &address;
+                scope.spawn(move || {
+                    let mut client = Client::connect(address).unwrap();
+                    for j in 0..20u8 {
+                        client.set(&[i, j], &[j]).unwrap();
+                    }
+                });
+            }
+        });
+        let mut client = Client::connect(&address).unwrap();
+        assert_eq!(client.scan(b"", None, 1000).unwrap().len(), 160);
+    }
+
+    #[test]
+    fn test_bad_frame_closes_the_connection() {
+        let address = start(Address::parse("127.0.0.1:0"));
+        let Address::Tcp(tcp) = &address else {
+            unreachable!()
+        };
+        let mut stream = TcpStream::connect(tcp).unwrap();
+        write_frame(&mut stream, &[99]).unwrap();
+        let response = Response::decode(&read_frame(&mut stream).unwrap().unwrap()).unwrap();
+        assert!(matches!(response, Response::Error(_)));
+        assert!(read_frame(&mut stream).unwrap().is_none());
+    }
+}
@@ -0,0 +1,661 @@
+//! Runs parsed statements against a `DB` and renders the rows the way Postgres sends them in text
+//! format.
+
+use std::collections::HashSet;
+
+use crate::prelude::*;
+use crate::relational_db::{records::Record, tables::TableDef, value::Value, DB};
+
+use super::sql::{Filter, Literal, SelectItem, SqlError, SqlResult, Statement};
+
+/// Postgres type OIDs
+pub const INT8_OID: u32 = 20;
+pub const BYTEA_OID: u32 = 17;
+pub const TEXT_OID: u32 = 25;
+
+/// A column of a result, as described to the client
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    pub name: String,
+    pub oid: u32,
+    /// Size of the type in bytes, -1 when it varies
+    pub size: i16,
+}
+
+impl Field {
+    fn new(name: &str, oid: u32) -> Field {
+        Field {
+            name: name.to_string(),
+            oid,
+            size: match oid {
+                INT8_OID => 8,
+                _ => -1,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Output {
+    Rows {
+        fields: Vec<Field>,
+        /// Each row holds its values in text format
+        rows: Vec<Vec<Vec<u8>>>,
+    },
+    /// A statement that returns no rows, with its command tag
+    Done(String),
+}
+
+/** The type OID a column of `value_type` is described with */
+pub fn type_oid(value_type: u32) -> u32 {
+    match value_type {
+        Value::INT64_TYPE => INT8_OID,
+        _ => BYTEA_OID,
+    }
+}
+
+/** A value in Postgres text format, bytea is written as `\x` and hex digits */
+fn render(value: &Value) -> Vec<u8> {
+    match value {
+        Value::Int64(Some(n)) => n.to_string().into_bytes(),
+        Value::Bytes(Some(bytes)) => {
+            let mut out = b"\\x".to_vec();
+            for byte in bytes {
+                out.extend_from_slice(format!("{:02x}", byte).as_bytes());
+            }
+            out
+        }
+        _ => Vec::new(),
+    }
+}
+
+fn render_literal(literal: &Literal) -> (u32, Vec<u8>) {
+    match literal {
+        Literal::Int(n) => (INT8_OID, n.to_string().into_bytes()),
+        Literal::Str(text) => (TEXT_OID, text.clone()),
+    }
+}
+
+/** Converts a literal to the type of a column, as Postgres would cast it */
+fn to_value(literal: &Literal, value_type: u32, column: &str) -> SqlResult<Value> {
+    match (literal, value_type) {
+        (Literal::Int(n), Value::INT64_TYPE) => Ok(Value::Int64(Some(*n))),
+        (Literal::Str(text), Value::INT64_TYPE) => std::str::from_utf8(text)
+            .ok()
+            .and_then(|text| text.trim().parse().ok())
+            .map(|n| Value::Int64(Some(n)))
+            .ok_or_else(|| {
+                SqlError::new(
+                    "22P02",
+                    format!(
+                        "invalid input syntax for type bigint: \"{}\"",
+                        String::from_utf8_lossy(text)
+                    ),
+                )
+            }),
+        (Literal::Str(text), _) => Ok(Value::Bytes(Some(parse_bytea(text)?))),
+        (Literal::Int(_), _) => Err(SqlError::new(
+            "42804",
+            format!(
+                "column \"{}\" is of type bytea but expression is of type integer",
+                column
+            ),
+        )),
+    }
+}
+
+/** A bytea literal is either `\x` and hex digits, or the bytes as written */
+fn parse_bytea(text: &[u8]) -> SqlResult<Vec<u8>> {
+    let Some(hex) = text.strip_prefix(b"\\x") else {
+        return Ok(text.to_vec());
+    };
+    let invalid = || SqlError::new("22P02", "invalid hexadecimal data");
+    if hex.len() % 2 != 0 {
+        return Err(invalid());
+    }
+    hex.chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(invalid)
+        })
+        .collect()
+}
+
+fn undefined_column(column: &str) -> SqlError {
+    SqlError::new("42703", format!("column \"{}\" does not exist", column))
+}
+
+fn column_index(table_def: &TableDef, column: &str) -> SqlResult<usize> {
+    table_def
+        .columns
+        .iter()
+        .position(|name| name == column)
+        .ok_or_else(|| undefined_column(column))
+}
+
+/** Runs the statements of one query in order and commits them together. The first that fails
+ * stops the query, and the ones before it are rolled back */
+pub fn execute_all(db: &mut DB, statements: Vec<Statement>) -> Vec<SqlResult<Output>> {
+    let mut results = Vec::new();
+    let applied = db.batched(|db| {
+        for statement in statements {
+            let result = execute(db, statement);
+            let failed = result.is_err();
+            results.push(result);
+            if failed {
+                return Err(Error::Static("statement failed"));
+            }
+        }
+        Ok(())
+    });
+    if let (Err(err), Some(Ok(_))) = (applied, results.last()) {
+        // every statement ran but the commit failed
+        results.push(Err(SqlError::new("XX000", err.to_string())));
+    }
+    results
+}
+
+pub fn execute(db: &mut DB, statement: Statement) -> SqlResult<Output> {
+    match statement {
+        Statement::CreateTable {
+            name,
+            columns,
+            primary_key,
+        } => create_table(db, name, columns, primary_key),
+        Statement::Insert {
+            table,
+            columns,
+            rows,
+        } => insert(db, &table, columns, rows),
+        Statement::Select {
+            items,
+            table: None,
+            filter: _,
+            limit,
+        } => {
+            let mut fields = Vec::new();
+            let mut row = Vec::new();
+            for item in items {
+                match item {
+                    SelectItem::Literal(literal) => {
+                        let (oid, text) = render_literal(&literal);
+                        fields.push(Field::new("?column?", oid));
+                        row.push(text);
+                    }
+                    SelectItem::Column(column) => return Err(undefined_column(&column)),
+                    SelectItem::All => {
+                        return Err(SqlError::new(
+                            "42601",
+                            "SELECT * with no tables specified is not valid",
+                        ))
+                    }
+                }
+            }
+            let rows = match limit {
+                Some(0) => Vec::new(),
+                _ => vec![row],
+            };
+            Ok(Output::Rows { fields, rows })
+        }
+        Statement::Select {
+            items,
+            table: Some(table),
+            filter,
+            limit,
+        } => select(db, &table, items, filter, limit),
+        Statement::Update {
+            table,
+            assignments,
+            filter,
+        } => update(db, &table, assignments, filter),
+        Statement::Delete { table, filter } => delete(db, &table, filter),
+        Statement::Transaction(_) => Err(SqlError::new(
+            "0A000",
+            "transactions are not supported, each query is committed as a whole",
+        )),
+        Statement::Set => Ok(Output::Done("SET".to_string())),
+    }
+}
+
+fn table_def(db: &mut DB, table: &str) -> SqlResult<TableDef> {
+    db.table_def(table)
+        .ok_or_else(|| SqlError::new("42P01", format!("relation \"{}\" does not exist", table)))
+}
+
+/** Like `table_def`, refusing the internal tables */
+fn writable_table_def(db: &mut DB, table: &str) -> SqlResult<TableDef> {
+    if table.starts_with('@') {
+        return Err(SqlError::new(
+            "42501",
+            format!("permission denied for table {}", table),
+        ));
+    }
+    table_def(db, table)
+}
+
+fn internal(err: crate::prelude::Error) -> SqlError {
+    SqlError::new("XX000", err.to_string())
+}
+
+/** Checked before writing, an oversized row would otherwise fail half way through a statement */
+fn check_row_size(table_def: &TableDef, values: &[Value]) -> SqlResult<()> {
+    DB::check_row_size(table_def, values).map_err(|err| SqlError::new("54000", err.to_string()))
+}
+
+fn create_table(
+    db: &mut DB,
+    name: String,
+    columns: Vec<(String, u32)>,
+    primary_key: Vec<String>,
+) -> SqlResult<Output> {
+    if name.starts_with('@') {
+        return Err(SqlError::new(
+            "42602",
+            format!("invalid table name \"{}\"", name),
+        ));
+    }
+    let mut seen = HashSet::new();
+    for (column, _) in &columns {
+        if !seen.insert(column) {
+            return Err(SqlError::new(
+                "42701",
+                format!("column \"{}\" specified more than once", column),
+            ));
+        }
+    }
+    if primary_key.is_empty() {
+        return Err(SqlError::new("0A000", "tables need a primary key"));
+    }
+
+    // the table keeps its primary key columns first
+    let mut table_def = TableDef {
+        name: name.clone(),
+        types: vec![],
+        columns: vec![],
+        primary_keys: primary_key.len(),
+        prefix: 0,
+    };
+    for key in &primary_key {
+        let Some((column, value_type)) = columns.iter().find(|(column, _)| column == key) else {
+            return Err(undefined_column(key));
+        };
+        if table_def.columns.contains(column) {
+            return Err(SqlError::new(
+                "42701",
+                format!("column \"{}\" appears twice in primary key", column),
+            ));
+        }
+        table_def.columns.push(column.clone());
+        table_def.types.push(*value_type);
+    }
+    for (column, value_type) in columns {
+        if !primary_key.contains(&column) {
+            table_def.columns.push(column);
+            table_def.types.push(value_type);
+        }
+    }
+
+    if db.table_def(&name).is_some() {
+        return Err(SqlError::new(
+            "42P07",
+            format!("relation \"{}\" already exists", name),
+        ));
+    }
+    db.table_new(table_def).map_err(internal)?;
+    Ok(Output::Done("CREATE TABLE".to_string()))
+}
+
+/** Every value of a row in table order */
+fn row_values(table_def: &TableDef, record: &Record) -> Vec<Value> {
+    table_def
+        .columns
+        .iter()
+        .map(|column| record.get(column).unwrap().clone())
+        .collect()
+}
+
+fn to_record(table_def: &TableDef, values: &[Value]) -> Record {
+    Record {
+        columns: table_def.columns[..values.len()].to_vec(),
+        values: values.to_vec(),
+    }
+}
+
+/** The rows matching `filter`, each with its values in table order. Looks the row up by its key
+ * when the filter gives the whole primary key, and scans the table otherwise */
+fn find_rows(db: &mut DB, table_def: &TableDef, filter: &Filter) -> SqlResult<Vec<Vec<Value>>> {
+    let mut conditions = Vec::new();
+    for (column, literal) in filter {
+        let i = column_index(table_def, column)?;
+        conditions.push((i, to_value(literal, table_def.types[i], column)?));
+    }
+    let matches = |values: &[Value]| conditions.iter().all(|(i, value)| values[*i] == *value);
+
+    let key: Option<Vec<Value>> = (0..table_def.primary_keys)
+        .map(|i| {
+            conditions
+                .iter()
+                .find(|(column, _)| *column == i)
+                .map(|(_, value)| value.clone())
+        })
+        .collect();
+    if let Some(key) = key {
+        let mut record = to_record(table_def, &key);
+        if !db.get(&table_def.name, &mut record).map_err(internal)? {
+            return Ok(Vec::new());
+        }
+        let values = row_values(table_def, &record);
+        return Ok(if matches(&values) {
+            vec![values]
+        } else {
+            vec![]
+        });
+    }
+
+    let scanner = db.scan(&table_def.name).map_err(internal)?;
+    Ok(scanner
+        .map(|record| row_values(table_def, &record))
+        .filter(|values| matches(values))
+        .collect())
+}
+
+fn select(
+    db: &mut DB,
+    table: &str,
+    items: Vec<SelectItem>,
+    filter: Filter,
+    limit: Option<u64>,
+) -> SqlResult<Output> {
+    let table_def = table_def(db, table)?;
+
+    enum Source {
+        Column(usize),
+        Literal(Vec<u8>),
+    }
+    let mut fields = Vec::new();
+    let mut sources = Vec::new();
+    for item in items {
+        match item {
+            SelectItem::All => {
+                for (i, column) in table_def.columns.iter().enumerate() {
+                    fields.push(Field::new(column, type_oid(table_def.types[i])));
+                    sources.push(Source::Column(i));
+                }
+            }
+            SelectItem::Column(column) => {
+                let i = column_index(&table_def, &column)?;
+                fields.push(Field::new(&column, type_oid(table_def.types[i])));
+                sources.push(Source::Column(i));
+            }
+            SelectItem::Literal(literal) => {
+                let (oid, text) = render_literal(&literal);
+                fields.push(Field::new("?column?", oid));
+                sources.push(Source::Literal(text));
+            }
+        }
+    }
+
+    let mut found = find_rows(db, &table_def, &filter)?;
+    if let Some(limit) = limit {
+        found.truncate(limit as usize);
+    }
+    let rows = found
+        .iter()
+        .map(|values| {
+            sources
+                .iter()
+                .map(|source| match source {
+                    Source::Column(i) => render(&values[*i]),
+                    Source::Literal(text) => text.clone(),
+                })
+                .collect()
+        })
+        .collect();
+    Ok(Output::Rows { fields, rows })
+}
+
+/** Checks every row before writing any, so a failing insert leaves the table as it was */
+fn insert(
+    db: &mut DB,
+    table: &str,
+    columns: Option<Vec<String>>,
+    rows: Vec<Vec<Literal>>,
+) -> SqlResult<Output> {
+    let table_def = writable_table_def(db, table)?;
+    let columns = columns.unwrap_or_else(|| table_def.columns.clone());
+    let indexes = columns
+        .iter()
+        .map(|column| column_index(&table_def, column))
+        .collect::<SqlResult<Vec<usize>>>()?;
+
+    let mut records = Vec::new();
+    let mut keys = HashSet::new();
+    for row in rows {
+        if row.len() != columns.len() {
+            return Err(SqlError::new(
+                "42601",
+                "INSERT has a different number of values than target columns",
+            ));
+        }
+        let mut values = vec![Value::Error; table_def.columns.len()];
+        for (&i, literal) in indexes.iter().zip(&row) {
+            values[i] = to_value(literal, table_def.types[i], &table_def.columns[i])?;
+        }
+        if let Some(i) = values.iter().position(|value| *value == Value::Error) {
+            return Err(SqlError::new(
+                "23502",
+                format!(
+                    "null value in column \"{}\" violates not-null constraint",
+                    table_def.columns[i]
+                ),
+            ));
+        }
+        check_row_size(&table_def, &values)?;
+
+        let mut key = to_record(&table_def, &values[..table_def.primary_keys]);
+        let key_bytes = DB::encode_key(None, table_def.prefix, &key.values);
+        if !keys.insert(key_bytes) || db.get(&table_def.name, &mut key).map_err(internal)? {
+            return Err(SqlError::new(
+                "23505",
+                format!(
+                    "duplicate key value violates unique constraint \"{}_pkey\"",
+                    table_def.name
+                ),
+            ));
+        }
+        records.push(to_record(&table_def, &values));
+    }
+
+    let count = records.len();
+    for record in records {
+        db.insert(&table_def.name, record).map_err(internal)?;
+    }
+    Ok(Output::Done(format!("INSERT 0 {}", count)))
+}
+
+fn update(
+    db: &mut DB,
+    table: &str,
+    assignments: Vec<(String, Literal)>,
+    filter: Filter,
+) -> SqlResult<Output> {
+    let table_def = writable_table_def(db, table)?;
+    let mut changes = Vec::new();
+    for (column, literal) in &assignments {
+        let i = column_index(&table_def, column)?;
+        if i < table_def.primary_keys {
+            return Err(SqlError::new(
+                "0A000",
+                format!("primary key column \"{}\" can't be updated", column),
+            ));
+        }
+        changes.push((i, to_value(literal, table_def.types[i], column)?));
+    }
+
+    let mut rows = find_rows(db, &table_def, &filter)?;
+    for values in &mut rows {
+        for (i, value) in &changes {
+            values[*i] = value.clone();
+        }
+        check_row_size(&table_def, values)?;
+    }
+    for values in &rows {
+        db.update(&table_def.name, to_record(&table_def, values))
+            .map_err(internal)?;
+    }
+    Ok(Output::Done(format!("UPDATE {}", rows.len())))
+}
+
+fn delete(db: &mut DB, table: &str, filter: Filter) -> SqlResult<Output> {
+    let table_def = writable_table_def(db, table)?;
+    let rows = find_rows(db, &table_def, &filter)?;
+    for values in &rows {
+        let key = to_record(&table_def, &values[..table_def.primary_keys]);
+        db.delete(&table_def.name, key).map_err(internal)?;
+    }
+    Ok(Output::Done(format!("DELETE {}", rows.len())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::postgres::sql::parse;
+
+    fn run(db: &mut DB, sql: &str) -> SqlResult<Output> {
+        execute_all(db, parse(sql)?).pop().unwrap()
+    }
+
+    fn rows(db: &mut DB, sql: &str) -> Vec<Vec<String>> {
+        match run(db, sql).unwrap() {
+            Output::Rows { rows, .. } => rows
+                .into_iter()
+                .map(|row| {
+                    row.into_iter()
+                        .map(|value| String::from_utf8(value).unwrap())
+                        .collect()
+                })
+                .collect(),
+            output => panic!("{:?}", output),
+        }
+    }
+
+    fn new_db() -> DB {
+        let mut db = DB::open_in_memory().unwrap();
+        run(
+            &mut db,
+            "CREATE TABLE t (name BYTEA, a INT, b INT, PRIMARY KEY (a, b));
+             INSERT INTO t (a, b, name) VALUES (1, 1, 'x'), (1, 2, 'y'), (2, 1, '\\x7a')",
+        )
+        .unwrap();
+        db
+    }
+
+    #[test]
+    fn test_primary_key_columns_come_first() {
+        let mut db = new_db();
+        let table_def = db.table_def("t").unwrap();
+        assert_eq!(table_def.columns, vec!["a", "b", "name"]);
+        assert_eq!(table_def.primary_keys, 2);
+        match run(&mut db, "SELECT * FROM t LIMIT 1").unwrap() {
+            Output::Rows { fields, .. } => {
+                let oids: Vec<u32> = fields.iter().map(|field| field.oid).collect();
+                assert_eq!(oids, vec![INT8_OID, INT8_OID, BYTEA_OID]);
+            }
+            output => panic!("{:?}", output),
+        }
+    }
+
+    #[test]
+    fn test_select() {
+        let mut db = new_db();
+        assert_eq!(rows(&mut db, "SELECT * FROM t").len(), 3);
+        assert_eq!(
+            rows(&mut db, "SELECT name, b FROM t WHERE a = 1"),
+            vec![vec!["\\x78", "1"], vec!["\\x79", "2"]]
+        );
+        // the whole key is looked up directly, other conditions still apply
+        assert_eq!(
+            rows(&mut db, "SELECT name FROM t WHERE b = 1 AND a = '2'"),
+            vec![vec!["\\x7a"]]
+        );
+        assert!(rows(
+            &mut db,
+            "SELECT name FROM t WHERE b = 1 AND a = 2 AND name = 'q'"
+        )
+        .is_empty());
+        assert_eq!(rows(&mut db, "SELECT 'hi', -3"), vec![vec!["hi", "-3"]]);
+        assert_eq!(run(&mut db, "SELECT c FROM t").unwrap_err().code, "42703");
+        assert_eq!(run(&mut db, "SELECT * FROM u").unwrap_err().code, "42P01");
+        assert_eq!(
+            run(&mut db, "SELECT * FROM t WHERE a = 'one'")
+                .unwrap_err()
+                .code,
+            "22P02"
+        );
+    }
+
+    #[test]
+    fn test_writes() {
+        let mut db = new_db();
+        // a duplicate anywhere in the statement writes nothing
+        let err = run(&mut db, "INSERT INTO t VALUES (3, 1, 'n'), (1, 1, 'd')").unwrap_err();
+        assert_eq!(err.code, "23505");
+        let err = run(&mut db, "INSERT INTO t VALUES (4, 1, 'n'), (4, 1, 'd')").unwrap_err();
+        assert_eq!(err.code, "23505");
+        assert_eq!(rows(&mut db, "SELECT * FROM t").len(), 3);
+        assert_eq!(
+            run(&mut db, "INSERT INTO t (a, name) VALUES (5, 'n')")
+                .unwrap_err()
+                .code,
+            "23502"
+        );
+
+        assert_eq!(
+            run(&mut db, "UPDATE t SET name = 'z' WHERE a = 1").unwrap(),
+            Output::Done("UPDATE 2".to_string())
+        );
+        assert_eq!(
+            rows(&mut db, "SELECT name FROM t WHERE a = 1 AND b = 2"),
+            vec![vec!["\\x7a"]]
+        );
+        assert_eq!(
+            run(&mut db, "UPDATE t SET a = 3").unwrap_err().code,
+            "0A000"
+        );
+
+        // rows too large to store write nothing
+        let long = "n".repeat(3001);
+        let sql = format!("INSERT INTO t VALUES (6, 1, 'n'), (6, 2, '{}')", long);
+        assert_eq!(run(&mut db, &sql).unwrap_err().code, "54000");
+        let sql = format!("UPDATE t SET name = '{}' WHERE a = 1", long);
+        assert_eq!(run(&mut db, &sql).unwrap_err().code, "54000");
+        assert_eq!(rows(&mut db, "SELECT * FROM t").len(), 3);
+        assert_eq!(
+            rows(&mut db, "SELECT name FROM t WHERE a = 1 AND b = 2"),
+            vec![vec!["\\x7a"]]
+        );
+        assert_eq!(
+            run(&mut db, "DELETE FROM t WHERE name = 'z'").unwrap(),
+            Output::Done("DELETE 3".to_string())
+        );
+        assert_eq!(
+            run(&mut db, "DELETE FROM \"@table\"").unwrap_err().code,
+            "42501"
+        );
+    }
+
+    #[test]
+    fn test_create_table_errors() {
+        let mut db = new_db();
+        for (sql, code) in [
+            ("CREATE TABLE t (a INT PRIMARY KEY)", "42P07"),
+            ("CREATE TABLE u (a INT)", "0A000"),
+            ("CREATE TABLE u (a INT, a BYTEA, PRIMARY KEY (a))", "42701"),
+            ("CREATE TABLE u (a INT, PRIMARY KEY (b))", "42703"),
+        ] {
+            assert_eq!(run(&mut db, sql).unwrap_err().code, code, "{}", sql);
+        }
+    }
+}
@@ -0,0 +1,420 @@
+//! Serves the tables of a `DB` over the Postgres wire protocol, so `psql` and ordinary drivers
+//! can query it. Only the simple query protocol is spoken. Every query runs as one job on the
+//! worker that owns the database, so its statements are committed together: a failing statement
+//! stops the query, and the ones before it are rolled back. There is no authentication and no
+//! TLS: it is meant for local use.
+
+pub mod exec;
+pub mod sql;
+
+use std::{
+    io::{self, Read, Write},
+    sync::Arc,
+    thread,
+};
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::prelude::*;
+use crate::{
+    relational_db::DB,
+    worker::{Worker, WorkerOptions},
+};
+
+use self::{
+    exec::Output,
+    sql::{SqlError, SqlResult},
+};
+use super::{protocol::MAX_FRAME_SIZE, Address, Listener};
+
+/// Protocol version 3.0, the only one spoken
+const PROTOCOL_VERSION: u32 = 196608;
+/// Asks for TLS before the startup message
+const SSL_REQUEST: u32 = 80877103;
+/// Asks for GSSAPI encryption before the startup message
+const GSSENC_REQUEST: u32 = 80877104;
+/// Sent on a new connection to cancel a running query
+const CANCEL_REQUEST: u32 = 80877102;
+
+/// Settings reported to the client after startup
+const PARAMETERS: &[(&str, &str)] = &[
+    ("server_version", "16.0"),
+    ("server_encoding", "UTF8"),
+    ("client_encoding", "UTF8"),
+    ("DateStyle", "ISO, MDY"),
+    ("integer_datetimes", "on"),
+    ("standard_conforming_strings", "on"),
+];
+
+pub struct PgServer {
+    listener: Listener,
+    worker: Arc<Worker<DB>>,
+}
+
+impl PgServer {
+    /** Listens on `address`, serving `db` once `serve` is called */
+    pub fn bind(address: &Address, db: DB) -> Result<PgServer> {
+        Ok(PgServer {
+            listener: Listener::bind(address)?,
+            worker: Arc::new(Worker::spawn(db, WorkerOptions::default())),
+        })
+    }
+
+    /** The address clients can connect to */
+    pub fn local_address(&self) -> Result<Address> {
+        self.listener.local_address()
+    }
+
+    /** Accepts connections until the listener fails, serving each on its own thread */
+    pub fn serve(self) -> Result<()> {
+        loop {
+            let stream = self.listener.accept()?;
+            let worker = self.worker.clone();
+            thread::spawn(move || {
+                // the connection is dropped on errors, the client sees it closed
+                let _ = handle(stream, &worker);
+            });
+        }
+    }
+}
+
+/// Builds one backend message: a type byte, then the length of the rest including itself
+struct Message {
+    data: Vec<u8>,
+}
+
+impl Message {
+    fn new(kind: u8) -> Message {
+        Message {
+            data: vec![kind, 0, 0, 0, 0],
+        }
+    }
+
+    fn i16(mut self, n: i16) -> Message {
+        self.data.extend_from_slice(&n.to_be_bytes());
+        self
+    }
+
+    fn i32(mut self, n: i32) -> Message {
+        self.data.extend_from_slice(&n.to_be_bytes());
+        self
+    }
+
+    fn bytes(mut self, bytes: &[u8]) -> Message {
+        self.data.extend_from_slice(bytes);
+        self
+    }
+
+    /// A null terminated string
+    fn str(self, text: &str) -> Message {
+        self.bytes(text.as_bytes()).bytes(&[0])
+    }
+
+    fn finish(mut self, out: &mut Vec<u8>) {
+        let len = self.data.len() as u32 - 1;
+        BigEndian::write_u32(&mut self.data[1..5], len);
+        out.extend_from_slice(&self.data);
+    }
+}
+
+fn error_message(err: &SqlError, out: &mut Vec<u8>) {
+    Message::new(b'E')
+        .bytes(b"S")
+        .str("ERROR")
+        .bytes(b"V")
+        .str("ERROR")
+        .bytes(b"C")
+        .str(err.code)
+        .bytes(b"M")
+        .str(&err.message)
+        .bytes(&[0])
+        .finish(out);
+}
+
+fn ready_for_query(out: &mut Vec<u8>) {
+    // always idle, each query commits on its own
+    Message::new(b'Z').bytes(b"I").finish(out);
+}
+
+fn output_messages(output: Output, out: &mut Vec<u8>) {
+    match output {
+        Output::Rows { fields, rows } => {
+            let mut description = Message::new(b'T').i16(fields.len() as i16);
+            for field in &fields {
+                description = description
+                    .str(&field.name)
+                    .i32(0) // no table oid
+                    .i16(0) // no column number
+                    .i32(field.oid as i32)
+                    .i16(field.size)
+                    .i32(-1) // no type modifier
+                    .i16(0); // text format
+            }
+            description.finish(out);
+            for row in &rows {
+                let mut data = Message::new(b'D').i16(row.len() as i16);
+                for value in row {
+                    data = data.i32(value.len() as i32).bytes(value);
+                }
+                data.finish(out);
+            }
+            Message::new(b'C')
+                .str(&format!("SELECT {}", rows.len()))
+                .finish(out);
+        }
+        Output::Done(tag) => Message::new(b'C').str(&tag).finish(out),
+    }
+}
+
+/** Reads a length prefixed body, the length counting itself */
+fn read_body(stream: &mut impl Read) -> Result<Vec<u8>> {
+    let mut header = [0; 4];
+    stream.read_exact(&mut header)?;
+    let len = BigEndian::read_u32(&header) as usize;
+    if !(4..=MAX_FRAME_SIZE).contains(&len) {
+        return Err(Error::Generic(format!("invalid message length {}", len)));
+    }
+    let mut body = vec![0; len - 4];
+    stream.read_exact(&mut body)?;
+    Ok(body)
+}
+
+fn send(stream: &mut impl Write, out: &[u8]) -> Result<()> {
+    stream.write_all(out)?;
+    stream.flush()?;
+    Ok(())
+}
+
+/** Takes the connection through startup. Returns false if it should be closed instead */
+fn startup(stream: &mut (impl Read + Write)) -> Result<bool> {
+    loop {
+        let body = read_body(stream)?;
+        if body.len() < 4 {
+            return Err(Error::Static("startup message too short"));
+        }
+        match BigEndian::read_u32(&body) {
+            // encryption is not offered, the client goes on in plain text or gives up
+            SSL_REQUEST | GSSENC_REQUEST => send(stream, b"N")?,
+            // queries are never long enough to need cancelling
+            CANCEL_REQUEST => return Ok(false),
+            PROTOCOL_VERSION => break,
+            version => {
+                let mut out = Vec::new();
+                let err = SqlError::new(
+                    "0A000",
+                    format!("unsupported frontend protocol {}", version),
+                );
+                error_message(&err, &mut out);
+                send(stream, &out)?;
+                return Ok(false);
+            }
+        }
+    }
+
+    // any user and database is let in
+    let mut out = Vec::new();
+    Message::new(b'R').i32(0).finish(&mut out);
+    for (name, value) in PARAMETERS {
+        Message::new(b'S').str(name).str(value).finish(&mut out);
+    }
+    Message::new(b'K')
+        .i32(std::process::id() as i32)
+        .i32(0)
+        .finish(&mut out);
+    ready_for_query(&mut out);
+    send(stream, &out)?;
+    Ok(true)
+}
+
+/** Answers the queries of one connection until it closes */
+fn handle(mut stream: impl Read + Write, worker: &Worker<DB>) -> Result<()> {
+    if !startup(&mut stream)? {
+        return Ok(());
+    }
+    // after an extended protocol message is refused, the rest up to Sync is skipped
+    let mut skipping = false;
+    loop {
+        let mut kind = [0];
+        match stream.read_exact(&mut kind) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(err.into()),
+        }
+        let body = read_body(&mut stream)?;
+
+        let mut out = Vec::new();
+        match kind[0] {
+            b'Q' => {
+                let query = String::from_utf8_lossy(body.strip_suffix(&[0]).unwrap_or(&body));
+                let results = query_results(&query, worker);
+                if results.is_empty() {
+                    Message::new(b'I').finish(&mut out);
+                }
+                for result in results {
+                    match result {
+                        Ok(output) => output_messages(output, &mut out),
+                        Err(err) => error_message(&err, &mut out),
+                    }
+                }
+                ready_for_query(&mut out);
+            }
+            b'X' => return Ok(()),
+            b'P' | b'B' | b'D' | b'E' | b'C' | b'H' => {
+                if !skipping {
+                    let err = SqlError::new(
+                        "0A000",
+                        "the extended query protocol is not supported, use simple queries",
+                    );
+                    error_message(&err, &mut out);
+                    skipping = true;
+                }
+            }
+            b'S' => {
+                skipping = false;
+                ready_for_query(&mut out);
+            }
+            kind => {
+                let err = SqlError::new(
+                    "08P01",
+                    format!("unexpected message type '{}'", kind as char),
+                );
+                error_message(&err, &mut out);
+                send(&mut stream, &out)?;
+                return Ok(());
+            }
+        }
+        send(&mut stream, &out)?;
+    }
+}
+
+/** Parses and runs one query, returning a result per statement run */
+fn query_results(query: &str, worker: &Worker<DB>) -> Vec<SqlResult<Output>> {
+    let statements = match sql::parse(query) {
+        Ok(statements) => statements,
+        Err(err) => return vec![Err(err)],
+    };
+    if statements.is_empty() {
+        return Vec::new();
+    }
+    match worker.run_blocking(move |db| Ok(exec::execute_all(db, statements))) {
+        Ok(results) => results,
+        Err(err) => vec![Err(SqlError::new("XX000", err.to_string()))],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::postgres::{Client, NoTls, SimpleQueryMessage};
+
+    use super::*;
+
+    fn start() -> Client {
+        let server = PgServer::bind(
+            &Address::parse("127.0.0.1:0"),
+            DB::open_in_memory().unwrap(),
+        )
+        .unwrap();
+        let Address::Tcp(address) = server.local_address().unwrap() else {
+            unreachable!()
+        };
+        thread::spawn(move || server.serve());
+        let (host, port) = address.rsplit_once(':').unwrap();
+        Client::connect(
+            &format!("host={} port={} user=test dbname=test", host, port),
+            NoTls,
+        )
+        .unwrap()
+    }
+
+    /// The rows of a query, with every value as text
+    fn query(client: &mut Client, sql: &str) -> Vec<Vec<String>> {
+        client
+            .simple_query(sql)
+            .unwrap()
+            .into_iter()
+            .filter_map(|message| match message {
+                SimpleQueryMessage::Row(row) => Some(
+                    (0..row.len())
+                        .map(|i| row.get(i).unwrap().to_string())
+                        .collect(),
+                ),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_postgres_client() {
+        let mut client = start();
+        client
+            .batch_execute(
+                "CREATE TABLE users (id BIGINT PRIMARY KEY, name BYTEA);
+                 INSERT INTO users VALUES (1, 'ada'), (2, 'bob');",
+            )
+            .unwrap();
+        assert_eq!(
+            query(&mut client, "SELECT id, name FROM users"),
+            vec![vec!["1", "\\x616461"], vec!["2", "\\x626f62"]]
+        );
+
+        let messages = client
+            .simple_query("UPDATE users SET name = 'eve' WHERE id = 2")
+            .unwrap();
+        assert!(matches!(
+            messages[0],
+            SimpleQueryMessage::CommandComplete(1)
+        ));
+        assert_eq!(query(&mut client, "SELECT 1"), vec![vec!["1"]]);
+
+        // the row description carries the column names and type OIDs
+        let messages = client
+            .simple_query("SELECT * FROM users WHERE id = 2")
+            .unwrap();
+        let SimpleQueryMessage::RowDescription(columns) = &messages[0] else {
+            panic!("no row description")
+        };
+        let names: Vec<&str> = columns.iter().map(|column| column.name()).collect();
+        assert_eq!(names, vec!["id", "name"]);
+        let SimpleQueryMessage::Row(row) = &messages[1] else {
+            panic!("no row")
+        };
+        assert_eq!(row.get("name"), Some("\\x657665"));
+    }
+
+    #[test]
+    fn test_errors_keep_the_connection() {
+        let mut client = start();
+        let err = client.simple_query("SELECT * FROM missing").unwrap_err();
+        assert_eq!(err.code().unwrap().code(), "42P01");
+        let err = client.simple_query("SELEC 1").unwrap_err();
+        assert_eq!(err.code().unwrap().code(), "42601");
+
+        // typed queries use the extended protocol, which is refused
+        let err = client.query("SELECT 1", &[]).unwrap_err();
+        assert_eq!(err.code().unwrap().code(), "0A000");
+        assert!(client.transaction().is_err());
+        assert_eq!(query(&mut client, "SELECT 2"), vec![vec!["2"]]);
+        // statements before a failing one in the same query are rolled back
+        client
+            .batch_execute("CREATE TABLE t (id BIGINT PRIMARY KEY, name BYTEA)")
+            .unwrap();
+        let err = client
+            .simple_query("INSERT INTO t VALUES (1, 'x'); INSERT INTO t VALUES (1, 'y')")
+            .unwrap_err();
+        assert_eq!(err.code().unwrap().code(), "23505");
+        assert!(query(&mut client, "SELECT name FROM t").is_empty());
+        let err = client
+            .simple_query("CREATE TABLE u (id BIGINT PRIMARY KEY); INSERT INTO v VALUES (1)")
+            .unwrap_err();
+        assert_eq!(err.code().unwrap().code(), "42P01");
+        let err = client.simple_query("SELECT * FROM u").unwrap_err();
+        assert_eq!(err.code().unwrap().code(), "42P01");
+
+        // an empty query is answered, and the client reports it as completing nothing
+        let messages = client.simple_query(" ; ").unwrap();
+        assert!(matches!(
+            messages[..],
+            [SimpleQueryMessage::CommandComplete(0)]
+        ));
+    }
+}
@@ -0,0 +1,540 @@
+//! The SQL this front end understands: CREATE TABLE, INSERT, SELECT, UPDATE and DELETE, with
+//! equality filters joined by AND. Unquoted names are folded to lower case as Postgres does.
+
+use std::fmt;
+
+use crate::relational_db::value::Value;
+
+/// An error reported to the client with its SQLSTATE code
+#[derive(Debug, Clone, PartialEq)]
+pub struct SqlError {
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl SqlError {
+    pub fn new(code: &'static str, message: impl Into<String>) -> SqlError {
+        SqlError {
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn syntax(message: impl Into<String>) -> SqlError {
+        SqlError::new("42601", message)
+    }
+}
+
+impl fmt::Display for SqlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+pub type SqlResult<T> = std::result::Result<T, SqlError>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal {
+    Int(i64),
+    Str(Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SelectItem {
+    /// `*`, every column of the table
+    All,
+    Column(String),
+    Literal(Literal),
+}
+
+/// `column = literal` conditions that must all hold
+pub type Filter = Vec<(String, Literal)>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Statement {
+    CreateTable {
+        name: String,
+        /// Columns in the order declared, with their `Value` type
+        columns: Vec<(String, u32)>,
+        primary_key: Vec<String>,
+    },
+    Insert {
+        table: String,
+        /// The columns the values are for, every column in table order when not given
+        columns: Option<Vec<String>>,
+        rows: Vec<Vec<Literal>>,
+    },
+    Select {
+        items: Vec<SelectItem>,
+        /// `None` for a select of literals only
+        table: Option<String>,
+        filter: Filter,
+        limit: Option<u64>,
+    },
+    Update {
+        table: String,
+        assignments: Vec<(String, Literal)>,
+        filter: Filter,
+    },
+    Delete {
+        table: String,
+        filter: Filter,
+    },
+    /// BEGIN, COMMIT and the like, named by the keyword
+    Transaction(String),
+    /// A session setting, accepted and ignored
+    Set,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    /// An unquoted word, lower cased
+    Word(String),
+    /// A double quoted name, as written
+    Quoted(String),
+    Number(i64),
+    Str(Vec<u8>),
+    Symbol(char),
+}
+
+fn tokenize(sql: &str) -> SqlResult<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = sql.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if sql[start..].starts_with("--") {
+            // a comment runs to the end of the line
+            while chars.next_if(|&(_, c)| c != '\n').is_some() {}
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let mut word = String::new();
+            while let Some((_, c)) = chars.next_if(|&(_, c)| c.is_ascii_alphanumeric() || c == '_')
+            {
+                word.push(c.to_ascii_lowercase());
+            }
+            tokens.push(Token::Word(word));
+        } else if c.is_ascii_digit() {
+            let mut digits = String::new();
+            while let Some((_, c)) = chars.next_if(|&(_, c)| c.is_ascii_digit()) {
+                digits.push(c);
+            }
+            let number = digits
+                .parse()
+                .map_err(|_| SqlError::new("22003", format!("{} is out of range", digits)))?;
+            tokens.push(Token::Number(number));
+        } else if c == '\'' || c == '"' {
+            chars.next();
+            let mut text = String::new();
+            loop {
+                match chars.next() {
+                    // a doubled quote stands for itself
+                    Some((_, q)) if q == c && chars.next_if(|&(_, next)| next == c).is_some() => {
+                        text.push(c)
+                    }
+                    Some((_, q)) if q == c => break,
+                    Some((_, other)) => text.push(other),
+                    None => return Err(SqlError::syntax("unterminated quoted string")),
+                }
+            }
+            tokens.push(match c {
+                '\'' => Token::Str(text.into_bytes()),
+                _ => Token::Quoted(text),
+            });
+        } else if "(),;*=-".contains(c) {
+            chars.next();
+            tokens.push(Token::Symbol(c));
+        } else {
+            return Err(SqlError::syntax(format!(
+                "syntax error at or near \"{}\"",
+                c
+            )));
+        }
+    }
+    Ok(tokens)
+}
+
+/** Parses a query string into its statements. Empty statements between semicolons are skipped */
+pub fn parse(sql: &str) -> SqlResult<Vec<Statement>> {
+    let mut parser = Parser {
+        tokens: tokenize(sql)?,
+        pos: 0,
+    };
+    let mut statements = Vec::new();
+    loop {
+        while parser.eat_symbol(';') {}
+        if parser.peek().is_none() {
+            return Ok(statements);
+        }
+        statements.push(parser.statement()?);
+        if parser.peek().is_some() && !parser.eat_symbol(';') {
+            return Err(parser.unexpected());
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn unexpected(&self) -> SqlError {
+        match self.peek() {
+            Some(Token::Word(word)) | Some(Token::Quoted(word)) => {
+                SqlError::syntax(format!("syntax error at or near \"{}\"", word))
+            }
+            Some(Token::Symbol(c)) => {
+                SqlError::syntax(format!("syntax error at or near \"{}\"", c))
+            }
+            Some(_) => SqlError::syntax("syntax error at or near a literal"),
+            None => SqlError::syntax("syntax error at end of input"),
+        }
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(word)) if word == keyword)
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        let found = self.is_keyword(keyword);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn keyword(&mut self, keyword: &str) -> SqlResult<()> {
+        if !self.eat_keyword(keyword) {
+            return Err(self.unexpected());
+        }
+        Ok(())
+    }
+
+    fn eat_symbol(&mut self, symbol: char) -> bool {
+        let found = self.peek() == Some(&Token::Symbol(symbol));
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn symbol(&mut self, symbol: char) -> SqlResult<()> {
+        if !self.eat_symbol(symbol) {
+            return Err(self.unexpected());
+        }
+        Ok(())
+    }
+
+    fn name(&mut self) -> SqlResult<String> {
+        match self.peek() {
+            Some(Token::Word(name)) | Some(Token::Quoted(name)) => {
+                let name = name.clone();
+                self.pos += 1;
+                Ok(name)
+            }
+            _ => Err(self.unexpected()),
+        }
+    }
+
+    /** Parses `item (, item)*` */
+    fn list<T>(&mut self, mut item: impl FnMut(&mut Parser) -> SqlResult<T>) -> SqlResult<Vec<T>> {
+        let mut items = vec![item(self)?];
+        while self.eat_symbol(',') {
+            items.push(item(self)?);
+        }
+        Ok(items)
+    }
+
+    fn literal(&mut self) -> SqlResult<Literal> {
+        let negative = self.eat_symbol('-');
+        match (self.next(), negative) {
+            (Some(Token::Number(n)), negative) => Ok(Literal::Int(if negative { -n } else { n })),
+            (Some(Token::Str(text)), false) => Ok(Literal::Str(text)),
+            (Some(Token::Word(word)), false) if word == "null" => {
+                Err(SqlError::new("0A000", "NULL values are not supported"))
+            }
+            _ => {
+                self.pos -= 1;
+                Err(self.unexpected())
+            }
+        }
+    }
+
+    fn statement(&mut self) -> SqlResult<Statement> {
+        let keyword = match self.next() {
+            Some(Token::Word(keyword)) => keyword,
+            _ => {
+                self.pos -= 1;
+                return Err(self.unexpected());
+            }
+        };
+        match keyword.as_str() {
+            "create" => self.create_table(),
+            "insert" => self.insert(),
+            "select" => self.select(),
+            "update" => self.update(),
+            "delete" => self.delete(),
+            "begin" | "start" | "commit" | "end" | "rollback" | "abort" => {
+                // the rest, such as TRANSACTION or WORK, does not change the meaning
+                self.skip_statement();
+                Ok(Statement::Transaction(keyword.to_ascii_uppercase()))
+            }
+            "set" => {
+                self.skip_statement();
+                Ok(Statement::Set)
+            }
+            _ => {
+                self.pos -= 1;
+                Err(self.unexpected())
+            }
+        }
+    }
+
+    /** Skips to the end of the statement */
+    fn skip_statement(&mut self) {
+        while self.peek().is_some() && self.peek() != Some(&Token::Symbol(';')) {
+            self.pos += 1;
+        }
+    }
+
+    fn create_table(&mut self) -> SqlResult<Statement> {
+        self.keyword("table")?;
+        let name = self.name()?;
+        self.symbol('(')?;
+        let mut columns = Vec::new();
+        let mut primary_key = Vec::new();
+        loop {
+            if self.eat_keyword("primary") {
+                self.keyword("key")?;
+                self.symbol('(')?;
+                primary_key.extend(self.list(Parser::name)?);
+                self.symbol(')')?;
+            } else {
+                let column = self.name()?;
+                columns.push((column.clone(), self.data_type()?));
+                if self.eat_keyword("primary") {
+                    self.keyword("key")?;
+                    primary_key.push(column);
+                }
+            }
+            if !self.eat_symbol(',') {
+                break;
+            }
+        }
+        self.symbol(')')?;
+        Ok(Statement::CreateTable {
+            name,
+            columns,
+            primary_key,
+        })
+    }
+
+    fn data_type(&mut self) -> SqlResult<u32> {
+        let name = self.name()?;
+        match name.as_str() {
+            "int" | "integer" | "bigint" | "smallint" | "int2" | "int4" | "int8" => {
+                Ok(Value::INT64_TYPE)
+            }
+            "bytea" => Ok(Value::BYTES_TYPE),
+            // the values could only be sent back as bytea, which clients expecting text would show
+            // in hex
+            "text" | "varchar" | "char" | "character" => Err(SqlError::new(
+                "0A000",
+                format!("type \"{}\" is not supported, use bytea", name),
+            )),
+            _ => Err(SqlError::new(
+                "42704",
+                format!("type \"{}\" does not exist", name),
+            )),
+        }
+    }
+
+    fn insert(&mut self) -> SqlResult<Statement> {
+        self.keyword("into")?;
+        let table = self.name()?;
+        let mut columns = None;
+        if self.eat_symbol('(') {
+            columns = Some(self.list(Parser::name)?);
+            self.symbol(')')?;
+        }
+        self.keyword("values")?;
+        let rows = self.list(|parser| {
+            parser.symbol('(')?;
+            let row = parser.list(Parser::literal)?;
+            parser.symbol(')')?;
+            Ok(row)
+        })?;
+        Ok(Statement::Insert {
+            table,
+            columns,
+            rows,
+        })
+    }
+
+    fn select(&mut self) -> SqlResult<Statement> {
+        let items = self.list(|parser| {
+            if parser.eat_symbol('*') {
+                return Ok(SelectItem::All);
+            }
+            match parser.peek() {
+                Some(Token::Word(_)) | Some(Token::Quoted(_)) => {
+                    Ok(SelectItem::Column(parser.name()?))
+                }
+                _ => Ok(SelectItem::Literal(parser.literal()?)),
+            }
+        })?;
+        let mut table = None;
+        let mut filter = Vec::new();
+        if self.eat_keyword("from") {
+            table = Some(self.name()?);
+            filter = self.filter()?;
+        }
+        let mut limit = None;
+        if self.eat_keyword("limit") {
+            limit = match self.next() {
+                Some(Token::Number(n)) => Some(n as u64),
+                _ => {
+                    self.pos -= 1;
+                    return Err(self.unexpected());
+                }
+            };
+        }
+        Ok(Statement::Select {
+            items,
+            table,
+            filter,
+            limit,
+        })
+    }
+
+    fn update(&mut self) -> SqlResult<Statement> {
+        let table = self.name()?;
+        self.keyword("set")?;
+        let assignments = self.list(Parser::condition)?;
+        let filter = self.filter()?;
+        Ok(Statement::Update {
+            table,
+            assignments,
+            filter,
+        })
+    }
+
+    fn delete(&mut self) -> SqlResult<Statement> {
+        self.keyword("from")?;
+        let table = self.name()?;
+        let filter = self.filter()?;
+        Ok(Statement::Delete { table, filter })
+    }
+
+    /** An optional WHERE clause */
+    fn filter(&mut self) -> SqlResult<Filter> {
+        if !self.eat_keyword("where") {
+            return Ok(Vec::new());
+        }
+        let mut filter = vec![self.condition()?];
+        while self.eat_keyword("and") {
+            filter.push(self.condition()?);
+        }
+        Ok(filter)
+    }
+
+    /** `column = literal` */
+    fn condition(&mut self) -> SqlResult<(String, Literal)> {
+        let column = self.name()?;
+        self.symbol('=')?;
+        Ok((column, self.literal()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let statements = parse(
+            "CREATE TABLE Users (id BIGINT, \"Name\" bytea, PRIMARY KEY (id));
+             insert into users values (1, 'it''s'), (-2, 'b');;
+             SELECT name, 1 FROM users WHERE id = 1 AND name = 'x' LIMIT 5 -- trailing comment",
+        )
+        .unwrap();
+        assert_eq!(
+            statements,
+            vec![
+                Statement::CreateTable {
+                    name: "users".to_string(),
+                    columns: vec![
+                        ("id".to_string(), Value::INT64_TYPE),
+                        ("Name".to_string(), Value::BYTES_TYPE)
+                    ],
+                    primary_key: vec!["id".to_string()],
+                },
+                Statement::Insert {
+                    table: "users".to_string(),
+                    columns: None,
+                    rows: vec![
+                        vec![Literal::Int(1), Literal::Str(b"it's".to_vec())],
+                        vec![Literal::Int(-2), Literal::Str(b"b".to_vec())],
+                    ],
+                },
+                Statement::Select {
+                    items: vec![
+                        SelectItem::Column("name".to_string()),
+                        SelectItem::Literal(Literal::Int(1))
+                    ],
+                    table: Some("users".to_string()),
+                    filter: vec![
+                        ("id".to_string(), Literal::Int(1)),
+                        ("name".to_string(), Literal::Str(b"x".to_vec()))
+                    ],
+                    limit: Some(5),
+                },
+            ]
+        );
+
+        assert_eq!(
+            parse("update t set a = 'x', b = 2 where k = 1").unwrap(),
+            vec![Statement::Update {
+                table: "t".to_string(),
+                assignments: vec![
+                    ("a".to_string(), Literal::Str(b"x".to_vec())),
+                    ("b".to_string(), Literal::Int(2))
+                ],
+                filter: vec![("k".to_string(), Literal::Int(1))],
+            }]
+        );
+        assert_eq!(
+            parse("BEGIN TRANSACTION; SET datestyle = 'ISO'").unwrap(),
+            vec![Statement::Transaction("BEGIN".to_string()), Statement::Set]
+        );
+        assert_eq!(parse(" ; ").unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for (sql, code) in [
+            ("SELEC 1", "42601"),
+            ("SELECT * FROM", "42601"),
+            ("SELECT 'open", "42601"),
+            ("SELECT 1 2", "42601"),
+            ("DELETE FROM t WHERE a > 1", "42601"),
+            ("INSERT INTO t VALUES (NULL)", "0A000"),
+            ("CREATE TABLE t (a float)", "42704"),
+            ("CREATE TABLE t (a text)", "0A000"),
+            ("CREATE TABLE t (a varchar(20))", "0A000"),
+            ("SELECT 99999999999999999999", "22003"),
+        ] {
+            assert_eq!(parse(sql).unwrap_err().code, code, "{}", sql);
+        }
+    }
+}
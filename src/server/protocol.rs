@@ -0,0 +1,306 @@
+//! The wire format. Every message is a frame: a little endian `u32` length followed by that many
+//! bytes. A request starts with an opcode and a response with a tag, followed by their fields.
+//! Byte strings are a `u32` length and the bytes, optional ones are preceded by a flag byte.
+
+use std::io::{self, Read, Write};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use crate::prelude::*;
+
+/// Largest frame either side accepts
+pub const MAX_FRAME_SIZE: usize = 64 << 20;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Request {
+    Get(Vec<u8>),
+    Set(Vec<u8>, Vec<u8>),
+    Del(Vec<u8>),
+    /// Up to `limit` pairs from `start`, stopping before `end`
+    Scan {
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: u32,
+    },
+    /// Starts buffering the writes of this connection
+    Begin,
+    /// Applies the buffered writes in one commit, unless what the connection read since `Begin`
+    /// was written in the meantime
+    Commit,
+    /// Drops the buffered writes
+    Abort,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Response {
+    Ok,
+    Value(Option<Vec<u8>>),
+    Bool(bool),
+    Items(Vec<(Vec<u8>, Vec<u8>)>),
+    Error(String),
+}
+
+const OP_GET: u8 = 1;
+const OP_SET: u8 = 2;
+const OP_DEL: u8 = 3;
+const OP_SCAN: u8 = 4;
+const OP_BEGIN: u8 = 5;
+const OP_COMMIT: u8 = 6;
+const OP_ABORT: u8 = 7;
+
+const TAG_OK: u8 = 0;
+const TAG_VALUE: u8 = 1;
+const TAG_BOOL: u8 = 2;
+const TAG_ITEMS: u8 = 3;
+const TAG_ERROR: u8 = 4;
+
+/** Reads one frame. Returns `None` if the stream ends before it starts */
+pub fn read_frame(stream: &mut impl Read) -> Result<Option<Vec<u8>>> {
+    let mut header = [0; 4];
+    match stream.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+    let len = LittleEndian::read_u32(&header) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(Error::Generic(format!(
+            "frame of {} bytes is too large",
+            len
+        )));
+    }
+    let mut frame = vec![0; len];
+    stream.read_exact(&mut frame)?;
+    Ok(Some(frame))
+}
+
+/** Writes `body` as one frame */
+pub fn write_frame(stream: &mut impl Write, body: &[u8]) -> Result<()> {
+    if body.len() > MAX_FRAME_SIZE {
+        return Err(Error::Generic(format!(
+            "frame of {} bytes is too large",
+            body.len()
+        )));
+    }
+    // header and body in one write, so small frames go out in one packet
+    let mut frame = Vec::with_capacity(4 + body.len());
+    frame.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    frame.extend_from_slice(body);
+    stream.write_all(&frame)?;
+    stream.flush()?;
+    Ok(())
+}
+
+impl Request {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        match self {
+            Request::Get(key) => {
+                out.push(OP_GET);
+                put_bytes(&mut out, key);
+            }
+            Request::Set(key, val) => {
+                out.push(OP_SET);
+                put_bytes(&mut out, key);
+                put_bytes(&mut out, val);
+            }
+            Request::Del(key) => {
+                out.push(OP_DEL);
+                put_bytes(&mut out, key);
+            }
+            Request::Scan { start, end, limit } => {
+                out.push(OP_SCAN);
+                put_bytes(&mut out, start);
+                put_option(&mut out, end.as_deref());
+                out.extend_from_slice(&limit.to_le_bytes());
+            }
+            Request::Begin => out.push(OP_BEGIN),
+            Request::Commit => out.push(OP_COMMIT),
+            Request::Abort => out.push(OP_ABORT),
+        }
+        out
+    }
+
+    pub fn decode(frame: &[u8]) -> Result<Request> {
+        let mut reader = Reader { data: frame };
+        let request = match reader.u8()? {
+            OP_GET => Request::Get(reader.bytes()?),
+            OP_SET => Request::Set(reader.bytes()?, reader.bytes()?),
+            OP_DEL => Request::Del(reader.bytes()?),
+            OP_SCAN => Request::Scan {
+                start: reader.bytes()?,
+                end: reader.option()?,
+                limit: reader.u32()?,
+            },
+            OP_BEGIN => Request::Begin,
+            OP_COMMIT => Request::Commit,
+            OP_ABORT => Request::Abort,
+            op => return Err(Error::Generic(format!("unknown opcode {}", op))),
+        };
+        reader.finish()?;
+        Ok(request)
+    }
+}
+
+impl Response {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        match self {
+            Response::Ok => out.push(TAG_OK),
+            Response::Value(val) => {
+                out.push(TAG_VALUE);
+                put_option(&mut out, val.as_deref());
+            }
+            Response::Bool(flag) => {
+                out.push(TAG_BOOL);
+                out.push(*flag as u8);
+            }
+            Response::Items(items) => {
+                out.push(TAG_ITEMS);
+                out.extend_from_slice(&(items.len() as u32).to_le_bytes());
+                for (key, val) in items {
+                    put_bytes(&mut out, key);
+                    put_bytes(&mut out, val);
+                }
+            }
+            Response::Error(message) => {
+                out.push(TAG_ERROR);
+                put_bytes(&mut out, message.as_bytes());
+            }
+        }
+        out
+    }
+
+    pub fn decode(frame: &[u8]) -> Result<Response> {
+        let mut reader = Reader { data: frame };
+        let response = match reader.u8()? {
+            TAG_OK => Response::Ok,
+            TAG_VALUE => Response::Value(reader.option()?),
+            TAG_BOOL => Response::Bool(reader.u8()? != 0),
+            TAG_ITEMS => {
+                let count = reader.u32()?;
+                let mut items = Vec::new();
+                for _ in 0..count {
+                    items.push((reader.bytes()?, reader.bytes()?));
+                }
+                Response::Items(items)
+            }
+            TAG_ERROR => Response::Error(String::from_utf8_lossy(&reader.bytes()?).to_string()),
+            tag => return Err(Error::Generic(format!("unknown response tag {}", tag))),
+        };
+        reader.finish()?;
+        Ok(response)
+    }
+}
+
+fn put_bytes(out: &mut Vec<u8>, data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+}
+
+fn put_option(out: &mut Vec<u8>, data: Option<&[u8]>) {
+    match data {
+        Some(data) => {
+            out.push(1);
+            put_bytes(out, data);
+        }
+        None => out.push(0),
+    }
+}
+
+/// Takes fields off the front of a frame
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8]> {
+        if self.data.len() < len {
+            return Err(Error::Static("truncated frame"));
+        }
+        let (head, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(LittleEndian::read_u32(self.take(4)?))
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn option(&mut self) -> Result<Option<Vec<u8>>> {
+        match self.u8()? {
+            0 => Ok(None),
+            _ => Ok(Some(self.bytes()?)),
+        }
+    }
+
+    fn finish(&self) -> Result<()> {
+        if !self.data.is_empty() {
+            return Err(Error::Static("trailing bytes in frame"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let requests = [
+            Request::Get(b"k".to_vec()),
+            Request::Set(b"k".to_vec(), vec![]),
+            Request::Del(vec![0, 255]),
+            Request::Scan {
+                start: vec![],
+                end: Some(b"z".to_vec()),
+                limit: 7,
+            },
+            Request::Begin,
+            Request::Commit,
+            Request::Abort,
+        ];
+        for request in requests {
+            assert_eq!(Request::decode(&request.encode()).unwrap(), request);
+        }
+        let responses = [
+            Response::Ok,
+            Response::Value(None),
+            Response::Value(Some(b"v".to_vec())),
+            Response::Bool(true),
+            Response::Items(vec![(b"a".to_vec(), b"1".to_vec())]),
+            Response::Error("bad".to_string()),
+        ];
+        for response in responses {
+            assert_eq!(Response::decode(&response.encode()).unwrap(), response);
+        }
+    }
+
+    #[test]
+    fn test_bad_frames() {
+        assert!(Request::decode(&[]).is_err());
+        assert!(Request::decode(&[99]).is_err());
+        // a key longer than the frame
+        assert!(Request::decode(&[OP_GET, 10, 0, 0, 0, b'k']).is_err());
+        assert!(Request::decode(&[OP_BEGIN, 0]).is_err());
+
+        let mut stream: &[u8] = &[];
+        assert!(read_frame(&mut stream).unwrap().is_none());
+        let huge = (MAX_FRAME_SIZE as u32 + 1).to_le_bytes();
+        assert!(read_frame(&mut &huge[..]).is_err());
+
+        let mut out = Vec::new();
+        write_frame(&mut out, b"abc").unwrap();
+        assert_eq!(read_frame(&mut &out[..]).unwrap().unwrap(), b"abc");
+    }
+}
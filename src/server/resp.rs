@@ -0,0 +1,729 @@
+//! A subset of the Redis protocol (RESP), so Redis tools and client libraries can be used against
+//! a `KV`. Supports GET, SET with NX or XX, DEL, EXISTS, INCR, SCAN with prefix patterns and
+//! MULTI/EXEC, plus PING, ECHO and QUIT.
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Read, Write},
+};
+
+use crate::prelude::*;
+use crate::{
+    kv_store::{InsertMode, KV},
+    worker::WriteQueue,
+};
+
+use super::protocol::MAX_FRAME_SIZE;
+
+/// Most arguments a command can have
+const MAX_ARGS: usize = 1 << 20;
+/// Keys a SCAN returns when no COUNT is given
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn ok() -> Reply {
+        Reply::Simple("OK".to_string())
+    }
+
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Reply::Simple(text) => out.extend_from_slice(format!("+{}\r\n", text).as_bytes()),
+            Reply::Error(text) => out.extend_from_slice(format!("-{}\r\n", text).as_bytes()),
+            Reply::Integer(n) => out.extend_from_slice(format!(":{}\r\n", n).as_bytes()),
+            Reply::Bulk(None) => out.extend_from_slice(b"$-1\r\n"),
+            Reply::Bulk(Some(data)) => {
+                out.extend_from_slice(format!("${}\r\n", data.len()).as_bytes());
+                out.extend_from_slice(data);
+                out.extend_from_slice(b"\r\n");
+            }
+            Reply::Array(items) => {
+                out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(out);
+                }
+            }
+        }
+    }
+}
+
+/** Reads one command, either an array of bulk strings or an inline line of words. Returns `None`
+ * if the stream ends before it starts */
+pub fn read_command(reader: &mut impl BufRead) -> Result<Option<Vec<Vec<u8>>>> {
+    loop {
+        let line = match read_line(reader)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        if line.first() != Some(&b'*') {
+            let words: Vec<Vec<u8>> = line
+                .split(|byte| byte.is_ascii_whitespace())
+                .filter(|word| !word.is_empty())
+                .map(<[u8]>::to_vec)
+                .collect();
+            // blank lines are skipped, as Redis does
+            if words.is_empty() {
+                continue;
+            }
+            return Ok(Some(words));
+        }
+
+        let count = parse_length(&line[1..], MAX_ARGS)?;
+        let mut args = Vec::with_capacity(count);
+        for _ in 0..count {
+            let header = read_line(reader)?.ok_or(Error::Static("Protocol error: truncated"))?;
+            if header.first() != Some(&b'$') {
+                return Err(Error::Static("Protocol error: expected '$'"));
+            }
+            let len = parse_length(&header[1..], MAX_FRAME_SIZE)?;
+            let mut arg = vec![0; len + 2];
+            reader.read_exact(&mut arg)?;
+            if !arg.ends_with(b"\r\n") {
+                return Err(Error::Static("Protocol error: bulk string not terminated"));
+            }
+            arg.truncate(len);
+            args.push(arg);
+        }
+        if args.is_empty() {
+            continue;
+        }
+        return Ok(Some(args));
+    }
+}
+
+/** A line without its line ending, or `None` at the end of the stream */
+fn read_line(reader: &mut impl BufRead) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    // a line can't be longer than the largest bulk string header
+    reader
+        .by_ref()
+        .take(64 << 10)
+        .read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(Error::Static("Protocol error: line too long"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_length(text: &[u8], max: usize) -> Result<usize> {
+    std::str::from_utf8(text)
+        .ok()
+        .and_then(|text| text.parse::<usize>().ok())
+        .filter(|len| *len <= max)
+        .ok_or(Error::Static("Protocol error: invalid length"))
+}
+
+/// A command that reads or writes the store, run as one job on the write queue
+#[derive(Debug, Clone, PartialEq)]
+enum Command {
+    Get(Vec<u8>),
+    Set(Vec<u8>, Vec<u8>, InsertMode),
+    Del(Vec<Vec<u8>>),
+    Exists(Vec<Vec<u8>>),
+    Incr(Vec<u8>),
+}
+
+fn wrong_arity(name: &str) -> Reply {
+    Reply::Error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        name.to_ascii_lowercase()
+    ))
+}
+
+fn syntax_error() -> Reply {
+    Reply::Error("ERR syntax error".to_string())
+}
+
+impl Command {
+    /** Returns `None` for names that are not data commands */
+    fn parse(name: &str, args: &[Vec<u8>]) -> Option<std::result::Result<Command, Reply>> {
+        let command = match (name, args) {
+            ("GET", [key]) => Ok(Command::Get(key.clone())),
+            ("SET", [key, val, options @ ..]) => {
+                let mut mode = InsertMode::Upsert;
+                for option in options {
+                    mode = match (option.to_ascii_uppercase().as_slice(), mode) {
+                        (b"NX", InsertMode::Upsert) => InsertMode::InsertOnly,
+                        (b"XX", InsertMode::Upsert) => InsertMode::UpdateOnly,
+                        _ => return Some(Err(syntax_error())),
+                    };
+                }
+                Ok(Command::Set(key.clone(), val.clone(), mode))
+            }
+            ("DEL", keys) if !keys.is_empty() => Ok(Command::Del(keys.to_vec())),
+            ("EXISTS", keys) if !keys.is_empty() => Ok(Command::Exists(keys.to_vec())),
+            ("INCR", [key]) => Ok(Command::Incr(key.clone())),
+            ("GET" | "SET" | "DEL" | "EXISTS" | "INCR", _) => Err(wrong_arity(name)),
+            _ => return None,
+        };
+        Some(command)
+    }
+
+    fn apply(self, kv: &mut KV) -> Reply {
+        self.run(kv)
+            .unwrap_or_else(|err| Reply::Error(format!("ERR {}", err)))
+    }
+
+    fn run(self, kv: &mut KV) -> Result<Reply> {
+        Ok(match self {
+            Command::Get(key) => Reply::Bulk(kv.get(&key)),
+            Command::Set(key, val, mode) => {
+                let exists = kv.get(&key).is_some();
+                let added = kv.update(&key, &val, mode)?;
+                let written = match mode {
+                    InsertMode::Upsert => true,
+                    InsertMode::InsertOnly => added,
+                    InsertMode::UpdateOnly => exists,
+                };
+                // NX and XX answer nil when they leave the key alone
+                if written {
+                    Reply::ok()
+                } else {
+                    Reply::Bulk(None)
+                }
+            }
+            Command::Del(keys) => {
+                let mut deleted = 0;
+                for key in keys {
+                    deleted += kv.del(&key)? as i64;
+                }
+                Reply::Integer(deleted)
+            }
+            Command::Exists(keys) => {
+                Reply::Integer(keys.iter().filter(|key| kv.get(key).is_some()).count() as i64)
+            }
+            Command::Incr(key) => {
+                let current = match kv.get(&key) {
+                    Some(val) => match std::str::from_utf8(&val)
+                        .ok()
+                        .and_then(|val| val.parse::<i64>().ok())
+                    {
+                        Some(n) => n,
+                        None => {
+                            return Ok(Reply::Error(
+                                "ERR value is not an integer or out of range".to_string(),
+                            ))
+                        }
+                    },
+                    None => 0,
+                };
+                let next = match current.checked_add(1) {
+                    Some(next) => next,
+                    None => {
+                        return Ok(Reply::Error(
+                            "ERR increment or decrement would overflow".to_string(),
+                        ))
+                    }
+                };
+                kv.set(&key, next.to_string().as_bytes())?;
+                Reply::Integer(next)
+            }
+        })
+    }
+}
+
+/// Commands queued between MULTI and EXEC
+struct Multi {
+    commands: Vec<Command>,
+    /// A command was rejected while queueing, EXEC refuses to run the rest
+    failed: bool,
+}
+
+/// The state of one connection
+struct Session<'a> {
+    queue: &'a WriteQueue,
+    multi: Option<Multi>,
+    /// Where each SCAN cursor handed out resumes
+    cursors: HashMap<u64, Vec<u8>>,
+    next_cursor: u64,
+    closing: bool,
+}
+
+/** Answers the commands of one connection until it closes or sends QUIT */
+pub fn handle(stream: impl Read + Write, queue: &WriteQueue) -> Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut session = Session {
+        queue,
+        multi: None,
+        cursors: HashMap::new(),
+        next_cursor: 1,
+        closing: false,
+    };
+    while !session.closing {
+        let (reply, result) = match read_command(&mut reader) {
+            Ok(Some(args)) => (session.execute(args), Ok(())),
+            Ok(None) => return Ok(()),
+            // the stream can't be trusted after a malformed command
+            Err(err) => (Reply::Error(format!("ERR {}", err)), Err(err)),
+        };
+        let mut out = Vec::new();
+        reply.encode(&mut out);
+        let stream = reader.get_mut();
+        stream.write_all(&out)?;
+        stream.flush()?;
+        result?;
+    }
+    Ok(())
+}
+
+impl Session<'_> {
+    fn execute(&mut self, args: Vec<Vec<u8>>) -> Reply {
+        let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+        let args = &args[1..];
+        match (name.as_str(), args) {
+            ("MULTI", []) => {
+                if self.multi.is_some() {
+                    return Reply::Error("ERR MULTI calls can not be nested".to_string());
+                }
+                self.multi = Some(Multi {
+                    commands: Vec::new(),
+                    failed: false,
+                });
+                return Reply::ok();
+            }
+            ("EXEC", []) => return self.exec(),
+            ("DISCARD", []) => {
+                return match self.multi.take() {
+                    Some(_) => Reply::ok(),
+                    None => Reply::Error("ERR DISCARD without MULTI".to_string()),
+                }
+            }
+            ("MULTI" | "EXEC" | "DISCARD", _) => return self.reject(wrong_arity(&name)),
+            _ => {}
+        }
+
+        let command = match Command::parse(&name, args) {
+            Some(Ok(command)) => command,
+            Some(Err(reply)) => return self.reject(reply),
+            None if self.multi.is_some() => {
+                return self.reject(Reply::Error(format!(
+                    "ERR '{}' is not supported inside MULTI",
+                    name.to_ascii_lowercase()
+                )))
+            }
+            None => return self.connection_command(&name, args),
+        };
+        if let Some(multi) = &mut self.multi {
+            multi.commands.push(command);
+            return Reply::Simple("QUEUED".to_string());
+        }
+        self.queue
+            .run(move |kv| Ok(command.apply(kv)))
+            .unwrap_or_else(|err| Reply::Error(format!("ERR {}", err)))
+    }
+
+    /** Replies with an error, failing the open MULTI if there is one */
+    fn reject(&mut self, reply: Reply) -> Reply {
+        if let Some(multi) = &mut self.multi {
+            multi.failed = true;
+        }
+        reply
+    }
+
+    /** Runs the queued commands as one job, so nothing runs in between and they commit together */
+    fn exec(&mut self) -> Reply {
+        let multi = match self.multi.take() {
+            Some(multi) => multi,
+            None => return Reply::Error("ERR EXEC without MULTI".to_string()),
+        };
+        if multi.failed {
+            return Reply::Error(
+                "EXECABORT Transaction discarded because of previous errors.".to_string(),
+            );
+        }
+        let commands = multi.commands;
+        self.queue
+            .run(move |kv| {
+                let replies = commands
+                    .into_iter()
+                    .map(|command| command.apply(kv))
+                    .collect();
+                Ok(Reply::Array(replies))
+            })
+            .unwrap_or_else(|err| Reply::Error(format!("ERR {}", err)))
+    }
+
+    /** Commands that don't touch the store, and SCAN whose cursors belong to the connection */
+    fn connection_command(&mut self, name: &str, args: &[Vec<u8>]) -> Reply {
+        match (name, args) {
+            ("PING", []) => Reply::Simple("PONG".to_string()),
+            ("PING" | "ECHO", [message]) => Reply::Bulk(Some(message.clone())),
+            // tools ask for the command table on connect, an empty one is allowed
+            ("COMMAND", _) => Reply::Array(Vec::new()),
+            ("QUIT", []) => {
+                self.closing = true;
+                Reply::ok()
+            }
+            ("SCAN", [cursor, options @ ..]) => self.scan(cursor, options),
+            ("PING" | "ECHO" | "QUIT" | "SCAN", _) => wrong_arity(name),
+            _ => Reply::Error(format!(
+                "ERR unknown command '{}'",
+                name.to_ascii_lowercase()
+            )),
+        }
+    }
+
+    /** Returns the next keys in order. A cursor is only valid on the connection that got it */
+    fn scan(&mut self, cursor: &[u8], options: &[Vec<u8>]) -> Reply {
+        let mut pattern = b"*".as_slice();
+        let mut count = DEFAULT_SCAN_COUNT;
+        let mut options = options.iter();
+        while let Some(option) = options.next() {
+            let value = match options.next() {
+                Some(value) => value,
+                None => return syntax_error(),
+            };
+            match option.to_ascii_uppercase().as_slice() {
+                b"MATCH" => pattern = value,
+                b"COUNT" => match std::str::from_utf8(value).ok().and_then(|n| n.parse().ok()) {
+                    Some(n) if n >= 1 => count = n,
+                    _ => return syntax_error(),
+                },
+                _ => return syntax_error(),
+            }
+        }
+        let (prefix, end) = match pattern_range(pattern) {
+            Some(range) => range,
+            None => {
+                return Reply::Error(
+                    "ERR only prefix patterns such as 'user:*' are supported".to_string(),
+                )
+            }
+        };
+
+        let start = match std::str::from_utf8(cursor)
+            .ok()
+            .and_then(|n| n.parse().ok())
+        {
+            Some(0) => prefix,
+            Some(cursor) => match self.cursors.remove(&cursor) {
+                Some(start) => start,
+                None => return Reply::Error("ERR invalid cursor".to_string()),
+            },
+            None => return Reply::Error("ERR invalid cursor".to_string()),
+        };
+        let mut items = match self.queue.scan(&start, end.as_deref(), count + 1) {
+            Ok(items) => items,
+            Err(err) => return Reply::Error(format!("ERR {}", err)),
+        };
+
+        // one key past the page says where the next call resumes
+        let mut next = 0;
+        if items.len() > count {
+            next = self.next_cursor;
+            self.next_cursor += 1;
+            self.cursors.insert(next, items.pop().unwrap().0);
+        }
+        let keys = items
+            .into_iter()
+            .map(|(key, _)| Reply::Bulk(Some(key)))
+            .collect();
+        Reply::Array(vec![
+            Reply::Bulk(Some(next.to_string().into_bytes())),
+            Reply::Array(keys),
+        ])
+    }
+}
+
+/** The key range a MATCH pattern covers: `prefix*` or an exact key. Returns `None` for other
+ * glob patterns */
+fn pattern_range(pattern: &[u8]) -> Option<(Vec<u8>, Option<Vec<u8>>)> {
+    let is_glob = |byte: &u8| matches!(byte, b'*' | b'?' | b'[' | b'\\');
+    match pattern.split_last() {
+        Some((b'*', prefix)) if !prefix.iter().any(is_glob) => {
+            Some((prefix.to_vec(), prefix_end(prefix)))
+        }
+        _ if !pattern.iter().any(is_glob) => {
+            let mut end = pattern.to_vec();
+            end.push(0);
+            Some((pattern.to_vec(), Some(end)))
+        }
+        _ => None,
+    }
+}
+
+/** The first key after every key starting with `prefix`, `None` if there is none */
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpStream, thread};
+
+    use super::*;
+    use crate::server::{Address, Protocol, Server};
+
+    fn start() -> TcpStream {
+        let server = Server::bind(
+            &Address::parse("127.0.0.1:0"),
+            KV::open_in_memory().unwrap(),
+        )
+        .unwrap()
+        .protocol(Protocol::Resp);
+        let Address::Tcp(address) = server.local_address().unwrap() else {
+            unreachable!()
+        };
+        thread::spawn(move || server.serve());
+        TcpStream::connect(address).unwrap()
+    }
+
+    /// Sends a command and checks the raw reply
+    fn call(stream: &mut TcpStream, args: &[&str], expected: &str) {
+        let mut out = Vec::new();
+        let args = args
+            .iter()
+            .map(|arg| Reply::Bulk(Some(arg.as_bytes().to_vec())))
+            .collect();
+        Reply::Array(args).encode(&mut out);
+        stream.write_all(&out).unwrap();
+        let mut reply = vec![0; expected.len()];
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!(String::from_utf8_lossy(&reply), expected);
+    }
+
+    #[test]
+    fn test_redis_client() {
+        let stream = start();
+        let address = format!("redis://{}/", stream.peer_addr().unwrap());
+        let mut con = redis::Client::open(address)
+            .unwrap()
+            .get_connection()
+            .unwrap();
+
+        let _: () = redis::cmd("SET")
+            .arg("user:1")
+            .arg("ada")
+            .query(&mut con)
+            .unwrap();
+        let name: Option<String> = redis::cmd("GET").arg("user:1").query(&mut con).unwrap();
+        assert_eq!(name.as_deref(), Some("ada"));
+        let set: Option<String> = redis::cmd("SET")
+            .arg("user:1")
+            .arg("bob")
+            .arg("NX")
+            .query(&mut con)
+            .unwrap();
+        assert_eq!(set, None);
+
+        let (count, exists): (i64, i64) = redis::pipe()
+            .atomic()
+            .cmd("INCR")
+            .arg("visits")
+            .cmd("EXISTS")
+            .arg("user:1")
+            .arg("user:2")
+            .query(&mut con)
+            .unwrap();
+        assert_eq!((count, exists), (1, 1));
+
+        for i in 2..30 {
+            let _: () = redis::cmd("SET")
+                .arg(format!("user:{}", i))
+                .arg("x")
+                .query(&mut con)
+                .unwrap();
+        }
+        let mut keys: Vec<String> = redis::cmd("SCAN")
+            .cursor_arg(0)
+            .arg("MATCH")
+            .arg("user:*")
+            .clone()
+            .iter(&mut con)
+            .unwrap()
+            .collect();
+        keys.sort();
+        keys.dedup();
+        assert_eq!(keys.len(), 29);
+    }
+
+    #[test]
+    fn test_read_command() {
+        let mut input: &[u8] = b"*2\r\n$3\r\nGET\r\n$0\r\n\r\n\r\nset a  b\n";
+        assert_eq!(
+            read_command(&mut input).unwrap().unwrap(),
+            vec![b"GET".to_vec(), vec![]]
+        );
+        assert_eq!(
+            read_command(&mut input).unwrap().unwrap(),
+            vec![b"set".to_vec(), b"a".to_vec(), b"b".to_vec()]
+        );
+        assert!(read_command(&mut input).unwrap().is_none());
+
+        for bad in [
+            &b"*1\r\n:3\r\n"[..],
+            b"*x\r\n",
+            b"*1\r\n$3\r\nGETXX",
+            b"*1\r\n",
+        ] {
+            assert!(read_command(&mut &bad[..]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_pattern_range() {
+        assert_eq!(pattern_range(b"*"), Some((vec![], None)));
+        assert_eq!(
+            pattern_range(b"ab*"),
+            Some((b"ab".to_vec(), Some(b"ac".to_vec())))
+        );
+        assert_eq!(
+            pattern_range(b"a\xff*"),
+            Some((b"a\xff".to_vec(), Some(b"b".to_vec())))
+        );
+        assert_eq!(
+            pattern_range(b"ab"),
+            Some((b"ab".to_vec(), Some(b"ab\0".to_vec())))
+        );
+        assert_eq!(pattern_range(b"a*b*"), None);
+        assert_eq!(pattern_range(b"a?"), None);
+    }
+
+    #[test]
+    fn test_strings() {
+        let mut stream = start();
+        call(&mut stream, &["PING"], "+PONG\r\n");
+        call(&mut stream, &["get", "a"], "$-1\r\n");
+        call(&mut stream, &["SET", "a", "1"], "+OK\r\n");
+        call(&mut stream, &["GET", "a"], "$1\r\n1\r\n");
+        call(&mut stream, &["SET", "a", "2", "NX"], "$-1\r\n");
+        call(&mut stream, &["SET", "b", "2", "xx"], "$-1\r\n");
+        call(&mut stream, &["SET", "b", "2", "NX"], "+OK\r\n");
+        call(&mut stream, &["SET", "a", "3", "XX"], "+OK\r\n");
+        call(&mut stream, &["GET", "a"], "$1\r\n3\r\n");
+        call(
+            &mut stream,
+            &["SET", "a", "3", "NX", "XX"],
+            "-ERR syntax error\r\n",
+        );
+        call(&mut stream, &["EXISTS", "a", "b", "c", "a"], ":3\r\n");
+        call(&mut stream, &["DEL", "a", "c"], ":1\r\n");
+
+        call(&mut stream, &["INCR", "n"], ":1\r\n");
+        call(&mut stream, &["INCR", "n"], ":2\r\n");
+        call(&mut stream, &["SET", "n", "x"], "+OK\r\n");
+        call(
+            &mut stream,
+            &["INCR", "n"],
+            "-ERR value is not an integer or out of range\r\n",
+        );
+        call(
+            &mut stream,
+            &["GET"],
+            "-ERR wrong number of arguments for 'get' command\r\n",
+        );
+        call(
+            &mut stream,
+            &["FLUSHALL"],
+            "-ERR unknown command 'flushall'\r\n",
+        );
+
+        // writes the store rejects fail without closing the connection
+        let long_key = "k".repeat(1001);
+        call(
+            &mut stream,
+            &["SET", &long_key, "1"],
+            "-ERR Generic error: keys can be at most 1000 bytes\r\n",
+        );
+        call(
+            &mut stream,
+            &["INCR", &long_key],
+            "-ERR Generic error: keys can be at most 1000 bytes\r\n",
+        );
+        call(
+            &mut stream,
+            &["SET", "a", &"v".repeat(3001)],
+            "-ERR Generic error: values can be at most 3000 bytes\r\n",
+        );
+        call(&mut stream, &["GET", &long_key], "$-1\r\n");
+
+        // inline commands, as typed into telnet
+        stream.write_all(b"GET b\r\n").unwrap();
+        let mut reply = [0; 7];
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"$1\r\n2\r\n");
+        call(&mut stream, &["QUIT"], "+OK\r\n");
+        assert_eq!(stream.read(&mut reply).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_scan() {
+        let mut stream = start();
+        for key in ["user:1", "user:2", "user:3", "item:1"] {
+            call(&mut stream, &["SET", key, "x"], "+OK\r\n");
+        }
+        call(
+            &mut stream,
+            &["SCAN", "0", "MATCH", "user:*", "COUNT", "2"],
+            "*2\r\n$1\r\n1\r\n*2\r\n$6\r\nuser:1\r\n$6\r\nuser:2\r\n",
+        );
+        call(
+            &mut stream,
+            &["SCAN", "1", "MATCH", "user:*", "COUNT", "2"],
+            "*2\r\n$1\r\n0\r\n*1\r\n$6\r\nuser:3\r\n",
+        );
+        call(&mut stream, &["SCAN", "1"], "-ERR invalid cursor\r\n");
+        call(
+            &mut stream,
+            &["SCAN", "0"],
+            "*2\r\n$1\r\n0\r\n*4\r\n$6\r\nitem:1\r\n$6\r\nuser:1\r\n$6\r\nuser:2\r\n$6\r\nuser:3\r\n",
+        );
+        call(
+            &mut stream,
+            &["SCAN", "0", "MATCH", "*:1"],
+            "-ERR only prefix patterns such as 'user:*' are supported\r\n",
+        );
+    }
+
+    #[test]
+    fn test_multi_exec() {
+        let mut stream = start();
+        call(&mut stream, &["EXEC"], "-ERR EXEC without MULTI\r\n");
+        call(&mut stream, &["MULTI"], "+OK\r\n");
+        call(&mut stream, &["SET", "a", "1"], "+QUEUED\r\n");
+        call(&mut stream, &["INCR", "a"], "+QUEUED\r\n");
+        call(&mut stream, &["GET", "a"], "+QUEUED\r\n");
+        call(&mut stream, &["EXEC"], "*3\r\n+OK\r\n:2\r\n$1\r\n2\r\n");
+
+        call(&mut stream, &["MULTI"], "+OK\r\n");
+        call(&mut stream, &["SET", "a", "5"], "+QUEUED\r\n");
+        call(&mut stream, &["DISCARD"], "+OK\r\n");
+        call(&mut stream, &["GET", "a"], "$1\r\n2\r\n");
+
+        // a command rejected while queueing discards the transaction
+        call(&mut stream, &["MULTI"], "+OK\r\n");
+        call(&mut stream, &["SET", "a", "5"], "+QUEUED\r\n");
+        call(
+            &mut stream,
+            &["SET", "a"],
+            "-ERR wrong number of arguments for 'set' command\r\n",
+        );
+        call(
+            &mut stream,
+            &["EXEC"],
+            "-EXECABORT Transaction discarded because of previous errors.\r\n",
+        );
+        call(&mut stream, &["GET", "a"], "$1\r\n2\r\n");
+    }
+}
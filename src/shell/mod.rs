@@ -0,0 +1,566 @@
+pub mod parse;
+
+use std::{fs::File, io::BufReader};
+
+use crate::kv_store::{render_dot, render_text, CmpOption};
+use crate::prelude::*;
+use crate::relational_db::{dump::DumpFormat, records::Record, tables::TableDef, value::Value, DB};
+
+use self::parse::{format_bytes, tokenize};
+
+/// Number of keys `scan` lists when no limit is given
+const DEFAULT_SCAN_LIMIT: usize = 20;
+
+pub const HELP: &str = "\
+Raw keys and values:
+  get <key>                          read a key
+  set <key> <value>                  write a key
+  del <key>                          delete a key
+  scan [start] [limit]               list keys from `start`, 20 by default
+Tables:
+  tables                             list the tables
+  schema <table>                     show a table definition
+  create <table> <pkeys> <col:int|bytes>...
+                                     create a table, the first `pkeys` columns are the key
+  select <table> [col=value]...      get a row by its primary key, or list every row
+  insert|update|upsert <table> col=value...
+                                     write a row
+  delete <table> col=value...        delete a row by its primary key
+  export <table> jsonl|csv           print every row of a table
+  import <table> jsonl|csv <file>    load rows from a file
+Storage:
+  master                             show the master page
+  freelist                           show the free list
+  tree [text|dot] [depth]            show the B-tree pages, down to `depth` levels
+  stats [table]                      show the size of the database or of one table
+  compact                            move pages to the start of the file and shrink it
+  backup <file>                      copy the database to a new file
+Arguments can be quoted, and escapes such as \\n or \\xff give arbitrary bytes.";
+
+/// Runs commands against an open database
+pub struct Shell {
+    db: DB,
+}
+
+impl Shell {
+    pub fn new(db: DB) -> Self {
+        Self { db }
+    }
+
+    pub fn into_db(self) -> DB {
+        self.db
+    }
+
+    /** Runs one command line. Returns the text to print */
+    pub fn execute(&mut self, line: &str) -> Result<String> {
+        self.run(tokenize(line)?)
+    }
+
+    /** Runs a command that has already been split into arguments */
+    pub fn run(&mut self, args: Vec<Vec<u8>>) -> Result<String> {
+        let Some((command, args)) = args.split_first() else {
+            return Ok(String::new());
+        };
+
+        match String::from_utf8_lossy(command).as_ref() {
+            "get" => {
+                let [key] = expect_args(args, "get <key>")?;
+                Ok(match self.db.kv().get(key) {
+                    Some(value) => format_bytes(&value),
+                    None => "(not found)".to_string(),
+                })
+            }
+            "set" => {
+                let [key, value] = expect_args(args, "set <key> <value>")?;
+                check_key(key)?;
+                self.db.kv_mut().set(key, value)?;
+                Ok(String::new())
+            }
+            "del" => {
+                let [key] = expect_args(args, "del <key>")?;
+                check_key(key)?;
+                let deleted = self.db.kv_mut().del(key)?;
+                Ok(if deleted { "deleted" } else { "(not found)" }.to_string())
+            }
+            "scan" => self.scan(args),
+            "tables" => {
+                let mut out = Vec::new();
+                for record in self.db.scan("@table")? {
+                    out.push(record.get("name").unwrap().bytes_to_string().unwrap());
+                }
+                Ok(out.join("\n"))
+            }
+            "schema" => {
+                let [table] = expect_args(args, "schema <table>")?;
+                let table_def = self.table_def(table)?;
+                let mut out = Vec::new();
+                for (i, column) in table_def.columns.iter().enumerate() {
+                    let key = if i < table_def.primary_keys {
+                        " primary key"
+                    } else {
+                        ""
+                    };
+                    out.push(format!(
+                        "{} {}{}",
+                        column,
+                        type_name(table_def.types[i]),
+                        key
+                    ));
+                }
+                Ok(out.join("\n"))
+            }
+            "create" => self.create(args),
+            "select" => self.select(args),
+            "insert" | "update" | "upsert" => {
+                let Some((table, values)) = args.split_first() else {
+                    return Err(usage("insert|update|upsert <table> col=value..."));
+                };
+                let table_def = self.table_def(table)?;
+                let record = parse_record(&table_def, values)?;
+                // the database only reports whether a row was added
+                let out = match command.as_slice() {
+                    b"insert" => match self.db.insert(&table_def.name, record)? {
+                        true => "inserted",
+                        false => "(already exists)",
+                    },
+                    b"update" => {
+                        let mut key = Record::new();
+                        for column in &table_def.columns[..table_def.primary_keys] {
+                            if let Some(value) = record.get(column) {
+                                key.columns.push(column.clone());
+                                key.values.push(value.clone());
+                            }
+                        }
+                        if !self.db.get(&table_def.name, &mut key)? {
+                            return Ok("(not found)".to_string());
+                        }
+                        self.db.update(&table_def.name, record)?;
+                        "updated"
+                    }
+                    _ => match self.db.upsert(&table_def.name, record)? {
+                        true => "inserted",
+                        false => "updated",
+                    },
+                };
+                Ok(out.to_string())
+            }
+            "delete" => {
+                let Some((table, values)) = args.split_first() else {
+                    return Err(usage("delete <table> col=value..."));
+                };
+                let table_def = self.table_def(table)?;
+                let record = parse_record(&table_def, values)?;
+                let deleted = self.db.delete(&table_def.name, record)?;
+                Ok(if deleted { "deleted" } else { "(not found)" }.to_string())
+            }
+            "export" => {
+                let [table, format] = expect_args(args, "export <table> jsonl|csv")?;
+                let table = utf8(table)?;
+                let mut out = Vec::new();
+                self.db
+                    .export_table(table, parse_format(format)?, &mut out)?;
+                Ok(String::from_utf8_lossy(&out).trim_end().to_string())
+            }
+            "import" => {
+                let [table, format, path] = expect_args(args, "import <table> jsonl|csv <file>")?;
+                let file = File::open(utf8(path)?)?;
+                let count = self.db.import_table(
+                    utf8(table)?,
+                    parse_format(format)?,
+                    &mut BufReader::new(file),
+                )?;
+                Ok(format!("imported {} rows", count))
+            }
+            "master" => {
+                let master_page = self.db.kv().master_page();
+                Ok(format!(
+                    "btree root: {}\ntotal used pages: {}\nfree list head: {}",
+                    master_page.btree_root,
+                    master_page.total_used_pages,
+                    master_page.free_list_head
+                ))
+            }
+            "freelist" => {
+                let free_list = self.db.kv().free_list();
+                if free_list.is_empty() {
+                    return Ok("free list is empty".to_string());
+                }
+                let mut out = Vec::new();
+                for (ptr, free) in free_list {
+                    let free: Vec<String> = free.iter().map(|ptr| ptr.to_string()).collect();
+                    out.push(format!(
+                        "page {}: {} free [{}]",
+                        ptr,
+                        free.len(),
+                        free.join(" ")
+                    ));
+                }
+                Ok(out.join("\n"))
+            }
+            "tree" => {
+                let (format, depth) = match args {
+                    [] => (&b"text"[..], None),
+                    [format] => (format.as_slice(), None),
+                    [format, depth] => (format.as_slice(), Some(parse_depth(depth)?)),
+                    _ => return Err(Error::Static("usage: tree [text|dot] [depth]")),
+                };
+                let nodes = self.db.kv().inspect();
+                match format {
+                    b"text" => Ok(render_text(&nodes, depth).trim_end().to_string()),
+                    b"dot" => Ok(render_dot(&nodes, depth).trim_end().to_string()),
+                    _ => Err(Error::Static("format must be text or dot")),
+                }
+            }
+            "stats" => match args {
+                [] => {
+                    let stats = self.db.kv().stats();
+                    Ok(format!(
+                        "depth: {}\ninternal nodes: {}\nleaf nodes: {}\nkeys: {}\n\
+                         average fill: {:.1}%\nfree pages: {}\nfree list nodes: {}\n\
+                         live pages: {}\ntotal pages: {}\nfile size: {} bytes\n\
+                         mapped chunks: {}\nsyncs: {}",
+                        stats.depth,
+                        stats.internal_nodes,
+                        stats.leaf_nodes,
+                        stats.keys,
+                        stats.avg_fill * 100.0,
+                        stats.free_pages,
+                        stats.free_list_nodes,
+                        stats.live_pages(),
+                        stats.total_pages,
+                        stats.file_size,
+                        stats.mapped_chunks,
+                        stats.syncs
+                    ))
+                }
+                [table] => {
+                    let stats = self.db.table_stats(utf8(table)?)?;
+                    Ok(format!(
+                        "rows: {}\nkey bytes: {}\nvalue bytes: {}",
+                        stats.rows, stats.key_bytes, stats.value_bytes
+                    ))
+                }
+                _ => Err(Error::Static("usage: stats [table]")),
+            },
+            "compact" => {
+                let before = self.db.kv().master_page().total_used_pages;
+                self.db.kv_mut().compact()?;
+                let after = self.db.kv().master_page().total_used_pages;
+                Ok(format!("{} pages -> {} pages", before, after))
+            }
+            "backup" => {
+                let [path] = expect_args(args, "backup <file>")?;
+                self.db.kv_mut().backup_to(utf8(path)?.to_string())?;
+                Ok(String::new())
+            }
+            "help" => Ok(HELP.to_string()),
+            other => Err(Error::Generic(format!(
+                "unknown command: {}, try `help`",
+                other
+            ))),
+        }
+    }
+
+    fn scan(&mut self, args: &[Vec<u8>]) -> Result<String> {
+        if args.len() > 2 {
+            return Err(usage("scan [start] [limit]"));
+        }
+        let start = args.first().map_or(&[][..], |start| start.as_slice());
+        let limit = match args.get(1) {
+            Some(limit) => match utf8(limit)?.parse() {
+                Ok(limit) => limit,
+                Err(_) => return Err(Error::Static("limit must be a number")),
+            },
+            None => DEFAULT_SCAN_LIMIT,
+        };
+
+        // the empty key is the sentinel every tree starts with
+        let compare = if start.is_empty() {
+            CmpOption::GT
+        } else {
+            CmpOption::GE
+        };
+        let mut iter = self.db.kv().seek(start, compare);
+        let mut out = Vec::new();
+        while iter.valid() && out.len() < limit {
+            let (key, value) = iter.deref();
+            out.push(format!("{} {}", format_bytes(&key), format_bytes(&value)));
+            if !iter.next() {
+                break;
+            }
+        }
+        Ok(out.join("\n"))
+    }
+
+    fn create(&mut self, args: &[Vec<u8>]) -> Result<String> {
+        const USAGE: &str = "create <table> <pkeys> <col:int|bytes>...";
+        if args.len() < 3 {
+            return Err(usage(USAGE));
+        }
+        let primary_keys = match utf8(&args[1])?.parse() {
+            Ok(primary_keys) => primary_keys,
+            Err(_) => return Err(usage(USAGE)),
+        };
+
+        let mut table_def = TableDef {
+            name: utf8(&args[0])?.to_string(),
+            types: vec![],
+            columns: vec![],
+            primary_keys,
+            prefix: 0,
+        };
+        for column in &args[2..] {
+            let Some((name, value_type)) = utf8(column)?.split_once(':') else {
+                return Err(usage(USAGE));
+            };
+            table_def.columns.push(name.to_string());
+            table_def.types.push(match value_type {
+                "int" | "int64" => Value::INT64_TYPE,
+                "bytes" => Value::BYTES_TYPE,
+                _ => return Err(Error::Generic(format!("unknown type: {}", value_type))),
+            });
+        }
+
+        self.db.table_new(table_def)?;
+        Ok(String::new())
+    }
+
+    fn select(&mut self, args: &[Vec<u8>]) -> Result<String> {
+        let Some((table, values)) = args.split_first() else {
+            return Err(usage("select <table> [col=value]..."));
+        };
+        let table_def = self.table_def(table)?;
+
+        if values.is_empty() {
+            let rows: Vec<String> = self
+                .db
+                .scan(&table_def.name)?
+                .map(|record| format_record(&record))
+                .collect();
+            return Ok(rows.join("\n"));
+        }
+
+        let mut record = parse_record(&table_def, values)?;
+        if self.db.get(&table_def.name, &mut record)? {
+            Ok(format_record(&record))
+        } else {
+            Ok("(not found)".to_string())
+        }
+    }
+
+    fn table_def(&mut self, table: &[u8]) -> Result<TableDef> {
+        let table = utf8(table)?;
+        match self.db.table_def(table) {
+            Some(table_def) => Ok(table_def),
+            None => Err(Error::Generic(format!("Table not found {}", table))),
+        }
+    }
+}
+
+fn usage(usage: &str) -> Error {
+    Error::Generic(format!("usage: {}", usage))
+}
+
+fn expect_args<'a, const N: usize>(args: &'a [Vec<u8>], usage_text: &str) -> Result<[&'a [u8]; N]> {
+    if args.len() != N {
+        return Err(usage(usage_text));
+    }
+    Ok(std::array::from_fn(|i| args[i].as_slice()))
+}
+
+fn utf8(arg: &[u8]) -> Result<&str> {
+    match std::str::from_utf8(arg) {
+        Ok(arg) => Ok(arg),
+        Err(_) => Err(Error::Static("argument is not valid UTF-8")),
+    }
+}
+
+fn check_key(key: &[u8]) -> Result<()> {
+    // the empty key is reserved for the sentinel at the start of the tree
+    if key.is_empty() {
+        return Err(Error::Static("key must not be empty"));
+    }
+    Ok(())
+}
+
+fn parse_depth(depth: &[u8]) -> Result<usize> {
+    utf8(depth)?
+        .parse()
+        .map_err(|_| Error::Static("depth must be a number"))
+}
+
+fn parse_format(format: &[u8]) -> Result<DumpFormat> {
+    match format {
+        b"jsonl" | b"json" => Ok(DumpFormat::JsonLines),
+        b"csv" => Ok(DumpFormat::Csv),
+        _ => Err(Error::Static("format must be jsonl or csv")),
+    }
+}
+
+fn type_name(value_type: u32) -> &'static str {
+    match value_type {
+        Value::INT64_TYPE => "int",
+        Value::BYTES_TYPE => "bytes",
+        _ => "unknown",
+    }
+}
+
+/** Builds a record out of `col=value` arguments, typed by the table definition */
+fn parse_record(table_def: &TableDef, args: &[Vec<u8>]) -> Result<Record> {
+    let mut record = Record::new();
+    for arg in args {
+        let Some(split) = arg.iter().position(|&b| b == b'=') else {
+            return Err(Error::Generic(format!(
+                "expected col=value, got {}",
+                format_bytes(arg)
+            )));
+        };
+        let (column, value) = (utf8(&arg[..split])?, &arg[split + 1..]);
+        let Some(i) = table_def.columns.iter().position(|c| c == column) else {
+            return Err(Error::Generic(format!("unknown column: {}", column)));
+        };
+        if record.get(column).is_some() {
+            return Err(Error::Generic(format!("duplicate column: {}", column)));
+        }
+
+        match table_def.types[i] {
+            Value::INT64_TYPE => match utf8(value)?.parse() {
+                Ok(int) => record.add_int64(column.to_string(), int),
+                Err(_) => return Err(Error::Generic(format!("{} must be an integer", column))),
+            },
+            _ => record.add_bytes(column.to_string(), value.to_vec()),
+        };
+    }
+    Ok(record)
+}
+
+fn format_record(record: &Record) -> String {
+    let columns: Vec<String> = record
+        .columns
+        .iter()
+        .zip(&record.values)
+        .map(|(column, value)| match value {
+            Value::Int64(Some(int)) => format!("{}={}", column, int),
+            Value::Bytes(Some(bytes)) => format!("{}={}", column, format_bytes(bytes)),
+            _ => format!("{}=?", column),
+        })
+        .collect();
+    columns.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shell() -> Shell {
+        Shell::new(DB::open_in_memory().unwrap())
+    }
+
+    fn run(shell: &mut Shell, line: &str) -> String {
+        shell.execute(line).unwrap()
+    }
+
+    #[test]
+    fn test_raw_keys() {
+        let mut shell = shell();
+        run(&mut shell, "set a 1");
+        run(&mut shell, "set b 'two words'");
+        run(&mut shell, "set c \\x00\\xff");
+
+        assert_eq!(run(&mut shell, "get b"), "\"two words\"");
+        assert_eq!(run(&mut shell, "get x"), "(not found)");
+        assert_eq!(
+            run(&mut shell, "scan"),
+            "a 1\nb \"two words\"\nc \"\\x00\\xff\""
+        );
+        assert_eq!(run(&mut shell, "scan b 1"), "b \"two words\"");
+
+        assert_eq!(run(&mut shell, "del a"), "deleted");
+        assert_eq!(run(&mut shell, "del a"), "(not found)");
+        assert!(shell.execute("set '' value").is_err());
+        assert!(shell.execute("get").is_err());
+    }
+
+    #[test]
+    fn test_tables() {
+        let mut shell = shell();
+        run(&mut shell, "create people 1 id:int name:bytes");
+        assert_eq!(run(&mut shell, "tables"), "people");
+        assert_eq!(
+            run(&mut shell, "schema people"),
+            "id int primary key\nname bytes"
+        );
+
+        assert_eq!(run(&mut shell, "insert people id=2 name=bob"), "inserted");
+        assert_eq!(
+            run(&mut shell, "insert people id=1 name='alice a'"),
+            "inserted"
+        );
+        assert_eq!(
+            run(&mut shell, "insert people id=1 name=x"),
+            "(already exists)"
+        );
+        assert_eq!(run(&mut shell, "update people id=3 name=x"), "(not found)");
+        assert_eq!(run(&mut shell, "select people id=2"), "id=2 name=bob");
+        assert_eq!(
+            run(&mut shell, "select people"),
+            "id=1 name=\"alice a\"\nid=2 name=bob"
+        );
+
+        assert_eq!(run(&mut shell, "upsert people id=2 name=robert"), "updated");
+        assert_eq!(run(&mut shell, "update people id=2 name=bobby"), "updated");
+        assert_eq!(run(&mut shell, "select people id=2"), "id=2 name=bobby");
+        assert_eq!(run(&mut shell, "upsert people id=2 name=robert"), "updated");
+        assert_eq!(run(&mut shell, "select people id=2"), "id=2 name=robert");
+        assert_eq!(run(&mut shell, "delete people id=2"), "deleted");
+        assert_eq!(run(&mut shell, "select people id=2"), "(not found)");
+
+        assert!(shell.execute("insert people id=one name=x").is_err());
+        assert!(shell.execute("insert people id=3 age=4").is_err());
+        assert!(shell.execute("select missing").is_err());
+        assert!(shell.execute("create bad 1 id:float").is_err());
+    }
+
+    #[test]
+    fn test_export_and_import() {
+        let mut shell = shell();
+        run(&mut shell, "create kv 1 k:bytes v:int");
+        run(&mut shell, "insert kv k=a v=1");
+        assert_eq!(run(&mut shell, "export kv csv"), "k,v\na,1");
+
+        std::fs::create_dir_all("test_run_dir").unwrap();
+        let path = "test_run_dir/shell_import.jsonl";
+        std::fs::write(path, "{\"k\":\"Yg==\",\"v\":2}\n").unwrap();
+        assert_eq!(
+            run(&mut shell, &format!("import kv jsonl {}", path)),
+            "imported 1 rows"
+        );
+        assert_eq!(run(&mut shell, "select kv k=b"), "k=b v=2");
+    }
+
+    #[test]
+    fn test_storage_commands() {
+        let mut shell = shell();
+        assert_eq!(run(&mut shell, "freelist"), "free list is empty");
+        for i in 0..200 {
+            run(&mut shell, &format!("set key{} {}", i, "v".repeat(200)));
+        }
+        for i in 0..200 {
+            run(&mut shell, &format!("del key{}", i));
+        }
+
+        let master = run(&mut shell, "master");
+        assert!(master.starts_with("btree root: "));
+        assert!(run(&mut shell, "freelist").starts_with("page "));
+        assert!(run(&mut shell, "tree").starts_with("page "));
+        assert!(run(&mut shell, "tree text 0").contains(" leaf keys=1 "));
+        assert!(run(&mut shell, "tree dot").starts_with("digraph btree {"));
+        assert!(shell.execute("tree svg").is_err());
+        assert!(run(&mut shell, "stats").starts_with("depth: 1\n"));
+        assert!(run(&mut shell, "stats @table").starts_with("rows: 0\n"));
+        assert!(shell.execute("stats missing").is_err());
+        assert!(run(&mut shell, "compact").contains(" pages -> "));
+        assert!(shell.execute("nonsense").is_err());
+        assert_eq!(run(&mut shell, ""), "");
+    }
+}
@@ -0,0 +1,128 @@
+use crate::prelude::*;
+
+/** Splits a command line into arguments. Quotes group words and backslash escapes such as `\n`
+ * or `\xff` allow arbitrary bytes, the same escapes `format_bytes` produces */
+pub fn tokenize(line: &str) -> Result<Vec<Vec<u8>>> {
+    let mut tokens = Vec::new();
+    let mut token = Vec::new();
+    // an empty pair of quotes is still an argument
+    let mut in_token = false;
+    let mut quote = None;
+
+    let mut bytes = line.bytes();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'\\' => {
+                in_token = true;
+                let escaped = match bytes.next() {
+                    Some(b'n') => b'\n',
+                    Some(b't') => b'\t',
+                    Some(b'r') => b'\r',
+                    Some(b'0') => 0,
+                    Some(b'x') => {
+                        let hex = [bytes.next(), bytes.next()];
+                        let digits = match hex {
+                            [Some(high), Some(low)] => [high, low],
+                            _ => return Err(Error::Static("incomplete \\x escape")),
+                        };
+                        let digits = std::str::from_utf8(&digits).unwrap_or("");
+                        match u8::from_str_radix(digits, 16) {
+                            Ok(value) => value,
+                            Err(_) => return Err(Error::Static("bad \\x escape")),
+                        }
+                    }
+                    Some(other) => other,
+                    None => return Err(Error::Static("trailing backslash")),
+                };
+                token.push(escaped);
+            }
+            b'"' | b'\'' if quote.is_none() => {
+                in_token = true;
+                quote = Some(byte);
+            }
+            _ if quote == Some(byte) => quote = None,
+            b' ' | b'\t' if quote.is_none() => {
+                if in_token {
+                    tokens.push(std::mem::take(&mut token));
+                    in_token = false;
+                }
+            }
+            _ => {
+                in_token = true;
+                token.push(byte);
+            }
+        }
+    }
+
+    if quote.is_some() {
+        return Err(Error::Static("unterminated quote"));
+    }
+    if in_token {
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+/** Formats bytes so they can be read back by `tokenize`. Plain words are left as they are,
+ * anything else is quoted with escapes */
+pub fn format_bytes(bytes: &[u8]) -> String {
+    let plain = !bytes.is_empty()
+        && bytes
+            .iter()
+            .all(|&b| b.is_ascii_graphic() && !matches!(b, b'"' | b'\'' | b'\\'));
+    if plain {
+        String::from_utf8(bytes.to_vec()).unwrap()
+    } else {
+        format!("\"{}\"", bytes.escape_ascii())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(tokens: Vec<Vec<u8>>) -> Vec<String> {
+        tokens
+            .into_iter()
+            .map(|token| String::from_utf8(token).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_tokenize_words_and_quotes() {
+        let tokens = tokenize("  set key 'two words' \"it's\" \"\" ").unwrap();
+        assert_eq!(strings(tokens), vec!["set", "key", "two words", "it's", ""]);
+    }
+
+    #[test]
+    fn test_tokenize_escapes() {
+        let tokens = tokenize(r#"a\ b "\x00\xff\n\"" x\\y"#).unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                b"a b".to_vec(),
+                vec![0, 0xff, b'\n', b'"'],
+                b"x\\y".to_vec()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tokenize_errors() {
+        assert!(tokenize("get \"key").is_err());
+        assert!(tokenize("get key\\").is_err());
+        assert!(tokenize("get \\x4").is_err());
+        assert!(tokenize("get \\xzz").is_err());
+    }
+
+    #[test]
+    fn test_format_bytes_round_trip() {
+        let cases: Vec<&[u8]> = vec![b"plain", b"", b"two words", b"quote\"s", &[0, 1, 0xff]];
+        for bytes in cases {
+            let formatted = format_bytes(bytes);
+            assert_eq!(tokenize(&formatted).unwrap(), vec![bytes.to_vec()]);
+        }
+        assert_eq!(format_bytes(b"plain"), "plain");
+        assert_eq!(format_bytes(&[0, b'a']), "\"\\x00a\"");
+    }
+}
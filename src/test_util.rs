@@ -0,0 +1,38 @@
+//! Helpers shared by the tests of several modules
+
+use std::fs;
+
+use crate::relational_db::{records::Record, tables::TableDef, value::Value, DB};
+
+/// A path under `test_run_dir` for a test database, removing what an earlier run left there
+pub fn test_file(path: &str) -> String {
+    fs::create_dir_all("test_run_dir").unwrap();
+    let file_name = format!("test_run_dir/{}", path);
+    fs::remove_file(&file_name).unwrap_or(());
+    file_name
+}
+
+/// An in-memory database with two tables, `first` and `second`, of `row`s keyed by id
+pub fn new_db() -> DB {
+    let mut db = DB::open_in_memory().unwrap();
+    for name in ["first", "second"] {
+        db.table_new(TableDef {
+            name: name.to_string(),
+            types: vec![Value::INT64_TYPE, Value::BYTES_TYPE],
+            columns: vec!["id".to_string(), "name".to_string()],
+            primary_keys: 1,
+            prefix: 0,
+        })
+        .unwrap();
+    }
+    db
+}
+
+/// A row of the tables of `new_db`
+pub fn row(id: i64, name: &str) -> Record {
+    let mut record = Record::new();
+    record
+        .add_int64("id".to_string(), id)
+        .add_bytes("name".to_string(), name.as_bytes().to_vec());
+    record
+}
@@ -0,0 +1,288 @@
+use std::collections::BTreeMap;
+
+use crate::prelude::*;
+use crate::{
+    kv_store::KV,
+    relational_db::{records::Record, DB},
+};
+
+use super::{scan, Worker, WorkerOptions};
+
+/// A `KV` served from its own thread, for use from async code
+pub type AsyncKV = Worker<KV>;
+/// A `DB` served from its own thread, for use from async code
+pub type AsyncDB = Worker<DB>;
+
+/// Reads and writes made inside `AsyncKV::transaction`. Reads see the transaction's own writes,
+/// which are only applied to the store if the transaction succeeds
+pub struct Tx<'a> {
+    kv: &'a KV,
+    /// Pending writes, `None` deletes the key
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl Tx<'_> {
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        match self.writes.get(key) {
+            Some(write) => write.clone(),
+            None => self.kv.get(key),
+        }
+    }
+
+    pub fn set(&mut self, key: &[u8], val: &[u8]) {
+        self.writes.insert(key.to_vec(), Some(val.to_vec()));
+    }
+
+    /** Deletes a key, returns whether it was there */
+    pub fn del(&mut self, key: &[u8]) -> bool {
+        let existed = self.get(key).is_some();
+        self.writes.insert(key.to_vec(), None);
+        existed
+    }
+}
+
+impl Worker<KV> {
+    pub fn open(kv: KV) -> AsyncKV {
+        Worker::spawn(kv, WorkerOptions::default())
+    }
+
+    pub async fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.run(move |kv| Ok(kv.get(&key))).await
+    }
+
+    pub async fn set(&self, key: Vec<u8>, val: Vec<u8>) -> Result<()> {
+        self.run(move |kv| kv.set(&key, &val)).await
+    }
+
+    /** Deletes a key, returns whether it was there */
+    pub async fn del(&self, key: Vec<u8>) -> Result<bool> {
+        self.run(move |kv| kv.del(&key)).await
+    }
+
+    /** Returns up to `limit` pairs in key order, starting from `start` and stopping before `end` */
+    pub async fn scan(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.run(move |kv| Ok(scan(kv, &start, end.as_deref(), limit)))
+            .await
+    }
+
+    /** Runs `f` on the worker thread with no other job in between. Its writes are applied and
+     * committed together if it succeeds, and dropped if it returns an error */
+    pub async fn transaction<R, F>(&self, f: F) -> Result<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut Tx) -> Result<R> + Send + 'static,
+    {
+        self.run(move |kv| {
+            let mut tx = Tx {
+                kv,
+                writes: BTreeMap::new(),
+            };
+            let result = f(&mut tx)?;
+            kv.apply_writes(tx.writes)?;
+            Ok(result)
+        })
+        .await
+    }
+}
+
+impl Worker<DB> {
+    pub fn open(db: DB) -> AsyncDB {
+        Worker::spawn(db, WorkerOptions::default())
+    }
+
+    /** Looks up the row with the primary key of `record`, see `DB::get` */
+    pub async fn get(&self, table: String, mut record: Record) -> Result<Option<Record>> {
+        self.run(move |db| Ok(db.get(&table, &mut record)?.then_some(record)))
+            .await
+    }
+
+    pub async fn insert(&self, table: String, record: Record) -> Result<bool> {
+        self.run(move |db| db.insert(&table, record)).await
+    }
+
+    pub async fn update(&self, table: String, record: Record) -> Result<bool> {
+        self.run(move |db| db.update(&table, record)).await
+    }
+
+    pub async fn upsert(&self, table: String, record: Record) -> Result<bool> {
+        self.run(move |db| db.upsert(&table, record)).await
+    }
+
+    pub async fn delete(&self, table: String, record: Record) -> Result<bool> {
+        self.run(move |db| db.delete(&table, record)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread, time::Duration};
+
+    use super::*;
+    use crate::relational_db::{tables::TableDef, value::Value};
+
+    #[tokio::test]
+    async fn test_async_kv() {
+        let kv = AsyncKV::open(KV::open_in_memory().unwrap());
+        for i in 0..10u8 {
+            kv.set(vec![b'k', i], vec![i]).await.unwrap();
+        }
+        assert_eq!(kv.get(vec![b'k', 3]).await.unwrap(), Some(vec![3]));
+        assert!(kv.del(vec![b'k', 3]).await.unwrap());
+        assert!(!kv.del(vec![b'k', 3]).await.unwrap());
+
+        let items = kv
+            .scan(vec![b'k', 2], Some(vec![b'k', 6]), 10)
+            .await
+            .unwrap();
+        let keys: Vec<u8> = items.iter().map(|(key, _)| key[1]).collect();
+        assert_eq!(keys, vec![2, 4, 5]);
+        assert_eq!(kv.scan(vec![], None, 4).await.unwrap().len(), 4);
+
+        let kv = kv.close().await.unwrap();
+        assert_eq!(kv.get(&[b'k', 9]).unwrap(), vec![9]);
+    }
+
+    #[tokio::test]
+    async fn test_transaction() {
+        let kv = AsyncKV::open(KV::open_in_memory().unwrap());
+        kv.set(b"a".to_vec(), b"1".to_vec()).await.unwrap();
+
+        let moved = kv
+            .transaction(|tx| {
+                let val = tx.get(b"a").unwrap();
+                assert!(tx.del(b"a"));
+                assert_eq!(tx.get(b"a"), None);
+                tx.set(b"b", &val);
+                Ok(val)
+            })
+            .await
+            .unwrap();
+        assert_eq!(moved, b"1");
+        assert_eq!(kv.get(b"a".to_vec()).await.unwrap(), None);
+        assert_eq!(kv.get(b"b".to_vec()).await.unwrap(), Some(b"1".to_vec()));
+
+        // a failed transaction leaves nothing behind
+        let failed: Result<()> = kv
+            .transaction(|tx| {
+                tx.set(b"c", b"3");
+                Err(Error::Static("abort"))
+            })
+            .await;
+        assert!(failed.is_err());
+        assert_eq!(kv.get(b"c".to_vec()).await.unwrap(), None);
+
+        // so does one with a write the store rejects
+        let failed = kv
+            .transaction(|tx| {
+                tx.set(b"c", b"3");
+                tx.set(b"d", &[b'v'; 3001]);
+                Ok(())
+            })
+            .await;
+        assert!(failed.is_err());
+        assert_eq!(kv.get(b"c".to_vec()).await.unwrap(), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_group_commit() {
+        let kv = Arc::new(AsyncKV::open(KV::open_in_memory().unwrap()));
+        let syncs = || {
+            let kv = kv.clone();
+            async move { kv.run(|kv| Ok(kv.stats().syncs)).await.unwrap() }
+        };
+        let before = syncs().await;
+
+        // hold the worker up so the writers below queue behind this job
+        let (started, running) = tokio::sync::oneshot::channel();
+        let blocker = tokio::spawn({
+            let kv = kv.clone();
+            async move {
+                kv.run(|_| {
+                    started.send(()).unwrap();
+                    thread::sleep(Duration::from_millis(100));
+                    Ok(())
+                })
+                .await
+            }
+        });
+        running.await.unwrap();
+        let writers: Vec<_> = (0..50u8)
+            .map(|i| {
+                let kv = kv.clone();
+                tokio::spawn(async move { kv.set(vec![i], vec![i]).await })
+            })
+            .collect();
+        blocker.await.unwrap().unwrap();
+        for writer in writers {
+            writer.await.unwrap().unwrap();
+        }
+
+        // 50 commits one by one would take 100 syncs
+        let used = syncs().await - before;
+        assert!(used < 20, "{} syncs", used);
+        for i in 0..50u8 {
+            assert_eq!(kv.get(vec![i]).await.unwrap(), Some(vec![i]));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_backpressure() {
+        let kv = Arc::new(Worker::spawn(
+            KV::open_in_memory().unwrap(),
+            WorkerOptions {
+                queue_size: 1,
+                max_batch: 1,
+            },
+        ));
+        let writers: Vec<_> = (0..20u8)
+            .map(|i| {
+                let kv = kv.clone();
+                tokio::spawn(async move { kv.set(vec![i], vec![i]).await })
+            })
+            .collect();
+        for writer in writers {
+            writer.await.unwrap().unwrap();
+        }
+        assert_eq!(kv.scan(vec![], None, 100).await.unwrap().len(), 20);
+    }
+
+    #[tokio::test]
+    async fn test_async_db() {
+        let mut db = DB::open_in_memory().unwrap();
+        db.table_new(TableDef {
+            name: "users".to_string(),
+            types: vec![Value::INT64_TYPE, Value::BYTES_TYPE],
+            columns: vec!["id".to_string(), "name".to_string()],
+            primary_keys: 1,
+            prefix: 0,
+        })
+        .unwrap();
+        let db = AsyncDB::open(db);
+
+        let mut record = Record::new();
+        record
+            .add_int64("id".to_string(), 1)
+            .add_bytes("name".to_string(), b"ada".to_vec());
+        assert!(db.insert("users".to_string(), record).await.unwrap());
+
+        let mut key = Record::new();
+        key.add_int64("id".to_string(), 1);
+        let found = db.get("users".to_string(), key.clone()).await.unwrap();
+        assert_eq!(
+            found.unwrap().get("name"),
+            Some(&Value::Bytes(Some(b"ada".to_vec())))
+        );
+        assert!(db.delete("users".to_string(), key.clone()).await.unwrap());
+        assert!(db.get("users".to_string(), key).await.unwrap().is_none());
+        assert!(db
+            .insert("missing".to_string(), Record::new())
+            .await
+            .is_err());
+        db.close().await.unwrap().close();
+    }
+}
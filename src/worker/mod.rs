@@ -0,0 +1,251 @@
+//! A store owned by a dedicated thread. Callers queue jobs, and the thread runs every job waiting
+//! in the queue before committing their writes together with one pair of syncs. `AsyncKV` and
+//! `AsyncDB` serve async callers, `WriteQueue` serves plain threads.
+
+pub mod async_api;
+pub mod queue;
+
+use std::{
+    any::Any,
+    panic::{self, AssertUnwindSafe},
+    thread,
+};
+
+use tokio::sync::{mpsc, oneshot};
+
+use crate::prelude::*;
+use crate::{
+    kv_store::{self, CmpOption, KV},
+    relational_db::{mvcc, DB},
+};
+
+pub use async_api::{AsyncDB, AsyncKV, Tx};
+pub use queue::WriteQueue;
+
+/// Something a worker can own, backed by a `KV` whose commits it batches
+pub trait Store: Send + 'static {
+    /// What a failed job is rolled back to
+    type Savepoint;
+
+    fn kv_mut(&mut self) -> &mut KV;
+    fn savepoint(&self) -> Self::Savepoint;
+    fn rollback(&mut self, savepoint: Self::Savepoint);
+}
+
+impl Store for KV {
+    type Savepoint = kv_store::Savepoint;
+
+    fn kv_mut(&mut self) -> &mut KV {
+        self
+    }
+
+    fn savepoint(&self) -> Self::Savepoint {
+        KV::savepoint(self)
+    }
+
+    fn rollback(&mut self, savepoint: Self::Savepoint) {
+        KV::rollback(self, savepoint)
+    }
+}
+
+impl Store for DB {
+    type Savepoint = mvcc::Savepoint;
+
+    fn kv_mut(&mut self) -> &mut KV {
+        DB::kv_mut(self)
+    }
+
+    fn savepoint(&self) -> Self::Savepoint {
+        DB::savepoint(self)
+    }
+
+    fn rollback(&mut self, savepoint: Self::Savepoint) {
+        DB::rollback(self, savepoint)
+    }
+}
+
+/// Hands the result of a job back once the batch it ran in is committed
+type Reply = Box<dyn FnOnce(&Result<()>) + Send>;
+/// Runs against the store on the worker thread
+type Job<S> = Box<dyn FnOnce(&mut S) -> Reply + Send>;
+
+#[derive(Debug, Clone)]
+pub struct WorkerOptions {
+    /// Jobs that can wait in the queue. Callers wait for room once it is full
+    pub queue_size: usize,
+    /// Most jobs committed together
+    pub max_batch: usize,
+}
+
+impl Default for WorkerOptions {
+    fn default() -> Self {
+        Self {
+            queue_size: 1024,
+            max_batch: 256,
+        }
+    }
+}
+
+pub struct Worker<S: Store> {
+    jobs: mpsc::Sender<Job<S>>,
+    /// Gives the store back once the thread is done with it
+    stopped: oneshot::Receiver<S>,
+}
+
+impl<S: Store> Worker<S> {
+    /** Moves `store` to a new thread that serves the jobs sent to this worker */
+    pub fn spawn(store: S, options: WorkerOptions) -> Worker<S> {
+        assert!(options.queue_size >= 1 && options.max_batch >= 1);
+        let (jobs, queue) = mpsc::channel(options.queue_size);
+        let (stop, stopped) = oneshot::channel();
+        thread::spawn(move || {
+            let store = serve(store, queue, options.max_batch);
+            let _ = stop.send(store);
+        });
+        Worker { jobs, stopped }
+    }
+
+    /** Wraps `f` as a job. Its result arrives on the receiver once the writes it made are
+     * durable, or as an error if committing them failed. The writes of a job that fails are
+     * dropped, the other jobs of the batch are still committed */
+    fn job<R, F>(f: F) -> (Job<S>, oneshot::Receiver<Result<R>>)
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut S) -> Result<R> + Send + 'static,
+    {
+        let (send, receive) = oneshot::channel();
+        let job: Job<S> = Box::new(move |store| {
+            let savepoint = store.savepoint();
+            // a job that panics fails on its own instead of taking the worker thread down
+            let result = match panic::catch_unwind(AssertUnwindSafe(|| f(store))) {
+                Ok(result) => result,
+                Err(payload) => Err(Error::Generic(format!(
+                    "job panicked: {}",
+                    panic_message(&*payload)
+                ))),
+            };
+            if result.is_err() {
+                store.rollback(savepoint);
+            }
+            Box::new(move |commit| {
+                let result = match (result, commit) {
+                    (Ok(_), Err(err)) => Err(Error::Generic(format!("commit failed: {}", err))),
+                    (result, _) => result,
+                };
+                // the caller may have given up waiting
+                let _ = send.send(result);
+            })
+        });
+        (job, receive)
+    }
+
+    /** Runs `f` on the worker thread, returning once its writes are durable. Waits for room
+     * when the queue is full */
+    pub async fn run<R, F>(&self, f: F) -> Result<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut S) -> Result<R> + Send + 'static,
+    {
+        let (job, result) = Self::job(f);
+        if self.jobs.send(job).await.is_err() {
+            return Err(Error::Static("the worker has stopped"));
+        }
+        match result.await {
+            Ok(result) => result,
+            Err(_) => Err(Error::Static("the worker has stopped")),
+        }
+    }
+
+    /** Stops the thread once the jobs already queued are done, and returns the store */
+    pub async fn close(self) -> Result<S> {
+        drop(self.jobs);
+        self.stopped
+            .await
+            .map_err(|_| Error::Static("the worker has stopped"))
+    }
+
+    /** Like `run`, blocking the calling thread instead. Panics if called from async code */
+    pub fn run_blocking<R, F>(&self, f: F) -> Result<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut S) -> Result<R> + Send + 'static,
+    {
+        let (job, result) = Self::job(f);
+        if self.jobs.blocking_send(job).is_err() {
+            return Err(Error::Static("the worker has stopped"));
+        }
+        match result.blocking_recv() {
+            Ok(result) => result,
+            Err(_) => Err(Error::Static("the worker has stopped")),
+        }
+    }
+
+    /** Like `close`, blocking the calling thread instead */
+    pub fn close_blocking(self) -> Result<S> {
+        drop(self.jobs);
+        self.stopped
+            .blocking_recv()
+            .map_err(|_| Error::Static("the worker has stopped"))
+    }
+}
+
+/** The message a panic was raised with */
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown cause"
+    }
+}
+
+/** Returns up to `limit` pairs in key order, starting from `start` and stopping before `end` */
+pub(crate) fn scan(
+    kv: &KV,
+    start: &[u8],
+    end: Option<&[u8]>,
+    limit: usize,
+) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let mut items = Vec::new();
+    let mut iter = kv.seek(start, CmpOption::GE);
+    while iter.valid() && items.len() < limit {
+        let (key, val) = iter.deref();
+        if end.is_some_and(|end| key.as_slice() >= end) {
+            break;
+        }
+        items.push((key, val));
+        if !iter.next() {
+            break;
+        }
+    }
+    items
+}
+
+/** The worker thread. Takes every job waiting in the queue, up to `max_batch`, runs them and
+ * commits their writes together. A batch that only read commits nothing */
+fn serve<S: Store>(mut store: S, mut queue: mpsc::Receiver<Job<S>>, max_batch: usize) -> S {
+    let mut batch = Vec::with_capacity(max_batch);
+    while let Some(job) = queue.blocking_recv() {
+        batch.push(job);
+        while batch.len() < max_batch {
+            match queue.try_recv() {
+                Ok(job) => batch.push(job),
+                Err(_) => break,
+            }
+        }
+
+        store.kv_mut().begin_batch();
+        let start = store.savepoint();
+        let replies: Vec<Reply> = batch.drain(..).map(|job| job(&mut store)).collect();
+        let commit = store.kv_mut().commit_batch();
+        if commit.is_err() {
+            // the store keeps no trace of the jobs, like after a crash
+            store.rollback(start);
+        }
+        for reply in replies {
+            reply(&commit);
+        }
+    }
+    store
+}
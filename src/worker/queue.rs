@@ -0,0 +1,206 @@
+use std::time::Duration;
+
+use crate::kv_store::{InsertMode, KV};
+use crate::prelude::*;
+
+use super::{scan, Worker, WorkerOptions};
+
+/** A queue of writers in front of a `KV`, for use from plain threads. Writes that arrive while a
+ * commit is in progress are applied together and committed with one pair of syncs, and each
+ * caller returns once its own write is durable. Share it between threads by reference or `Arc` */
+pub struct WriteQueue {
+    worker: Worker<KV>,
+}
+
+impl WriteQueue {
+    pub fn new(kv: KV) -> WriteQueue {
+        Self::with_options(kv, WorkerOptions::default())
+    }
+
+    pub fn with_options(kv: KV, options: WorkerOptions) -> WriteQueue {
+        WriteQueue {
+            worker: Worker::spawn(kv, options),
+        }
+    }
+
+    /** Reads go through the queue too, so they see every write acknowledged before them */
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let key = key.to_vec();
+        self.worker.run_blocking(move |kv| Ok(kv.get(&key)))
+    }
+
+    pub fn set(&self, key: &[u8], val: &[u8]) -> Result<()> {
+        let (key, val) = (key.to_vec(), val.to_vec());
+        self.worker.run_blocking(move |kv| kv.set(&key, &val))
+    }
+
+    /** See `KV::set_with_ttl` */
+    pub fn set_with_ttl(&self, key: &[u8], val: &[u8], ttl: Duration) -> Result<()> {
+        let (key, val) = (key.to_vec(), val.to_vec());
+        self.worker
+            .run_blocking(move |kv| kv.set_with_ttl(&key, &val, ttl))
+    }
+
+    /** Removes the expired entries, see `KV::sweep_expired`. Calling it from a thread of its own
+     * now and then keeps them from piling up */
+    pub fn sweep_expired(&self) -> Result<usize> {
+        self.worker.run_blocking(|kv| kv.sweep_expired())
+    }
+
+    /** Deletes a key, returns whether it was there */
+    pub fn del(&self, key: &[u8]) -> Result<bool> {
+        let key = key.to_vec();
+        self.worker.run_blocking(move |kv| kv.del(&key))
+    }
+
+    /** Returns up to `limit` pairs in key order, starting from `start` and stopping before `end` */
+    pub fn scan(
+        &self,
+        start: &[u8],
+        end: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let (start, end) = (start.to_vec(), end.map(<[u8]>::to_vec));
+        self.worker
+            .run_blocking(move |kv| Ok(scan(kv, &start, end.as_deref(), limit)))
+    }
+
+    /** See `KV::update` */
+    pub fn update(&self, key: &[u8], val: &[u8], mode: InsertMode) -> Result<bool> {
+        let (key, val) = (key.to_vec(), val.to_vec());
+        self.worker
+            .run_blocking(move |kv| kv.update(&key, &val, mode))
+    }
+
+    /** Runs `f` against the store with no other write in between, returning once its writes are
+     * durable */
+    pub fn run<R, F>(&self, f: F) -> Result<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut KV) -> Result<R> + Send + 'static,
+    {
+        self.worker.run_blocking(f)
+    }
+
+    /** Waits for the queued writes and returns the store */
+    pub fn close(self) -> Result<KV> {
+        self.worker.close_blocking()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::mpsc, thread};
+
+    use super::*;
+    use crate::free_list::faulty::{Fault, FaultyDisk};
+
+    #[test]
+    fn test_write_queue() {
+        let queue = WriteQueue::new(KV::open_in_memory().unwrap());
+        queue.set(b"a", b"1").unwrap();
+        assert!(!queue.update(b"a", b"2", InsertMode::InsertOnly).unwrap());
+        assert_eq!(queue.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert!(queue.del(b"a").unwrap());
+        assert_eq!(queue.get(b"a").unwrap(), None);
+        queue.set(b"c", b"3").unwrap();
+        queue.set(b"d", b"4").unwrap();
+        assert_eq!(queue.scan(b"", Some(b"d"), 10).unwrap().len(), 1);
+        queue.set_with_ttl(b"e", b"5", Duration::ZERO).unwrap();
+        assert_eq!(queue.get(b"e").unwrap(), None);
+        assert_eq!(queue.sweep_expired().unwrap(), 1);
+
+        // a failing job does not affect the ones committed with it, and its own writes are dropped
+        let failed: Result<()> = queue.run(|kv| {
+            kv.set(b"x", b"1")?;
+            Err(Error::Static("abort"))
+        });
+        assert!(failed.is_err());
+        queue.set(b"b", b"2").unwrap();
+        assert_eq!(queue.get(b"x").unwrap(), None);
+        let kv = queue.close().unwrap();
+        assert_eq!(kv.get(b"b").unwrap(), b"2");
+    }
+
+    #[test]
+    fn test_writers_share_commits() {
+        let queue = WriteQueue::new(KV::open_in_memory().unwrap());
+        let before = queue.run(|kv| Ok(kv.stats().syncs)).unwrap();
+
+        thread::scope(|scope| {
+            // hold the queue up so the writers below arrive while it is busy
+            let (started, running) = mpsc::channel();
+            scope.spawn(|| {
+                queue
+                    .run(move |_| {
+                        started.send(()).unwrap();
+                        thread::sleep(Duration::from_millis(100));
+                        Ok(())
+                    })
+                    .unwrap()
+            });
+            running.recv().unwrap();
+            for i in 0..50u8 {
+                let queue = &queue;
+                scope.spawn(move || queue.set(&[i], &[i]).unwrap());
+            }
+        });
+
+        // 50 commits one by one would take 100 syncs
+        let used = queue.run(|kv| Ok(kv.stats().syncs)).unwrap() - before;
+        assert!(used < 20, "{} syncs", used);
+        for i in 0..50u8 {
+            assert_eq!(queue.get(&[i]).unwrap(), Some(vec![i]));
+        }
+    }
+
+    #[test]
+    fn test_failed_commit_reaches_every_writer() {
+        let disk = FaultyDisk::new();
+        let queue = WriteQueue::new(KV::open_storage(disk.storage()).unwrap());
+        queue.set(b"a", b"1").unwrap();
+
+        disk.inject(Fault::Call(0));
+        let err = queue.set(b"b", b"2").unwrap_err();
+        assert!(err.to_string().contains("commit failed"), "{}", err);
+        assert!(queue.del(b"a").is_err());
+        // the tree in memory went back to the last commit, reads commit nothing so they still work
+        assert_eq!(queue.get(b"a").unwrap().unwrap(), b"1");
+        assert!(queue.get(b"b").unwrap().is_none());
+
+        // only the acknowledged write survives
+        let kv = KV::open_storage(disk.restart(false).storage()).unwrap();
+        assert_eq!(kv.get(b"a").unwrap(), b"1");
+        assert!(kv.get(b"b").is_none());
+    }
+
+    #[test]
+    fn test_worker_survives_a_panicking_job() {
+        let queue = WriteQueue::new(KV::open_in_memory().unwrap());
+        assert!(queue.set(&[b'k'; 1001], b"v").is_err());
+        let err = queue
+            .run(|kv| -> Result<()> {
+                kv.set(b"x", b"1")?;
+                panic!("broken job")
+            })
+            .unwrap_err();
+        assert!(err.to_string().contains("broken job"), "{}", err);
+
+        queue.set(b"a", b"1").unwrap();
+        assert_eq!(queue.get(b"a").unwrap().unwrap(), b"1");
+        assert!(queue.get(b"x").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_reads_commit_nothing() {
+        let queue = WriteQueue::new(KV::open_in_memory().unwrap());
+        queue.set(b"a", b"1").unwrap();
+        let before = queue.run(|kv| Ok(kv.stats().syncs)).unwrap();
+        for _ in 0..10 {
+            queue.get(b"a").unwrap();
+            queue.scan(b"", None, 10).unwrap();
+        }
+        assert!(!queue.del(b"missing").unwrap());
+        assert_eq!(queue.run(|kv| Ok(kv.stats().syncs)).unwrap(), before);
+    }
+}